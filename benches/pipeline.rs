@@ -0,0 +1,150 @@
+//! Benchmarks of the streaming add_piece pipeline.
+//!
+//! ```text
+//! cargo bench --features bench
+//! cargo bench --features bench,sha2-asm
+//! ```
+
+use std::io::{self, Cursor};
+
+use add_piece::bench::{write_zeros, ChunksReader, CommitmentReader};
+use add_piece::committer::CommitterBackend;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use filecoin_proofs::{PaddedBytesAmount, UnpaddedBytesAmount};
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+/// Padded piece sizes exercised by the benchmarks.
+const PIECE_SIZES: [u64; 4] = [2 * KIB, MIB, 8 * MIB, 32 * MIB];
+
+fn payload(padded: u64) -> Vec<u8> {
+    let unpadded: UnpaddedBytesAmount = PaddedBytesAmount(padded).into();
+    (0..u64::from(unpadded)).map(|i| (i * 31) as u8).collect()
+}
+
+/// Padded bytes are fed to the readers as-is, only their hashing is measured.
+fn padded(size: u64) -> Vec<u8> {
+    (0..size).map(|i| (i * 31) as u8 & 0x3f).collect()
+}
+
+fn commitment_reader(c: &mut Criterion) {
+    let mut group = c.benchmark_group("commitment_reader");
+    group.sample_size(10);
+    for size in [MIB, 32 * MIB] {
+        let data = padded(size);
+        group.throughput(Throughput::Bytes(size));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| {
+                let mut reader = CommitmentReader::new(Cursor::new(data));
+                io::copy(&mut reader, &mut io::sink()).expect("io copy failed");
+                reader.compute()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn chunks_reader(c: &mut Criterion) {
+    let size = 32 * MIB;
+    let data = padded(size);
+
+    let mut group = c.benchmark_group("chunks_reader");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(size));
+    for chunk_size in [MIB, 8 * MIB, 32 * MIB] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(chunk_size),
+            &chunk_size,
+            |b, &chunk_size| {
+                b.iter(|| {
+                    let mut reader = ChunksReader::new(chunk_size as usize, Cursor::new(&data));
+                    io::copy(&mut reader, &mut io::sink()).expect("io copy failed");
+                    reader.finish().expect("finish failed")
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn committers(c: &mut Criterion) {
+    let size = 32 * MIB;
+    let data = padded(size);
+
+    let mut group = c.benchmark_group("committers");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(size));
+    for backend in [CommitterBackend::Streaming, CommitterBackend::Parallel] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", backend)),
+            &backend,
+            |b, &backend| {
+                b.iter(|| {
+                    let mut committer = backend.committer();
+                    for piece in data.chunks(64 * KIB as usize) {
+                        committer.update(piece);
+                    }
+                    committer.finalize()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn alignment_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("alignment_writes");
+    for size in [MIB, 64 * MIB] {
+        group.throughput(Throughput::Bytes(size));
+        // written to memory, io::sink would let the copy be skipped entirely
+        let mut target = Vec::with_capacity(size as usize);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| {
+                target.clear();
+                write_zeros(&mut target, PaddedBytesAmount(size))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn add_piece(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add_piece");
+    group.sample_size(10);
+    for size in PIECE_SIZES {
+        let source = payload(size);
+        let piece_size = UnpaddedBytesAmount(source.len() as u64);
+        group.throughput(Throughput::Bytes(size));
+
+        group.bench_with_input(BenchmarkId::new("stream", size), &source, |b, source| {
+            b.iter(|| {
+                add_piece::add_piece(&source[..], io::sink(), piece_size, &[])
+                    .expect("add_piece failed")
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("slice", size), &source, |b, source| {
+            b.iter(|| {
+                add_piece::add_piece_from_slice(source, io::sink(), piece_size, &[])
+                    .expect("add_piece_from_slice failed")
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("pad_only", size), &source, |b, source| {
+            b.iter(|| {
+                add_piece::pad_only(&source[..], io::sink(), piece_size, &[])
+                    .expect("pad_only failed")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    commitment_reader,
+    chunks_reader,
+    committers,
+    alignment_writes,
+    add_piece
+);
+criterion_main!(benches);
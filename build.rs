@@ -0,0 +1,28 @@
+//! Records the version of filecoin-proofs the crate is built against, for the
+//! provenance of the manifests.
+
+use std::{env, fs, path::Path};
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("set by cargo");
+    let lock = Path::new(&manifest_dir).join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock.display());
+
+    let version = fs::read_to_string(&lock)
+        .ok()
+        .and_then(|lock| locked_version(&lock, "filecoin-proofs"))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=FILECOIN_PROOFS_VERSION={}", version);
+}
+
+/// Version of the package `name` in the content of a `Cargo.lock`.
+fn locked_version(lock: &str, name: &str) -> Option<String> {
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line.trim() == format!("name = \"{}\"", name) {
+            let version = lines.next()?.trim().strip_prefix("version = \"")?;
+            return Some(version.trim_end_matches('"').to_string());
+        }
+    }
+    None
+}
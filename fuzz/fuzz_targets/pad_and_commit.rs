@@ -0,0 +1,34 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use add_piece::{pure, unpad::read_unpadded};
+use filecoin_proofs::UnpaddedBytesAmount;
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks a piece size between 127 bytes and 16KiB, the rest is
+// the payload, zero extended or truncated to that size.
+fuzz_target!(|data: &[u8]| {
+    let Some((size, payload)) = data.split_first() else {
+        return;
+    };
+    let piece_size = 127usize << (size % 8);
+    let mut payload = payload[..payload.len().min(piece_size)].to_vec();
+    payload.resize(piece_size, 0);
+
+    let (padded, piece_info) = pure::pad_and_commit(&payload).expect("pad_and_commit failed");
+
+    let mut expected = Vec::new();
+    let (expected_info, _) = filecoin_proofs::add_piece(
+        &payload[..],
+        &mut expected,
+        UnpaddedBytesAmount(piece_size as u64),
+        &[],
+    )
+    .expect("filecoin_proofs::add_piece failed");
+    assert_eq!(padded, expected);
+    assert_eq!(piece_info, expected_info);
+
+    let unpadded = read_unpadded(Cursor::new(&padded), 0, 0, piece_size).expect("unpad failed");
+    assert_eq!(unpadded, payload);
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use add_piece::pure;
+use filecoin_proofs::{pieces::sum_piece_bytes_with_alignment, UnpaddedBytesAmount};
+use libfuzzer_sys::fuzz_target;
+
+// Each byte is a piece of 127 << (byte % 24) unpadded bytes, up to 1GiB padded.
+fuzz_target!(|data: &[u8]| {
+    let sizes: Vec<_> = data
+        .iter()
+        .map(|b| UnpaddedBytesAmount(127 << (b % 24)))
+        .collect();
+
+    let placements = pure::plan_pieces(&sizes).expect("plan_pieces failed");
+    assert_eq!(placements.len(), sizes.len());
+
+    let mut end = 0;
+    for (i, placement) in placements.iter().enumerate() {
+        let (offset, size) = (u64::from(placement.offset), u64::from(placement.size));
+        assert_eq!(offset, end + u64::from(placement.left));
+        assert_eq!(offset % size, 0, "piece {} is not aligned", i);
+        end = u64::from(placement.end());
+
+        let expected = sum_piece_bytes_with_alignment(&sizes[..=i]);
+        assert_eq!(placement.end(), expected.into());
+    }
+});
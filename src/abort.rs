@@ -0,0 +1,103 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// Out of band cancellation of the standalone commands, for schedulers which
+/// can only talk to workers through the filesystem.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AbortConfig {
+    /// The running command aborts once this file exists, the file is removed
+    /// so that it doesn't abort the next command.
+    pub file: Option<PathBuf>,
+    /// The running command aborts once a line is written to this named pipe.
+    pub fifo: Option<PathBuf>,
+    #[serde(default = "default_poll_ms")]
+    pub poll_ms: u64,
+}
+
+fn default_poll_ms() -> u64 {
+    500
+}
+
+static ABORTED: AtomicBool = AtomicBool::new(false);
+static REASON: OnceLock<String> = OnceLock::new();
+
+fn abort(reason: String) {
+    warn!(%reason, "aborting");
+    let _ = REASON.set(reason);
+    ABORTED.store(true, Ordering::Release);
+}
+
+/// Starts watching for the abort file and pipe, if configured.
+pub fn watch(config: Option<&AbortConfig>) {
+    let config = match config {
+        Some(c) => c.clone(),
+        None => return,
+    };
+
+    if let Some(file) = config.file {
+        let poll = Duration::from_millis(config.poll_ms.max(1));
+        thread::spawn(move || loop {
+            if file.exists() {
+                if let Err(e) = fs::remove_file(&file) {
+                    warn!(err = ?e, file = %file.display(), "failed to remove abort file");
+                }
+                abort(format!("abort file {} appeared", file.display()));
+                return;
+            }
+            thread::sleep(poll);
+        });
+    }
+
+    if let Some(fifo) = config.fifo {
+        thread::spawn(move || loop {
+            // blocks until a writer opens the pipe, which is reopened after
+            // writers that closed it without a message
+            let pipe = match fs::File::open(&fifo) {
+                Ok(pipe) => pipe,
+                Err(e) => {
+                    warn!(err = ?e, fifo = %fifo.display(), "failed to open abort pipe");
+                    return;
+                }
+            };
+            for line in BufReader::new(pipe).lines() {
+                match line {
+                    Ok(line) if !line.trim().is_empty() => {
+                        abort(format!(
+                            "abort requested through {}: {}",
+                            fifo.display(),
+                            line.trim()
+                        ));
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        debug!(err = ?e, "abort pipe read failed");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Fails once the command was asked to abort.
+pub fn check() -> io::Result<()> {
+    if !ABORTED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    let reason = REASON.get().map_or("aborted", String::as_str);
+    Err(io::Error::other(reason.to_string()))
+}
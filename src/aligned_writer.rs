@@ -0,0 +1,128 @@
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// How the padded bytes of a piece are written to the target.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlignedWriterConfig {
+    /// Size of each write issued to the target, only the last write of a
+    /// piece may be shorter.
+    pub write_size: usize,
+    /// Flush the target every time this many bytes have been written to it,
+    /// the target is only flushed once the piece is complete if unset.
+    pub flush_interval: Option<u64>,
+}
+
+impl Default for AlignedWriterConfig {
+    fn default() -> Self {
+        Self {
+            write_size: 64 << 20,
+            flush_interval: None,
+        }
+    }
+}
+
+/// Buffers writes so that the target receives writes of exactly
+/// `write_size` bytes, and flushes it at a fixed interval.
+///
+/// Unlike `BufWriter`, large writes are not passed through, keeping every
+/// write aligned to `write_size` relative to the first byte written.
+pub struct AlignedWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    write_size: usize,
+    flush_interval: Option<u64>,
+    unflushed: u64,
+}
+
+impl<W: Write> AlignedWriter<W> {
+    pub fn new(inner: W, config: AlignedWriterConfig) -> Self {
+        let write_size = config.write_size.max(1);
+        Self {
+            inner,
+            buf: Vec::with_capacity(write_size),
+            write_size,
+            flush_interval: config.flush_interval.filter(|n| *n > 0),
+            unflushed: 0,
+        }
+    }
+
+    fn write_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        self.inner.write_all(&self.buf)?;
+        self.unflushed += self.buf.len() as u64;
+        self.buf.clear();
+
+        if let Some(interval) = self.flush_interval {
+            if self.unflushed >= interval {
+                self.inner.flush()?;
+                self.unflushed = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for AlignedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.write_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == self.write_size {
+            self.write_buffered()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffered()?;
+        self.inner.flush()?;
+        self.unflushed = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<usize>,
+        flushes: Vec<usize>,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.push(self.writes.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_aligned_writer() {
+        let mut recorder = Recorder::default();
+        let mut writer = AlignedWriter::new(
+            &mut recorder,
+            AlignedWriterConfig {
+                write_size: 4,
+                flush_interval: Some(8),
+            },
+        );
+
+        writer.write_all(&[0u8; 3]).expect("write failed");
+        writer.write_all(&[0u8; 10]).expect("write failed");
+        writer.flush().expect("flush failed");
+
+        assert_eq!(recorder.writes, vec![4, 4, 4, 1]);
+        assert_eq!(recorder.flushes, vec![2, 4]);
+    }
+}
@@ -0,0 +1,450 @@
+//! Cold storage of completed staged files as seekable zstd archives, and
+//! their restoration.
+//!
+//! An archive is a skippable frame holding the manifest and the length and
+//! blake3 hash of the staged file, then the staged file compressed as
+//! independent zstd frames of `FRAME_SIZE` bytes, then the seek table of the
+//! zstd seekable format. Any zstd decoder decompresses it to the staged file,
+//! and tools reading the seekable format can get at a range of it without
+//! decompressing what lies before.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use add_piece::{manifest::Manifest, staged_format::Header};
+use anyhow::{anyhow, ensure, Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::staging;
+
+/// Staged bytes compressed in each frame, the granularity of seeks.
+const FRAME_SIZE: usize = 4 << 20;
+
+/// Magic of the skippable frame holding the `ArchiveHeader`.
+const HEADER_MAGIC: u32 = 0x184D_2A50;
+/// Magic of the skippable frame holding the seek table.
+const SEEK_TABLE_MAGIC: u32 = 0x184D_2A5E;
+/// Magic ending the seek table.
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+/// Bytes of the footer of the seek table: frame count, descriptor, magic.
+const SEEK_FOOTER_LEN: usize = 9;
+
+/// What an archive records of its staged file.
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveHeader {
+    version: u32,
+    staged_len: u64,
+    /// Hex encoded blake3 hash of the staged file.
+    blake3: String,
+    /// The manifest of the staged file, or its v2 piece table.
+    manifest: Manifest,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchiveReport {
+    pub archive: PathBuf,
+    pub staged_len: u64,
+    pub archive_len: u64,
+    pub frames: usize,
+    pub blake3: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreReport {
+    pub staged: PathBuf,
+    pub staged_len: u64,
+    pub pieces: usize,
+    pub blake3: String,
+}
+
+/// The default archive of the staged file `staged`, `<staged>.zst`.
+pub fn path_for(staged: &Path) -> PathBuf {
+    let mut path = OsString::from(staged.as_os_str());
+    path.push(".zst");
+    PathBuf::from(path)
+}
+
+/// Compresses the completed staged file `staged` into the archive `dest`,
+/// which must not exist, at the zstd `level`.
+///
+/// The archive is written as `<dest>.partial` and only renamed once synced.
+pub fn archive(staged: &Path, dest: &Path, level: i32) -> Result<ArchiveReport> {
+    ensure!(!dest.exists(), "{} already exists", dest.display());
+    let manifest = staging::load_manifest(staged)?
+        .ok_or_else(|| anyhow!("no manifest found for {}", staged.display()))?;
+    let src = fs::File::open(staged)
+        .with_context(|| format!("open staged file: {}", staged.display()))?;
+    let staged_len = src.metadata().context("stat staged file")?.len();
+    let data_offset = Header::read_from(&src)?.map_or(0, |h| h.data_offset);
+    ensure!(
+        manifest
+            .provenance
+            .as_ref()
+            .is_none_or(|p| p.finished_at.is_some()),
+        "{} is still being staged",
+        staged.display()
+    );
+    ensure!(
+        staged_len >= data_offset + manifest.end(),
+        "{} is shorter than its manifest",
+        staged.display()
+    );
+
+    // hashed first, the header precedes the frames
+    let blake3 = hash_file(&src)?;
+    let partial = partial_path(dest);
+    let out =
+        fs::File::create(&partial).with_context(|| format!("create {}", partial.display()))?;
+    let header = ArchiveHeader {
+        version: 1,
+        staged_len,
+        blake3: blake3.clone(),
+        manifest,
+    };
+    let (frames, archive_len) = match compress(src, &header, level, out) {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial, dest).with_context(|| format!("rename to {}", dest.display()))?;
+    info!(
+        archive = %dest.display(),
+        staged_len,
+        archive_len,
+        "staged file archived"
+    );
+    Ok(ArchiveReport {
+        archive: dest.to_path_buf(),
+        staged_len,
+        archive_len,
+        frames,
+        blake3,
+    })
+}
+
+/// Decompresses the archive `archive` into the staged file `dest`, which
+/// must not exist, checking its frames against the seek table and its bytes
+/// against the recorded length and hash, and writes the manifest next to
+/// it.
+pub fn restore(archive: &Path, dest: &Path) -> Result<RestoreReport> {
+    ensure!(!dest.exists(), "{} already exists", dest.display());
+    let file =
+        fs::File::open(archive).with_context(|| format!("open archive: {}", archive.display()))?;
+    let seek_table = read_seek_table(&file)?;
+    let mut src = BufReader::new(file);
+
+    let header = read_skippable(&mut src, HEADER_MAGIC)?
+        .ok_or_else(|| anyhow!("{} is not an archive of a staged file", archive.display()))?;
+    let header: ArchiveHeader = serde_json::from_slice(&header).context("parse archive header")?;
+    ensure!(
+        header.version == 1,
+        "unsupported archive version {}",
+        header.version
+    );
+
+    let partial = partial_path(dest);
+    let out =
+        fs::File::create(&partial).with_context(|| format!("create {}", partial.display()))?;
+    let res = decompress(&mut src, &seek_table, out).and_then(|(staged_len, blake3)| {
+        ensure!(
+            staged_len == header.staged_len,
+            "restored {} bytes, {} were archived",
+            staged_len,
+            header.staged_len
+        );
+        ensure!(
+            blake3 == header.blake3,
+            "restored staged file hashes to {}, {} was archived",
+            blake3,
+            header.blake3
+        );
+        Ok((staged_len, blake3))
+    });
+    let (staged_len, blake3) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+
+    fs::rename(&partial, dest).with_context(|| format!("rename to {}", dest.display()))?;
+    header.manifest.save(dest)?;
+    info!(staged = %dest.display(), staged_len, "staged file restored");
+    Ok(RestoreReport {
+        staged: dest.to_path_buf(),
+        staged_len,
+        pieces: header.manifest.pieces.len(),
+        blake3,
+    })
+}
+
+/// Decompresses the frames of `seek_table` from `src` into `out`, returning
+/// the bytes written and their hash once synced.
+fn decompress(
+    src: &mut impl Read,
+    seek_table: &[(u32, u32)],
+    out: fs::File,
+) -> Result<(u64, String)> {
+    let mut out = BufWriter::new(out);
+    let mut hasher = blake3::Hasher::new();
+    let mut staged_len = 0;
+    for (index, &(compressed, decompressed)) in seek_table.iter().enumerate() {
+        let mut frame = vec![0; compressed as usize];
+        src.read_exact(&mut frame)
+            .with_context(|| format!("read frame {}", index))?;
+        let data = zstd::bulk::decompress(&frame, decompressed as usize)
+            .with_context(|| format!("decompress frame {}", index))?;
+        ensure!(
+            data.len() == decompressed as usize,
+            "frame {} holds {} bytes, the seek table says {}",
+            index,
+            data.len(),
+            decompressed
+        );
+        hasher.update(&data);
+        out.write_all(&data).context("write staged file")?;
+        staged_len += data.len() as u64;
+    }
+    let out = out.into_inner().map_err(|e| e.into_error())?;
+    out.sync_all().context("sync staged file")?;
+    Ok((staged_len, hasher.finalize().to_hex().to_string()))
+}
+
+fn partial_path(dest: &Path) -> PathBuf {
+    let mut partial = OsString::from(dest.as_os_str());
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+fn hash_file(mut file: &fs::File) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    file.seek(SeekFrom::Start(0))
+        .context("rewind staged file")?;
+    io::copy(&mut BufReader::new(file), &mut hasher).context("hash staged file")?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Writes `header` then `src` compressed at `level` to `out`, returning the
+/// frames written and the length of the archive once synced.
+fn compress(
+    mut src: fs::File,
+    header: &ArchiveHeader,
+    level: i32,
+    out: fs::File,
+) -> Result<(usize, u64)> {
+    src.seek(SeekFrom::Start(0)).context("rewind staged file")?;
+    let mut out = BufWriter::new(out);
+    let header = serde_json::to_vec(header).context("serialize archive header")?;
+    write_skippable(&mut out, HEADER_MAGIC, &header)?;
+
+    // batches of frames compressed in parallel, written in order
+    let batch = rayon::current_num_threads().max(1);
+    let mut src = BufReader::new(src);
+    let mut seek_table = Vec::new();
+    loop {
+        let mut chunks = Vec::with_capacity(batch);
+        for _ in 0..batch {
+            let mut chunk = Vec::with_capacity(FRAME_SIZE);
+            (&mut src)
+                .take(FRAME_SIZE as u64)
+                .read_to_end(&mut chunk)
+                .context("read staged file")?;
+            if chunk.is_empty() {
+                break;
+            }
+            chunks.push(chunk);
+        }
+        if chunks.is_empty() {
+            break;
+        }
+        let frames = chunks
+            .par_iter()
+            .map(|chunk| compress_frame(chunk, level))
+            .collect::<Result<Vec<_>>>()?;
+        for (chunk, frame) in chunks.iter().zip(&frames) {
+            out.write_all(frame).context("write archive")?;
+            seek_table.push((frame.len() as u32, chunk.len() as u32));
+        }
+    }
+    write_seek_table(&mut out, &seek_table)?;
+
+    let out = out.into_inner().map_err(|e| e.into_error())?;
+    out.sync_all().context("sync archive")?;
+    let len = out.metadata().context("stat archive")?.len();
+    Ok((seek_table.len(), len))
+}
+
+fn compress_frame(chunk: &[u8], level: i32) -> Result<Vec<u8>> {
+    let mut compressor = zstd::bulk::Compressor::new(level).context("zstd compressor")?;
+    compressor
+        .include_checksum(true)
+        .context("zstd checksums")?;
+    compressor.compress(chunk).context("compress frame")
+}
+
+fn write_skippable(out: &mut impl Write, magic: u32, content: &[u8]) -> Result<()> {
+    out.write_all(&magic.to_le_bytes())
+        .and_then(|_| out.write_all(&(content.len() as u32).to_le_bytes()))
+        .and_then(|_| out.write_all(content))
+        .context("write archive")
+}
+
+/// Reads the content of the skippable frame of `magic` at the start of
+/// `src`, `None` if it starts with anything else.
+fn read_skippable(src: &mut impl Read, magic: u32) -> Result<Option<Vec<u8>>> {
+    let mut head = [0; 8];
+    src.read_exact(&mut head).context("read archive")?;
+    if head[..4] != magic.to_le_bytes() {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(head[4..].try_into().expect("4 bytes"));
+    let mut content = vec![0; len as usize];
+    src.read_exact(&mut content).context("read archive")?;
+    Ok(Some(content))
+}
+
+/// Writes the seek table of frames of `(compressed, decompressed)` bytes,
+/// without checksums, which the frames carry themselves.
+fn write_seek_table(out: &mut impl Write, frames: &[(u32, u32)]) -> Result<()> {
+    let mut table = Vec::with_capacity(frames.len() * 8 + SEEK_FOOTER_LEN);
+    for (compressed, decompressed) in frames {
+        table.extend_from_slice(&compressed.to_le_bytes());
+        table.extend_from_slice(&decompressed.to_le_bytes());
+    }
+    table.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    table.push(0);
+    table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    write_skippable(out, SEEK_TABLE_MAGIC, &table)
+}
+
+fn read_seek_table(mut file: &fs::File) -> Result<Vec<(u32, u32)>> {
+    let mut footer = [0; SEEK_FOOTER_LEN];
+    file.seek(SeekFrom::End(-(SEEK_FOOTER_LEN as i64)))
+        .and_then(|_| file.read_exact(&mut footer))
+        .context("read seek table")?;
+    ensure!(
+        footer[5..] == SEEKABLE_MAGIC.to_le_bytes(),
+        "not a seekable zstd archive"
+    );
+    let descriptor = footer[4];
+    ensure!(
+        descriptor & 0x80 == 0,
+        "seek tables with checksums are not supported"
+    );
+    let frames = u32::from_le_bytes(footer[..4].try_into().expect("4 bytes")) as usize;
+    let table_len = frames * 8;
+    let mut table = vec![0; table_len];
+    file.seek(SeekFrom::End(-((table_len + SEEK_FOOTER_LEN) as i64)))
+        .and_then(|_| file.read_exact(&mut table))
+        .context("read seek table")?;
+    file.seek(SeekFrom::Start(0)).context("rewind archive")?;
+    Ok(table
+        .chunks_exact(8)
+        .map(|entry| {
+            let field = |range: std::ops::Range<usize>| {
+                u32::from_le_bytes(entry[range].try_into().expect("4 bytes"))
+            };
+            (field(0..4), field(4..8))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use add_piece::manifest::ManifestPiece;
+    use filecoin_proofs::{PieceInfo, UnpaddedBytesAmount};
+
+    /// A staged file of a bit more than a frame, with its manifest.
+    fn staged(dir: &Path) -> (PathBuf, Vec<u8>) {
+        let staged = dir.join("staged");
+        let data: Vec<u8> = (0..FRAME_SIZE + 4096).map(|i| (i % 251) as u8).collect();
+        fs::write(&staged, &data).unwrap();
+        let manifest = Manifest {
+            pieces: vec![ManifestPiece {
+                source: "test".to_string(),
+                payload_size: 8128,
+                piece_info: PieceInfo {
+                    commitment: [1u8; 32],
+                    size: UnpaddedBytesAmount(8128),
+                },
+                offset: 0,
+                len: 8192,
+                chunk_roots: Vec::new(),
+                encryption: None,
+                excessive_alignment: None,
+                payload_blake3: None,
+                payload_sha256: None,
+            }],
+            ..Default::default()
+        };
+        manifest.save(&staged).unwrap();
+        (staged, data)
+    }
+
+    #[test]
+    fn test_archive_layout_and_restore() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let (staged, data) = staged(dir.path());
+        let dest = path_for(&staged);
+
+        let report = archive(&staged, &dest, 3).expect("archive failed");
+        assert_eq!((report.staged_len, report.frames), (data.len() as u64, 2));
+        assert!(archive(&staged, &dest, 3).is_err());
+        assert!(!partial_path(&dest).exists());
+
+        // the header frame, the frames of the seek table, then the table
+        let file = fs::File::open(&dest).unwrap();
+        let seek_table = read_seek_table(&file).unwrap();
+        assert_eq!(seek_table.len(), 2);
+        assert_eq!(seek_table[0].1 as usize, FRAME_SIZE);
+        assert_eq!(seek_table[1].1, 4096);
+        let mut src = BufReader::new(file);
+        let header = read_skippable(&mut src, HEADER_MAGIC).unwrap().unwrap();
+        let header: ArchiveHeader = serde_json::from_slice(&header).unwrap();
+        assert_eq!(header.staged_len, data.len() as u64);
+        assert_eq!(header.blake3, blake3::hash(&data).to_hex().to_string());
+        assert_eq!(header.manifest.pieces.len(), 1);
+
+        // plain zstd decoders skip the header and seek table
+        let decoded = zstd::decode_all(fs::File::open(&dest).unwrap()).unwrap();
+        assert!(decoded == data);
+
+        let restored = dir.path().join("restored");
+        let report = restore(&dest, &restored).expect("restore failed");
+        assert_eq!((report.staged_len, report.pieces), (data.len() as u64, 1));
+        assert!(fs::read(&restored).unwrap() == data);
+        let manifest = Manifest::load(&restored).unwrap().expect("no manifest");
+        assert_eq!(manifest.pieces[0].piece_info.commitment, [1u8; 32]);
+    }
+
+    #[test]
+    fn test_restore_rejects_corrupt_frame() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let (staged, _) = staged(dir.path());
+        let dest = path_for(&staged);
+        archive(&staged, &dest, 3).expect("archive failed");
+
+        // flip a byte of the last frame
+        let mut archived = fs::read(&dest).unwrap();
+        let table_len = 2 * 8 + SEEK_FOOTER_LEN + 8;
+        let at = archived.len() - table_len - 16;
+        archived[at] ^= 0xff;
+        fs::write(&dest, archived).unwrap();
+
+        let restored = dir.path().join("restored");
+        assert!(restore(&dest, &restored).is_err());
+        assert!(!restored.exists());
+        assert!(!partial_path(&restored).exists());
+    }
+}
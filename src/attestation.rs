@@ -0,0 +1,315 @@
+//! Signs the manifests of the staged files with the ed25519 key of the worker,
+//! and checks such signatures, so that the hosts sealing a staged sector can
+//! prove which worker produced it.
+//!
+//! The signature covers a statement derived from the manifest: the CID,
+//! size, offset and length of every piece, the CommD of the sector when the
+//! pieces fill one, the provenance, the worker and when it was signed.
+//!
+//! Any key makes a valid signature, so an attestation is only valid when it
+//! was signed by one of the keys the verifier trusts.
+
+use add_piece::{
+    manifest::{self, Attestation, Manifest, Provenance},
+    piece_cid, precommit, seal_proof,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use filecoin_proofs::SectorSize;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{config, keys::KeySource};
+
+/// Prefixed to the signed statements, so that the key can't be tricked into
+/// signing them for another purpose.
+const CONTEXT: &[u8] = b"add_piece attestation v1\n";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttestationConfig {
+    /// Hex encoded 32 bytes seed of the ed25519 key, the manifests aren't
+    /// signed without one.
+    #[serde(default)]
+    pub key: Option<KeySource>,
+
+    /// Name of the worker in the attestations, its host name by default.
+    #[serde(default)]
+    pub worker: Option<String>,
+
+    /// Hex encoded public keys of the workers whose attestations
+    /// `verify-attestation` accepts, besides those it is given.
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Statement<'a> {
+    worker: &'a str,
+    pieces: Vec<StatementPiece>,
+    comm_d: Option<&'a str>,
+    provenance: Option<&'a Provenance>,
+    signed_at: u64,
+}
+
+#[derive(Serialize)]
+struct StatementPiece {
+    piece_cid: String,
+    size: u64,
+    offset: u64,
+    len: u64,
+}
+
+fn statement(
+    manifest: &Manifest,
+    worker: &str,
+    comm_d: Option<&str>,
+    signed_at: u64,
+) -> Result<Vec<u8>> {
+    let statement = Statement {
+        worker,
+        pieces: manifest
+            .pieces
+            .iter()
+            .map(|p| StatementPiece {
+                piece_cid: piece_cid::encode(&p.piece_info.commitment),
+                size: u64::from(p.piece_info.size),
+                offset: p.offset,
+                len: p.len,
+            })
+            .collect(),
+        comm_d,
+        provenance: manifest.provenance.as_ref(),
+        signed_at,
+    };
+    let mut message = CONTEXT.to_vec();
+    serde_json::to_writer(&mut message, &statement).context("serialize statement")?;
+    Ok(message)
+}
+
+/// CID of the CommD of the sector the pieces of `manifest` fill, `None` if
+/// they don't fill one.
+fn comm_d(manifest: &Manifest) -> Result<Option<String>> {
+    let sector_size = manifest.end();
+    if seal_proof::ensure_sector_size(sector_size).is_err() {
+        return Ok(None);
+    }
+    let pieces = match precommit::sector_piece_infos_for_size(sector_size, manifest) {
+        Ok(pieces) => pieces,
+        Err(_) => return Ok(None),
+    };
+    let comm_d = filecoin_proofs::compute_comm_d(SectorSize(sector_size), &pieces)
+        .context("compute CommD")?;
+    Ok(Some(piece_cid::encode(&comm_d)))
+}
+
+/// Signs `manifest` if an attestation key is configured. A manifest rewritten
+/// without a key loses the attestation it had, which no longer covers it.
+pub fn sign(manifest: &mut Manifest) -> Result<()> {
+    manifest.attestation = None;
+    let config = match &config::global().attestation {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    let source = match &config.key {
+        Some(source) => source,
+        None => return Ok(()),
+    };
+
+    let seed = source.read("attestation")?;
+    let key = key_pair(seed.trim())?;
+    let worker = config.worker.clone().unwrap_or_else(manifest::hostname);
+    sign_with(manifest, &key, worker, manifest::unix_now())
+}
+
+fn key_pair(seed: &str) -> Result<Ed25519KeyPair> {
+    let mut raw = [0u8; 32];
+    hex::decode_to_slice(seed, &mut raw)
+        .context("attestation key must be a 32 bytes hex encoded seed")?;
+    Ed25519KeyPair::from_seed_unchecked(&raw).map_err(|e| anyhow!("invalid attestation key: {}", e))
+}
+
+fn sign_with(
+    manifest: &mut Manifest,
+    key: &Ed25519KeyPair,
+    worker: String,
+    signed_at: u64,
+) -> Result<()> {
+    let comm_d = comm_d(manifest)?;
+    let message = statement(manifest, &worker, comm_d.as_deref(), signed_at)?;
+    manifest.attestation = Some(Attestation {
+        worker,
+        comm_d,
+        signed_at,
+        public_key: hex::encode(key.public_key()),
+        signature: hex::encode(key.sign(&message)),
+    });
+    debug!(pieces = manifest.pieces.len(), "manifest signed");
+    Ok(())
+}
+
+/// Outcome of `verify`, printed by `verify-attestation`.
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    pub worker: String,
+    pub public_key: String,
+    pub signed_at: u64,
+    pub pieces: usize,
+    pub comm_d: Option<String>,
+    /// The signature matches the statement derived from the manifest.
+    pub signature_valid: bool,
+    /// The attested CommD is the one of the recorded pieces.
+    pub comm_d_valid: bool,
+    /// The public key is one of the trusted keys.
+    pub trusted: bool,
+    pub valid: bool,
+}
+
+/// Checks that the attestation of `manifest` was signed by one of
+/// `trusted_keys` (hex encoded), which can't be empty.
+pub fn verify(manifest: &Manifest, trusted_keys: &[String]) -> Result<Verification> {
+    ensure!(
+        !trusted_keys.is_empty(),
+        "no trusted key, give --public-key or configure attestation.trusted_keys"
+    );
+    let attestation = manifest
+        .attestation
+        .as_ref()
+        .ok_or_else(|| anyhow!("the manifest carries no attestation"))?;
+    let public_key = hex::decode(&attestation.public_key).context("decode public key")?;
+    let sig = hex::decode(&attestation.signature).context("decode signature")?;
+    ensure!(public_key.len() == 32, "public key must be 32 bytes");
+
+    let message = statement(
+        manifest,
+        &attestation.worker,
+        attestation.comm_d.as_deref(),
+        attestation.signed_at,
+    )?;
+    let signature_valid = signature::UnparsedPublicKey::new(&signature::ED25519, &public_key)
+        .verify(&message, &sig)
+        .is_ok();
+    let comm_d_valid = comm_d(manifest)? == attestation.comm_d;
+    let trusted = trusted_keys
+        .iter()
+        .any(|k| k.trim().eq_ignore_ascii_case(&attestation.public_key));
+
+    Ok(Verification {
+        worker: attestation.worker.clone(),
+        public_key: attestation.public_key.clone(),
+        signed_at: attestation.signed_at,
+        pieces: manifest.pieces.len(),
+        comm_d: attestation.comm_d.clone(),
+        signature_valid,
+        comm_d_valid,
+        trusted,
+        valid: signature_valid && comm_d_valid && trusted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use add_piece::manifest::ManifestPiece;
+    use filecoin_proofs::{PieceInfo, UnpaddedBytesAmount};
+
+    const SEED: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    /// A signed manifest of two pieces filling a 2KiB sector.
+    fn signed() -> (Manifest, String) {
+        let pieces = [(0, 1024), (1024, 1024)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(offset, len))| ManifestPiece {
+                source: format!("piece-{}", i),
+                payload_size: 1016,
+                piece_info: PieceInfo {
+                    commitment: [i as u8 + 1; 32],
+                    size: UnpaddedBytesAmount(1016),
+                },
+                offset,
+                len,
+                chunk_roots: Vec::new(),
+                encryption: None,
+                excessive_alignment: None,
+                payload_blake3: None,
+                payload_sha256: None,
+            })
+            .collect();
+        let mut manifest = Manifest {
+            pieces,
+            ..Default::default()
+        };
+        let key = key_pair(SEED).unwrap();
+        sign_with(&mut manifest, &key, "worker-1".to_string(), 1714564800).unwrap();
+        (manifest, hex::encode(key.public_key()))
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let (manifest, public_key) = signed();
+        let attestation = manifest.attestation.as_ref().unwrap();
+        assert_eq!(attestation.public_key, public_key);
+        assert!(attestation.comm_d.is_some());
+
+        let verification = verify(&manifest, &[public_key.to_uppercase()]).unwrap();
+        assert!(verification.signature_valid);
+        assert!(verification.comm_d_valid);
+        assert!(verification.trusted);
+        assert!(verification.valid);
+        assert_eq!(verification.worker, "worker-1");
+        assert_eq!(verification.pieces, 2);
+
+        let err = verify(&manifest, &[]).unwrap_err();
+        assert!(err.to_string().contains("no trusted key"), "{:?}", err);
+    }
+
+    #[test]
+    fn test_verify_tampered() {
+        let (manifest, public_key) = signed();
+        let trusted = [public_key];
+
+        let mut moved = manifest.clone();
+        moved.pieces.swap(0, 1);
+        moved.pieces[0].offset = 0;
+        moved.pieces[1].offset = 1024;
+        let verification = verify(&moved, &trusted).unwrap();
+        assert!(!verification.signature_valid);
+        assert!(!verification.valid);
+
+        let mut offset = manifest.clone();
+        offset.pieces[1].offset = 1152;
+        assert!(!verify(&offset, &trusted).unwrap().valid);
+
+        let mut comm_d = manifest.clone();
+        let forged = piece_cid::encode(&[7u8; 32]);
+        comm_d.attestation.as_mut().unwrap().comm_d = Some(forged.clone());
+        let verification = verify(&comm_d, &trusted).unwrap();
+        assert!(!verification.signature_valid);
+        assert!(!verification.comm_d_valid);
+        assert!(!verification.valid);
+
+        // signed by the trusted key, a made up CommD still isn't the sector's
+        let message = statement(&comm_d, "worker-1", Some(&forged), 1714564800).unwrap();
+        let signature = key_pair(SEED).unwrap().sign(&message);
+        comm_d.attestation.as_mut().unwrap().signature = hex::encode(signature);
+        let verification = verify(&comm_d, &trusted).unwrap();
+        assert!(verification.signature_valid);
+        assert!(!verification.comm_d_valid);
+        assert!(!verification.valid);
+    }
+
+    #[test]
+    fn test_verify_foreign_key() {
+        let (mut manifest, trusted) = signed();
+        let foreign = key_pair(&"02".repeat(32)).unwrap();
+        sign_with(&mut manifest, &foreign, "worker-1".to_string(), 1714564800).unwrap();
+
+        let verification = verify(&manifest, &[trusted]).unwrap();
+        assert!(verification.signature_valid);
+        assert!(verification.comm_d_valid);
+        assert!(!verification.trusted);
+        assert!(!verification.valid);
+    }
+}
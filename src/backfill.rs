@@ -0,0 +1,140 @@
+use std::{fs, path::Path};
+
+use add_piece::{
+    manifest::{Manifest, ManifestPiece},
+    piece_cid, pure, seal_proof, sizes,
+    staged_format::Header,
+};
+use anyhow::{bail, ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, UnpaddedBytesAmount};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{staging, transfer::read_at};
+
+/// Source recorded for the pieces of a backfilled manifest, which doesn't
+/// know where they were read from.
+const BACKFILLED_SOURCE: &str = "backfilled";
+
+#[derive(Debug, Serialize)]
+pub struct BackfillPiece {
+    pub offset: u64,
+    /// Unpadded size of the piece.
+    pub piece_size: u64,
+    pub piece_cid: String,
+}
+
+/// Parses a piece size given as it comes to mind, padded (`8MiB`) or
+/// unpadded (`8323072`).
+pub fn parse_piece_size(s: &str) -> Result<UnpaddedBytesAmount, String> {
+    let size = seal_proof::parse_size(s)?;
+    if size >= 128 && size.is_power_of_two() {
+        return Ok(PaddedBytesAmount(size).into());
+    }
+    match sizes::is_piece_size(UnpaddedBytesAmount(size)) {
+        true => Ok(UnpaddedBytesAmount(size)),
+        false => Err(format!("not a piece size: {}", s)),
+    }
+}
+
+/// Writes a manifest for the staged file `staged`, staged by a version which
+/// didn't write any, from a guess of the sizes of its pieces in order.
+///
+/// The pieces are laid out as `add_piece` would have and hashed from the
+/// staged bytes, chunk by chunk. The bytes between them must be zeros, which
+/// catches most wrong guesses, but any bytes hash into some piece: the piece
+/// CIDs returned should be checked against the deals before the manifest is
+/// trusted. The sources of the pieces and the size of their payloads are
+/// unknown, every piece is recorded as a full payload read from
+/// `backfilled`.
+pub fn backfill_manifest(
+    staged: &Path,
+    piece_sizes: &[UnpaddedBytesAmount],
+    force: bool,
+) -> Result<Vec<BackfillPiece>> {
+    ensure!(!piece_sizes.is_empty(), "no piece size given");
+    if !force && staging::load_manifest(staged)?.is_some() {
+        bail!(
+            "{} already has a manifest, pass --force to replace it",
+            staged.display()
+        );
+    }
+    let file = fs::File::open(staged)
+        .with_context(|| format!("open staged file: {}", staged.display()))?;
+    let header = Header::read_from(&file)?;
+    let data_offset = header.as_ref().map_or(0, |h| h.data_offset);
+    let len = file.metadata().context("stat staged file")?.len();
+
+    let placements = pure::plan_pieces(piece_sizes)?;
+    let end = data_offset + placements.last().map_or(0, |p| u64::from(p.end()));
+    ensure!(
+        len >= end,
+        "the pieces end at {}, past the {} bytes of the staged file",
+        end,
+        len
+    );
+    if header.is_none() && len > end {
+        warn!(
+            extra = len - end,
+            "the staged file holds more than the pieces given"
+        );
+    }
+
+    let mut manifest = Manifest::default();
+    let mut report = Vec::with_capacity(piece_sizes.len());
+    let mut buf = Vec::new();
+    let mut pos = 0;
+    for (index, (size, placement)) in piece_sizes.iter().zip(&placements).enumerate() {
+        let offset = u64::from(placement.offset);
+        let alignment = data_offset + pos..data_offset + offset;
+        read_at(&file, alignment.clone(), &mut buf)?;
+        ensure!(
+            buf.iter().all(|b| *b == 0),
+            "the bytes at {:?} before piece {} aren't zeros, the pieces given don't match the staged file",
+            alignment,
+            index
+        );
+
+        let mut piece = ManifestPiece {
+            source: BACKFILLED_SOURCE.to_string(),
+            payload_size: u64::from(*size),
+            piece_info: PieceInfo {
+                commitment: [0; 32],
+                size: *size,
+            },
+            offset,
+            len: u64::from(placement.end()) - offset,
+            chunk_roots: Vec::new(),
+            encryption: None,
+            excessive_alignment: None,
+            payload_blake3: None,
+            payload_sha256: None,
+        };
+        for range in piece.chunk_ranges() {
+            let range = data_offset + range.start..data_offset + range.end;
+            ensure!(
+                read_at(&file, range.clone(), &mut buf)?,
+                "staged file ends before {}",
+                range.end
+            );
+            piece.chunk_roots.push(pure::commit(&buf)?);
+        }
+        piece.piece_info.commitment = pure::reduce_chunk_roots(&piece.chunk_roots)?;
+
+        report.push(BackfillPiece {
+            offset,
+            piece_size: u64::from(*size),
+            piece_cid: piece_cid::encode(&piece.piece_info.commitment),
+        });
+        pos = piece.end();
+        manifest.pieces.push(piece);
+    }
+
+    manifest.save(staged)?;
+    info!(
+        staged = %staged.display(),
+        pieces = manifest.pieces.len(),
+        "manifest backfilled"
+    );
+    Ok(report)
+}
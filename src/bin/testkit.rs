@@ -0,0 +1,382 @@
+//! End-to-end acceptance test of the processor, for packagers to check a
+//! build in one command:
+//!
+//! ```text
+//! cargo build --release --features testkit
+//! target/release/add_piece_testkit
+//! ```
+//!
+//! Random pieces are served by a mock HTTP server to the `add_piece
+//! processor` built next to it, driven like the sealing workers do, which
+//! stages them into 2KiB and 8MiB sectors. The `PieceInfo`s and staged bytes
+//! must match the ones of `filecoin_proofs::add_piece` for the same pieces,
+//! and the CommD of the staged sector the one `filecoin_proofs` computes from
+//! them.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use add_piece::{precommit, pure, seal_proof};
+use anyhow::{ensure, Context, Result};
+use clap::{Arg, ArgAction, Command};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, SectorSize, UnpaddedBytesAmount};
+use serde::Serialize;
+use tracing::{info, warn};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+use vc_processors::{
+    builtin::{
+        processors::piece::PieceFile,
+        tasks::{AddPieces, Piece},
+    },
+    core::{ext::ProducerBuilder, Processor},
+    fil_proofs::RegisteredSealProof,
+};
+
+/// Sector sizes exercised, small enough to check CommD against the bytes.
+const PROOFS: [RegisteredSealProof; 2] = [
+    RegisteredSealProof::StackedDrg2KiBV1_1,
+    RegisteredSealProof::StackedDrg8MiBV1_1,
+];
+
+/// Pieces staged into a sector at most.
+const MAX_PIECES: usize = 6;
+
+#[derive(Debug, Serialize)]
+struct Report {
+    proof_type: String,
+    seed: u64,
+    pieces: usize,
+    /// CommD of the staged sector as its unsealed CID.
+    comm_d_cid: String,
+    elapsed_ms: u128,
+}
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env()
+                .context("env filter")?,
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    let m = Command::new("add_piece_testkit")
+        .about("stage random pieces through the processor and check them against filecoin_proofs")
+        .arg(
+            Arg::new("processor")
+                .long("processor")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("add_piece binary, defaults to the one next to this one"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("config file of the processor"),
+        )
+        .arg(
+            Arg::new("rounds")
+                .long("rounds")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("3")
+                .help("sectors staged for every sector size"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(u64))
+                .help("seed of the first sector, the others follow it, random by default"),
+        )
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .takes_value(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("where the sectors are staged, a temporary directory by default"),
+        )
+        .arg(
+            Arg::new("keep")
+                .long("keep")
+                .action(ArgAction::SetTrue)
+                .help("keep the staged sectors"),
+        )
+        .get_matches();
+
+    let processor = match m.get_one::<PathBuf>("processor") {
+        Some(path) => path.clone(),
+        None => env::current_exe()
+            .context("locate the testkit binary")?
+            .with_file_name("add_piece"),
+    };
+    ensure!(
+        processor.is_file(),
+        "no processor at {}, build add_piece or pass --processor",
+        processor.display()
+    );
+    let dir = m
+        .get_one::<PathBuf>("dir")
+        .cloned()
+        .unwrap_or_else(|| env::temp_dir().join(format!("add_piece-testkit-{}", process::id())));
+    fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+    let rounds = *m.get_one::<u64>("rounds").expect("has a default");
+    let seed = m.get_one::<u64>("seed").copied().unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+    });
+
+    let mut args = Vec::new();
+    if let Some(config) = m.get_one::<PathBuf>("config") {
+        args.push("--config".to_string());
+        args.push(config.to_string_lossy().into_owned());
+    }
+    args.push("processor".to_string());
+    let producer = ProducerBuilder::new(processor.clone(), args)
+        .spawn::<AddPieces>()
+        .with_context(|| format!("spawn processor {}", processor.display()))?;
+    let server = PieceServer::start()?;
+    info!(processor = %processor.display(), url = %server.url, seed, "testkit started");
+
+    let mut reports = Vec::new();
+    for proof in PROOFS {
+        for round in 0..rounds {
+            let seed = seed.wrapping_add(round);
+            let staged = dir.join(format!("{}-{}", seal_proof::name(proof), seed));
+            let report = run(&producer, &server, proof, seed, &staged)
+                .with_context(|| format!("{} with seed {}", seal_proof::name(proof), seed))?;
+            info!(proof = %report.proof_type, seed, pieces = report.pieces, "sector checked");
+            reports.push(report);
+        }
+    }
+
+    if m.get_flag("keep") {
+        info!(dir = %dir.display(), "staged sectors kept");
+    } else if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!(err = ?e, dir = %dir.display(), "failed to remove the staged sectors");
+    }
+    println!("{}", serde_json::to_string_pretty(&reports)?);
+    Ok(())
+}
+
+/// Stages random pieces into a sector of `proof` at `staged` through the
+/// processor, then checks it.
+fn run(
+    producer: &impl Processor<AddPieces>,
+    server: &PieceServer,
+    proof: RegisteredSealProof,
+    seed: u64,
+    staged: &Path,
+) -> Result<Report> {
+    let started = Instant::now();
+    let sector_size = u64::from(proof.sector_size());
+    let mut rng = SplitMix64(seed);
+    let pieces = random_pieces(&mut rng, sector_size)?;
+
+    let mut task_pieces = Vec::new();
+    for (i, (payload, piece_size)) in pieces.iter().enumerate() {
+        let url = server.serve(&format!("{}/{}", seed, i), payload.clone());
+        task_pieces.push(Piece {
+            piece_file: PieceFile::Url(url),
+            payload_size: payload.len() as u64,
+            piece_size: *piece_size,
+        });
+    }
+    let piece_infos = producer
+        .process(AddPieces {
+            seal_proof_type: proof,
+            pieces: task_pieces,
+            staged_filepath: staged.to_path_buf(),
+        })
+        .context("add_pieces")?;
+
+    // the same pieces, zero padded as the fetcher does, through filecoin_proofs
+    let mut expected_staged = Vec::new();
+    let mut expected = Vec::new();
+    let mut piece_lengths = Vec::new();
+    for (payload, piece_size) in &pieces {
+        let mut source = payload.to_vec();
+        source.resize(u64::from(*piece_size) as usize, 0);
+        let (piece_info, _) = filecoin_proofs::add_piece(
+            &source[..],
+            &mut expected_staged,
+            *piece_size,
+            &piece_lengths,
+        )
+        .context("filecoin_proofs::add_piece")?;
+        piece_lengths.push(*piece_size);
+        expected.push(piece_info);
+    }
+    ensure!(
+        piece_infos == expected,
+        "piece infos {:?} don't match the ones of filecoin_proofs {:?}",
+        piece_infos,
+        expected
+    );
+    let mut staged_bytes =
+        fs::read(staged).with_context(|| format!("read {}", staged.display()))?;
+    ensure!(
+        staged_bytes == expected_staged,
+        "the staged bytes don't match the ones of filecoin_proofs"
+    );
+
+    let end = PaddedBytesAmount(staged_bytes.len() as u64);
+    let mut sector_pieces: Vec<PieceInfo> = piece_infos;
+    sector_pieces.extend(precommit::filler_pieces(
+        end,
+        PaddedBytesAmount(sector_size),
+    )?);
+    let comm_d = filecoin_proofs::compute_comm_d(SectorSize(sector_size), &sector_pieces)
+        .context("compute CommD")?;
+    staged_bytes.resize(sector_size as usize, 0);
+    ensure!(
+        pure::commit(&staged_bytes)? == comm_d,
+        "CommD of the staged sector doesn't match the one of filecoin_proofs"
+    );
+
+    Ok(Report {
+        proof_type: seal_proof::name(proof),
+        seed,
+        pieces: pieces.len(),
+        comm_d_cid: add_piece::piece_cid::encode(&comm_d),
+        elapsed_ms: started.elapsed().as_millis(),
+    })
+}
+
+/// Payloads and piece sizes of up to `MAX_PIECES` pieces fitting a sector of
+/// `sector_size`, the payloads often smaller than their piece.
+fn random_pieces(
+    rng: &mut SplitMix64,
+    sector_size: u64,
+) -> Result<Vec<(Arc<Vec<u8>>, UnpaddedBytesAmount)>> {
+    let max_shift = (sector_size / 128).trailing_zeros() as u64;
+    let mut sizes = Vec::new();
+    let mut pieces = Vec::new();
+    // sizes not fitting what is left of the sector are drawn again, a few times
+    for _ in 0..MAX_PIECES * 2 {
+        let padded = 128u64 << rng.below(max_shift + 1);
+        let piece_size = UnpaddedBytesAmount::from(PaddedBytesAmount(padded));
+        sizes.push(piece_size);
+        let end = pure::plan_pieces(&sizes)?
+            .last()
+            .map_or(0, |p| u64::from(p.end()));
+        if end > sector_size {
+            sizes.pop();
+            continue;
+        }
+        let payload_size = match rng.below(2) {
+            0 => u64::from(piece_size),
+            _ => 1 + rng.below(u64::from(piece_size)),
+        };
+        let payload: Vec<u8> = (0..payload_size).map(|_| rng.next() as u8).collect();
+        pieces.push((Arc::new(payload), piece_size));
+        if pieces.len() == MAX_PIECES {
+            break;
+        }
+    }
+    Ok(pieces)
+}
+
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which must not be 0.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Mock of the HTTP servers pieces are fetched from: serves the payloads
+/// registered with `serve` on `GET /pieces/<name>`.
+struct PieceServer {
+    url: String,
+    payloads: Arc<Mutex<HashMap<String, Arc<Vec<u8>>>>>,
+}
+
+impl PieceServer {
+    fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").context("bind piece server")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let payloads = Arc::new(Mutex::new(HashMap::new()));
+        let served = payloads.clone();
+        thread::Builder::new()
+            .name("piece-server".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let served = served.clone();
+                    let res = stream
+                        .map_err(anyhow::Error::from)
+                        .and_then(|stream| respond(stream, &served));
+                    if let Err(e) = res {
+                        warn!(err = ?e, "failed to serve a piece");
+                    }
+                }
+            })
+            .context("spawn piece server")?;
+        Ok(Self { url, payloads })
+    }
+
+    /// Serves `payload` as `name`, returns its URL.
+    fn serve(&self, name: &str, payload: Arc<Vec<u8>>) -> String {
+        self.payloads
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), payload);
+        format!("{}/pieces/{}", self.url, name)
+    }
+}
+
+fn respond(mut stream: TcpStream, payloads: &Mutex<HashMap<String, Arc<Vec<u8>>>>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are not needed
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let payload = request_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|path| path.strip_prefix("/pieces/"))
+        .and_then(|name| payloads.lock().unwrap().get(name).cloned());
+    match payload {
+        Some(payload) => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                payload.len()
+            )?;
+            stream.write_all(&payload)?;
+        }
+        None => stream.write_all(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )?,
+    }
+    stream.flush()?;
+    Ok(())
+}
@@ -0,0 +1,787 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+use log::warn;
+
+use crate::piece_cid::{base32_decode, base32_encode};
+
+/// Sections of a CAR larger than this are rejected rather than buffered.
+const MAX_SECTION_LEN: u64 = 64 << 20;
+
+const BASE58: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Multihash codes `CarVerifier` checks blocks against.
+const IDENTITY: u64 = 0x00;
+const SHA2_256: u64 = 0x12;
+const BLAKE3: u64 = 0x1e;
+
+/// A CID in its binary form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cid(Vec<u8>);
+
+impl Cid {
+    /// Parses a CIDv0 (`Qm...`) or a base32 CIDv1 (`b...`).
+    pub fn parse(s: &str) -> Result<Self, CarError> {
+        let bytes = match s.strip_prefix('b') {
+            Some(data) if !s.starts_with("Qm") => base32_decode(data),
+            _ => base58_decode(s),
+        };
+        let cid = bytes.ok_or_else(|| CarError::InvalidCid(s.to_string()))?;
+        match read_cid(&cid) {
+            Some(len) if len == cid.len() => Ok(Self(cid)),
+            _ => Err(CarError::InvalidCid(s.to_string())),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.first() {
+            Some(&1) => write!(f, "b{}", base32_encode(&self.0)),
+            _ => f.write_str(&base58_encode(&self.0)),
+        }
+    }
+}
+
+fn base58_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.bytes() {
+        let mut carry = BASE58.iter().position(|b| *b == c)? as u32;
+        for b in bytes.iter_mut().rev() {
+            carry += *b as u32 * 58;
+            *b = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = s.bytes().take_while(|c| *c == b'1').count();
+    let mut out = vec![0u8; zeros];
+    out.extend(bytes);
+    Some(out)
+}
+
+fn base58_encode(bytes: &[u8]) -> String {
+    let mut digits: Vec<u8> = Vec::new();
+    for b in bytes {
+        let mut carry = *b as u32;
+        for d in digits.iter_mut().rev() {
+            carry += (*d as u32) << 8;
+            *d = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.insert(0, (carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+    std::iter::repeat_n('1', zeros)
+        .chain(digits.iter().map(|d| BASE58[*d as usize] as char))
+        .collect()
+}
+
+/// A CAR payload failing verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CarError {
+    InvalidCid(String),
+    /// The payload is not a well formed CARv1.
+    Malformed(&'static str),
+    /// The CAR header doesn't list the expected root.
+    RootNotDeclared(String),
+    /// The CAR holds no block for the expected root.
+    RootMissing(String),
+    /// The data of a block doesn't hash to its CID.
+    HashMismatch {
+        block: u64,
+        cid: String,
+    },
+}
+
+impl fmt::Display for CarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCid(cid) => write!(f, "invalid cid: {}", cid),
+            Self::Malformed(what) => write!(f, "malformed car: {}", what),
+            Self::RootNotDeclared(root) => write!(f, "car header doesn't list root {}", root),
+            Self::RootMissing(root) => write!(f, "car holds no block for root {}", root),
+            Self::HashMismatch { block, cid } => {
+                write!(f, "block {} doesn't match its cid {}", block, cid)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CarError {}
+
+/// Reads an unsigned varint at the start of `data`, returns it with its length
+/// or `None` if `data` ends before it does.
+fn read_varint(data: &[u8]) -> Option<Result<(u64, usize), CarError>> {
+    let mut value = 0u64;
+    for (i, b) in data.iter().enumerate().take(10) {
+        value |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some(Ok((value, i + 1)));
+        }
+    }
+    match data.len() >= 10 {
+        true => Some(Err(CarError::Malformed("varint overflow"))),
+        false => None,
+    }
+}
+
+/// Returns the length of the CID starting `data`.
+fn read_cid(data: &[u8]) -> Option<usize> {
+    if data.starts_with(&[SHA2_256 as u8, 32]) {
+        return (data.len() >= 34).then_some(34);
+    }
+    let mut pos = 0;
+    let mut next = || {
+        let (value, len) = read_varint(&data[pos..])?.ok()?;
+        pos += len;
+        Some(value)
+    };
+    let version = next()?;
+    let (_codec, _code, digest_len) = (next()?, next()?, next()?);
+    (version == 1 && data.len() - pos >= digest_len as usize).then(|| pos + digest_len as usize)
+}
+
+/// Returns the code and the digest of the multihash of `cid`.
+fn multihash(cid: &[u8]) -> Option<(u64, &[u8])> {
+    let multihash = match cid[0] {
+        1 => {
+            let (_, version_len) = read_varint(cid)?.ok()?;
+            let (_, codec_len) = read_varint(&cid[version_len..])?.ok()?;
+            &cid[version_len + codec_len..]
+        }
+        _ => cid,
+    };
+    let (code, code_len) = read_varint(multihash)?.ok()?;
+    let (_, len_len) = read_varint(&multihash[code_len..])?.ok()?;
+    Some((code, &multihash[code_len + len_len..]))
+}
+
+/// Checks `block` against the multihash of `cid`, `None` if its hash function
+/// is not supported.
+fn verify_block(cid: &[u8], block: &[u8]) -> Option<bool> {
+    let (code, digest) = multihash(cid)?;
+    match code {
+        IDENTITY => Some(digest == block),
+        SHA2_256 => Some(digest == hmac_sha256::Hash::hash(block)),
+        BLAKE3 => {
+            let mut hash = vec![0u8; digest.len()];
+            blake3::Hasher::new()
+                .update(block)
+                .finalize_xof()
+                .fill(&mut hash);
+            Some(digest == hash)
+        }
+        _ => None,
+    }
+}
+
+/// Minimal dag-cbor reading, enough for the CAR header.
+struct Cbor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cbor<'a> {
+    /// Reads the major type and argument of the next item.
+    fn head(&mut self) -> Result<(u8, u64), CarError> {
+        let err = CarError::Malformed("truncated header");
+        let first = *self.data.get(self.pos).ok_or(err.clone())?;
+        self.pos += 1;
+        let (major, info) = (first >> 5, first & 0x1f);
+        let len = match info {
+            0..=23 => return Ok((major, info as u64)),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err(CarError::Malformed("indefinite length in header")),
+        };
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(err)?;
+        self.pos += len;
+        Ok((major, bytes.iter().fold(0, |v, b| v << 8 | *b as u64)))
+    }
+
+    fn bytes(&mut self, len: u64) -> Result<&'a [u8], CarError> {
+        let end = self.pos.saturating_add(len as usize);
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or(CarError::Malformed("truncated header"))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Skips the next item, nested at most `depth` levels.
+    fn skip(&mut self, depth: usize) -> Result<(), CarError> {
+        if depth == 0 {
+            return Err(CarError::Malformed("header nested too deep"));
+        }
+        let (major, arg) = self.head()?;
+        match major {
+            2 | 3 => {
+                self.bytes(arg)?;
+            }
+            4 => (0..arg).try_for_each(|_| self.skip(depth - 1))?,
+            5 => (0..arg.saturating_mul(2)).try_for_each(|_| self.skip(depth - 1))?,
+            6 => self.skip(depth - 1)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Returns the roots of a CARv1 header.
+fn parse_header(header: &[u8]) -> Result<Vec<Vec<u8>>, CarError> {
+    let mut cbor = Cbor {
+        data: header,
+        pos: 0,
+    };
+    let (major, entries) = cbor.head()?;
+    if major != 5 {
+        return Err(CarError::Malformed("header is not a map"));
+    }
+
+    let (mut roots, mut version) = (Vec::new(), None);
+    for _ in 0..entries {
+        let (major, len) = cbor.head()?;
+        if major != 3 {
+            return Err(CarError::Malformed("header key is not a string"));
+        }
+        match cbor.bytes(len)? {
+            b"version" => version = Some(cbor.head()?),
+            b"roots" => {
+                let (major, count) = cbor.head()?;
+                if major != 4 {
+                    return Err(CarError::Malformed("roots is not an array"));
+                }
+                for _ in 0..count {
+                    let tag = cbor.head()?;
+                    let (major, len) = cbor.head()?;
+                    if tag != (6, 42) || major != 2 || len == 0 {
+                        return Err(CarError::Malformed("root is not a cid"));
+                    }
+                    // links are prefixed with the identity multibase
+                    roots.push(cbor.bytes(len)?[1..].to_vec());
+                }
+            }
+            _ => cbor.skip(16)?,
+        }
+    }
+    match version {
+        Some((0, 1)) => Ok(roots),
+        _ => Err(CarError::Malformed("not a CARv1")),
+    }
+}
+
+/// Codec of the `MultihashIndexSorted` CARv2 index.
+const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+
+#[derive(Debug, Default)]
+struct CarIndexState {
+    /// Multihash code, digest and section offset of every block.
+    blocks: Vec<(u64, Vec<u8>, u64)>,
+    /// Why the payload couldn't be indexed.
+    error: Option<CarError>,
+    /// Bytes of the CARv1, set once the whole payload was read.
+    car_len: Option<u64>,
+}
+
+/// Collects the offsets of the blocks of a CARv1 payload while `add_piece`
+/// reads it, so that its CARv2 index is built without reading it again.
+#[derive(Debug, Default)]
+pub struct CarIndex {
+    state: Mutex<CarIndexState>,
+}
+
+impl CarIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&self, cid: &[u8], offset: u64) {
+        if let Some((code, digest)) = multihash(cid) {
+            let mut state = self.state.lock().expect("lock car index");
+            state.blocks.push((code, digest.to_vec(), offset));
+        }
+    }
+
+    fn complete(&self, car_len: u64) {
+        self.state.lock().expect("lock car index").car_len = Some(car_len);
+    }
+
+    fn abandon(&self, error: CarError) {
+        let mut state = self.state.lock().expect("lock car index");
+        state.blocks.clear();
+        state.error.get_or_insert(error);
+    }
+
+    /// Writes the index as a `MultihashIndexSorted` prefixed with its codec,
+    /// like the standalone `.idx` files of go-car. Fails with the reason the
+    /// payload couldn't be indexed, e.g. if it is not a CARv1.
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let state = self.state.lock().expect("lock car index");
+        Self::indexed_len(&state)?;
+
+        // buckets by multihash code and digest length
+        let mut buckets = BTreeMap::new();
+        for (code, digest, offset) in &state.blocks {
+            let bucket = buckets
+                .entry((*code, digest.len()))
+                .or_insert_with(Vec::new);
+            bucket.push((digest.as_slice(), *offset));
+        }
+        let mut codes: Vec<u64> = buckets.keys().map(|(code, _)| *code).collect();
+        codes.dedup();
+
+        let mut codec = Vec::new();
+        let mut v = MULTIHASH_INDEX_SORTED;
+        while v >= 0x80 {
+            codec.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        codec.push(v as u8);
+        w.write_all(&codec)?;
+
+        w.write_all(&(codes.len() as i32).to_le_bytes())?;
+        for code in codes {
+            let widths: Vec<_> = buckets.range_mut((code, 0)..=(code, usize::MAX)).collect();
+            w.write_all(&code.to_le_bytes())?;
+            w.write_all(&(widths.len() as i32).to_le_bytes())?;
+            for ((_, len), entries) in widths {
+                entries.sort();
+                entries.dedup_by(|a, b| a.0 == b.0);
+                let width = len + 8;
+                w.write_all(&(width as u32).to_le_bytes())?;
+                w.write_all(&((entries.len() * width) as i64).to_le_bytes())?;
+                for (digest, offset) in entries.iter() {
+                    w.write_all(digest)?;
+                    w.write_all(&offset.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of the CARv1 indexed, the zero padding following it excluded.
+    /// Fails like `write_to` if it couldn't be indexed.
+    pub fn car_len(&self) -> io::Result<u64> {
+        Self::indexed_len(&self.state.lock().expect("lock car index"))
+    }
+
+    fn indexed_len(state: &CarIndexState) -> io::Result<u64> {
+        if let Some(error) = &state.error {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error.clone()));
+        }
+        state
+            .car_len
+            .ok_or_else(|| io::Error::other("the payload was not read to its end"))
+    }
+}
+
+/// Parsing progress of a CAR streamed through `CarVerifier`.
+#[derive(Default)]
+struct CarState {
+    /// Bytes of the current section read so far.
+    buf: Vec<u8>,
+    /// Offset of the current section in the CAR.
+    offset: u64,
+    header_read: bool,
+    blocks: u64,
+    root_seen: bool,
+    /// Set once a zero section length is read, only zero padding follows.
+    trailer: bool,
+    /// Bytes of the CAR before its zero padding, once it is reached.
+    car_len: Option<u64>,
+    /// Whether a block with an unsupported hash function was reported.
+    unsupported_reported: bool,
+}
+
+/// Reads `inner`, a CARv1 possibly followed by zero padding.
+///
+/// With a `root`, fails as soon as a block doesn't match its CID and at the
+/// end if the CAR doesn't hold the `root` block listed in its header. The
+/// blocks are recorded into `index` if given, which is abandoned rather than
+/// failing the read if the payload is not a CARv1.
+pub(crate) struct CarVerifier<'a, R> {
+    inner: R,
+    root: Option<&'a Cid>,
+    index: Option<&'a CarIndex>,
+    state: Option<CarState>,
+}
+
+impl<'a, R: Read> CarVerifier<'a, R> {
+    pub(crate) fn new(inner: R, root: Option<&'a Cid>, index: Option<&'a CarIndex>) -> Self {
+        let state = (root.is_some() || index.is_some()).then(CarState::default);
+        Self {
+            inner,
+            root,
+            index,
+            state,
+        }
+    }
+
+    fn consume(&self, state: &mut CarState, mut data: &[u8]) -> Result<(), CarError> {
+        while !data.is_empty() {
+            if state.trailer {
+                if data.iter().any(|b| *b != 0) {
+                    return Err(CarError::Malformed("data after the end of the car"));
+                }
+                return Ok(());
+            }
+
+            // take bytes until the current section is complete
+            let section = loop {
+                let (len, varint_len) = match read_varint(&state.buf) {
+                    Some(res) => res?,
+                    None => {
+                        state.buf.push(data[0]);
+                        data = &data[1..];
+                        if data.is_empty() {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                };
+                if len > MAX_SECTION_LEN {
+                    return Err(CarError::Malformed("section too large"));
+                }
+                let missing = (varint_len + len as usize).saturating_sub(state.buf.len());
+                let n = missing.min(data.len());
+                state.buf.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                if missing > n {
+                    return Ok(());
+                }
+                break (len, varint_len);
+            };
+            let (len, varint_len) = section;
+            let section = std::mem::take(&mut state.buf);
+            let offset = state.offset;
+            state.offset += section.len() as u64;
+            let section = &section[varint_len..];
+
+            if len == 0 {
+                if !state.header_read {
+                    return Err(CarError::Malformed("empty header"));
+                }
+                state.trailer = true;
+                state.car_len = Some(offset);
+                continue;
+            }
+            if !state.header_read {
+                let roots = parse_header(section)?;
+                if let Some(root) = self.root {
+                    if !roots.iter().any(|r| r == root.as_bytes()) {
+                        return Err(CarError::RootNotDeclared(root.to_string()));
+                    }
+                }
+                state.header_read = true;
+                continue;
+            }
+
+            let cid_len = read_cid(section).ok_or(CarError::Malformed("invalid block cid"))?;
+            let (cid, block) = section.split_at(cid_len);
+            if let Some(index) = self.index {
+                index.push(cid, offset);
+            }
+            state.blocks += 1;
+            let root = match self.root {
+                Some(root) => root,
+                None => continue,
+            };
+            match verify_block(cid, block) {
+                Some(true) => {}
+                Some(false) => {
+                    return Err(CarError::HashMismatch {
+                        block: state.blocks - 1,
+                        cid: Cid(cid.to_vec()).to_string(),
+                    })
+                }
+                None if !state.unsupported_reported => {
+                    warn!(
+                        "car block {} has an unsupported hash function, not verified",
+                        Cid(cid.to_vec())
+                    );
+                    state.unsupported_reported = true;
+                }
+                None => {}
+            }
+            state.root_seen |= cid == root.as_bytes();
+        }
+        Ok(())
+    }
+
+    fn finish(&self, state: &CarState) -> Result<(), CarError> {
+        if !state.buf.is_empty() || !state.header_read {
+            return Err(CarError::Malformed("truncated car"));
+        }
+        match self.root {
+            Some(root) if !state.root_seen => Err(CarError::RootMissing(root.to_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Read for CarVerifier<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut state = match self.state.take() {
+            Some(state) => state,
+            None => return Ok(n),
+        };
+        let res = match n {
+            0 if !buf.is_empty() => self.finish(&state).map(|_| {
+                if let Some(index) = self.index {
+                    index.complete(state.car_len.unwrap_or(state.offset));
+                }
+            }),
+            _ => self.consume(&mut state, &buf[..n]),
+        };
+
+        match (res, self.root, self.index) {
+            (Ok(()), _, _) => self.state = Some(state),
+            (Err(e), None, Some(index)) => index.abandon(e),
+            (Err(e), _, index) => {
+                if let Some(index) = index {
+                    index.abandon(e.clone());
+                }
+                return Err(io::Error::other(e));
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Reads the whole CARv1 `payload`, checking its blocks the way `add_piece`
+/// does when given the payload root. Returns the bytes read.
+pub fn verify<R: Read>(payload: R, root: &Cid) -> io::Result<u64> {
+    io::copy(
+        &mut CarVerifier::new(payload, Some(root), None),
+        &mut io::sink(),
+    )
+}
+
+/// Copies the CARv1 `car`, possibly followed by zero padding, to `out` and
+/// returns its index. Fails once copied if `car` is not a CARv1.
+pub fn copy_indexed<R: Read, W: Write>(car: R, mut out: W) -> io::Result<CarIndex> {
+    let index = CarIndex::new();
+    io::copy(&mut CarVerifier::new(car, None, Some(&index)), &mut out)?;
+    index.car_len()?;
+    Ok(index)
+}
+
+/// First bytes of a CARv2: a CARv1 header listing no roots, `version: 2`.
+const CARV2_PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+/// Bytes of the pragma and the CARv2 header, where the CARv1 starts.
+const CARV2_DATA_OFFSET: u64 = CARV2_PRAGMA.len() as u64 + 40;
+
+/// Wraps the CARv1 `car`, read again from its start, into a CARv2 holding
+/// `index`, as built by `copy_indexed` from the same bytes. The zero padding
+/// of the CARv1 is left out. Returns the bytes written to `out`.
+pub fn write_v2<R: Read, W: Write>(car: R, index: &CarIndex, mut out: W) -> io::Result<u64> {
+    let car_len = index.car_len()?;
+    out.write_all(&CARV2_PRAGMA)?;
+    // characteristics, none set, then the data and index offsets
+    out.write_all(&[0u8; 16])?;
+    out.write_all(&CARV2_DATA_OFFSET.to_le_bytes())?;
+    out.write_all(&car_len.to_le_bytes())?;
+    out.write_all(&(CARV2_DATA_OFFSET + car_len).to_le_bytes())?;
+
+    let copied = io::copy(&mut car.take(car_len), &mut out)?;
+    if copied != car_len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("car ended after {} of {} bytes", copied, car_len),
+        ));
+    }
+    let mut out = Counted { inner: out, n: 0 };
+    index.write_to(&mut out)?;
+    Ok(CARV2_DATA_OFFSET + car_len + out.n)
+}
+
+/// Counts the bytes written through it.
+struct Counted<W> {
+    inner: W,
+    n: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.n += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn raw_cid(data: &[u8]) -> Vec<u8> {
+        let mut cid = vec![0x01, 0x55, 0x12, 0x20];
+        cid.extend_from_slice(&hmac_sha256::Hash::hash(data));
+        cid
+    }
+
+    fn car(root: &[u8], blocks: &[&[u8]]) -> Vec<u8> {
+        let mut header = b"\xa2\x65roots\x81\xd8\x2a\x58".to_vec();
+        header.push(root.len() as u8 + 1);
+        header.push(0);
+        header.extend_from_slice(root);
+        header.extend_from_slice(b"\x67version\x01");
+
+        let mut car = Vec::new();
+        varint(header.len() as u64, &mut car);
+        car.extend(header);
+        for block in blocks {
+            let cid = raw_cid(block);
+            varint((cid.len() + block.len()) as u64, &mut car);
+            car.extend(cid);
+            car.extend_from_slice(block);
+        }
+        car
+    }
+
+    fn verify(car: &[u8], root: &Cid, read_size: usize) -> io::Result<Vec<u8>> {
+        let mut reader = CarVerifier::new(car, Some(root), None);
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; read_size];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(out);
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn test_cid() {
+        let v0 = "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n";
+        assert_eq!(Cid::parse(v0).unwrap().to_string(), v0);
+        let v1 = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+        assert_eq!(Cid::parse(v1).unwrap().to_string(), v1);
+        assert!(Cid::parse("bafy").is_err());
+    }
+
+    #[test]
+    fn test_car_verifier() {
+        let blocks: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 100 * i as usize]).collect();
+        let blocks: Vec<&[u8]> = blocks.iter().map(Vec::as_slice).collect();
+        let root = Cid(raw_cid(blocks[3]));
+        let mut payload = car(root.as_bytes(), &blocks);
+        payload.resize(payload.len() + 300, 0);
+
+        for read_size in [1, 7, 4096] {
+            assert_eq!(verify(&payload, &root, read_size).unwrap(), payload);
+        }
+
+        let mut corrupted = payload.clone();
+        corrupted[2000] ^= 1;
+        assert!(verify(&corrupted, &root, 64).is_err());
+
+        let other = Cid(raw_cid(b"other"));
+        let err = verify(&payload, &other, 64).unwrap_err();
+        assert_eq!(
+            err.into_inner()
+                .unwrap()
+                .downcast::<CarError>()
+                .unwrap()
+                .as_ref(),
+            &CarError::RootNotDeclared(other.to_string())
+        );
+
+        let without_root = car(root.as_bytes(), &blocks[4..]);
+        assert!(verify(&without_root, &root, 64).is_err());
+        assert_eq!(
+            super::verify(payload.as_slice(), &root).unwrap(),
+            payload.len() as u64
+        );
+        assert!(super::verify(without_root.as_slice(), &root).is_err());
+        assert!(verify(&payload[..payload.len() - 400], &root, 64).is_err());
+    }
+
+    #[test]
+    fn test_car_index() {
+        let blocks: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 100 * i as usize]).collect();
+        let blocks: Vec<&[u8]> = blocks.iter().map(Vec::as_slice).collect();
+        let root = Cid(raw_cid(blocks[0]));
+        let mut payload = car(root.as_bytes(), &blocks);
+        payload.resize(payload.len() + 300, 0);
+
+        let index = CarIndex::new();
+        let mut read = Vec::new();
+        CarVerifier::new(payload.as_slice(), None, Some(&index))
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, payload);
+
+        let mut idx = Vec::new();
+        index.write_to(&mut idx).unwrap();
+        assert_eq!(&idx[..2], &[0x81, 0x08]);
+        assert_eq!(&idx[2..6], &1i32.to_le_bytes());
+        assert_eq!(&idx[6..14], &SHA2_256.to_le_bytes());
+        assert_eq!(&idx[14..18], &1i32.to_le_bytes());
+        assert_eq!(&idx[18..22], &40u32.to_le_bytes());
+        assert_eq!(&idx[22..30], &(40i64 * 20).to_le_bytes());
+
+        let entries: Vec<_> = idx[30..].chunks(40).collect();
+        assert_eq!(entries.len(), 20);
+        assert!(entries.windows(2).all(|w| w[0] < w[1]));
+        for entry in entries {
+            let offset = u64::from_le_bytes(entry[32..].try_into().unwrap()) as usize;
+            let (_, varint_len) = read_varint(&payload[offset..]).unwrap().unwrap();
+            let cid = &payload[offset + varint_len..offset + varint_len + 36];
+            assert_eq!(&cid[4..], &entry[..32]);
+        }
+
+        let car_len = payload.len() as u64 - 300;
+        assert_eq!(index.car_len().unwrap(), car_len);
+        let mut v2 = Vec::new();
+        let written = write_v2(payload.as_slice(), &index, &mut v2).unwrap();
+        assert_eq!(written, v2.len() as u64);
+        assert_eq!(&v2[..11], &CARV2_PRAGMA);
+        assert_eq!(&v2[27..35], &51u64.to_le_bytes());
+        assert_eq!(&v2[35..43], &car_len.to_le_bytes());
+        assert_eq!(&v2[43..51], &(51 + car_len).to_le_bytes());
+        assert_eq!(&v2[51..51 + car_len as usize], &payload[..car_len as usize]);
+        assert_eq!(&v2[51 + car_len as usize..], &idx[..]);
+
+        // payloads which are not CARs are still read, without an index
+        let index = CarIndex::new();
+        let mut read = Vec::new();
+        CarVerifier::new(&b"not a car"[..], None, Some(&index))
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, b"not a car");
+        assert!(index.write_to(Vec::new()).is_err());
+        assert!(copy_indexed(&b"not a car"[..], io::sink()).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! Checksum listings delivered next to the pieces, `SHA256SUMS` style, when
+//! `source_checksums` is configured.
+//!
+//! Every line of a listing gives the checksum of a file of its directory,
+//! `<sha256 in hex>  <name>` as written by `sha256sum`, or `<piece CID>
+//! <name>`. Pieces given by path pick up the checksum listed for them: the
+//! sha256 is checked while the piece is streamed, the piece CID against its
+//! commitment like a `piece_cid` of the task.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use add_piece::piece_cid;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{config, PieceFile};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChecksumsConfig {
+    /// Names of the listings looked up in the directory of each piece.
+    pub file_names: Vec<String>,
+    /// Fail tasks with pieces no listing covers.
+    pub required: bool,
+}
+
+impl Default for ChecksumsConfig {
+    fn default() -> Self {
+        Self {
+            file_names: vec!["SHA256SUMS".to_string()],
+            required: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Checksum {
+    Sha256(String),
+    PieceCid(String),
+}
+
+/// Parses a listing, relative names resolved against `dir`.
+fn parse_listing(dir: &Path, content: &str) -> Result<HashMap<PathBuf, Checksum>> {
+    let mut entries = HashMap::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (sum, name) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("line {}: no file name", n + 1))?;
+        // `sha256sum` marks files read in binary mode with `*`
+        let name = name.trim_start();
+        let name = name.strip_prefix('*').unwrap_or(name);
+        let checksum = match sum.len() == 64 && sum.bytes().all(|b| b.is_ascii_hexdigit()) {
+            true => Checksum::Sha256(sum.to_ascii_lowercase()),
+            false => {
+                piece_cid::decode(sum).with_context(|| format!("line {}", n + 1))?;
+                Checksum::PieceCid(sum.to_string())
+            }
+        };
+        entries.insert(dir.join(name.trim_start_matches("./")), checksum);
+    }
+    Ok(entries)
+}
+
+/// Fills in the `sha256` or `piece_cid` of the pieces from the listings of
+/// their directories, if `source_checksums` is configured. Those the task
+/// gives already must agree with the listings.
+pub fn resolve(pieces: &mut [PieceFile]) -> Result<()> {
+    let config = match &config::global().source_checksums {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+
+    let mut listings: HashMap<PathBuf, HashMap<PathBuf, Checksum>> = HashMap::new();
+    for (index, piece) in pieces.iter_mut().enumerate() {
+        let dir = piece.path.parent().unwrap_or(Path::new(""));
+        let mut listed = None;
+        for name in &config.file_names {
+            let listing_path = dir.join(name);
+            if !listings.contains_key(&listing_path) {
+                let listing = match fs::read_to_string(&listing_path) {
+                    Ok(content) => parse_listing(dir, &content)
+                        .with_context(|| format!("parse {}", listing_path.display()))?,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                    Err(e) => {
+                        return Err(e).with_context(|| format!("read {}", listing_path.display()))
+                    }
+                };
+                listings.insert(listing_path.clone(), listing);
+            }
+            listed = listings[&listing_path].get(&piece.path).cloned();
+            if listed.is_some() {
+                debug!(path = %piece.path.display(), listing = %listing_path.display(), "checksum listed");
+                break;
+            }
+        }
+
+        match listed {
+            Some(Checksum::Sha256(sum)) => {
+                if let Some(given) = &piece.sha256 {
+                    ensure!(
+                        given.eq_ignore_ascii_case(&sum),
+                        "piece {}: sha256 {} of the task isn't the listed {}",
+                        index,
+                        given,
+                        sum
+                    );
+                }
+                piece.sha256 = Some(sum);
+            }
+            Some(Checksum::PieceCid(cid)) => {
+                if let Some(given) = &piece.piece_cid {
+                    ensure!(
+                        *given == cid,
+                        "piece {}: piece CID {} of the task isn't the listed {}",
+                        index,
+                        given,
+                        cid
+                    );
+                }
+                piece.piece_cid = Some(cid);
+            }
+            None if config.required => bail!(
+                "piece {}: no checksum listed for {}",
+                index,
+                piece.path.display()
+            ),
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// How a piece of a task was checked against a checksum, reported with the
+/// piece infos of `add_pieces`.
+#[derive(Debug, Serialize)]
+pub struct ChecksumStatus {
+    pub index: usize,
+    /// `sha256`, `piece_cid` or `none`.
+    pub checked: &'static str,
+    /// `verified` when the piece matched, `unlisted` without a checksum.
+    pub status: &'static str,
+}
+
+impl ChecksumStatus {
+    /// Status of `piece`, staged successfully as `index` of the task.
+    pub fn of(index: usize, piece: &PieceFile) -> Self {
+        let checked = match (&piece.sha256, &piece.piece_cid) {
+            (Some(_), _) => "sha256",
+            (None, Some(_)) => "piece_cid",
+            (None, None) => "none",
+        };
+        Self {
+            index,
+            checked,
+            status: if checked == "none" {
+                "unlisted"
+            } else {
+                "verified"
+            },
+        }
+    }
+}
+
+/// A payload whose sha256 isn't the one listed for it.
+#[derive(Debug, Clone)]
+pub struct ChecksumMismatch {
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} has sha256 {}, not {}",
+            self.path.display(),
+            self.actual,
+            self.expected
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Hashes what is read through it with sha256, up to the payload size of the
+/// piece, if the piece has a sha256 to check.
+pub struct Sha256Reader<'a, R> {
+    inner: R,
+    piece: &'a PieceFile,
+    hash: Option<hmac_sha256::Hash>,
+    left: u64,
+}
+
+impl<'a, R: Read> Sha256Reader<'a, R> {
+    pub fn new(inner: R, piece: &'a PieceFile) -> Self {
+        Self {
+            inner,
+            piece,
+            hash: piece.sha256.as_ref().map(|_| hmac_sha256::Hash::new()),
+            left: piece.payload_size(),
+        }
+    }
+
+    /// Fails unless what was read, the piece file up to its end, has the
+    /// sha256 of the piece.
+    pub fn check(self) -> Result<()> {
+        let (hash, expected) = match (self.hash, &self.piece.sha256) {
+            (Some(hash), Some(expected)) => (hash, expected),
+            _ => return Ok(()),
+        };
+        let actual = hex::encode(hash.finalize());
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(ChecksumMismatch {
+                path: self.piece.path.clone(),
+                expected: expected.clone(),
+                actual,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Sha256Reader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hash) = &mut self.hash {
+            let hashed = (n as u64).min(self.left);
+            hash.update(&buf[..hashed as usize]);
+            self.left -= hashed;
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,54 @@
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use crate::dedup::ChunkRoot;
+
+/// Receives the root of every padded chunk of a piece as soon as it has been
+/// hashed, in chunk order.
+///
+/// An error aborts the piece, so sinks which are only informative should log
+/// their failures and return `Ok`.
+pub trait ChunkRootSink: fmt::Debug + Send + Sync {
+    /// `index` counts the chunks of the piece from 0.
+    fn chunk_root(&self, index: usize, root: &ChunkRoot) -> io::Result<()>;
+}
+
+/// Keeps the chunk roots of a piece, so that its commitment can later be
+/// checked against them with `pure::reduce_chunk_roots` without reading the
+/// piece again.
+#[derive(Debug, Default)]
+pub struct ChunkRoots {
+    roots: Mutex<Vec<ChunkRoot>>,
+    next: Option<Arc<dyn ChunkRootSink>>,
+}
+
+impl ChunkRoots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also passes every root on to `next`, if any.
+    pub fn forwarding_to(next: Option<Arc<dyn ChunkRootSink>>) -> Self {
+        Self {
+            next,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the roots collected so far, in chunk order.
+    pub fn take(&self) -> Vec<ChunkRoot> {
+        mem::take(&mut *self.roots.lock().expect("lock chunk roots"))
+    }
+}
+
+impl ChunkRootSink for ChunkRoots {
+    fn chunk_root(&self, index: usize, root: &ChunkRoot) -> io::Result<()> {
+        if let Some(next) = &self.next {
+            next.chunk_root(index, root)?;
+        }
+        self.roots.lock().expect("lock chunk roots").push(*root);
+        Ok(())
+    }
+}
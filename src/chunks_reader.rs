@@ -1,16 +1,51 @@
 use std::io;
 use std::mem;
+use std::sync::Arc;
 
-use filecoin_hashers::{HashFunction, Hasher};
+use anyhow::{ensure, Result};
+use filecoin_hashers::{Domain, HashFunction, Hasher};
 use filecoin_proofs::constants::DefaultPieceHasher;
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::commitment_reader::CommitmentReader;
+use crate::chunk_sink::ChunkRootSink;
+use crate::commitment_reader::{compute_padded, to_root, CommitmentReader, CommitmentState};
+use crate::committer::{to_domain, PieceCommitter};
+use crate::dedup::{ChunkIndex, ChunkRoot};
+
+type HashDomain = <DefaultPieceHasher as Hasher>::Domain;
 
 pub struct ChunksReader<R: io::Read> {
     inner: CommitmentReader<R>,
     read_pos: usize,
     chunk_size: usize,
-    chunk_roots: Vec<<DefaultPieceHasher as Hasher>::Domain>,
+    chunk_roots: Vec<HashDomain>,
+    dedup: Option<Dedup>,
+    sink: Option<Arc<dyn ChunkRootSink>>,
+    committer: Option<Box<dyn PieceCommitter>>,
+}
+
+/// The hashing progress of a `ChunksReader`, which can be saved to resume
+/// hashing the same data in another process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunksState {
+    pub chunk_size: usize,
+    /// Bytes read into the current chunk.
+    pub read_pos: usize,
+    /// Roots of the chunks completed so far.
+    pub chunk_roots: Vec<ChunkRoot>,
+    /// Progress within the current chunk.
+    pub current: CommitmentState,
+    /// Bytes of the current chunk when looking up chunks in an index, which
+    /// only hashes a chunk once it is complete.
+    pub dedup_chunk: Option<Vec<u8>>,
+}
+
+/// State for chunks whose roots are looked up in a `ChunkIndex` before hashing.
+struct Dedup {
+    index: Arc<ChunkIndex>,
+    chunk: Vec<u8>,
+    hits: usize,
 }
 
 impl<R: io::Read> ChunksReader<R> {
@@ -21,10 +56,114 @@ impl<R: io::Read> ChunksReader<R> {
             read_pos: 0,
             chunk_size: chunk_size_in_bytes,
             chunk_roots: Vec::new(),
+            dedup: None,
+            sink: None,
+            committer: None,
+        }
+    }
+
+    /// Like `new`, but buffers each chunk and reuses the root recorded in
+    /// `index` when an identical chunk has been seen before.
+    pub fn with_index(chunk_size_in_bytes: usize, inner: R, index: Arc<ChunkIndex>) -> Self {
+        let mut reader = Self::new(chunk_size_in_bytes, inner);
+        reader.dedup = Some(Dedup {
+            index,
+            chunk: Vec::with_capacity(chunk_size_in_bytes),
+            hits: 0,
+        });
+        reader
+    }
+
+    /// Resumes hashing from `state`, `inner` must continue right after the
+    /// bytes `state` was taken at. `index` must be given if and only if the
+    /// snapshot was taken from a reader created by `with_index`.
+    pub fn restore(inner: R, state: &ChunksState, index: Option<Arc<ChunkIndex>>) -> Result<Self> {
+        ensure!(
+            state.dedup_chunk.is_some() == index.is_some(),
+            "chunk index given for a snapshot taken {} one",
+            if index.is_some() { "without" } else { "with" }
+        );
+
+        let dedup = match (index, &state.dedup_chunk) {
+            (Some(index), Some(chunk)) => {
+                let mut buffered = Vec::with_capacity(state.chunk_size);
+                buffered.extend_from_slice(chunk);
+                Some(Dedup {
+                    index,
+                    chunk: buffered,
+                    hits: 0,
+                })
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            inner: CommitmentReader::restore(inner, &state.current)?,
+            read_pos: state.read_pos,
+            chunk_size: state.chunk_size,
+            chunk_roots: state
+                .chunk_roots
+                .iter()
+                .map(|r| HashDomain::try_from_bytes(r))
+                .collect::<Result<_>>()?,
+            dedup,
+            sink: None,
+            committer: None,
+        })
+    }
+
+    /// Captures the hashing progress. Snapshots must be taken before `inner`
+    /// reports its end, which completes the current chunk when using an index.
+    ///
+    /// # Panics
+    ///
+    /// If the chunks are hashed by a committer set with `set_committer`.
+    pub fn snapshot(&self) -> ChunksState {
+        assert!(
+            self.committer.is_none(),
+            "can't snapshot chunks hashed by a committer"
+        );
+        ChunksState {
+            chunk_size: self.chunk_size,
+            read_pos: self.read_pos,
+            chunk_roots: self.chunk_roots.iter().map(to_root).collect(),
+            current: self.inner.snapshot(),
+            dedup_chunk: self.dedup.as_ref().map(|d| d.chunk.clone()),
         }
     }
 
-    pub fn finish(self) -> <DefaultPieceHasher as Hasher>::Domain {
+    /// Passes the root of every chunk to `sink` once it has been hashed.
+    pub fn set_sink(&mut self, sink: Arc<dyn ChunkRootSink>) {
+        self.sink = Some(sink);
+    }
+
+    /// Hashes the chunks with `committer` instead of hashing their leaves as
+    /// they are read. Chunks looked up in an index are still hashed in memory.
+    pub fn set_committer(&mut self, committer: Box<dyn PieceCommitter>) {
+        self.committer = Some(committer);
+    }
+
+    pub fn finish(mut self) -> io::Result<HashDomain> {
+        if let Some(dedup) = self.dedup.as_mut() {
+            let last = match dedup.chunk.is_empty() {
+                true => None,
+                false => Some(dedup.chunk_root()),
+            };
+            let hits = dedup.hits;
+            if let Some(root) = last {
+                self.push_root(root)?;
+            }
+            trace!("chunk index hits: {}/{}", hits, self.chunk_roots.len());
+        } else if self.read_pos > 0 {
+            // the last chunk is only pushed by a read following it, which
+            // never happens for a piece smaller than a chunk
+            let root = match self.committer.as_mut() {
+                Some(committer) => to_domain(&committer.finalize()),
+                None => self.inner.compute(),
+            };
+            self.push_root(root)?;
+        }
+
         let mut current_row = self.chunk_roots;
 
         while current_row.len() > 1 {
@@ -34,7 +173,7 @@ impl<R: io::Read> ChunksReader<R> {
                     let buf = unsafe {
                         std::slice::from_raw_parts(
                             chunk.as_ptr() as *const u8,
-                            mem::size_of::<<DefaultPieceHasher as Hasher>::Domain>() * 2,
+                            mem::size_of::<HashDomain>() * 2,
                         )
                     };
                     <DefaultPieceHasher as Hasher>::Function::hash(buf)
@@ -45,18 +184,92 @@ impl<R: io::Read> ChunksReader<R> {
         }
         debug_assert_eq!(current_row.len(), 1);
 
-        current_row
+        Ok(current_row
             .into_iter()
             .next()
-            .expect("should have been caught by debug build: len==1")
+            .expect("should have been caught by debug build: len==1"))
+    }
+
+    fn push_root(&mut self, root: HashDomain) -> io::Result<()> {
+        if let Some(sink) = &self.sink {
+            let mut raw = [0u8; 32];
+            raw.copy_from_slice(root.as_ref());
+            sink.chunk_root(self.chunk_roots.len(), &raw)?;
+        }
+        self.chunk_roots.push(root);
+        Ok(())
+    }
+
+    fn read_dedup(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let dedup = self.dedup.as_mut().expect("dedup enabled");
+        let want = buf.len().min(self.chunk_size - dedup.chunk.len());
+        let r = self.inner.get_mut().read(&mut buf[..want])?;
+        dedup.chunk.extend_from_slice(&buf[..r]);
+
+        let eof = r == 0 && want != 0;
+        if dedup.chunk.len() >= self.chunk_size || (eof && !dedup.chunk.is_empty()) {
+            let root = dedup.chunk_root();
+            dedup.chunk.clear();
+            self.push_root(root)?;
+        }
+
+        Ok(r)
+    }
+
+    fn read_committed(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_pos >= self.chunk_size {
+            self.read_pos = 0;
+            let root = self.committer.as_mut().expect("committer set").finalize();
+            self.push_root(to_domain(&root))?;
+        }
+
+        // reads stop at the end of the chunk, its root is computed by the
+        // next read or by `finish`
+        let want = buf.len().min(self.chunk_size - self.read_pos);
+        let r = self.inner.get_mut().read(&mut buf[..want])?;
+        self.committer
+            .as_mut()
+            .expect("committer set")
+            .update(&buf[..r]);
+        self.read_pos += r;
+        Ok(r)
+    }
+}
+
+impl Dedup {
+    fn chunk_root(&mut self) -> HashDomain {
+        let fingerprint = ChunkIndex::fingerprint(&self.chunk);
+        if let Some(root) = self.index.get(&fingerprint) {
+            self.hits += 1;
+            return HashDomain::try_from_bytes(&root).expect("chunk roots are 32 bytes");
+        }
+
+        let root = compute_padded(&self.chunk);
+
+        let mut raw = [0u8; 32];
+        raw.copy_from_slice(root.as_ref());
+        if let Err(e) = self.index.insert(fingerprint, raw) {
+            warn!("failed to record chunk root: {:?}", e);
+        }
+
+        root
     }
 }
 
 impl<R: io::Read> io::Read for ChunksReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.dedup.is_some() {
+            return self.read_dedup(buf);
+        }
+
+        if self.committer.is_some() {
+            return self.read_committed(buf);
+        }
+
         if self.read_pos >= self.chunk_size {
             self.read_pos = 0;
-            self.chunk_roots.push(self.inner.compute());
+            let root = self.inner.compute();
+            self.push_root(root)?;
             self.inner.reset();
         }
 
@@ -71,7 +284,6 @@ mod tests {
     use super::*;
 
     use std::io::Cursor;
-    use std::mem;
 
     use fr32::Fr32Reader;
     use storage_proofs_core::pieces::generate_piece_commitment_bytes_from_source;
@@ -96,8 +308,167 @@ mod tests {
         let mut chunks_reader = ChunksReader::new(NODE_SIZE * 4, fr32_reader);
         io::copy(&mut chunks_reader, &mut io::sink()).expect("io copy failed");
 
-        let commitment2 = chunks_reader.finish();
+        let commitment2 = chunks_reader.finish().expect("finish failed");
 
         assert_eq!(&commitment1[..], AsRef::<[u8]>::as_ref(&commitment2));
     }
+
+    #[test]
+    fn test_chunks_reader_committer() {
+        use crate::committer::CommitterBackend;
+
+        const NODE_SIZE: usize = mem::size_of::<HashDomain>();
+
+        let source: Vec<u8> = (0..127 * 16).map(|i| i as u8).collect();
+        let padded = crate::pure::pad(&source);
+
+        let mut chunks_reader = ChunksReader::new(NODE_SIZE * 4, &padded[..]);
+        io::copy(&mut chunks_reader, &mut io::sink()).expect("io copy failed");
+        let expected = chunks_reader.finish().expect("finish failed");
+
+        for backend in [CommitterBackend::Streaming, CommitterBackend::Parallel] {
+            let mut chunks_reader = ChunksReader::new(NODE_SIZE * 4, &padded[..]);
+            chunks_reader.set_committer(backend.committer());
+            let mut copied = Vec::new();
+            io::copy(&mut chunks_reader, &mut copied).expect("io copy failed");
+            assert_eq!(copied, padded);
+            assert_eq!(chunks_reader.finish().expect("finish failed"), expected);
+        }
+    }
+
+    #[test]
+    fn test_chunks_reader_smaller_than_chunk() {
+        let piece_size = 127 * 4;
+        let source: Vec<u8> = (0..piece_size).map(|i| i as u8).collect();
+        let mut fr32_reader = Fr32Reader::new(Cursor::new(&source));
+
+        let commitment1 = generate_piece_commitment_bytes_from_source::<DefaultPieceHasher>(
+            &mut fr32_reader,
+            PaddedBytesAmount::from(UnpaddedBytesAmount(piece_size as u64)).into(),
+        )
+        .expect("failed to generate piece commitment bytes from source");
+
+        let fr32_reader = Fr32Reader::new(Cursor::new(&source));
+        let mut chunks_reader = ChunksReader::new(64 * 1024 * 1024, fr32_reader);
+        io::copy(&mut chunks_reader, &mut io::sink()).expect("io copy failed");
+
+        let commitment2 = chunks_reader.finish().expect("finish failed");
+        assert_eq!(&commitment1[..], AsRef::<[u8]>::as_ref(&commitment2));
+    }
+
+    #[test]
+    fn test_chunks_reader_with_index() {
+        const NODE_SIZE: usize = mem::size_of::<HashDomain>();
+
+        // every padded chunk of the source is identical
+        let piece_size = 127 * 8;
+        let source = vec![255u8; piece_size];
+        let mut fr32_reader = Fr32Reader::new(Cursor::new(&source));
+
+        let commitment1 = generate_piece_commitment_bytes_from_source::<DefaultPieceHasher>(
+            &mut fr32_reader,
+            PaddedBytesAmount::from(UnpaddedBytesAmount(piece_size as u64)).into(),
+        )
+        .expect("failed to generate piece commitment bytes from source");
+
+        let index = Arc::new(ChunkIndex::in_memory());
+        for _ in 0..2 {
+            let fr32_reader = Fr32Reader::new(Cursor::new(&source));
+            let mut chunks_reader =
+                ChunksReader::with_index(NODE_SIZE * 4, fr32_reader, index.clone());
+            io::copy(&mut chunks_reader, &mut io::sink()).expect("io copy failed");
+
+            let commitment2 = chunks_reader.finish().expect("finish failed");
+            assert_eq!(&commitment1[..], AsRef::<[u8]>::as_ref(&commitment2));
+        }
+
+        assert!(!index.is_empty());
+    }
+
+    #[test]
+    fn test_chunks_reader_snapshot() {
+        const NODE_SIZE: usize = mem::size_of::<HashDomain>();
+
+        let source: Vec<u8> = (0..127 * 8).map(|i| i as u8).collect();
+        let padded = crate::pure::pad(&source);
+        let index = Arc::new(ChunkIndex::in_memory());
+
+        for index in [None, Some(index)] {
+            let new = |data| match &index {
+                Some(index) => ChunksReader::with_index(NODE_SIZE * 4, data, index.clone()),
+                None => ChunksReader::new(NODE_SIZE * 4, data),
+            };
+
+            let mut chunks_reader = new(&padded[..]);
+            io::copy(&mut chunks_reader, &mut io::sink()).expect("io copy failed");
+            let expected = chunks_reader.finish().expect("finish failed");
+
+            // stop in the middle of a leaf of the third chunk
+            let split = NODE_SIZE * 4 * 2 + 70;
+            let mut chunks_reader = new(&padded[..]);
+            io::copy(
+                &mut io::Read::take(&mut chunks_reader, split as u64),
+                &mut io::sink(),
+            )
+            .expect("io copy failed");
+            let state = serde_json::to_vec(&chunks_reader.snapshot()).expect("serialize");
+
+            let state: ChunksState = serde_json::from_slice(&state).expect("deserialize");
+            let mut chunks_reader = ChunksReader::restore(&padded[split..], &state, index.clone())
+                .expect("restore failed");
+            io::copy(&mut chunks_reader, &mut io::sink()).expect("io copy failed");
+            assert_eq!(chunks_reader.finish().expect("finish failed"), expected);
+
+            assert!(
+                ChunksReader::restore(&padded[split..], &state, None).is_err() == index.is_some()
+            );
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Collect {
+        roots: std::sync::Mutex<Vec<(usize, [u8; 32])>>,
+        fail_at: Option<usize>,
+    }
+
+    impl ChunkRootSink for Collect {
+        fn chunk_root(&self, index: usize, root: &[u8; 32]) -> io::Result<()> {
+            if self.fail_at == Some(index) {
+                return Err(io::Error::other("sink failed"));
+            }
+            self.roots.lock().unwrap().push((index, *root));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_chunks_reader_sink() {
+        const NODE_SIZE: usize = mem::size_of::<HashDomain>();
+
+        let piece_size = 127 * 8;
+        let source: Vec<u8> = (0..piece_size).map(|i| i as u8).collect();
+
+        let sink = Arc::new(Collect::default());
+        let mut chunks_reader = ChunksReader::new(NODE_SIZE * 4, Fr32Reader::new(&source[..]));
+        chunks_reader.set_sink(sink.clone());
+        let mut padded = Vec::new();
+        io::copy(&mut chunks_reader, &mut padded).expect("io copy failed");
+        chunks_reader.finish().expect("finish failed");
+
+        let roots = sink.roots.lock().unwrap();
+        assert_eq!(roots.len(), padded.len() / (NODE_SIZE * 4));
+        for (i, (index, root)) in roots.iter().enumerate() {
+            let chunk = &padded[i * NODE_SIZE * 4..(i + 1) * NODE_SIZE * 4];
+            assert_eq!(*index, i);
+            assert_eq!(&root[..], AsRef::<[u8]>::as_ref(&compute_padded(chunk)));
+        }
+
+        let sink = Arc::new(Collect {
+            fail_at: Some(2),
+            ..Default::default()
+        });
+        let mut chunks_reader = ChunksReader::new(NODE_SIZE * 4, Fr32Reader::new(&source[..]));
+        chunks_reader.set_sink(sink);
+        assert!(io::copy(&mut chunks_reader, &mut io::sink()).is_err());
+    }
 }
@@ -2,9 +2,13 @@ use std::cmp::min;
 use std::io::{self, Read};
 use std::mem;
 
-use filecoin_hashers::{HashFunction, Hasher};
+use anyhow::{ensure, Result};
+use filecoin_hashers::{Domain, HashFunction, Hasher};
 use filecoin_proofs::constants::DefaultPieceHasher;
 use rayon::prelude::{ParallelIterator, ParallelSlice};
+use serde::{Deserialize, Serialize};
+
+use crate::dedup::ChunkRoot;
 
 type HashDomain = <DefaultPieceHasher as Hasher>::Domain;
 
@@ -17,6 +21,15 @@ pub struct CommitmentReader<R> {
     current_tree: Vec<HashDomain>,
 }
 
+/// The hashing progress of a `CommitmentReader`, without its source.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitmentState {
+    /// Bytes read past the last hashed leaf, less than 64.
+    pub pending: Vec<u8>,
+    /// Hashes of the 64 bytes leaves read so far.
+    pub leaves: Vec<ChunkRoot>,
+}
+
 impl<R: Read> CommitmentReader<R> {
     pub fn new(source: R) -> Self {
         CommitmentReader {
@@ -27,6 +40,40 @@ impl<R: Read> CommitmentReader<R> {
         }
     }
 
+    /// Resumes hashing from `state`, `source` must continue right after the
+    /// bytes `state` was taken at.
+    pub fn restore(source: R, state: &CommitmentState) -> Result<Self> {
+        ensure!(
+            state.pending.len() < 64,
+            "invalid commitment state: {} pending bytes",
+            state.pending.len()
+        );
+
+        let mut buffer = [0u8; 64];
+        buffer[..state.pending.len()].copy_from_slice(&state.pending);
+        Ok(CommitmentReader {
+            source,
+            buffer,
+            buffer_pos: state.pending.len(),
+            current_tree: state
+                .leaves
+                .iter()
+                .map(|l| HashDomain::try_from_bytes(l))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn snapshot(&self) -> CommitmentState {
+        CommitmentState {
+            pending: self.buffer[..self.buffer_pos].to_vec(),
+            leaves: self.current_tree.iter().map(to_root).collect(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
     /// Attempt to generate the next hash, but only if the buffers are full.
     fn try_hash(&mut self) {
         if self.buffer_pos < 63 {
@@ -44,31 +91,7 @@ impl<R: Read> CommitmentReader<R> {
     pub fn compute(&self) -> HashDomain {
         // ensure!(self.buffer_pos == 0, "not enough inputs provided");
 
-        fn compute_row(row: &Vec<HashDomain>) -> Vec<HashDomain> {
-            row.par_chunks(2)
-                .map(|chunk| {
-                    let buf = unsafe {
-                        std::slice::from_raw_parts(
-                            chunk.as_ptr() as *const u8,
-                            mem::size_of::<HashDomain>() * 2,
-                        )
-                    };
-                    <DefaultPieceHasher as Hasher>::Function::hash(buf)
-                })
-                .collect::<Vec<_>>()
-        }
-
-        let mut current_row = compute_row(&self.current_tree);
-
-        while current_row.len() > 1 {
-            current_row = compute_row(&current_row);
-        }
-
-        debug_assert_eq!(current_row.len(), 1);
-
-        current_row
-            .pop()
-            .expect("should have been caught by debug build: len==1")
+        reduce(compute_row(&self.current_tree))
     }
 
     pub fn reset(&mut self) {
@@ -77,6 +100,49 @@ impl<R: Read> CommitmentReader<R> {
     }
 }
 
+pub(crate) fn to_root(hash: &HashDomain) -> ChunkRoot {
+    let mut root = [0u8; 32];
+    root.copy_from_slice(hash.as_ref());
+    root
+}
+
+fn compute_row(row: &[HashDomain]) -> Vec<HashDomain> {
+    row.par_chunks(2)
+        .map(|chunk| {
+            let buf = unsafe {
+                std::slice::from_raw_parts(
+                    chunk.as_ptr() as *const u8,
+                    mem::size_of::<HashDomain>() * 2,
+                )
+            };
+            <DefaultPieceHasher as Hasher>::Function::hash(buf)
+        })
+        .collect::<Vec<_>>()
+}
+
+pub(crate) fn reduce(mut current_row: Vec<HashDomain>) -> HashDomain {
+    while current_row.len() > 1 {
+        current_row = compute_row(&current_row);
+    }
+
+    debug_assert_eq!(current_row.len(), 1);
+
+    current_row
+        .pop()
+        .expect("should have been caught by debug build: len==1")
+}
+
+/// Calculates comm-d of bit padded, power of 2 sized data held in memory,
+/// hashing its nodes in parallel.
+pub fn compute_padded(data: &[u8]) -> HashDomain {
+    let leaves = data
+        .par_chunks(64)
+        .map(<DefaultPieceHasher as Hasher>::Function::hash)
+        .collect::<Vec<_>>();
+
+    reduce(leaves)
+}
+
 impl<R: Read> Read for CommitmentReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let start = self.buffer_pos;
@@ -129,4 +195,27 @@ mod tests {
 
         assert_eq!(&commitment1[..], AsRef::<[u8]>::as_ref(&commitment2));
     }
+
+    #[test]
+    fn test_commitment_reader_snapshot() {
+        let padded = crate::pure::pad(&[3u8; 127 * 8]);
+
+        let mut commitment_reader = CommitmentReader::new(Cursor::new(&padded));
+        io::copy(&mut commitment_reader, &mut io::sink()).expect("io copy failed");
+        let expected = commitment_reader.compute();
+
+        // stop in the middle of a leaf
+        let split = 64 * 5 + 10;
+        let mut commitment_reader = CommitmentReader::new(Cursor::new(&padded[..split]));
+        io::copy(&mut commitment_reader, &mut io::sink()).expect("io copy failed");
+        let state = serde_json::to_string(&commitment_reader.snapshot()).expect("serialize");
+
+        let state: CommitmentState = serde_json::from_str(&state).expect("deserialize");
+        let mut commitment_reader =
+            CommitmentReader::restore(Cursor::new(&padded[split..]), &state)
+                .expect("restore failed");
+        io::copy(&mut commitment_reader, &mut io::sink()).expect("io copy failed");
+
+        assert_eq!(commitment_reader.compute(), expected);
+    }
 }
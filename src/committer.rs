@@ -0,0 +1,320 @@
+//! Hashing of the padded chunks of a piece into their roots, behind a trait
+//! so that other hashing backends can be plugged in and benchmarked.
+//!
+//! The sha256 implementation the backends hash with is chosen at build time,
+//! the portable one or the `sha2-asm` feature's, both use the SHA extensions
+//! of the CPU when it has them. `CommitterBackend::Auto` picks the backend
+//! from the CPU features found at runtime, so one binary suits every host.
+
+use std::{mem, sync::OnceLock};
+
+use filecoin_hashers::{Domain, HashFunction, Hasher};
+use filecoin_proofs::constants::DefaultPieceHasher;
+use serde::{Deserialize, Serialize};
+
+use crate::commitment_reader::{reduce, to_root};
+use crate::dedup::ChunkRoot;
+use crate::hash_queue;
+
+type HashDomain = <DefaultPieceHasher as Hasher>::Domain;
+
+/// Computes the root of a chunk of bit padded, power of 2 sized data fed to
+/// it in pieces of any size.
+pub trait PieceCommitter: Send {
+    fn update(&mut self, padded: &[u8]);
+
+    /// Root of the bytes fed since the last call, after which the committer
+    /// starts over.
+    fn finalize(&mut self) -> ChunkRoot;
+}
+
+/// The committers `add_piece` can be configured with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitterBackend {
+    /// `streaming` if the CPU has SHA extensions, which hash faster than the
+    /// sources are usually read, or a single core, `parallel` otherwise.
+    #[default]
+    Auto,
+    /// Hashes the leaves as they are read, on the reading thread.
+    Streaming,
+    /// Buffers each chunk and hashes its leaves on all cores. The roots are
+    /// the same as `streaming`'s however the batches are scheduled, and the
+    /// padded bytes are written in order all the same.
+    Parallel,
+}
+
+impl CommitterBackend {
+    /// The backend `Auto` stands for on this host, other backends as is.
+    pub fn resolve(self) -> CommitterBackend {
+        static AUTO: OnceLock<CommitterBackend> = OnceLock::new();
+        match self {
+            CommitterBackend::Auto => *AUTO.get_or_init(|| {
+                let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+                match CpuFeatures::detect().sha_extensions || cores == 1 {
+                    true => CommitterBackend::Streaming,
+                    false => CommitterBackend::Parallel,
+                }
+            }),
+            backend => backend,
+        }
+    }
+
+    /// The backend hashing a piece of `padded_size` bytes: `Auto` picks it
+    /// by the size of the piece past the `thresholds`, by the CPU in
+    /// between. Other backends are kept whatever the size.
+    pub fn for_piece(self, padded_size: u64, thresholds: &CommitterThresholds) -> Self {
+        match self {
+            CommitterBackend::Auto if padded_size <= thresholds.streaming_up_to => {
+                CommitterBackend::Streaming
+            }
+            CommitterBackend::Auto if padded_size >= thresholds.parallel_from => {
+                CommitterBackend::Parallel
+            }
+            backend => backend.resolve(),
+        }
+    }
+
+    pub fn committer(self) -> Box<dyn PieceCommitter> {
+        self.piece_committer(0)
+    }
+
+    /// The committer of a piece of `padded_size` bytes, 0 if unknown.
+    pub fn piece_committer(self, padded_size: u64) -> Box<dyn PieceCommitter> {
+        match self.resolve() {
+            CommitterBackend::Auto => unreachable!("resolved"),
+            CommitterBackend::Streaming => Box::<StreamingCommitter>::default(),
+            CommitterBackend::Parallel => Box::new(ParallelCommitter::for_piece(padded_size)),
+        }
+    }
+}
+
+/// Piece sizes from which `Auto` picks the backend by the size of the piece
+/// rather than by the CPU: spreading small pieces over the cores costs more
+/// than it saves, while hashing huge ones on the reading thread leaves the
+/// other cores idle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommitterThresholds {
+    /// Pieces up to this padded size are hashed `streaming`.
+    pub streaming_up_to: u64,
+    /// Pieces from this padded size on are hashed `parallel`.
+    pub parallel_from: u64,
+}
+
+impl Default for CommitterThresholds {
+    fn default() -> Self {
+        Self {
+            streaming_up_to: 8 << 20,
+            parallel_from: 4 << 30,
+        }
+    }
+}
+
+/// The CPU features which speed up hashing, found at runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CpuFeatures {
+    /// SHA-NI on x86, the SHA2 instructions on aarch64.
+    pub sha_extensions: bool,
+    pub avx2: bool,
+    pub neon: bool,
+}
+
+impl CpuFeatures {
+    #[allow(unreachable_code)]
+    pub fn detect() -> CpuFeatures {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        return CpuFeatures {
+            sha_extensions: std::arch::is_x86_feature_detected!("sha")
+                && std::arch::is_x86_feature_detected!("sse4.1"),
+            avx2: std::arch::is_x86_feature_detected!("avx2"),
+            neon: false,
+        };
+        #[cfg(target_arch = "aarch64")]
+        return CpuFeatures {
+            sha_extensions: std::arch::is_aarch64_feature_detected!("sha2"),
+            avx2: false,
+            neon: std::arch::is_aarch64_feature_detected!("neon"),
+        };
+        CpuFeatures::default()
+    }
+}
+
+/// Hashes every 64 bytes leaf as soon as it is complete, like
+/// `CommitmentReader`.
+#[derive(Debug, Default)]
+pub struct StreamingCommitter {
+    pending: Vec<u8>,
+    leaves: Vec<HashDomain>,
+}
+
+impl PieceCommitter for StreamingCommitter {
+    fn update(&mut self, mut padded: &[u8]) {
+        let hash = <DefaultPieceHasher as Hasher>::Function::hash;
+        if !self.pending.is_empty() {
+            let take = padded.len().min(64 - self.pending.len());
+            self.pending.extend_from_slice(&padded[..take]);
+            padded = &padded[take..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            self.leaves.push(hash(&self.pending));
+            self.pending.clear();
+        }
+
+        let mut leaves = padded.chunks_exact(64);
+        for leaf in &mut leaves {
+            self.leaves.push(hash(leaf));
+        }
+        self.pending.extend_from_slice(leaves.remainder());
+    }
+
+    fn finalize(&mut self) -> ChunkRoot {
+        self.pending.clear();
+        to_root(&reduce(std::mem::take(&mut self.leaves)))
+    }
+}
+
+/// Keeps the chunk in memory and hashes it on the shared `hash_queue` once
+/// complete, trading a chunk of memory for the cores the hashing spreads to.
+#[derive(Debug, Default)]
+pub struct ParallelCommitter {
+    chunk: Vec<u8>,
+    /// Padded size of the piece, 0 if unknown.
+    piece_size: u64,
+    /// Padded bytes of the piece hashed in the previous chunks.
+    hashed: u64,
+}
+
+impl ParallelCommitter {
+    /// A committer for a piece of `padded_size` bytes, whose chunks are
+    /// hashed ahead of those of larger pieces.
+    pub fn for_piece(padded_size: u64) -> Self {
+        Self {
+            piece_size: padded_size,
+            ..Default::default()
+        }
+    }
+}
+
+impl PieceCommitter for ParallelCommitter {
+    fn update(&mut self, padded: &[u8]) {
+        self.chunk.extend_from_slice(padded);
+    }
+
+    fn finalize(&mut self) -> ChunkRoot {
+        let remaining = match self.piece_size {
+            0 => u64::MAX,
+            size => size.saturating_sub(self.hashed),
+        };
+        self.hashed += self.chunk.len() as u64;
+        let (root, chunk) = hash_queue::compute_root(mem::take(&mut self.chunk), remaining);
+        self.chunk = chunk;
+        to_root(&root)
+    }
+}
+
+pub(crate) fn to_domain(root: &ChunkRoot) -> HashDomain {
+    HashDomain::try_from_bytes(root).expect("chunk roots are 32 bytes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{self, Cursor};
+
+    use crate::commitment_reader::CommitmentReader;
+
+    #[test]
+    fn test_committers_match_commitment_reader() {
+        let padded: Vec<u8> = (0..64 * 64u32).map(|i| (i * 31) as u8 & 0x3f).collect();
+        let mut reader = CommitmentReader::new(Cursor::new(&padded));
+        io::copy(&mut reader, &mut io::sink()).expect("io copy failed");
+        let expected = to_root(&reader.compute());
+
+        for backend in [
+            CommitterBackend::Auto,
+            CommitterBackend::Streaming,
+            CommitterBackend::Parallel,
+        ] {
+            let mut committer = backend.committer();
+            // fed in pieces which split the leaves
+            for piece in padded.chunks(100) {
+                committer.update(piece);
+            }
+            assert_eq!(committer.finalize(), expected, "{:?}", backend);
+
+            // starts over once finalized
+            committer.update(&padded);
+            assert_eq!(committer.finalize(), expected, "{:?}", backend);
+        }
+        assert_ne!(CommitterBackend::Auto.resolve(), CommitterBackend::Auto);
+    }
+
+    #[test]
+    fn test_parallel_committers_deterministic() {
+        // a few batches of the hash queue per piece
+        let pieces: Vec<Vec<u8>> = (0..4u32)
+            .map(|p| {
+                (0..(2 << 20) as u32)
+                    .map(|i| (i.wrapping_mul(31 + p) >> 3) as u8 & 0x3f)
+                    .collect()
+            })
+            .collect();
+        let expected: Vec<_> = pieces
+            .iter()
+            .map(|padded| {
+                let mut committer = CommitterBackend::Streaming.committer();
+                committer.update(padded);
+                committer.finalize()
+            })
+            .collect();
+
+        // pieces hashed at once share the queue, each gets its own roots
+        for _ in 0..2 {
+            let roots: Vec<_> = std::thread::scope(|s| {
+                let handles: Vec<_> = pieces
+                    .iter()
+                    .map(|padded| {
+                        s.spawn(move || {
+                            let mut committer =
+                                CommitterBackend::Parallel.piece_committer(padded.len() as u64);
+                            for piece in padded.chunks(100_000) {
+                                committer.update(piece);
+                            }
+                            committer.finalize()
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            });
+            assert_eq!(roots, expected);
+        }
+    }
+
+    #[test]
+    fn test_committer_for_piece() {
+        let thresholds = CommitterThresholds::default();
+        let auto = CommitterBackend::Auto;
+        assert_eq!(
+            auto.for_piece(2 << 10, &thresholds),
+            CommitterBackend::Streaming
+        );
+        assert_eq!(
+            auto.for_piece(8 << 20, &thresholds),
+            CommitterBackend::Streaming
+        );
+        assert_eq!(auto.for_piece(16 << 20, &thresholds), auto.resolve());
+        assert_eq!(
+            auto.for_piece(32 << 30, &thresholds),
+            CommitterBackend::Parallel
+        );
+
+        // explicit backends are kept
+        for backend in [CommitterBackend::Streaming, CommitterBackend::Parallel] {
+            assert_eq!(backend.for_piece(2 << 10, &thresholds), backend);
+            assert_eq!(backend.for_piece(32 << 30, &thresholds), backend);
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use add_piece::{
+    piece_cid,
+    predict::{self, IncrementalCommitment, UNPADDED_CHUNK_SIZE},
+    sizes,
+};
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::PaddedBytesAmount;
+use rayon::prelude::*;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::status;
+
+/// The piece a local file becomes once zero padded, as negotiated in deals.
+#[derive(Debug, Serialize)]
+pub struct CommpResult {
+    pub path: PathBuf,
+    pub payload_size: u64,
+    /// Unpadded size of the piece.
+    pub piece_size: u64,
+    pub piece_cid: String,
+}
+
+/// Computes the piece of the file at `path`, which must fit in sectors of
+/// `sector_size` bytes if given.
+pub fn commp(path: &Path, sector_size: Option<u64>) -> Result<CommpResult> {
+    let payload_size = path
+        .metadata()
+        .with_context(|| format!("stat {}", path.display()))?
+        .len();
+    if let Some(sector_size) = sector_size {
+        let padded = PaddedBytesAmount::from(sizes::piece_size_for(payload_size));
+        ensure!(
+            u64::from(padded) <= sector_size,
+            "a piece of {} padded bytes doesn't fit in a {} bytes sector",
+            u64::from(padded),
+            sector_size
+        );
+    }
+    // the file is hashed in one go, its bytes are counted once it is
+    let task_status = status::start_task(path, vec![payload_size]);
+    let progress = task_status.start_piece(0, "", payload_size);
+    let piece_info = predict::predict_piece_info(path)?;
+    progress.add(payload_size);
+    Ok(CommpResult {
+        path: path.to_path_buf(),
+        payload_size,
+        piece_size: piece_info.size.into(),
+        piece_cid: piece_cid::encode(&piece_info.commitment),
+    })
+}
+
+/// A line of the output of `commp_all`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum CommpLine {
+    Ok(CommpResult),
+    Err { path: PathBuf, error: String },
+}
+
+/// Replaces the directories of `paths` by the files directly in them, sorted.
+fn expand(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut entries = fs::read_dir(path)
+            .with_context(|| format!("read dir {}", path.display()))?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.retain(|p| p.is_file());
+        entries.sort();
+        files.extend(entries);
+    }
+    Ok(files)
+}
+
+/// Runs `f` on `items` on the threads of `pool` and hands its results to
+/// `emit` in the order of `items`, each as soon as the ones before it are.
+/// Stops at the first error of `emit`, leaving the remaining items out.
+pub fn in_order<'a, T, R>(
+    pool: &rayon::ThreadPool,
+    items: &'a [T],
+    f: impl Fn(&'a T) -> R + Sync,
+    mut emit: impl FnMut(R) -> Result<()>,
+) -> Result<()>
+where
+    T: Sync,
+    R: Send,
+{
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|s| {
+        s.spawn(|| {
+            pool.install(|| {
+                items
+                    .par_iter()
+                    .enumerate()
+                    .try_for_each_with(tx, |tx, (i, item)| tx.send((i, f(item))))
+            })
+        });
+        // dropping the receiver on error makes the workers stop
+        let rx = rx;
+        let mut done = BTreeMap::new();
+        let mut next = 0;
+        for (i, res) in rx.iter() {
+            done.insert(i, res);
+            while let Some(res) = done.remove(&next) {
+                emit(res)?;
+                next += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Computes the pieces of the files of `paths` on `jobs` threads, writing a
+/// JSON line per file to `out` in the order of the files, each as soon as the
+/// ones before it are done. Fails at the end if any file failed, its line
+/// holding the error, and stops quietly once `out` is a closed pipe.
+pub fn commp_all(
+    paths: &[PathBuf],
+    jobs: usize,
+    sector_size: Option<u64>,
+    out: &mut impl Write,
+) -> Result<()> {
+    let files = expand(paths)?;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .context("build commp thread pool")?;
+
+    let mut failed = 0;
+    let res = in_order(
+        &pool,
+        &files,
+        |path| (path, commp(path, sector_size)),
+        |(path, res)| {
+            let line = match res {
+                Ok(res) => CommpLine::Ok(res),
+                Err(e) => {
+                    let error = format!("{:#}", e);
+                    warn!(path = %path.display(), err = %error, "commp failed");
+                    failed += 1;
+                    CommpLine::Err {
+                        path: path.clone(),
+                        error,
+                    }
+                }
+            };
+            let line = serde_json::to_string(&line).expect("serialize commp line");
+            writeln!(out, "{}", line)?;
+            Ok(())
+        },
+    );
+    // the reader went away, as `head` does once it has its lines: the files
+    // left would be hashed for nothing
+    let closed = res
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<io::Error>());
+    if closed.is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) {
+        debug!("output closed, stop computing pieces");
+        return Ok(());
+    }
+    res.context("write commp line")?;
+
+    ensure!(
+        failed == 0,
+        "commp failed for {} of {} files",
+        failed,
+        files.len()
+    );
+    Ok(())
+}
+
+/// How `commp_tail` follows a file being written.
+#[derive(Debug, Clone)]
+pub struct TailOptions {
+    /// Created by the writer once the file is complete.
+    pub done_marker: PathBuf,
+    /// Progress saved at every chunk so that a restart resumes from it.
+    pub state: PathBuf,
+    pub poll: Duration,
+}
+
+impl TailOptions {
+    /// `<path>.done` and `<path>.commp-state`, polling every 5 seconds.
+    pub fn for_file(path: &Path) -> Self {
+        let with_suffix = |suffix: &str| {
+            let mut p = OsString::from(path.as_os_str());
+            p.push(suffix);
+            PathBuf::from(p)
+        };
+        Self {
+            done_marker: with_suffix(".done"),
+            state: with_suffix(".commp-state"),
+            poll: Duration::from_secs(5),
+        }
+    }
+}
+
+fn save_state(path: &Path, commitment: &IncrementalCommitment) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let content = serde_json::to_vec(commitment).context("serialize commp state")?;
+    fs::write(&tmp, content)
+        .and_then(|_| fs::rename(&tmp, path))
+        .with_context(|| format!("save commp state {}", path.display()))
+}
+
+/// Computes the piece of a file still being written, hashing its bytes as
+/// they land and finishing once `done_marker` exists.
+pub fn commp_tail(path: &Path, options: &TailOptions) -> Result<CommpResult> {
+    let mut commitment = match fs::read(&options.state) {
+        Ok(content) => serde_json::from_slice(&content)
+            .with_context(|| format!("parse commp state {}", options.state.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => IncrementalCommitment::new(),
+        Err(e) => return Err(e).context("read commp state"),
+    };
+    if commitment.consumed() > 0 {
+        info!(consumed = commitment.consumed(), "resuming commp");
+    }
+
+    let mut file = fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut buf = Vec::new();
+    loop {
+        // the marker is checked first so that the length read after it is final
+        let done = options.done_marker.exists();
+        let len = file.metadata().context("stat payload")?.len();
+        ensure!(
+            len >= commitment.consumed(),
+            "{} shrank below the {} bytes already hashed",
+            path.display(),
+            commitment.consumed()
+        );
+
+        // hash up to the end of the current chunk at once, so the state is
+        // saved when it is small
+        let available = len - commitment.consumed();
+        let to_boundary = UNPADDED_CHUNK_SIZE - commitment.consumed() % UNPADDED_CHUNK_SIZE;
+        let want = available.min(to_boundary);
+        if want >= 127 || (done && available > 0) {
+            buf.resize(want as usize, 0);
+            file.seek(SeekFrom::Start(commitment.consumed()))?;
+            file.read_exact(&mut buf).context("read payload")?;
+            let n = commitment.update(&buf)?;
+            debug!(consumed = commitment.consumed(), len, "hashed");
+            if commitment.consumed() % UNPADDED_CHUNK_SIZE == 0 {
+                save_state(&options.state, &commitment)?;
+            }
+            if n > 0 {
+                continue;
+            }
+        }
+
+        if done {
+            let tail = &buf[..(available % 127) as usize];
+            let piece_info = commitment.finish(tail)?;
+            if let Err(e) = fs::remove_file(&options.state) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!(err = ?e, "failed to remove commp state");
+                }
+            }
+            return Ok(CommpResult {
+                path: path.to_path_buf(),
+                payload_size: len,
+                piece_size: piece_info.size.into(),
+                piece_cid: piece_cid::encode(&piece_info.commitment),
+            });
+        }
+        thread::sleep(options.poll);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_order() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        // the first items finish last
+        let items: Vec<u64> = (0..8).rev().collect();
+        let mut emitted = Vec::new();
+        in_order(
+            &pool,
+            &items,
+            |ms| {
+                thread::sleep(Duration::from_millis(ms * 20));
+                *ms
+            },
+            |ms| {
+                emitted.push(ms);
+                Ok(())
+            },
+        )
+        .expect("in_order failed");
+        assert_eq!(emitted, items);
+
+        let mut emitted = 0;
+        let res = in_order(
+            &pool,
+            &items,
+            |ms| *ms,
+            |_| {
+                emitted += 1;
+                ensure!(emitted < 3, "closed");
+                Ok(())
+            },
+        );
+        assert!(res.is_err());
+        assert_eq!(emitted, 3);
+    }
+
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_commp_all_closed_output() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("file");
+        fs::write(&path, [1u8; 200]).unwrap();
+        commp_all(&[path], 2, None, &mut ClosedPipe).expect("commp_all failed");
+    }
+
+    #[test]
+    fn test_commp_all_in_order() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let paths: Vec<_> = [1 << 20, 64 << 10, 1 << 10, 200]
+            .iter()
+            .enumerate()
+            .map(|(i, len)| {
+                let path = dir.path().join(format!("file-{}", i));
+                fs::write(&path, vec![i as u8; *len]).unwrap();
+                path
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        commp_all(&paths, 4, None, &mut out).expect("commp_all failed");
+        let listed: Vec<PathBuf> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let line: serde_json::Value = serde_json::from_str(line).unwrap();
+                PathBuf::from(line["path"].as_str().unwrap())
+            })
+            .collect();
+        assert_eq!(listed, paths);
+    }
+}
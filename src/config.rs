@@ -0,0 +1,406 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use add_piece::{
+    aligned_writer::AlignedWriterConfig,
+    committer::{CommitterBackend, CommitterThresholds},
+    content_type::ContentPolicy,
+    dedup::ChunkIndex,
+    empty_source::EmptySourcePolicy,
+    middleware::RateLimit,
+    overflow::SourceOverflow,
+    periodic_sync::PeriodicSyncConfig,
+    read_ahead::ReadAheadConfig,
+    reservation,
+    staged_format::StagedFormat,
+    would_block::WouldBlockWait,
+    write_behind::WriteBehindConfig,
+    zero_fill::ZeroFillConfig,
+    AddPieceOptions, AlignmentLimit, PaddingWarning,
+};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    abort::AbortConfig,
+    attestation::AttestationConfig,
+    checksums::ChecksumsConfig,
+    credentials::CredentialChain,
+    deadline::DeadlineConfig,
+    duplicates::DuplicatePolicy,
+    http_target::HttpTargetConfig,
+    keys::EncryptionConfig,
+    local_staging::LocalStagingConfig,
+    metrics::MetricsConfig,
+    mount_limits::MountLimit,
+    psi::MemoryPressureConfig,
+    registration::RegistrationConfig,
+    s3::S3Config,
+    seen_chunks::SeenChunksConfig,
+    source::{SizeCheckConfig, SourcePathPolicy},
+    staging::StagedOpenMode,
+    target_health::TargetHealthConfig,
+    target_profile::TargetProfile,
+    verifier::ChunkVerifierConfig,
+    watchdog::WatchdogConfig,
+    webhook::WebhookConfig,
+};
+
+static GLOBAL: OnceLock<Config> = OnceLock::new();
+static CHUNK_INDEX: OnceLock<Arc<ChunkIndex>> = OnceLock::new();
+
+/// Runtime configuration shared by the processor and the standalone commands.
+///
+/// Loaded from a JSON file given by `--config` or the `ADD_PIECE_CONFIG`
+/// environment variable; every section is optional.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Webhooks fired when a task finishes.
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// Reuse the roots of identical padded chunks across pieces.
+    pub dedup: Option<DedupConfig>,
+
+    /// Flag tasks made mostly of chunks staged before.
+    pub seen_chunks: Option<SeenChunksConfig>,
+
+    /// Directories holding pieces under their piece CID, searched in order
+    /// for the pieces of a task given by CID only.
+    pub piece_dirs: Vec<PathBuf>,
+
+    /// Bytes per second each piece source is read at most, not used by
+    /// `--origin`.
+    pub source_rate_limit: Option<u64>,
+
+    /// Bytes per second written at most to each staged file, not used by
+    /// `--origin`.
+    pub target_rate_limit: Option<u64>,
+
+    /// Prefetch piece sources on a dedicated thread.
+    pub read_ahead: Option<ReadAheadConfig>,
+
+    /// Queue writes to the staged file so hashing can run ahead of a slow target.
+    pub write_behind: Option<WriteBehindConfig>,
+
+    /// Size of the writes to the staged file and how often it is synced.
+    pub target_writes: Option<AlignedWriterConfig>,
+
+    /// Sync the staged file every `bytes` written or once data has been
+    /// waiting `interval_ms` for a sync, on the write-behind thread if any,
+    /// bounding the hashed work a crash may lose.
+    pub staged_sync: Option<PeriodicSyncConfig>,
+
+    /// How zero filled regions are written: the alignment around pieces is
+    /// always written out, cc sectors and the zero pieces completing sectors
+    /// may be left as holes or zeroed by the filesystem.
+    pub zero_fill: ZeroFillConfig,
+
+    /// Layout of the staged files, `v2` is not supported by remote targets.
+    pub staged_format: StagedFormat,
+
+    /// What becomes of a staged file which already exists, overridden by
+    /// `--staged-open` and by the `staged_open` of a CLI task:
+    /// `truncate_existing`, `create_new`, `append_existing`, `fail_if_exists`
+    /// or `resume_existing`.
+    pub staged_open: StagedOpenMode,
+
+    /// Journal every chunk of the staged files in `<staged>.journal` once it
+    /// is synced, resumes then only reuse the pieces it covers.
+    pub chunk_journal: bool,
+
+    /// Staged files written and piece files read at once under some paths,
+    /// e.g. at most 2 staged files written to `/mnt/nfs1`, across all tasks.
+    pub mount_limits: Vec<MountLimit>,
+
+    /// Tuning for the filesystem holding the staged files, overridden by
+    /// `--target-profile`.
+    pub target_profile: TargetProfile,
+
+    /// Fail (or warn) when a sector would hold too many alignment bytes.
+    pub alignment_limit: Option<AlignmentLimit>,
+
+    /// Warn about pieces whose alignment is more than a percentage of their
+    /// size, they are flagged in the manifest and counted in the status.
+    pub padding_warning: Option<PaddingWarning>,
+
+    /// Compare the length of local piece files with their declared size before
+    /// staging anything, on by default.
+    pub source_size_check: SizeCheckConfig,
+
+    /// Whether symlinked, cross-device and network piece files are accepted
+    /// and how the latter are opened.
+    pub source_paths: SourcePathPolicy,
+
+    /// What to do with source bytes past the piece size: `unchecked` (fail
+    /// after reading them all), `ignore` or `error`.
+    pub source_overflow: SourceOverflow,
+
+    /// What to do with piece files holding no bytes: `error` (before staging
+    /// anything) or `pad_up` (stage a piece of zeros).
+    pub empty_source: EmptySourcePolicy,
+
+    /// How long to wait for sources which would block, as non-blocking
+    /// sockets do while no data arrived: the backoff between two reads and an
+    /// optional `timeout_ms` after which the piece fails.
+    pub source_would_block: WouldBlockWait,
+
+    /// Check the pieces of `add_pieces` tasks against the `SHA256SUMS` or
+    /// piece CID listings delivered in their directories.
+    pub source_checksums: Option<ChecksumsConfig>,
+
+    /// How the padded chunks of the pieces are hashed: `auto` (picked from the
+    /// CPU features), `streaming` (as they are read) or `parallel` (on all
+    /// cores once a chunk is buffered).
+    pub committer: CommitterBackend,
+
+    /// Piece sizes from which `auto` picks the committer by the size of the
+    /// piece: `streaming` up to `streaming_up_to` (8MiB), `parallel` from
+    /// `parallel_from` (4GiB).
+    pub committer_thresholds: CommitterThresholds,
+
+    /// Stream the chunk roots of every piece to an external verifier, not used
+    /// by `--origin`.
+    pub chunk_verifier: Option<ChunkVerifierConfig>,
+
+    /// Store receiving the staged files of `s3://` targets.
+    pub s3: Option<S3Config>,
+
+    /// Providers of the credentials of the targets, by scheme (`s3`, `http`,
+    /// `https`): `{"env": {name: var}}`, `{"file": path}`, `{"command":
+    /// [program, args...]}` or `"imds"`, tried in order.
+    pub credentials: BTreeMap<String, CredentialChain>,
+
+    /// Uploads of the staged files of `http://` and `https://` targets.
+    pub http_target: Option<HttpTargetConfig>,
+
+    /// Encrypt the payloads before they are padded, not used by `--origin`.
+    pub encryption: Option<EncryptionConfig>,
+
+    /// Reject pieces by the type of their payload, not used by `--origin`.
+    pub content_policy: Option<ContentPolicy>,
+
+    /// Write the CARv2 index of every CARv1 payload to `<piece cid>.idx` in
+    /// this directory, not used by `--origin`.
+    pub car_index_dir: Option<PathBuf>,
+
+    /// Hash the payload of every piece with blake3 while staging, recorded
+    /// in the manifest as `payload_blake3`, not used by `--origin`.
+    pub payload_blake3: bool,
+
+    /// Stage a piece once more with the origin `filecoin_proofs`
+    /// implementation if its commitment doesn't match the `piece_cid` of its
+    /// task, reporting both commitments. Not with `encryption`.
+    pub origin_retry: bool,
+
+    /// Reject tasks too large to be legitimate before doing any work.
+    pub task_limits: Option<TaskLimits>,
+
+    /// What to do with pieces repeated within a task: `allow`, `warn` (the
+    /// default), `dedupe` or `error`. Processor tasks take `dedupe` as `warn`,
+    /// the worker expecting a piece info for each of their pieces.
+    pub duplicate_pieces: DuplicatePolicy,
+
+    /// Converts the deal start epochs of the pieces into deadlines.
+    pub deadlines: Option<DeadlineConfig>,
+
+    /// Warn about (or abort) pieces whose source is read too slowly.
+    pub stall_watchdog: Option<WatchdogConfig>,
+
+    /// Record every task with its source bytes under this directory so it can
+    /// be replayed, overridden by `--record`.
+    pub record_dir: Option<PathBuf>,
+
+    /// Abort the standalone commands when a file appears or a pipe receives a
+    /// message, the processor is not affected.
+    pub abort: Option<AbortConfig>,
+
+    /// Root of the scratch directories of the tasks, by default a hidden
+    /// directory next to the staged files.
+    pub scratch_dir: Option<PathBuf>,
+
+    /// SQLite database recording every staged piece, read by `history` and
+    /// `query`.
+    pub ledger: Option<PathBuf>,
+
+    /// Stage the files of the processor tasks in this local directory and
+    /// move them to their path once complete, copying and verifying them
+    /// across filesystems. Only raw staged files can be moved.
+    pub local_staging: Option<LocalStagingConfig>,
+
+    /// Also write the fr32-unpadded bytes of the staged files to
+    /// `<staged>.unpadded` while staging, a fast retrieval copy. Local staged
+    /// files only, not with `encryption`.
+    pub unpadded_copy: bool,
+
+    /// How long a staging directory whose filesystem failed a task (read
+    /// only, full or over quota) keeps rejecting the next tasks.
+    pub target_health: TargetHealthConfig,
+
+    /// Serves Prometheus metrics of the processor tasks and pieces.
+    pub metrics: Option<MetricsConfig>,
+
+    /// Claim a lease on every local staged file while staging it, so that
+    /// workers on several hosts sharing the staging storage never stage the
+    /// same sector at once.
+    pub lease: Option<LeaseConfig>,
+
+    /// Hold less memory while the processor's cgroup is short of it, rather
+    /// than be killed in the middle of a sector.
+    pub memory_pressure: Option<MemoryPressureConfig>,
+
+    /// Registers the processor with a cluster manager when it starts, then
+    /// keeps pinging it.
+    pub registration: Option<RegistrationConfig>,
+
+    /// Sign the manifests of the staged files with the ed25519 key of the
+    /// worker, and trust the keys of the workers listed, see
+    /// `verify-attestation`.
+    pub attestation: Option<AttestationConfig>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    /// File persisting the chunk index, it is kept in memory only if unset.
+    pub index_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaseConfig {
+    /// Name the leases are held under, unique to each worker process by
+    /// default. The `claim` and `release` commands default to the host name.
+    pub holder: Option<String>,
+    /// Seconds a lease lasts unless renewed, it is renewed as every piece
+    /// starts.
+    pub ttl_secs: u64,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        Self {
+            holder: None,
+            ttl_secs: 3600,
+        }
+    }
+}
+
+impl LeaseConfig {
+    pub fn holder(&self) -> String {
+        self.holder
+            .clone()
+            .unwrap_or_else(reservation::default_holder)
+    }
+}
+
+/// Bounds on the tasks accepted, unset bounds are not checked.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaskLimits {
+    pub max_pieces: Option<usize>,
+    /// Sum of the unpadded piece sizes of a task.
+    pub max_total_bytes: Option<u64>,
+    /// Unpadded size of any single piece.
+    pub max_piece_bytes: Option<u64>,
+}
+
+impl TaskLimits {
+    /// Checks a task adding pieces of the given unpadded sizes.
+    pub fn check(&self, piece_sizes: &[u64]) -> Result<()> {
+        if let Some(max) = self.max_pieces {
+            ensure!(
+                piece_sizes.len() <= max,
+                "task holds {} pieces, at most {} are allowed",
+                piece_sizes.len(),
+                max
+            );
+        }
+        if let Some(max) = self.max_piece_bytes {
+            if let Some((index, size)) = piece_sizes.iter().enumerate().find(|(_, s)| **s > max) {
+                bail!(
+                    "piece {} is {} bytes, at most {} are allowed",
+                    index,
+                    size,
+                    max
+                );
+            }
+        }
+        if let Some(max) = self.max_total_bytes {
+            let total = piece_sizes
+                .iter()
+                .fold(0u64, |acc, s| acc.saturating_add(*s));
+            ensure!(
+                total <= max,
+                "task holds {} bytes of pieces, at most {} are allowed",
+                total,
+                max
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("read config file: {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("parse config file: {}", path.display()))
+    }
+
+    /// Builds the `add_piece` options described by this config.
+    pub fn add_piece_options(&self) -> Result<AddPieceOptions> {
+        let mut options = AddPieceOptions {
+            writes: self.target_writes.unwrap_or_default(),
+            alignment_limit: self.alignment_limit,
+            padding_warning: self.padding_warning,
+            content_policy: self.content_policy.clone(),
+            source_overflow: self.source_overflow,
+            empty_source: self.empty_source,
+            would_block: self.source_would_block,
+            committer: self.committer,
+            committer_thresholds: self.committer_thresholds,
+            zero_fill: self.zero_fill,
+            ..Default::default()
+        };
+
+        if let Some(bytes_per_sec) = self.source_rate_limit {
+            options = options.source_layer(RateLimit { bytes_per_sec });
+        }
+        if let Some(bytes_per_sec) = self.target_rate_limit {
+            options = options.target_layer(RateLimit { bytes_per_sec });
+        }
+
+        if let Some(dedup) = &self.dedup {
+            let index = match CHUNK_INDEX.get() {
+                Some(index) => index.clone(),
+                None => {
+                    let index = match &dedup.index_path {
+                        Some(p) => ChunkIndex::open(p)?,
+                        None => ChunkIndex::in_memory(),
+                    };
+                    CHUNK_INDEX.get_or_init(|| Arc::new(index)).clone()
+                }
+            };
+            options.chunk_index = Some(index);
+        }
+
+        Ok(options)
+    }
+}
+
+/// Installs the process wide config, must be called before the first `global()`.
+pub fn init(config: Config) {
+    let _ = GLOBAL.set(config);
+}
+
+/// Returns the process wide config, falling back to the defaults.
+pub fn global() -> &'static Config {
+    GLOBAL.get_or_init(Config::default)
+}
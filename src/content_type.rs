@@ -0,0 +1,191 @@
+use std::fmt;
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+
+/// Number of leading payload bytes `detect` needs to recognize every known type.
+pub const SNIFF_LEN: usize = 512;
+
+/// Well known payload types, recognized by their leading magic bytes.
+const MAGICS: &[(&str, usize, &[u8])] = &[
+    ("carv2", 0, b"\x0a\xa1\x67version\x02"),
+    ("gzip", 0, b"\x1f\x8b"),
+    ("zstd", 0, b"\x28\xb5\x2f\xfd"),
+    ("xz", 0, b"\xfd7zXZ\x00"),
+    ("bzip2", 0, b"BZh"),
+    ("zip", 0, b"PK\x03\x04"),
+    ("7z", 0, b"7z\xbc\xaf\x27\x1c"),
+    ("tar", 257, b"ustar"),
+    ("pdf", 0, b"%PDF-"),
+    ("png", 0, b"\x89PNG\r\n\x1a\n"),
+    ("jpeg", 0, b"\xff\xd8\xff"),
+    ("elf", 0, b"\x7fELF"),
+    ("pe", 0, b"MZ"),
+    ("mach-o", 0, b"\xcf\xfa\xed\xfe"),
+    ("shebang", 0, b"#!"),
+];
+
+/// Detects the type of a payload from its first bytes (up to `SNIFF_LEN`).
+pub fn detect(head: &[u8]) -> Option<&'static str> {
+    if let Some((name, _, _)) = MAGICS
+        .iter()
+        .find(|(_, offset, magic)| head.get(*offset..offset + magic.len()) == Some(*magic))
+    {
+        return Some(name);
+    }
+
+    if is_carv1(head) {
+        return Some("carv1");
+    }
+
+    if !head.is_empty() && head.iter().all(|b| *b == 0) {
+        return Some("zeros");
+    }
+
+    None
+}
+
+/// A CARv1 starts with a varint length followed by a dag-cbor map holding
+/// `roots` and `version`.
+fn is_carv1(head: &[u8]) -> bool {
+    let header_start = match head.iter().position(|b| b & 0x80 == 0) {
+        Some(pos) if pos < 9 => pos + 1,
+        _ => return false,
+    };
+
+    let header = &head[header_start..];
+    header.first() == Some(&0xa2)
+        && contains(header, b"\x65roots")
+        && contains(header, b"\x67version")
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Name `ContentPolicy` gives to payloads `detect` doesn't recognize.
+pub const UNKNOWN: &str = "unknown";
+
+/// Payload types allowed into a sector, as named by `detect` or `UNKNOWN`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContentPolicy {
+    /// Only these types are accepted, unless empty.
+    pub allow: Vec<String>,
+    /// These types are rejected, whatever `allow` says.
+    pub deny: Vec<String>,
+}
+
+/// A payload rejected by a `ContentPolicy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyError {
+    pub content_type: &'static str,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "content policy rejects {} payloads", self.content_type)
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl ContentPolicy {
+    /// Checks the type of a payload starting with `head` (up to `SNIFF_LEN`).
+    pub fn check(&self, head: &[u8]) -> Result<(), PolicyError> {
+        let content_type = detect(head).unwrap_or(UNKNOWN);
+        let listed = |types: &[String]| types.iter().any(|t| t == content_type);
+        if listed(&self.deny) || !(self.allow.is_empty() || listed(&self.allow)) {
+            return Err(PolicyError { content_type });
+        }
+        Ok(())
+    }
+}
+
+/// Reads `inner`, failing before anything is read if its first bytes are
+/// rejected by `policy`.
+pub(crate) struct PolicyReader<'a, R> {
+    inner: R,
+    policy: Option<&'a ContentPolicy>,
+    head: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R: Read> PolicyReader<'a, R> {
+    pub(crate) fn new(inner: R, policy: Option<&'a ContentPolicy>) -> Self {
+        Self {
+            inner,
+            policy,
+            head: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for PolicyReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(policy) = self.policy.take() {
+            (&mut self.inner)
+                .take(SNIFF_LEN as u64)
+                .read_to_end(&mut self.head)?;
+            policy.check(&self.head).map_err(io::Error::other)?;
+        }
+
+        if self.pos < self.head.len() {
+            let n = buf.len().min(self.head.len() - self.pos);
+            buf[..n].copy_from_slice(&self.head[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let carv1 = b"\x3a\xa2\x65roots\x81\xd8\x2a\x58\x25\x00\x01\x71\x12\x20\x67version\x01";
+        assert_eq!(detect(carv1), Some("carv1"));
+        assert_eq!(detect(b"\x0a\xa1\x67version\x02rest"), Some("carv2"));
+        assert_eq!(detect(b"\x1f\x8b\x08\x00"), Some("gzip"));
+        assert_eq!(detect(b"\x7fELF\x02\x01"), Some("elf"));
+        assert_eq!(detect(&[0u8; 64]), Some("zeros"));
+        assert_eq!(detect(b"hello world"), None);
+        assert_eq!(detect(b""), None);
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = ContentPolicy {
+            allow: vec![
+                "carv1".to_string(),
+                "carv2".to_string(),
+                UNKNOWN.to_string(),
+            ],
+            deny: vec!["elf".to_string()],
+        };
+        assert!(policy.check(b"\x0a\xa1\x67version\x02rest").is_ok());
+        assert!(policy.check(b"hello world").is_ok());
+        assert_eq!(
+            policy.check(b"\x7fELF\x02\x01"),
+            Err(PolicyError {
+                content_type: "elf"
+            })
+        );
+        assert!(policy.check(b"%PDF-1.7").is_err());
+
+        let payload = b"\x0a\xa1\x67version\x02rest".repeat(100);
+        let mut read = Vec::new();
+        PolicyReader::new(&payload[..], Some(&policy))
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, payload);
+        let err = PolicyReader::new(&b"\x7fELF"[..], Some(&policy))
+            .read_to_end(&mut read)
+            .unwrap_err();
+        assert!(err.get_ref().unwrap().is::<PolicyError>());
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use add_piece::{
+    manifest::Manifest,
+    staged_format::{Header, StagedFormat},
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::info;
+
+/// Rewrites the staged file `src` into `dest` in the `format` layout, copying
+/// the staged data as is.
+///
+/// Nothing is hashed again: the commitment of every piece is only checked
+/// against its recorded chunk roots, if any.
+pub fn convert(src: &Path, dest: &Path, format: StagedFormat, proof_type: &str) -> Result<()> {
+    let mut src_file =
+        fs::File::open(src).with_context(|| format!("open staged file: {}", src.display()))?;
+    let header = Header::read_from(&src_file)?;
+
+    let manifest = match (Manifest::load(src)?, &header) {
+        (Some(m), _) => m,
+        (None, Some(h)) => h
+            .read_piece_table(&src_file)?
+            .ok_or_else(|| anyhow!("{} is not complete", src.display()))?,
+        (None, None) => bail!("no manifest found for {}", src.display()),
+    };
+    for (index, piece) in manifest.pieces.iter().enumerate() {
+        ensure!(
+            piece.verify_chunk_roots()? != Some(false),
+            "chunk roots of piece {} don't match its commitment",
+            index
+        );
+    }
+
+    let data_offset = header.as_ref().map_or(0, |h| h.data_offset);
+    let data_len = manifest.end();
+    ensure!(
+        src_file.metadata()?.len() >= data_offset + data_len,
+        "{} is shorter than its manifest says",
+        src.display()
+    );
+
+    let mut dest_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dest)
+        .with_context(|| format!("create {}", dest.display()))?;
+    let mut header = match format {
+        StagedFormat::Raw => None,
+        StagedFormat::V2 => {
+            let proof_type = match (proof_type, &header) {
+                ("", Some(h)) => h.proof_type.as_str(),
+                _ => proof_type,
+            };
+            let header = Header::new(proof_type);
+            dest_file.write_all(&header.encode()?)?;
+            Some(header)
+        }
+    };
+
+    src_file.seek(SeekFrom::Start(data_offset))?;
+    let copied = io::copy(&mut (&mut src_file).take(data_len), &mut dest_file)
+        .with_context(|| format!("copy staged data to {}", dest.display()))?;
+    ensure!(copied == data_len, "{} was truncated", src.display());
+
+    if let Some(header) = &mut header {
+        let table = serde_json::to_vec(&manifest).context("serialize piece table")?;
+        header.data_len = data_len;
+        header.piece_table_offset = header.data_offset + data_len;
+        header.piece_table_len = table.len() as u64;
+        dest_file.write_all(&table)?;
+        dest_file.seek(SeekFrom::Start(0))?;
+        dest_file.write_all(&header.encode()?)?;
+    }
+    dest_file.sync_all().context("sync converted staged file")?;
+    manifest.save(dest)?;
+
+    info!(
+        src = %src.display(),
+        dest = %dest.display(),
+        ?format,
+        "staged file converted"
+    );
+    Ok(())
+}
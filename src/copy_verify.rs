@@ -0,0 +1,216 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use add_piece::{
+    dedup::ChunkRoot,
+    manifest::{Manifest, ManifestPiece},
+    pure,
+    staged_format::Header,
+    CHUNK_SIZE,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{staging, transfer::read_at};
+
+#[derive(Debug, Default, Serialize)]
+pub struct CopyReport {
+    pub pieces: usize,
+    /// Chunks hashed while copied and again once read back.
+    pub chunks: usize,
+    pub bytes_copied: u64,
+}
+
+/// Copies the staged file `src` to `dest`, which must not exist, with its
+/// manifest.
+///
+/// Every chunk of the pieces is hashed as it is copied and compared to the
+/// root recorded by the manifest, or the roots of a piece recorded without
+/// them to its commitment. The copy is then synced, read back and checked
+/// again, the bytes outside the pieces against `src`. It is written as
+/// `<dest>.partial` and only renamed to `dest`, followed by its manifest,
+/// once verified.
+pub fn copy_verify(src: &Path, dest: &Path) -> Result<CopyReport> {
+    ensure!(!dest.exists(), "{} already exists", dest.display());
+    let manifest = staging::load_manifest(src)?
+        .ok_or_else(|| anyhow!("no manifest found for {}", src.display()))?;
+    let src_file =
+        fs::File::open(src).with_context(|| format!("open staged file: {}", src.display()))?;
+    let data_offset = Header::read_from(&src_file)?.map_or(0, |h| h.data_offset);
+    let len = src_file.metadata().context("stat staged file")?.len();
+
+    let mut partial = OsString::from(dest.as_os_str());
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let dest_file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(&partial)
+        .with_context(|| format!("create {}", partial.display()))?;
+
+    let mut copy = Copy {
+        src: src_file,
+        dest: dest_file,
+        buf: Vec::new(),
+        report: CopyReport::default(),
+    };
+    let res = copy
+        .run(&manifest.pieces, data_offset, len)
+        .and_then(|regions| copy.verify(regions));
+    if let Err(e) = res {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    fs::rename(&partial, dest).with_context(|| format!("rename {}", partial.display()))?;
+    if Manifest::load(src)?.is_some() {
+        manifest.save(dest)?;
+    }
+
+    let report = copy.report;
+    info!(
+        src = %src.display(),
+        dest = %dest.display(),
+        chunks = report.chunks,
+        bytes = report.bytes_copied,
+        "staged file copied and verified"
+    );
+    Ok(report)
+}
+
+/// A range of the staged file and, for the chunks of the pieces, its root.
+type Region = (Range<u64>, Option<ChunkRoot>);
+
+struct Copy {
+    src: fs::File,
+    dest: fs::File,
+    buf: Vec<u8>,
+    report: CopyReport,
+}
+
+impl Copy {
+    /// Copies the whole staged file in order, hashing the chunks of `pieces`
+    /// on the way, and returns the regions copied.
+    fn run(&mut self, pieces: &[ManifestPiece], data_offset: u64, len: u64) -> Result<Vec<Region>> {
+        let mut regions = Vec::new();
+        let mut pos = 0;
+        for (index, piece) in pieces.iter().enumerate() {
+            let start = data_offset + piece.offset;
+            // the header of v2 staged files, then the left alignment
+            regions.extend(self.raw(pos..data_offset.max(pos), false)?);
+            regions.extend(self.raw(data_offset.max(pos)..start, true)?);
+
+            let mut roots = Vec::new();
+            for (i, range) in piece.chunk_ranges().into_iter().enumerate() {
+                let range = data_offset + range.start..data_offset + range.end;
+                let root = self
+                    .chunk(range.clone())
+                    .with_context(|| format!("piece {} ({}) chunk {}", index, piece.source, i))?;
+                if let Some(expected) = piece.chunk_roots.get(i) {
+                    ensure!(
+                        root == *expected,
+                        "piece {} ({}) chunk {} doesn't match its recorded root",
+                        index,
+                        piece.source,
+                        i
+                    );
+                }
+                roots.push(root);
+                regions.push((range, Some(root)));
+            }
+            ensure!(
+                pure::reduce_chunk_roots(&roots)? == piece.piece_info.commitment,
+                "piece {} ({}) doesn't match its commitment",
+                index,
+                piece.source
+            );
+            self.report.pieces += 1;
+            pos = start + u64::from(piece.padded_size());
+        }
+        let end = data_offset + pieces.last().map_or(0, ManifestPiece::end);
+        regions.extend(self.raw(pos..end, true)?);
+        // the piece table of v2 staged files
+        regions.extend(self.raw(end..len, false)?);
+
+        self.dest.sync_all().context("sync copy")?;
+        Ok(regions)
+    }
+
+    fn chunk(&mut self, range: Range<u64>) -> Result<ChunkRoot> {
+        ensure!(
+            read_at(&self.src, range.clone(), &mut self.buf)?,
+            "staged file ends before {}",
+            range.end
+        );
+        let root = pure::commit(&self.buf)?;
+        self.write()?;
+        self.report.chunks += 1;
+        Ok(root)
+    }
+
+    /// Copies bytes outside the pieces, checked against `src` once read back.
+    /// Alignment bytes, `zeros`, must be zeros.
+    fn raw(&mut self, range: Range<u64>, zeros: bool) -> Result<Vec<Region>> {
+        let mut regions = Vec::new();
+        let mut start = range.start;
+        while start < range.end {
+            let end = range.end.min(start + CHUNK_SIZE as u64);
+            ensure!(
+                read_at(&self.src, start..end, &mut self.buf)?,
+                "staged file ends before {}",
+                end
+            );
+            ensure!(
+                !zeros || self.buf.iter().all(|b| *b == 0),
+                "alignment bytes at {:?} aren't zeros",
+                start..end
+            );
+            self.write()?;
+            regions.push((start..end, None));
+            start = end;
+        }
+        Ok(regions)
+    }
+
+    fn write(&mut self) -> Result<()> {
+        self.dest.write_all(&self.buf).context("write copy")?;
+        self.report.bytes_copied += self.buf.len() as u64;
+        Ok(())
+    }
+
+    /// Reads the copy back and checks every region of it.
+    fn verify(&mut self, regions: Vec<Region>) -> Result<()> {
+        let mut expected = Vec::new();
+        for (range, root) in regions {
+            ensure!(
+                read_at(&self.dest, range.clone(), &mut self.buf)?,
+                "copy ends before {}",
+                range.end
+            );
+            let matches = match root {
+                Some(root) => pure::commit(&self.buf)? == root,
+                None => {
+                    read_at(&self.src, range.clone(), &mut expected)?;
+                    expected == self.buf
+                }
+            };
+            if !matches {
+                bail!("copied data at {:?} doesn't match the staged file", range);
+            }
+        }
+        let mut rest = [0u8; 1];
+        ensure!(
+            (&self.dest).read(&mut rest)? == 0,
+            "copy is longer than the staged file"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+//! Credentials of the `s3://` and `http(s)://` targets and of the pieces
+//! fetched by URL, looked up by the scheme of the URL through a chain of
+//! providers, so that secrets never need to be part of the tasks, the config
+//! or the command line.
+//!
+//! The providers of a scheme are tried in order, the first one holding
+//! credentials wins; a provider which isn't available, a missing file, a
+//! command which fails or an unreachable metadata service, is skipped, while
+//! malformed credentials are an error. What the chain returns is cached until
+//! the credentials expire or `refresh_secs` pass, whichever comes first, then
+//! fetched again.
+//!
+//! Credentials are named values: `access_key_id`, `secret_access_key` and
+//! `session_token` for S3, headers sent with every request, e.g.
+//! `Authorization`, for HTTP.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs, io,
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use add_piece::manifest::unix_now;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info, warn};
+
+use crate::config;
+
+const IMDS_ENDPOINT: &str = "http://169.254.169.254";
+
+/// Credentials fetched again this long before they expire.
+const EXPIRY_MARGIN_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialChain {
+    pub providers: Vec<CredentialSource>,
+
+    /// Seconds credentials without an expiry are cached for.
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u64,
+}
+
+fn default_refresh_secs() -> u64 {
+    900
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialSource {
+    /// Environment variables holding the credentials, by credential name.
+    /// Holds credentials if any of them is set.
+    Env(BTreeMap<String, String>),
+    /// JSON file of the credentials, read again on every refresh.
+    File(PathBuf),
+    /// Program printing the credentials as JSON, e.g. an AWS
+    /// `credential_process`.
+    Command(Vec<String>),
+    /// Credentials of the role of the EC2 instance, from its metadata
+    /// service, at `AWS_EC2_METADATA_SERVICE_ENDPOINT` if set.
+    Imds,
+}
+
+/// Named credentials, with the unix seconds they expire at if they do.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    pub values: BTreeMap<String, String>,
+    pub expires_at: Option<u64>,
+}
+
+impl Credentials {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+/// Values are never printed, only their names.
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("names", &self.values.keys().collect::<Vec<_>>())
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+struct Cached {
+    credentials: Option<Arc<Credentials>>,
+    fetched: Instant,
+    valid_for: Duration,
+}
+
+/// The credentials of a scheme, locked on their own while fetched.
+type Entry = Arc<Mutex<Option<Cached>>>;
+
+static CACHE: OnceLock<Mutex<HashMap<String, Entry>>> = OnceLock::new();
+
+/// The credentials of `scheme` from its configured chain, or from the
+/// default one (`AWS_*` environment variables for `s3`), `None` if no
+/// provider holds any.
+pub fn get(scheme: &str) -> Result<Option<Arc<Credentials>>> {
+    let chain = match config::global().credentials.get(scheme) {
+        Some(chain) => chain.clone(),
+        None => match default_chain(scheme) {
+            Some(chain) => chain,
+            None => return Ok(None),
+        },
+    };
+
+    let entry = CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .expect("credentials cache poisoned")
+        .entry(scheme.to_string())
+        .or_default()
+        .clone();
+    // held while fetching, so that concurrent tasks of a scheme fetch once
+    let mut cached = entry.lock().expect("credentials cache poisoned");
+    if let Some(cached) = &*cached {
+        if cached.fetched.elapsed() < cached.valid_for {
+            return Ok(cached.credentials.clone());
+        }
+    }
+
+    let credentials = fetch(&chain).with_context(|| format!("credentials of {}", scheme))?;
+    let mut valid_for = Duration::from_secs(chain.refresh_secs);
+    if let Some(expires_at) = credentials.as_ref().and_then(|c| c.expires_at) {
+        let left = expires_at.saturating_sub(unix_now() + EXPIRY_MARGIN_SECS);
+        valid_for = valid_for.min(Duration::from_secs(left));
+    }
+    debug!(scheme, ?credentials, ?valid_for, "credentials fetched");
+    let credentials = credentials.map(Arc::new);
+    *cached = Some(Cached {
+        credentials: credentials.clone(),
+        fetched: Instant::now(),
+        valid_for,
+    });
+    Ok(credentials)
+}
+
+/// Headers carrying the credentials of the scheme of `url`.
+pub fn http_headers(url: &str) -> Result<BTreeMap<String, String>> {
+    let scheme = url.split_once("://").map(|(s, _)| s).unwrap_or("http");
+    Ok(get(scheme)?.map(|c| c.values.clone()).unwrap_or_default())
+}
+
+fn default_chain(scheme: &str) -> Option<CredentialChain> {
+    match scheme {
+        "s3" => Some(CredentialChain {
+            providers: vec![CredentialSource::Env(
+                [
+                    ("access_key_id", "AWS_ACCESS_KEY_ID"),
+                    ("secret_access_key", "AWS_SECRET_ACCESS_KEY"),
+                    ("session_token", "AWS_SESSION_TOKEN"),
+                ]
+                .into_iter()
+                .map(|(name, var)| (name.to_string(), var.to_string()))
+                .collect(),
+            )],
+            refresh_secs: default_refresh_secs(),
+        }),
+        _ => None,
+    }
+}
+
+fn fetch(chain: &CredentialChain) -> Result<Option<Credentials>> {
+    for provider in &chain.providers {
+        let credentials = match provider {
+            CredentialSource::Env(vars) => {
+                let values: BTreeMap<_, _> = vars
+                    .iter()
+                    .filter_map(|(name, var)| {
+                        let value = env::var(var).ok().filter(|v| !v.is_empty())?;
+                        Some((name.clone(), value))
+                    })
+                    .collect();
+                (!values.is_empty()).then_some(Credentials {
+                    values,
+                    expires_at: None,
+                })
+            }
+            CredentialSource::File(path) => match fs::read(path) {
+                Ok(content) => {
+                    Some(parse(&content).with_context(|| format!("parse {}", path.display()))?)
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    debug!(path = %path.display(), "no credentials file");
+                    None
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("read credentials file: {}", path.display()))
+                }
+            },
+            CredentialSource::Command(command) => run_command(command)?,
+            CredentialSource::Imds => imds().context("instance metadata service")?,
+        };
+        if let Some(credentials) = credentials {
+            info!(provider = provider_name(provider), "credentials provided");
+            return Ok(Some(credentials));
+        }
+    }
+    Ok(None)
+}
+
+fn provider_name(provider: &CredentialSource) -> &'static str {
+    match provider {
+        CredentialSource::Env(_) => "env",
+        CredentialSource::File(_) => "file",
+        CredentialSource::Command(_) => "command",
+        CredentialSource::Imds => "imds",
+    }
+}
+
+/// Parses a JSON object of credentials. The fields of AWS credentials,
+/// `AccessKeyId`, `SecretAccessKey`, `SessionToken` and `Expiration`, are
+/// renamed, and `expires_at` (unix seconds) or `Expiration` (RFC 3339) give
+/// the expiry.
+fn parse(content: &[u8]) -> Result<Credentials> {
+    let fields: BTreeMap<String, Value> =
+        serde_json::from_slice(content).context("credentials must be a JSON object")?;
+    let mut credentials = Credentials::default();
+    for (name, value) in fields {
+        match (name.as_str(), value) {
+            ("Version", _) => {}
+            ("expires_at", Value::Number(n)) => credentials.expires_at = n.as_u64(),
+            ("Expiration", Value::String(s)) => credentials.expires_at = Some(parse_rfc3339(&s)?),
+            (name, Value::String(s)) => {
+                let name = match name {
+                    "AccessKeyId" => "access_key_id",
+                    "SecretAccessKey" => "secret_access_key",
+                    "SessionToken" | "Token" => "session_token",
+                    name => name,
+                };
+                credentials.values.insert(name.to_string(), s);
+            }
+            (name, _) => bail!("credential {} is not a string", name),
+        }
+    }
+    Ok(credentials)
+}
+
+/// Runs the credentials command, `None` if it is missing or fails.
+fn run_command(command: &[String]) -> Result<Option<Credentials>> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("empty credentials command"))?;
+    let output = match process::Command::new(program)
+        .args(args)
+        .stderr(process::Stdio::inherit())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!(program = program.as_str(), "no credentials command");
+            return Ok(None);
+        }
+        Err(e) => return Err(e).with_context(|| format!("run credentials command {}", program)),
+    };
+    if !output.status.success() {
+        warn!(
+            program = program.as_str(),
+            status = %output.status,
+            "credentials command failed"
+        );
+        return Ok(None);
+    }
+    parse(&output.stdout)
+        .map(Some)
+        .context("credentials command output")
+}
+
+/// Fetches the credentials of the instance role with IMDSv2, `None` if the
+/// metadata service can't be reached.
+fn imds() -> Result<Option<Credentials>> {
+    let endpoint =
+        env::var("AWS_EC2_METADATA_SERVICE_ENDPOINT").unwrap_or_else(|_| IMDS_ENDPOINT.to_string());
+    let endpoint = endpoint.trim_end_matches('/');
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(5))
+        .build();
+
+    let token = match agent
+        .put(&format!("{}/latest/api/token", endpoint))
+        .set("X-aws-ec2-metadata-token-ttl-seconds", "300")
+        .call()
+    {
+        Ok(resp) => resp.into_string()?,
+        Err(ureq::Error::Transport(e)) => {
+            debug!(endpoint, "metadata service unreachable: {}", e);
+            return Ok(None);
+        }
+        Err(e) => return Err(e).context("get session token"),
+    };
+    let get = |path: &str| -> Result<String> {
+        Ok(agent
+            .get(&format!(
+                "{}/latest/meta-data/iam/security-credentials/{}",
+                endpoint, path
+            ))
+            .set("X-aws-ec2-metadata-token", &token)
+            .call()
+            .with_context(|| format!("get security credentials {}", path))?
+            .into_string()?)
+    };
+    let roles = get("")?;
+    let role = roles
+        .lines()
+        .next()
+        .filter(|r| !r.is_empty())
+        .ok_or_else(|| anyhow!("the instance has no role"))?;
+    let mut credentials = parse(get(role)?.as_bytes())?;
+    for field in ["Code", "LastUpdated", "Type"] {
+        credentials.values.remove(field);
+    }
+    Ok(Some(credentials))
+}
+
+/// Unix seconds of an RFC 3339 time, e.g. `2024-05-01T12:00:00Z` or
+/// `2024-05-01T20:00:00+08:00`.
+fn parse_rfc3339(s: &str) -> Result<u64> {
+    let err = || anyhow!("invalid time: {}", s);
+    let (date, time) = s.split_once('T').ok_or_else(err)?;
+    let num = |s: &str| s.parse::<i64>().map_err(|_| err());
+    let (time, offset) = match time.strip_suffix('Z') {
+        Some(time) => (time, 0),
+        None => {
+            let at = time.rfind(['+', '-']).ok_or_else(err)?;
+            let (hours, minutes) = time[at + 1..].split_once(':').ok_or_else(err)?;
+            let offset = num(hours)? * 3600 + num(minutes)? * 60;
+            match &time[at..at + 1] {
+                "+" => (&time[..at], offset),
+                _ => (&time[..at], -offset),
+            }
+        }
+    };
+    let time = time.split_once('.').map(|(t, _)| t).unwrap_or(time);
+    let d: Vec<_> = date.split('-').map(num).collect::<Result<_>>()?;
+    let t: Vec<_> = time.split(':').map(num).collect::<Result<_>>()?;
+    ensure!(d.len() == 3 && t.len() == 3, "invalid time: {}", s);
+
+    // days since the epoch of a civil date, after Howard Hinnant
+    let (year, month, day) = (d[0] - i64::from(d[1] <= 2), d[1], d[2]);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = days * 86400 + t[0] * 3600 + t[1] * 60 + t[2] - offset;
+    u64::try_from(secs).map_err(|_| err())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_http::{MockServer, Response};
+
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z").unwrap(), 0);
+        assert_eq!(parse_rfc3339("2024-05-01T12:00:00Z").unwrap(), 1714564800);
+        assert_eq!(
+            parse_rfc3339("2024-05-01T12:00:00.250Z").unwrap(),
+            1714564800
+        );
+        assert_eq!(
+            parse_rfc3339("2024-05-01T20:00:00+08:00").unwrap(),
+            1714564800
+        );
+        assert_eq!(
+            parse_rfc3339("2024-05-01T06:30:00-05:30").unwrap(),
+            1714564800
+        );
+        assert_eq!(parse_rfc3339("2024-02-29T23:59:59Z").unwrap(), 1709251199);
+
+        for invalid in [
+            "2024-05-01",
+            "2024-05-01T12:00Z",
+            "2024-05-01T12:00:00",
+            "2024-05-01T12:00:00+0800",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert!(parse_rfc3339(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let credentials = parse(
+            br#"{
+                "Version": 1,
+                "AccessKeyId": "AKID",
+                "SecretAccessKey": "secret",
+                "SessionToken": "token",
+                "Expiration": "2024-05-01T20:00:00+08:00"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(credentials.get("access_key_id"), Some("AKID"));
+        assert_eq!(credentials.get("secret_access_key"), Some("secret"));
+        assert_eq!(credentials.get("session_token"), Some("token"));
+        assert_eq!(credentials.expires_at, Some(1714564800));
+
+        let credentials = parse(br#"{"Authorization": "Bearer x", "expires_at": 10}"#).unwrap();
+        assert_eq!(credentials.get("Authorization"), Some("Bearer x"));
+        assert_eq!(credentials.expires_at, Some(10));
+        assert!(!format!("{:?}", credentials).contains("Bearer"));
+
+        assert!(parse(br#"{"AccessKeyId": 1}"#).is_err());
+        assert!(parse(br#"["AKID"]"#).is_err());
+    }
+
+    #[test]
+    fn test_unavailable_providers_skipped() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let var = format!("ADD_PIECE_TEST_TOKEN_{}", process::id());
+        env::set_var(&var, "Bearer env");
+        let chain = |providers| CredentialChain {
+            providers,
+            refresh_secs: default_refresh_secs(),
+        };
+        let unavailable = vec![
+            CredentialSource::File(dir.path().join("missing.json")),
+            CredentialSource::Command(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "exit 1".to_string(),
+            ]),
+            CredentialSource::Command(vec![dir.path().join("missing").display().to_string()]),
+            CredentialSource::Env([("Authorization".to_string(), var.clone())].into()),
+        ];
+        let credentials = fetch(&chain(unavailable)).unwrap().expect("no credentials");
+        assert_eq!(credentials.get("Authorization"), Some("Bearer env"));
+
+        let malformed = dir.path().join("malformed.json");
+        fs::write(&malformed, "Bearer file").unwrap();
+        let providers = vec![
+            CredentialSource::File(malformed),
+            CredentialSource::Env([("Authorization".to_string(), var.clone())].into()),
+        ];
+        assert!(fetch(&chain(providers)).is_err());
+        env::remove_var(&var);
+    }
+
+    #[test]
+    fn test_imds() {
+        // the only test touching the endpoint variable
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        env::set_var("AWS_EC2_METADATA_SERVICE_ENDPOINT", &unreachable);
+        assert!(imds().unwrap().is_none());
+
+        let server = MockServer::start(|req| match req.path.as_str() {
+            "/latest/api/token" => Response::new(200).body("session"),
+            "/latest/meta-data/iam/security-credentials/" => Response::new(200).body("role\n"),
+            "/latest/meta-data/iam/security-credentials/role" => Response::new(200).body(
+                r#"{"Code": "Success", "Type": "AWS-HMAC", "AccessKeyId": "AKID",
+                    "SecretAccessKey": "secret", "Token": "token",
+                    "Expiration": "2024-05-01T12:00:00Z"}"#,
+            ),
+            _ => Response::new(404),
+        });
+        env::set_var("AWS_EC2_METADATA_SERVICE_ENDPOINT", server.url());
+        let credentials = imds().unwrap().expect("no credentials");
+        env::remove_var("AWS_EC2_METADATA_SERVICE_ENDPOINT");
+        assert_eq!(credentials.get("access_key_id"), Some("AKID"));
+        assert_eq!(credentials.get("session_token"), Some("token"));
+        assert_eq!(credentials.expires_at, Some(1714564800));
+        assert!(credentials.get("Code").is_none());
+        assert!(server
+            .requests()
+            .iter()
+            .skip(1)
+            .all(|req| req.header("X-aws-ec2-metadata-token") == Some("session")));
+    }
+}
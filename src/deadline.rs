@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+
+use add_piece::manifest;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::PieceFile;
+
+/// How the deal start epochs of the pieces are converted to wall time, the
+/// defaults are those of mainnet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadlineConfig {
+    /// Unix time of epoch 0.
+    pub genesis_timestamp: u64,
+    pub epoch_secs: u64,
+    /// Time needed after a piece is staged to seal the sector before the deal
+    /// starts, pieces are skipped if less is left.
+    pub margin_secs: u64,
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> Self {
+        Self {
+            genesis_timestamp: 1598306400,
+            epoch_secs: 30,
+            margin_secs: 0,
+        }
+    }
+}
+
+impl DeadlineConfig {
+    /// Unix time by which a piece of a deal starting at `epoch` must be staged.
+    pub fn deadline(&self, epoch: i64) -> u64 {
+        let start = self.genesis_timestamp as i64 + epoch.saturating_mul(self.epoch_secs as i64);
+        start.saturating_sub(self.margin_secs as i64).max(0) as u64
+    }
+}
+
+/// A piece left out of its task because its deal would start before it could
+/// be sealed.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiredPiece {
+    /// Index of the piece in the task.
+    pub index: usize,
+    pub path: PathBuf,
+    pub deal_start_epoch: i64,
+    /// Unix time the piece had to be staged by.
+    pub deadline: u64,
+}
+
+/// Returns the indexes of the pieces whose deadline has not passed, and the
+/// expired ones.
+pub fn split_expired(
+    pieces: &[PieceFile],
+    config: &DeadlineConfig,
+) -> (Vec<usize>, Vec<ExpiredPiece>) {
+    let now = manifest::unix_now();
+
+    let mut live = Vec::with_capacity(pieces.len());
+    let mut expired = Vec::new();
+    for (index, piece) in pieces.iter().enumerate() {
+        match piece.deal_start_epoch {
+            Some(epoch) if config.deadline(epoch) <= now => {
+                let deadline = config.deadline(epoch);
+                warn!(
+                    index,
+                    path = %piece.path.display(),
+                    epoch,
+                    deadline,
+                    "skip piece, its deal starts too soon"
+                );
+                expired.push(ExpiredPiece {
+                    index,
+                    path: piece.path.clone(),
+                    deal_start_epoch: epoch,
+                    deadline,
+                });
+            }
+            _ => live.push(index),
+        }
+    }
+    (live, expired)
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+use anyhow::{ensure, Context, Result};
+use log::warn;
+
+/// Fingerprint of the padded bytes of a chunk.
+pub type Fingerprint = [u8; 32];
+
+/// Merkle root of a chunk, as produced by the piece hasher.
+pub type ChunkRoot = [u8; 32];
+
+/// `fingerprint || root || checksum`, the checksum being the first bytes of
+/// the blake3 hash of the fingerprint and root.
+const RECORD_SIZE: usize = 72;
+
+/// Maps chunk fingerprints to their merkle roots so identical padded chunks
+/// are only hashed once, across pieces and tasks.
+///
+/// Fingerprints are blake3 hashes, which are much cheaper to compute than the
+/// sha256 merkle tree over a chunk. The index optionally persists to an
+/// append-only file of checksummed `fingerprint || root` records; a record
+/// failing its checksum would give a wrong commitment, so it is dropped.
+#[derive(Debug, Default)]
+pub struct ChunkIndex {
+    entries: RwLock<HashMap<Fingerprint, ChunkRoot>>,
+    file: Option<Mutex<fs::File>>,
+}
+
+impl ChunkIndex {
+    /// Creates an index living only as long as the process.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Opens (or creates) an index persisted at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open chunk index: {}", path.display()))?;
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)
+            .with_context(|| format!("read chunk index: {}", path.display()))?;
+
+        // drop a torn trailing record, so that the next records appended stay
+        // aligned
+        let torn = content.len() % RECORD_SIZE;
+        if torn != 0 {
+            content.truncate(content.len() - torn);
+            file.set_len(content.len() as u64)
+                .with_context(|| format!("truncate chunk index: {}", path.display()))?;
+        }
+
+        let valid: Vec<_> = content
+            .chunks_exact(RECORD_SIZE)
+            .filter(|record| record[64..] == checksum(&record[..64]))
+            .collect();
+        let dropped = content.len() / RECORD_SIZE - valid.len();
+        if dropped > 0 {
+            warn!(
+                "dropping {} corrupt records of chunk index {}",
+                dropped,
+                path.display()
+            );
+            file.set_len(0)
+                .and_then(|_| file.write_all(&valid.concat()))
+                .with_context(|| format!("rewrite chunk index: {}", path.display()))?;
+        }
+
+        let entries = valid
+            .into_iter()
+            .map(|record| {
+                let mut fingerprint = [0u8; 32];
+                let mut root = [0u8; 32];
+                fingerprint.copy_from_slice(&record[..32]);
+                root.copy_from_slice(&record[32..64]);
+                (fingerprint, root)
+            })
+            .collect();
+
+        Ok(Self {
+            entries: RwLock::new(entries),
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    pub fn fingerprint(padded: &[u8]) -> Fingerprint {
+        blake3::hash(padded).into()
+    }
+
+    pub fn get(&self, fingerprint: &Fingerprint) -> Option<ChunkRoot> {
+        self.entries
+            .read()
+            .expect("chunk index lock poisoned")
+            .get(fingerprint)
+            .copied()
+    }
+
+    pub fn insert(&self, fingerprint: Fingerprint, root: ChunkRoot) -> io::Result<()> {
+        let prev = self
+            .entries
+            .write()
+            .expect("chunk index lock poisoned")
+            .insert(fingerprint, root);
+
+        if let (None, Some(file)) = (prev, &self.file) {
+            let mut record = [0u8; RECORD_SIZE];
+            record[..32].copy_from_slice(&fingerprint);
+            record[32..64].copy_from_slice(&root);
+            let checksum = checksum(&record[..64]);
+            record[64..].copy_from_slice(&checksum);
+            file.lock()
+                .expect("chunk index lock poisoned")
+                .write_all(&record)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .read()
+            .expect("chunk index lock poisoned")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn checksum(record: &[u8]) -> [u8; RECORD_SIZE - 64] {
+    let mut checksum = [0u8; RECORD_SIZE - 64];
+    checksum.copy_from_slice(&blake3::hash(record).as_bytes()[..RECORD_SIZE - 64]);
+    checksum
+}
+
+/// A Bloom filter of chunk roots, remembering which chunks were seen in a
+/// fixed amount of memory, at the cost of some false positives.
+///
+/// Roots are already uniformly distributed hashes, so the bits of a root are
+/// picked from its own bytes instead of hashing it again.
+#[derive(Debug)]
+pub struct ChunkBloom {
+    words: RwLock<Vec<u64>>,
+    hashes: u32,
+}
+
+impl ChunkBloom {
+    /// Sized for `expected` roots with a `false_positive_rate` chance of
+    /// mistaking a new root for a seen one.
+    pub fn new(expected: u64, false_positive_rate: f64) -> Self {
+        let expected = expected.max(1) as f64;
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-expected * rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = (bits / expected * ln2).round().clamp(1.0, 32.0) as u32;
+        Self {
+            words: RwLock::new(vec![0; (bits as usize).div_ceil(64)]),
+            hashes,
+        }
+    }
+
+    fn positions(&self, root: &ChunkRoot, bits: u64) -> impl Iterator<Item = u64> {
+        let word = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&root[i..i + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let (h1, h2) = (word(0), word(8) | 1);
+        (0..u64::from(self.hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+    }
+
+    /// Whether `root` was probably inserted before, it certainly wasn't if
+    /// not.
+    pub fn contains(&self, root: &ChunkRoot) -> bool {
+        let words = self.words.read().expect("chunk bloom lock poisoned");
+        self.positions(root, words.len() as u64 * 64)
+            .all(|bit| words[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn insert(&self, root: &ChunkRoot) {
+        let mut words = self.words.write().expect("chunk bloom lock poisoned");
+        for bit in self.positions(root, words.len() as u64 * 64) {
+            words[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Loads a filter written by `save`, `None` if there is no file at
+    /// `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("read chunk bloom: {}", path.display()))
+            }
+        };
+        ensure!(
+            content.len() >= 12 && (content.len() - 4) % 8 == 0,
+            "truncated chunk bloom: {}",
+            path.display()
+        );
+
+        let mut hashes = [0u8; 4];
+        hashes.copy_from_slice(&content[..4]);
+        let words = content[4..]
+            .chunks_exact(8)
+            .map(|word| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(word);
+                u64::from_le_bytes(bytes)
+            })
+            .collect();
+        Ok(Some(Self {
+            words: RwLock::new(words),
+            hashes: u32::from_le_bytes(hashes),
+        }))
+    }
+
+    /// Writes the filter to `path`, replacing it at once.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut content = self.hashes.to_le_bytes().to_vec();
+        for word in self.words.read().expect("chunk bloom lock poisoned").iter() {
+            content.extend_from_slice(&word.to_le_bytes());
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, path))
+            .with_context(|| format!("write chunk bloom: {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persisted_index() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("chunks.idx");
+        let fingerprint = ChunkIndex::fingerprint(b"chunk");
+
+        let index = ChunkIndex::open(&path).expect("failed to open index");
+        assert!(index.get(&fingerprint).is_none());
+        index.insert(fingerprint, [1u8; 32]).expect("insert");
+        index.insert(fingerprint, [1u8; 32]).expect("insert");
+        drop(index);
+
+        let index = ChunkIndex::open(&path).expect("failed to reopen index");
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.get(&fingerprint), Some([1u8; 32]));
+        assert_eq!(fs::metadata(&path).unwrap().len(), RECORD_SIZE as u64);
+    }
+
+    #[test]
+    fn test_torn_record_truncated() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("chunks.idx");
+        let first = ChunkIndex::fingerprint(b"first");
+        let second = ChunkIndex::fingerprint(b"second");
+
+        let index = ChunkIndex::open(&path).expect("failed to open index");
+        index.insert(first, [1u8; 32]).expect("insert");
+        drop(index);
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0xff; RECORD_SIZE / 2]).unwrap();
+        drop(file);
+
+        let index = ChunkIndex::open(&path).expect("failed to reopen index");
+        assert_eq!(index.len(), 1);
+        index.insert(second, [2u8; 32]).expect("insert");
+        drop(index);
+
+        let index = ChunkIndex::open(&path).expect("failed to reload index");
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get(&first), Some([1u8; 32]));
+        assert_eq!(index.get(&second), Some([2u8; 32]));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * RECORD_SIZE as u64);
+    }
+
+    #[test]
+    fn test_corrupt_record_dropped() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("chunks.idx");
+        let fingerprints = [b"first", b"other"].map(|c| ChunkIndex::fingerprint(c));
+
+        let index = ChunkIndex::open(&path).expect("failed to open index");
+        index.insert(fingerprints[0], [1u8; 32]).expect("insert");
+        index.insert(fingerprints[1], [2u8; 32]).expect("insert");
+        drop(index);
+        // a single bit of the first root
+        let mut content = fs::read(&path).unwrap();
+        content[40] ^= 0x10;
+        fs::write(&path, content).unwrap();
+
+        let index = ChunkIndex::open(&path).expect("failed to reopen index");
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.get(&fingerprints[0]), None);
+        assert_eq!(index.get(&fingerprints[1]), Some([2u8; 32]));
+        index.insert(fingerprints[0], [1u8; 32]).expect("insert");
+        drop(index);
+
+        let index = ChunkIndex::open(&path).expect("failed to reload index");
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.get(&fingerprints[0]), Some([1u8; 32]));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * RECORD_SIZE as u64);
+    }
+
+    #[test]
+    fn test_chunk_bloom() {
+        let root = |i: u32| -> ChunkRoot { *blake3::hash(&i.to_le_bytes()).as_bytes() };
+        let bloom = ChunkBloom::new(1000, 0.01);
+        for i in 0..1000 {
+            bloom.insert(&root(i));
+        }
+        assert!((0..1000).all(|i| bloom.contains(&root(i))));
+        let false_positives = (1000..11000).filter(|i| bloom.contains(&root(*i))).count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("chunks.bloom");
+        assert!(ChunkBloom::load(&path).expect("load").is_none());
+        bloom.save(&path).expect("save");
+        let loaded = ChunkBloom::load(&path).expect("load").expect("saved");
+        assert!((0..1000).all(|i| loaded.contains(&root(i))));
+        assert_eq!(
+            (1000..11000).filter(|i| loaded.contains(&root(*i))).count(),
+            false_positives
+        );
+    }
+}
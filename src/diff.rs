@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use add_piece::{
+    dedup::ChunkRoot, manifest::Manifest, piece_cid, staged_format::Header, CHUNK_SIZE,
+};
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::staging::load_manifest;
+
+/// Bytes compared at once when the chunk roots can't tell.
+const BLOCK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    pub left: String,
+    pub right: String,
+    /// Padded bytes of staged data, headers excluded.
+    pub left_len: u64,
+    pub right_len: u64,
+    /// `CHUNK_SIZE` chunks found equal by their recorded chunk roots, without
+    /// being read.
+    pub chunks_from_roots: u64,
+    /// Chunks compared byte by byte.
+    pub chunks_read: u64,
+    /// Where the staged data first differs, if it does.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub divergence: Option<Divergence>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Divergence {
+    /// Padded offset of the first differing byte in the staged data, or the
+    /// end of the shorter file.
+    pub offset: u64,
+    pub left_piece: Option<PieceAt>,
+    pub right_piece: Option<PieceAt>,
+}
+
+/// The piece of a staged file an offset falls in, according to its manifest.
+#[derive(Debug, Serialize)]
+pub struct PieceAt {
+    pub index: usize,
+    pub source: String,
+    pub piece_cid: String,
+    /// Padded offset from the start of the piece, negative in the alignment
+    /// written before it.
+    pub offset_in_piece: i64,
+}
+
+struct Side {
+    file: fs::File,
+    data_offset: u64,
+    len: u64,
+    manifest: Option<Manifest>,
+    /// Recorded chunk roots by the padded offset of their chunk.
+    roots: HashMap<u64, ChunkRoot>,
+}
+
+impl Side {
+    fn open(path: &Path) -> Result<Self> {
+        let file =
+            fs::File::open(path).with_context(|| format!("open staged file {}", path.display()))?;
+        let size = file.metadata().context("stat staged file")?.len();
+        let data_offset = Header::read_from(&file)?.map_or(0, |h| h.data_offset);
+        let manifest = load_manifest(path)?;
+
+        let mut roots = HashMap::new();
+        for piece in manifest.iter().flat_map(|m| &m.pieces) {
+            for (range, root) in piece.chunk_ranges().into_iter().zip(&piece.chunk_roots) {
+                if range.end - range.start == CHUNK_SIZE as u64 {
+                    roots.insert(range.start, *root);
+                }
+            }
+        }
+        Ok(Self {
+            file,
+            data_offset,
+            len: size.saturating_sub(data_offset),
+            manifest,
+            roots,
+        })
+    }
+
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(self.data_offset + offset))?;
+        self.file.read_exact(buf).context("read staged file")
+    }
+
+    fn piece_at(&self, offset: u64) -> Option<PieceAt> {
+        let pieces = &self.manifest.as_ref()?.pieces;
+        let index = pieces.iter().position(|p| offset < p.end())?;
+        let piece = &pieces[index];
+        Some(PieceAt {
+            index,
+            source: piece.source.clone(),
+            piece_cid: piece_cid::encode(&piece.piece_info.commitment),
+            offset_in_piece: offset as i64 - piece.offset as i64,
+        })
+    }
+}
+
+/// Compares the staged data of `left` and `right` chunk by chunk, trusting
+/// the chunk roots recorded by both manifests where there are some.
+pub fn diff(left: &Path, right: &Path) -> Result<DiffReport> {
+    let mut sides = [Side::open(left)?, Side::open(right)?];
+    let common = sides[0].len.min(sides[1].len);
+
+    let mut report = DiffReport {
+        left: left.display().to_string(),
+        right: right.display().to_string(),
+        left_len: sides[0].len,
+        right_len: sides[1].len,
+        chunks_from_roots: 0,
+        chunks_read: 0,
+        divergence: None,
+    };
+
+    let mut bufs = [vec![0u8; BLOCK_SIZE], vec![0u8; BLOCK_SIZE]];
+    let mut diverged_at = None;
+    let mut chunk = 0;
+    'chunks: while chunk < common {
+        let chunk_len = (CHUNK_SIZE as u64).min(common - chunk);
+        let roots = (sides[0].roots.get(&chunk), sides[1].roots.get(&chunk));
+        if let (Some(l), Some(r)) = roots {
+            if l == r && chunk_len == CHUNK_SIZE as u64 {
+                report.chunks_from_roots += 1;
+                chunk += chunk_len;
+                continue;
+            }
+        }
+
+        report.chunks_read += 1;
+        let mut offset = chunk;
+        while offset < chunk + chunk_len {
+            let n = (BLOCK_SIZE as u64).min(chunk + chunk_len - offset) as usize;
+            for (side, buf) in sides.iter_mut().zip(&mut bufs) {
+                side.read_block(offset, &mut buf[..n])?;
+            }
+            if let Some(i) = (0..n).find(|i| bufs[0][*i] != bufs[1][*i]) {
+                diverged_at = Some(offset + i as u64);
+                break 'chunks;
+            }
+            offset += n as u64;
+        }
+        chunk += chunk_len;
+    }
+
+    if diverged_at.is_none() && sides[0].len != sides[1].len {
+        diverged_at = Some(common);
+    }
+    report.divergence = diverged_at.map(|offset| Divergence {
+        offset,
+        left_piece: sides[0].piece_at(offset),
+        right_piece: sides[1].piece_at(offset),
+    });
+    Ok(report)
+}
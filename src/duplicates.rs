@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// What to do with a piece repeating an earlier piece of its task, which
+/// would take sector space twice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Stage every piece without looking for duplicates.
+    Allow,
+    /// Stage every piece, logging the duplicates.
+    #[default]
+    Warn,
+    /// Stage the first of the duplicates only.
+    Dedupe,
+    /// Reject the task.
+    Error,
+}
+
+/// A piece repeating an earlier one of its task.
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicatePiece {
+    /// Index of the piece in the task.
+    pub index: usize,
+    /// Index of the earlier piece it repeats.
+    pub first: usize,
+    /// What the two pieces have in common.
+    pub key: String,
+}
+
+/// Identifies the piece read from the file `path`, `size` bytes of which are
+/// used.
+pub fn file_key(path: &Path, size: u64) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    format!("{}:{}", path.display(), size)
+}
+
+/// Finds the pieces of a task repeating an earlier one, given what
+/// identifies each piece: pieces sharing any key are duplicates. Returns the
+/// indexes of the pieces to stage under `policy` and the duplicates.
+pub fn resolve(
+    policy: DuplicatePolicy,
+    piece_keys: impl IntoIterator<Item = Vec<String>>,
+) -> Result<(Vec<usize>, Vec<DuplicatePiece>)> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut all = Vec::new();
+    let mut unique = Vec::new();
+    let mut duplicates = Vec::new();
+    for (index, keys) in piece_keys.into_iter().enumerate() {
+        all.push(index);
+        if policy == DuplicatePolicy::Allow {
+            continue;
+        }
+        match keys
+            .iter()
+            .find_map(|k| seen.get(k).map(|first| (k, *first)))
+        {
+            Some((key, first)) => duplicates.push(DuplicatePiece {
+                index,
+                first,
+                key: key.clone(),
+            }),
+            None => {
+                seen.extend(keys.into_iter().map(|k| (k, index)));
+                unique.push(index);
+            }
+        }
+    }
+
+    for dup in &duplicates {
+        warn!(
+            index = dup.index,
+            first = dup.first,
+            key = %dup.key,
+            ?policy,
+            "duplicate piece in the task"
+        );
+    }
+    match policy {
+        DuplicatePolicy::Allow | DuplicatePolicy::Warn => Ok((all, duplicates)),
+        DuplicatePolicy::Dedupe => Ok((unique, duplicates)),
+        DuplicatePolicy::Error if duplicates.is_empty() => Ok((all, duplicates)),
+        DuplicatePolicy::Error => bail!(
+            "duplicate pieces in the task: {}",
+            duplicates
+                .iter()
+                .map(|d| format!("{} repeats {} ({})", d.index, d.first, d.key))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
@@ -0,0 +1,33 @@
+//! Sources yielding no bytes at all.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// What `add_piece` does with a source which is empty from the start.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptySourcePolicy {
+    /// Fails with `EmptySource` before anything is written.
+    #[default]
+    Error,
+    /// Pads the empty payload up to the piece size like any payload smaller
+    /// than its piece, staging a piece of zeros. Empty payloads are declared
+    /// with the minimum piece size, see `sizes::piece_size_for`.
+    PadUp,
+}
+
+/// The source of a piece held no bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmptySource {
+    /// Unpadded piece size the source was staged for.
+    pub piece_size: u64,
+}
+
+impl fmt::Display for EmptySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "empty source for a piece of {} bytes", self.piece_size)
+    }
+}
+
+impl std::error::Error for EmptySource {}
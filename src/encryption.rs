@@ -0,0 +1,260 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Mutex;
+
+use aes_gcm::{
+    aead::{consts::U12, rand_core::RngCore, AeadInPlace, KeyInit, OsRng},
+    Aes256Gcm, Nonce, Tag,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// AES-256-GCM over fixed size segments of the payload.
+pub const AES_256_GCM: &str = "aes-256-gcm";
+
+/// Payload bytes sealed by each tag unless configured otherwise.
+pub const SEGMENT_SIZE: usize = 16 << 20;
+
+pub type Key = [u8; 32];
+
+/// Supplies the keys encrypting the payloads, e.g. from a KMS.
+pub trait KeyProvider: fmt::Debug + Send + Sync {
+    /// Returns the key for the piece read from `source`, with the id recorded
+    /// in the manifest to find it again.
+    fn key_for(&self, source: &str) -> Result<(String, Key)>;
+}
+
+/// How the payload of a piece was encrypted, as recorded in the manifest.
+///
+/// Segment `i` of the payload is sealed with the nonce made of `nonce_prefix`
+/// followed by `i` as a big-endian u32. The tags are kept out of the staged
+/// data so that encryption doesn't change the size of the piece.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PieceEncryption {
+    pub scheme: String,
+    pub key_id: String,
+    pub segment_size: usize,
+    /// Hex encoded, 8 bytes.
+    pub nonce_prefix: String,
+    /// Hex encoded tag of every segment, in order.
+    pub tags: Vec<String>,
+}
+
+/// Encrypts the payload of one piece when set as `AddPieceOptions::encryption`,
+/// and keeps how it did so for the manifest.
+pub struct PayloadEncryption {
+    key: Key,
+    key_id: String,
+    segment_size: usize,
+    record: Mutex<Option<PieceEncryption>>,
+}
+
+impl fmt::Debug for PayloadEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PayloadEncryption")
+            .field("key_id", &self.key_id)
+            .field("segment_size", &self.segment_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PayloadEncryption {
+    pub fn new(key_id: impl Into<String>, key: Key) -> Self {
+        Self {
+            key,
+            key_id: key_id.into(),
+            segment_size: SEGMENT_SIZE,
+            record: Mutex::new(None),
+        }
+    }
+
+    /// Encrypts the payload of the piece read from `source` with the key
+    /// `provider` gives for it.
+    pub fn for_piece(provider: &dyn KeyProvider, source: &str) -> Result<Self> {
+        let (key_id, key) = provider
+            .key_for(source)
+            .with_context(|| format!("get encryption key for {}", source))?;
+        Ok(Self::new(key_id, key))
+    }
+
+    pub fn with_segment_size(mut self, segment_size: usize) -> Self {
+        self.segment_size = segment_size.max(1);
+        self
+    }
+
+    /// Returns how the last payload read to its end was encrypted.
+    pub fn take(&self) -> Option<PieceEncryption> {
+        self.record.lock().expect("lock encryption record").take()
+    }
+}
+
+/// Reads `inner`, encrypted by `encryption` if set, under a fresh nonce prefix.
+pub(crate) struct EncryptingReader<'a, R> {
+    inner: R,
+    encryption: Option<(&'a PayloadEncryption, Aes256Gcm)>,
+    nonce_prefix: [u8; 8],
+    buf: Vec<u8>,
+    pos: usize,
+    tags: Vec<String>,
+}
+
+impl<'a, R: Read> EncryptingReader<'a, R> {
+    pub(crate) fn new(inner: R, encryption: Option<&'a PayloadEncryption>) -> Self {
+        let mut nonce_prefix = [0u8; 8];
+        if encryption.is_some() {
+            OsRng.fill_bytes(&mut nonce_prefix);
+        }
+        Self {
+            inner,
+            encryption: encryption.map(|e| (e, Aes256Gcm::new(&e.key.into()))),
+            nonce_prefix,
+            buf: Vec::new(),
+            pos: 0,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Reads and encrypts the next segment, returns false at the end of
+    /// `inner`.
+    fn next_segment(&mut self) -> io::Result<bool> {
+        let (encryption, cipher) = self.encryption.as_ref().expect("checked by read");
+        self.buf.resize(encryption.segment_size, 0);
+        let mut n = 0;
+        while n < self.buf.len() {
+            match self.inner.read(&mut self.buf[n..]) {
+                Ok(0) => break,
+                Ok(read) => n += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.buf.truncate(n);
+        self.pos = 0;
+
+        if n == 0 {
+            *encryption.record.lock().expect("lock encryption record") = Some(PieceEncryption {
+                scheme: AES_256_GCM.to_string(),
+                key_id: encryption.key_id.clone(),
+                segment_size: encryption.segment_size,
+                nonce_prefix: hex::encode(self.nonce_prefix),
+                tags: std::mem::take(&mut self.tags),
+            });
+            return Ok(false);
+        }
+
+        let nonce = segment_nonce(&self.nonce_prefix, self.tags.len())?;
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce, b"", &mut self.buf)
+            .map_err(|_| io::Error::other("encrypt payload segment"))?;
+        self.tags.push(hex::encode(tag));
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for EncryptingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.encryption.is_none() {
+            return self.inner.read(buf);
+        }
+
+        if self.pos == self.buf.len() && !self.next_segment()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn segment_nonce(prefix: &[u8; 8], index: usize) -> io::Result<Nonce<U12>> {
+    let index = u32::try_from(index)
+        .map_err(|_| io::Error::other("too many segments for the nonce space"))?;
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&index.to_be_bytes());
+    Ok(nonce.into())
+}
+
+/// Decrypts in place a payload recovered from the staged file, checking the
+/// tag of every segment.
+pub fn decrypt(key: &Key, record: &PieceEncryption, payload: &mut [u8]) -> Result<()> {
+    ensure!(
+        record.scheme == AES_256_GCM,
+        "unsupported encryption scheme: {}",
+        record.scheme
+    );
+    ensure!(record.segment_size > 0, "segment size must not be 0");
+    let segments = payload.len().div_ceil(record.segment_size);
+    ensure!(
+        segments == record.tags.len(),
+        "{} tags are recorded for {} segments",
+        record.tags.len(),
+        segments
+    );
+
+    let mut prefix = [0u8; 8];
+    hex::decode_to_slice(&record.nonce_prefix, &mut prefix).context("parse nonce prefix")?;
+    let cipher = Aes256Gcm::new(&(*key).into());
+    for (index, (segment, tag)) in payload
+        .chunks_mut(record.segment_size)
+        .zip(&record.tags)
+        .enumerate()
+    {
+        let mut raw_tag = [0u8; 16];
+        hex::decode_to_slice(tag, &mut raw_tag).context("parse tag")?;
+        cipher
+            .decrypt_in_place_detached(
+                &segment_nonce(&prefix, index)?,
+                b"",
+                segment,
+                Tag::from_slice(&raw_tag),
+            )
+            .map_err(|_| anyhow!("segment {} failed authentication", index))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use filecoin_proofs::UnpaddedBytesAmount;
+
+    use crate::{add_piece_with_options, unpad::read_unpadded, AddPieceOptions};
+
+    #[test]
+    fn test_encrypted_piece() {
+        let payload: Vec<u8> = (0..1016).map(|i| (i % 251) as u8).collect();
+        let key = [9u8; 32];
+        let encryption = Arc::new(PayloadEncryption::new("test", key).with_segment_size(300));
+        let options = AddPieceOptions {
+            encryption: Some(encryption.clone()),
+            ..Default::default()
+        };
+
+        let mut staged = Vec::new();
+        add_piece_with_options(
+            Cursor::new(&payload),
+            &mut staged,
+            UnpaddedBytesAmount(1016),
+            &[],
+            &options,
+        )
+        .expect("add_piece failed");
+        let record = encryption.take().expect("no encryption recorded");
+        assert_eq!(record.tags.len(), 4);
+
+        let mut recovered = read_unpadded(Cursor::new(&staged), 0, 0, 1016).unwrap();
+        assert_ne!(recovered, payload);
+        let mut tampered = recovered.clone();
+        decrypt(&key, &record, &mut recovered).expect("decrypt failed");
+        assert_eq!(recovered, payload);
+
+        tampered[500] ^= 1;
+        assert!(decrypt(&key, &record, &mut tampered).is_err());
+    }
+}
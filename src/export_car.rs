@@ -0,0 +1,112 @@
+//! Export of the CAR payloads of staged pieces, so that the unsealed copy of
+//! a sector feeds retrieval markets without the original files.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use add_piece::{car, sector_reader::SectorReader};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::Serialize;
+use tracing::info;
+
+use crate::staging;
+
+#[derive(Debug, Serialize)]
+pub struct ExportReport {
+    pub piece_cid: String,
+    pub out: PathBuf,
+    /// `carv1`, the payload as staged, or `carv2`.
+    pub format: &'static str,
+    pub payload_size: u64,
+    /// Bytes of the CARv1, the zero padding following it excluded.
+    pub car_len: u64,
+    pub bytes_written: u64,
+}
+
+/// Unpads the payload of the piece `piece_cid` of `staged`, which must be a
+/// CARv1, into `out`, which must not exist. The payload is written as staged,
+/// or wrapped into a CARv2 indexing its blocks if `carv2`.
+///
+/// The CAR is written as `<out>.partial` and only renamed once complete.
+pub fn export_car(staged: &Path, piece_cid: &str, out: &Path, carv2: bool) -> Result<ExportReport> {
+    ensure!(!out.exists(), "{} already exists", out.display());
+    let manifest = staging::load_manifest(staged)?
+        .ok_or_else(|| anyhow!("no manifest found for {}", staged.display()))?;
+    let mut reader = SectorReader::open(staged, manifest)?;
+    let payload_size = reader.piece(piece_cid)?.payload_size;
+
+    let partial = partial_path(out);
+    let file =
+        fs::File::create(&partial).with_context(|| format!("create {}", partial.display()))?;
+    let res = write_car(&mut reader, piece_cid, file, carv2);
+    let (car_len, bytes_written) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial, out).with_context(|| format!("rename to {}", out.display()))?;
+    info!(
+        piece = piece_cid,
+        out = %out.display(),
+        car_len,
+        bytes_written,
+        "car exported"
+    );
+    Ok(ExportReport {
+        piece_cid: piece_cid.to_string(),
+        out: out.to_path_buf(),
+        format: if carv2 { "carv2" } else { "carv1" },
+        payload_size,
+        car_len,
+        bytes_written,
+    })
+}
+
+/// Writes the CAR of the piece to `file`, synced. Returns the length of the
+/// CARv1 and the bytes written.
+fn write_car(
+    reader: &mut SectorReader<fs::File>,
+    piece_cid: &str,
+    file: fs::File,
+    carv2: bool,
+) -> Result<(u64, u64)> {
+    let mut out = BufWriter::new(file);
+    let not_car = || format!("the payload of piece {} is not a CARv1", piece_cid);
+    let (car_len, written) = match carv2 {
+        false => {
+            let payload = reader.read_piece(piece_cid)?;
+            let index = car::copy_indexed(payload, &mut out).with_context(not_car)?;
+            let payload_size = reader.piece(piece_cid)?.payload_size;
+            (index.car_len()?, payload_size)
+        }
+        true => {
+            // the CARv2 header holds the length of the CARv1, only known once
+            // it is read: the payload is read twice
+            let index = car::copy_indexed(reader.read_piece(piece_cid)?, io::sink())
+                .with_context(not_car)?;
+            let payload = reader.read_piece(piece_cid)?;
+            let written = car::write_v2(payload, &index, &mut out).context("write carv2")?;
+            (index.car_len()?, written)
+        }
+    };
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all().context("sync car")?;
+    Ok((car_len, written))
+}
+
+fn partial_path(out: &Path) -> PathBuf {
+    let mut partial = OsString::from(out.as_os_str());
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+/// The default CAR of the piece `piece_cid`, `<piece_cid>.car`.
+pub fn path_for(piece_cid: &str) -> PathBuf {
+    PathBuf::from(format!("{}.car", piece_cid))
+}
@@ -0,0 +1,219 @@
+//! Golden data for the sealing test suites downstream: a small sector staged
+//! from generated pieces, with everything PreCommit is expected to see.
+//!
+//! The same seal proof and seed always give the same files, whatever the
+//! config, so that the expected values can be checked into other repos.
+
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use add_piece::{
+    chunk_sink::ChunkRoots,
+    manifest::{Manifest, ManifestPiece},
+    piece_cid, precommit, pure, seal_proof, AddPieceOptions,
+};
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, SectorSize, UnpaddedBytesAmount};
+use serde::Serialize;
+use tracing::info;
+use vc_processors::fil_proofs::RegisteredSealProof;
+
+/// Padded sizes of the pieces of the scenario, as fractions of the sector:
+/// the second piece is aligned after the first, the last one needs none, and
+/// zero pieces complete the sector.
+const PIECE_FRACTIONS: [u64; 3] = [16, 4, 8];
+
+/// What the staged fixture holds and what sealing it must yield.
+#[derive(Debug, Serialize)]
+pub struct Fixture {
+    pub proof_type: String,
+    pub sector_size: u64,
+    pub seed: u64,
+    /// Staged file, relative to the fixture directory, its manifest is next
+    /// to it.
+    pub staged_file: PathBuf,
+    /// The deal pieces, then the zero pieces completing the sector.
+    pub pieces: Vec<FixturePiece>,
+    /// The `PieceInfo`s PreCommit takes, in order.
+    pub piece_infos: Vec<PieceInfo>,
+    /// Hex encoded CommD of the sector.
+    pub comm_d: String,
+    /// CommD as the unsealed CID of the sector.
+    pub comm_d_cid: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FixturePiece {
+    /// Payload relative to the fixture directory, none for zero pieces.
+    pub path: Option<PathBuf>,
+    pub payload_size: u64,
+    /// Unpadded size of the piece.
+    pub piece_size: u64,
+    /// Padded offset of the piece in the sector.
+    pub offset: u64,
+    pub commitment: String,
+    pub piece_cid: String,
+}
+
+/// Writes the fixture of a sector of `proof`, 2KiB or 8MiB, into `dir`: the
+/// payloads under `pieces/`, the staged file `staged` and its manifest, and
+/// `fixture.json` describing them.
+pub fn generate(dir: &Path, proof: RegisteredSealProof, seed: u64) -> Result<Fixture> {
+    let sector_size = u64::from(proof.sector_size());
+    ensure!(
+        sector_size <= 8 << 20,
+        "fixtures are only generated for 2KiB and 8MiB sectors, not {}",
+        seal_proof::name(proof)
+    );
+    fs::create_dir_all(dir.join("pieces"))
+        .with_context(|| format!("create fixture dir {}", dir.display()))?;
+
+    let staged_file = PathBuf::from("staged");
+    let staged_path = dir.join(&staged_file);
+    let file = fs::File::create(&staged_path)
+        .with_context(|| format!("create {}", staged_path.display()))?;
+    let mut target = BufWriter::new(file);
+
+    let mut manifest = Manifest::default();
+    let mut pieces = Vec::new();
+    for (index, fraction) in PIECE_FRACTIONS.into_iter().enumerate() {
+        let piece_size = UnpaddedBytesAmount::from(PaddedBytesAmount(sector_size / fraction));
+        let payload = payload(seed, index, u64::from(piece_size));
+        let path = PathBuf::from("pieces").join(format!("{}.bin", index));
+        fs::write(dir.join(&path), &payload)
+            .with_context(|| format!("write {}", path.display()))?;
+
+        let chunk_roots = Arc::new(ChunkRoots::new());
+        let options = AddPieceOptions {
+            chunk_sink: Some(chunk_roots.clone()),
+            ..Default::default()
+        };
+        let piece_lengths = manifest.piece_lengths()?;
+        let (piece_info, placement) = add_piece::add_piece_with_layout(
+            &payload[..],
+            &mut target,
+            piece_size,
+            &piece_lengths,
+            &options,
+        )
+        .with_context(|| format!("add piece {}", index))?;
+
+        let offset = u64::from(placement.offset);
+        pieces.push(fixture_piece(
+            Some(path.clone()),
+            payload.len(),
+            &piece_info,
+            offset,
+        ));
+        manifest.pieces.push(ManifestPiece {
+            source: path.to_string_lossy().into_owned(),
+            payload_size: payload.len() as u64,
+            piece_info,
+            offset,
+            len: u64::from(placement.end()) - offset,
+            chunk_roots: chunk_roots.take(),
+            encryption: None,
+            excessive_alignment: None,
+            payload_blake3: None,
+            payload_sha256: None,
+        });
+    }
+
+    let fillers = precommit::filler_pieces(
+        PaddedBytesAmount(manifest.end()),
+        PaddedBytesAmount(sector_size),
+    )?;
+    for filler in fillers {
+        let offset = manifest.end();
+        let len = u64::from(PaddedBytesAmount::from(filler.size));
+        add_piece::zero_fill::ZeroFillConfig::default().write(&mut target, len)?;
+        pieces.push(fixture_piece(None, 0, &filler, offset));
+        manifest.pieces.push(ManifestPiece {
+            source: "filler".to_string(),
+            payload_size: 0,
+            piece_info: filler,
+            offset,
+            len,
+            chunk_roots: Vec::new(),
+            encryption: None,
+            excessive_alignment: None,
+            payload_blake3: None,
+            payload_sha256: None,
+        });
+    }
+    target
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("write {}", staged_path.display()))?;
+    manifest.save(&staged_path)?;
+
+    let piece_infos = precommit::sector_piece_infos(proof, &manifest)?;
+    let comm_d = filecoin_proofs::compute_comm_d(SectorSize(sector_size), &piece_infos)
+        .context("compute CommD")?;
+    // the sector is small enough to check the golden CommD against its bytes
+    let staged =
+        fs::read(&staged_path).with_context(|| format!("read {}", staged_path.display()))?;
+    ensure!(
+        pure::commit(&staged)? == comm_d,
+        "CommD of the pieces doesn't match the staged sector"
+    );
+    let fixture = Fixture {
+        proof_type: seal_proof::name(proof),
+        sector_size,
+        seed,
+        staged_file,
+        pieces,
+        piece_infos,
+        comm_d: hex::encode(comm_d),
+        comm_d_cid: piece_cid::encode(&comm_d),
+    };
+
+    let path = dir.join("fixture.json");
+    let mut content = serde_json::to_vec_pretty(&fixture)?;
+    content.push(b'\n');
+    fs::File::create(&path)
+        .and_then(|mut f| f.write_all(&content))
+        .with_context(|| format!("write {}", path.display()))?;
+    info!(dir = %dir.display(), comm_d = %fixture.comm_d_cid, "fixture generated");
+    Ok(fixture)
+}
+
+fn fixture_piece(
+    path: Option<PathBuf>,
+    payload_size: usize,
+    piece_info: &PieceInfo,
+    offset: u64,
+) -> FixturePiece {
+    FixturePiece {
+        path,
+        payload_size: payload_size as u64,
+        piece_size: piece_info.size.into(),
+        offset,
+        commitment: hex::encode(piece_info.commitment),
+        piece_cid: piece_cid::encode(&piece_info.commitment),
+    }
+}
+
+/// `len` bytes of the piece `index`, from a splitmix64 generator so that the
+/// payloads never depend on the version of a random number crate.
+fn payload(seed: u64, index: usize, len: u64) -> Vec<u8> {
+    let mut state = seed ^ (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let mut payload = Vec::with_capacity(len as usize + 8);
+    while (payload.len() as u64) < len {
+        payload.extend_from_slice(&next().to_le_bytes());
+    }
+    payload.truncate(len as usize);
+    payload
+}
@@ -0,0 +1,278 @@
+//! A queue of node hashing batches shared by the pieces hashed at once with
+//! the `parallel` committer, served by one thread per core.
+//!
+//! Batches are taken by the bytes their piece still has to hash, fewest
+//! first, so that a piece about to be done isn't held back by a large piece
+//! started after it. Hashing everything on the global rayon pool would
+//! interleave their batches instead.
+
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    mem,
+    ops::Range,
+    sync::{
+        atomic::{self, AtomicU64, AtomicUsize},
+        mpsc, Arc, Condvar, Mutex, OnceLock,
+    },
+    thread,
+};
+
+use filecoin_hashers::{HashFunction, Hasher};
+use filecoin_proofs::constants::DefaultPieceHasher;
+use serde::Serialize;
+
+type HashDomain = <DefaultPieceHasher as Hasher>::Domain;
+
+/// Nodes of 64 bytes hashed by a batch, 1MiB of leaves.
+pub const BATCH_NODES: usize = 16 << 10;
+
+/// Activity of the queue since the process started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct HashQueueStats {
+    /// Threads hashing the batches, none until the first batch.
+    pub workers: usize,
+    /// Batches waiting for a thread.
+    pub queued: usize,
+    /// Pieces waiting for their batches.
+    pub pieces: usize,
+    pub batches_hashed: u64,
+    pub nodes_hashed: u64,
+}
+
+/// Bytes split into nodes: the padded leaves of a chunk, or a row of its tree
+/// whose pairs of hashes are the nodes of the next row.
+#[derive(Clone)]
+enum Nodes {
+    Leaves(Arc<Vec<u8>>),
+    Row(Arc<Vec<HashDomain>>),
+}
+
+impl Nodes {
+    fn len(&self) -> usize {
+        match self {
+            Nodes::Leaves(data) => data.len() / 64,
+            Nodes::Row(row) => row.len() / 2,
+        }
+    }
+
+    fn hash(&self, range: Range<usize>) -> Vec<HashDomain> {
+        let hash = <DefaultPieceHasher as Hasher>::Function::hash;
+        let bytes = match self {
+            Nodes::Leaves(data) => &data[range.start * 64..range.end * 64],
+            Nodes::Row(row) => {
+                let pairs = &row[range.start * 2..range.end * 2];
+                // hashes are plain 32 bytes arrays, as in `compute_row`
+                unsafe {
+                    std::slice::from_raw_parts(pairs.as_ptr() as *const u8, mem::size_of_val(pairs))
+                }
+            }
+        };
+        bytes.chunks_exact(64).map(hash).collect()
+    }
+}
+
+struct Batch {
+    /// Bytes left to hash of the piece, the fewest go first.
+    remaining: u64,
+    /// Submission order, among batches of equal `remaining`.
+    seq: u64,
+    nodes: Nodes,
+    index: usize,
+    range: Range<usize>,
+    done: mpsc::Sender<(usize, Vec<HashDomain>)>,
+}
+
+impl Ord for Batch {
+    /// The batch taken first is the greatest.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.remaining, other.seq).cmp(&(self.remaining, self.seq))
+    }
+}
+
+impl PartialOrd for Batch {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Batch {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Batch {}
+
+#[derive(Default)]
+struct Queue {
+    batches: Mutex<BinaryHeap<Batch>>,
+    ready: Condvar,
+    seq: AtomicU64,
+    workers: AtomicUsize,
+    pieces: AtomicUsize,
+    batches_hashed: AtomicU64,
+    nodes_hashed: AtomicU64,
+}
+
+static QUEUE: OnceLock<Arc<Queue>> = OnceLock::new();
+
+fn queue() -> &'static Arc<Queue> {
+    QUEUE.get_or_init(|| {
+        let queue = Arc::new(Queue::default());
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        for i in 0..workers {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("hash-queue-{}", i))
+                .spawn(move || queue.work())
+                .expect("spawn hash queue worker");
+        }
+        queue.workers.store(workers, atomic::Ordering::Relaxed);
+        queue
+    })
+}
+
+impl Queue {
+    fn work(&self) {
+        loop {
+            let batch = {
+                let mut batches = self.batches.lock().expect("hash queue poisoned");
+                loop {
+                    match batches.pop() {
+                        Some(batch) => break batch,
+                        None => batches = self.ready.wait(batches).expect("hash queue poisoned"),
+                    }
+                }
+            };
+            let Batch {
+                nodes,
+                index,
+                range,
+                done,
+                ..
+            } = batch;
+            let hashed = nodes.hash(range);
+            // the nodes go back to their piece once every batch is done
+            drop(nodes);
+            self.batches_hashed.fetch_add(1, atomic::Ordering::Relaxed);
+            self.nodes_hashed
+                .fetch_add(hashed.len() as u64, atomic::Ordering::Relaxed);
+            let _ = done.send((index, hashed));
+        }
+    }
+
+    /// Hashes every node through the queue and waits for them.
+    fn hash(&self, nodes: &Nodes, remaining: u64) -> Vec<HashDomain> {
+        let len = nodes.len();
+        let (done, results) = mpsc::channel();
+        let mut batches = 0;
+        {
+            let mut queued = self.batches.lock().expect("hash queue poisoned");
+            for (index, start) in (0..len).step_by(BATCH_NODES).enumerate() {
+                queued.push(Batch {
+                    remaining,
+                    seq: self.seq.fetch_add(1, atomic::Ordering::Relaxed),
+                    nodes: nodes.clone(),
+                    index,
+                    range: start..(start + BATCH_NODES).min(len),
+                    done: done.clone(),
+                });
+                batches += 1;
+            }
+        }
+        self.ready.notify_all();
+        drop(done);
+
+        self.pieces.fetch_add(1, atomic::Ordering::Relaxed);
+        let mut hashed = vec![Vec::new(); batches];
+        for _ in 0..batches {
+            let (index, batch) = results.recv().expect("hash queue workers never stop");
+            hashed[index] = batch;
+        }
+        self.pieces.fetch_sub(1, atomic::Ordering::Relaxed);
+        hashed.concat()
+    }
+}
+
+/// Root of the bit padded, power of 2 sized `data`, its nodes hashed on the
+/// shared queue by `remaining`, the bytes its piece has left to hash
+/// including `data`. `data` is handed back emptied, to be reused.
+pub fn compute_root(data: Vec<u8>, remaining: u64) -> (HashDomain, Vec<u8>) {
+    let hash = <DefaultPieceHasher as Hasher>::Function::hash;
+    if data.len() <= 64 * BATCH_NODES {
+        let leaves = data.chunks_exact(64).map(hash).collect();
+        let mut data = data;
+        data.clear();
+        return (crate::commitment_reader::reduce(leaves), data);
+    }
+
+    let _gate = crate::memory_pressure::chunk_gate();
+    let leaves = Nodes::Leaves(Arc::new(data));
+    let mut row = queue().hash(&leaves, remaining);
+    let mut data = match leaves {
+        Nodes::Leaves(data) => Arc::try_unwrap(data).unwrap_or_default(),
+        Nodes::Row(_) => unreachable!(),
+    };
+    data.clear();
+
+    while row.len() > 2 * BATCH_NODES {
+        row = queue().hash(&Nodes::Row(Arc::new(row)), remaining);
+    }
+    (crate::commitment_reader::reduce(row), data)
+}
+
+pub fn stats() -> HashQueueStats {
+    let queue = match QUEUE.get() {
+        Some(queue) => queue,
+        None => return HashQueueStats::default(),
+    };
+    HashQueueStats {
+        workers: queue.workers.load(atomic::Ordering::Relaxed),
+        queued: queue.batches.lock().expect("hash queue poisoned").len(),
+        pieces: queue.pieces.load(atomic::Ordering::Relaxed),
+        batches_hashed: queue.batches_hashed.load(atomic::Ordering::Relaxed),
+        nodes_hashed: queue.nodes_hashed.load(atomic::Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::commitment_reader::compute_padded;
+
+    #[test]
+    fn test_hash_queue() {
+        let data: Vec<u8> = (0..64 * BATCH_NODES * 8)
+            .map(|i| (i * 7 % 251) as u8 & 0x3f)
+            .collect();
+        let expected = compute_padded(&data);
+        let (root, data) = compute_root(data, 0);
+        assert_eq!(root, expected);
+        assert!(data.is_empty() && data.capacity() > 0);
+
+        let small = vec![1u8; 64 * 4];
+        assert_eq!(compute_root(small.clone(), 0).0, compute_padded(&small));
+
+        let stats = stats();
+        assert!(stats.workers > 0);
+        assert!(stats.batches_hashed >= 8);
+
+        // fewest remaining bytes first, then in order
+        let (done, _) = mpsc::channel();
+        let batch = |remaining, seq| Batch {
+            remaining,
+            seq,
+            nodes: Nodes::Leaves(Arc::default()),
+            index: 0,
+            range: 0..0,
+            done: done.clone(),
+        };
+        let mut heap = BinaryHeap::from(vec![batch(10, 0), batch(5, 2), batch(5, 1)]);
+        let order: Vec<_> = std::iter::from_fn(|| heap.pop())
+            .map(|b| (b.remaining, b.seq))
+            .collect();
+        assert_eq!(order, vec![(5, 1), (5, 2), (10, 0)]);
+    }
+}
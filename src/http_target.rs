@@ -0,0 +1,371 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{credentials, staged_target::StagedTarget};
+
+/// How staged files are written to `http://` and `https://` targets.
+///
+/// The staged file is sent as a sequence of `PUT` requests, each carrying one
+/// segment with a `Content-Range` header, which WebDAV servers and most
+/// storage gateways accept. When a segment fails, the length the server
+/// already holds is checked with `HEAD` before sending it again, so a dropped
+/// connection only costs the current segment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpTargetConfig {
+    /// Bytes sent by each request.
+    pub segment_size: usize,
+
+    /// Attempts made for each segment before the upload is aborted.
+    pub segment_attempts: u32,
+
+    pub timeout_secs: u64,
+
+    /// Create the missing parent collections of the target with `MKCOL`.
+    pub webdav: bool,
+
+    /// Extra headers sent with every request, never written back, e.g. into
+    /// recordings. Secrets are refused: `Authorization`, `Proxy-Authorization`
+    /// and `Cookie` are given by the `http` or `https` chain of `credentials`.
+    #[serde(skip_serializing)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for HttpTargetConfig {
+    fn default() -> Self {
+        Self {
+            segment_size: 64 << 20,
+            segment_attempts: 5,
+            timeout_secs: 300,
+            webdav: false,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+/// Headers only the credential chains may give, lower case.
+const SECRET_HEADERS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+/// Uploads a staged file to `url`, one segment at a time.
+pub struct HttpUpload {
+    config: HttpTargetConfig,
+    agent: ureq::Agent,
+    url: String,
+    buf: Vec<u8>,
+    /// Bytes acknowledged by the server.
+    uploaded: u64,
+}
+
+impl HttpUpload {
+    pub fn create(config: &HttpTargetConfig, url: &str) -> Result<Self> {
+        ensure!(config.segment_size > 0, "segment size must not be 0");
+        if let Some(name) = config
+            .headers
+            .keys()
+            .find(|name| SECRET_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+        {
+            bail!(
+                "http_target header {} holds a secret, give it by the credentials of the scheme",
+                name
+            );
+        }
+
+        let upload = Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(config.timeout_secs))
+                .build(),
+            config: config.clone(),
+            url: url.to_string(),
+            buf: Vec::with_capacity(config.segment_size),
+            uploaded: 0,
+        };
+
+        if config.webdav {
+            upload.create_collections()?;
+        }
+        Ok(upload)
+    }
+
+    /// A request carrying the configured headers and the credentials of the
+    /// scheme of `url`, which take precedence.
+    fn request(&self, method: &str, url: &str) -> Result<ureq::Request> {
+        let provided = credentials::http_headers(url)?;
+        Ok(self
+            .config
+            .headers
+            .iter()
+            .chain(&provided)
+            .fold(self.agent.request(method, url), |req, (k, v)| req.set(k, v)))
+    }
+
+    /// Creates the collections above the target, leaving existing ones alone.
+    fn create_collections(&self) -> Result<()> {
+        let (scheme, rest) = self
+            .url
+            .split_once("://")
+            .ok_or_else(|| anyhow!("invalid url: {}", self.url))?;
+        let segments: Vec<_> = rest.split('/').collect();
+
+        for depth in 2..segments.len() {
+            let collection = format!("{}://{}/", scheme, segments[..depth].join("/"));
+            match self.request("MKCOL", &collection)?.call() {
+                // 405: the collection already exists
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+                Err(e) => return Err(e).with_context(|| format!("MKCOL {}", collection)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the buffered segment, `last` tells the server the total length.
+    fn put_segment(&mut self, last: bool) -> Result<()> {
+        let start = self.uploaded;
+        let end = start + self.buf.len() as u64;
+        let content_range = match last {
+            true => format!("bytes {}-{}/{}", start, end - 1, end),
+            false => format!("bytes {}-{}/*", start, end - 1),
+        };
+
+        let mut attempt = 1;
+        loop {
+            let res = self
+                .request("PUT", &self.url)?
+                .set("Content-Range", &content_range)
+                .send_bytes(&self.buf);
+            let err = match res {
+                Ok(_) => break,
+                Err(e) => e,
+            };
+
+            ensure!(
+                attempt < self.config.segment_attempts,
+                "PUT {} ({}): {}",
+                self.url,
+                content_range,
+                err
+            );
+            warn!(
+                url = self.url.as_str(),
+                range = content_range.as_str(),
+                attempt,
+                "retry segment: {}",
+                err
+            );
+            thread::sleep(Duration::from_secs(1 << attempt.min(6)));
+            attempt += 1;
+
+            // the server may have stored the segment before the connection dropped
+            if let Some(stored) = self.stored_len() {
+                ensure!(
+                    stored >= start,
+                    "{} holds {} bytes, {} were acknowledged",
+                    self.url,
+                    stored,
+                    start
+                );
+                if stored >= end {
+                    debug!(url = self.url.as_str(), stored, "segment already stored");
+                    break;
+                }
+            }
+        }
+
+        self.uploaded = end;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Length of the target on the server, if it can be told.
+    fn stored_len(&self) -> Option<u64> {
+        let resp = self.request("HEAD", &self.url).ok()?.call().ok()?;
+        resp.header("Content-Length")?.parse().ok()
+    }
+}
+
+impl Write for HttpUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.config.segment_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == self.config.segment_size {
+            self.put_segment(false).map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+
+    /// Segments are only sent once full, flushing does nothing.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StagedTarget for HttpUpload {
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let end = self.uploaded + self.buf.len() as u64;
+        if offset != end {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("uploads are only appended to, at {} not {}", end, offset),
+            ));
+        }
+        self.write_all(buf)
+    }
+
+    /// The server is left to allocate the segments as they come.
+    fn preallocate(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Segments are stored once acknowledged, the last one is only sent by
+    /// `finalize`.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>, manifest: &[u8]) -> Result<()> {
+        if !self.buf.is_empty() {
+            self.put_segment(true)?;
+        }
+
+        let manifest_url = format!("{}.manifest.json", self.url);
+        self.request("PUT", &manifest_url)?
+            .set("Content-Type", "application/json")
+            .send_bytes(manifest)
+            .with_context(|| format!("PUT {}", manifest_url))?;
+        Ok(())
+    }
+
+    fn abort(self: Box<Self>) {
+        let res = self
+            .request("DELETE", &self.url)
+            .and_then(|req| Ok(req.call()?));
+        if let Err(e) = res {
+            warn!(url = self.url.as_str(), "delete partial upload: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::mock_http::{MockServer, Response};
+
+    /// A server storing ranged `PUT`s of the target, which drops the
+    /// acknowledgement of the first segment once.
+    fn server(stored: Arc<Mutex<Vec<u8>>>) -> MockServer {
+        let mut dropped = false;
+        MockServer::start(move |req| {
+            let mut stored = stored.lock().unwrap();
+            match (req.method.as_str(), req.path.as_str()) {
+                ("MKCOL", "/sectors/") => Response::new(405),
+                ("MKCOL", _) => Response::new(201),
+                ("HEAD", "/sectors/staging/s-t01000-1") => {
+                    Response::new(200).header("Content-Length", stored.len())
+                }
+                ("PUT", "/sectors/staging/s-t01000-1") => {
+                    let range = req.header("Content-Range").unwrap();
+                    let start: usize = range["bytes ".len()..range.find('-').unwrap()]
+                        .parse()
+                        .unwrap();
+                    if start != stored.len() {
+                        return Response::new(416);
+                    }
+                    stored.extend_from_slice(&req.body);
+                    match dropped {
+                        true => Response::new(200),
+                        false => {
+                            dropped = true;
+                            Response::new(502)
+                        }
+                    }
+                }
+                _ => Response::new(201),
+            }
+        })
+    }
+
+    #[test]
+    fn test_resumes_after_dropped_segment() {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let server = server(stored.clone());
+        let config = HttpTargetConfig {
+            segment_size: 4,
+            segment_attempts: 2,
+            webdav: true,
+            ..Default::default()
+        };
+        let url = format!("{}/sectors/staging/s-t01000-1", server.url());
+
+        let mut upload = Box::new(HttpUpload::create(&config, &url).expect("create"));
+        upload.write_at(b"0123456789", 0).expect("write");
+        assert!(upload.write_at(b"x", 4).is_err());
+        upload.finalize(b"{}").expect("finalize");
+        assert_eq!(&stored.lock().unwrap()[..], b"0123456789");
+
+        let requests: Vec<_> = server
+            .requests()
+            .into_iter()
+            .map(|r| {
+                let range = r.header("Content-Range").unwrap_or_default().to_string();
+                (r.method, r.path, range)
+            })
+            .collect();
+        let expected = [
+            ("MKCOL", "/sectors/", ""),
+            ("MKCOL", "/sectors/staging/", ""),
+            // the first segment was stored, it isn't sent again
+            ("PUT", "/sectors/staging/s-t01000-1", "bytes 0-3/*"),
+            ("HEAD", "/sectors/staging/s-t01000-1", ""),
+            ("PUT", "/sectors/staging/s-t01000-1", "bytes 4-7/*"),
+            ("PUT", "/sectors/staging/s-t01000-1", "bytes 8-9/10"),
+            ("PUT", "/sectors/staging/s-t01000-1.manifest.json", ""),
+        ];
+        assert_eq!(requests.len(), expected.len());
+        for (req, (method, path, range)) in requests.iter().zip(expected) {
+            assert_eq!(
+                (req.0.as_str(), req.1.as_str(), req.2.as_str()),
+                (method, path, range)
+            );
+        }
+    }
+
+    #[test]
+    fn test_fails_when_server_lost_acknowledged_data() {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let server = server(stored.clone());
+        let config = HttpTargetConfig {
+            segment_size: 4,
+            segment_attempts: 2,
+            ..Default::default()
+        };
+        let url = format!("{}/sectors/staging/s-t01000-1", server.url());
+
+        // the server only ever sees the second segment
+        let mut upload = HttpUpload::create(&config, &url).expect("create");
+        upload.uploaded = 4;
+        assert!(upload.write_all(b"4567").is_err());
+        assert!(stored.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_secret_headers_refused() {
+        let config = HttpTargetConfig {
+            headers: [("authorization".to_string(), "Bearer x".to_string())].into(),
+            ..Default::default()
+        };
+        let err = HttpUpload::create(&config, "http://127.0.0.1:1/staged")
+            .err()
+            .expect("secret header accepted");
+        assert!(err.to_string().contains("secret"), "{:?}", err);
+    }
+}
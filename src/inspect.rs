@@ -0,0 +1,144 @@
+use std::{fs, path::Path};
+
+use add_piece::{
+    content_type,
+    manifest::Manifest,
+    staged_format::{Header, StagedFormat},
+    unpad::read_unpadded,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct InspectReport {
+    pub staged_file: String,
+    pub staged_size: u64,
+    pub format: StagedFormat,
+    /// Proof type recorded by the v2 header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proof_type: Option<String>,
+    pub pieces: Vec<PieceReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PieceReport {
+    pub source: String,
+    pub payload_size: u64,
+    pub piece_size: u64,
+    pub commitment: String,
+    pub offset: u64,
+    pub len: u64,
+    pub chunk_roots: usize,
+    /// Whether the recorded chunk roots reduce to the commitment, unknown if
+    /// no chunk roots were recorded.
+    pub chunk_roots_match: Option<bool>,
+    /// Scheme and key id of the encrypted payload, samples are ciphertext.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<String>,
+    /// Alignment bytes around the piece, flagged by the padding warning.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub excessive_alignment: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_blake3: Option<String>,
+    /// Sha256 the piece file was checked against.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample: Option<PieceSample>,
+}
+
+/// The first and last payload bytes of a piece, recovered from the staged file.
+#[derive(Debug, Serialize)]
+pub struct PieceSample {
+    pub head: String,
+    pub tail: String,
+    pub content_type: Option<&'static str>,
+}
+
+/// Describes the pieces of a staged file according to its manifest, with the
+/// first and last `sample` payload bytes of each piece if requested.
+///
+/// The piece table of a v2 staged file stands in for a missing manifest.
+pub fn inspect(staged: &Path, sample: Option<usize>) -> Result<InspectReport> {
+    let file = fs::File::open(staged)
+        .with_context(|| format!("open staged file: {}", staged.display()))?;
+    let staged_size = file.metadata().context("stat staged file")?.len();
+    let header = Header::read_from(&file)?;
+
+    let manifest = match Manifest::load(staged)? {
+        Some(m) => Some(m),
+        None => match &header {
+            Some(h) => h.read_piece_table(&file)?,
+            None => None,
+        },
+    };
+    let manifest = manifest.ok_or_else(|| anyhow!("no manifest found for {}", staged.display()))?;
+    let data_offset = header.as_ref().map_or(0, |h| h.data_offset);
+
+    let pieces = manifest
+        .pieces
+        .iter()
+        .map(|piece| {
+            let sample = match sample {
+                Some(n) => Some(sample_piece(
+                    &file,
+                    data_offset + piece.offset,
+                    piece.payload_size,
+                    n,
+                )?),
+                None => None,
+            };
+
+            Ok(PieceReport {
+                source: piece.source.clone(),
+                payload_size: piece.payload_size,
+                piece_size: piece.piece_info.size.into(),
+                commitment: hex::encode(piece.piece_info.commitment),
+                offset: piece.offset,
+                len: piece.len,
+                chunk_roots: piece.chunk_roots.len(),
+                chunk_roots_match: piece.verify_chunk_roots()?,
+                encryption: piece
+                    .encryption
+                    .as_ref()
+                    .map(|e| format!("{} ({})", e.scheme, e.key_id)),
+                excessive_alignment: piece.excessive_alignment,
+                payload_blake3: piece.payload_blake3.clone(),
+                payload_sha256: piece.payload_sha256.clone(),
+                sample,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(InspectReport {
+        staged_file: staged.display().to_string(),
+        staged_size,
+        format: match header {
+            Some(_) => StagedFormat::V2,
+            None => StagedFormat::Raw,
+        },
+        proof_type: header.map(|h| h.proof_type).filter(|p| !p.is_empty()),
+        pieces,
+    })
+}
+
+fn sample_piece(file: &fs::File, offset: u64, payload_size: u64, n: usize) -> Result<PieceSample> {
+    let n = (n as u64).min(payload_size) as usize;
+    let head = read_unpadded(file, offset, 0, n).context("read piece head")?;
+    let tail =
+        read_unpadded(file, offset, payload_size - n as u64, n).context("read piece tail")?;
+
+    let sniff = read_unpadded(
+        file,
+        offset,
+        0,
+        (content_type::SNIFF_LEN as u64).min(payload_size) as usize,
+    )
+    .context("read piece head")?;
+
+    Ok(PieceSample {
+        head: hex::encode(head),
+        tail: hex::encode(tail),
+        content_type: content_type::detect(&sniff),
+    })
+}
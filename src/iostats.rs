@@ -0,0 +1,248 @@
+use std::{fs, path::Path, time::Duration};
+
+use add_piece::metered::IoStats;
+use serde::Serialize;
+
+/// Bandwidth observed on one device or remote host.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceBandwidth {
+    pub device: String,
+    pub bytes: u64,
+    pub busy_secs: f64,
+    pub bytes_per_sec: f64,
+}
+
+/// Wall time spent adding pieces, split by what the pipeline was blocked on:
+/// reading the source, writing the target, or neither while it padded and
+/// hashed. Tells whether faster disks or more CPUs would help. Syncing the
+/// target once a piece is written isn't counted.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TimeBreakdown {
+    pub wall_secs: f64,
+    pub source_secs: f64,
+    pub target_secs: f64,
+    pub hashing_secs: f64,
+}
+
+impl TimeBreakdown {
+    pub fn new(wall: Duration, source: Duration, target: Duration) -> Self {
+        Self {
+            wall_secs: wall.as_secs_f64(),
+            source_secs: source.as_secs_f64(),
+            target_secs: target.as_secs_f64(),
+            hashing_secs: wall.saturating_sub(source + target).as_secs_f64(),
+        }
+    }
+
+    fn add(&mut self, other: &TimeBreakdown) {
+        self.wall_secs += other.wall_secs;
+        self.source_secs += other.source_secs;
+        self.target_secs += other.target_secs;
+        self.hashing_secs += other.hashing_secs;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PieceTimes {
+    /// Index of the piece in its task.
+    pub index: usize,
+    #[serde(flatten)]
+    pub times: TimeBreakdown,
+}
+
+/// Source and target bandwidth of a task, grouped by device so schedulers can
+/// learn which storage backends are slow.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IoReport {
+    pub sources: Vec<DeviceBandwidth>,
+    pub targets: Vec<DeviceBandwidth>,
+    /// Of the pieces added, reused pieces aren't timed.
+    pub times: TimeBreakdown,
+    pub pieces: Vec<PieceTimes>,
+}
+
+/// Accumulates the `IoStats` of a task per device.
+#[derive(Debug, Default)]
+pub struct TaskIoStats {
+    sources: Vec<(String, IoStats)>,
+    targets: Vec<(String, IoStats)>,
+    pieces: Vec<PieceTimes>,
+}
+
+impl TaskIoStats {
+    pub fn record_source(&mut self, device: &str, stats: IoStats) {
+        record(&mut self.sources, device, stats)
+    }
+
+    pub fn record_target(&mut self, device: &str, stats: IoStats) {
+        record(&mut self.targets, device, stats)
+    }
+
+    /// Records the time piece `index` took, `source` and `target` of which
+    /// were spent blocked on its reads and writes.
+    pub fn record_piece(
+        &mut self,
+        index: usize,
+        wall: Duration,
+        source: Duration,
+        target: Duration,
+    ) {
+        self.pieces.push(PieceTimes {
+            index,
+            times: TimeBreakdown::new(wall, source, target),
+        });
+    }
+
+    /// Adds the stats of `other`, e.g. of a sub task.
+    pub fn merge(&mut self, other: TaskIoStats) {
+        for (device, stats) in other.sources {
+            record(&mut self.sources, &device, stats);
+        }
+        for (device, stats) in other.targets {
+            record(&mut self.targets, &device, stats);
+        }
+        self.pieces.extend(other.pieces);
+    }
+
+    pub fn report(&self) -> IoReport {
+        let mut times = TimeBreakdown::default();
+        self.pieces.iter().for_each(|p| times.add(&p.times));
+        IoReport {
+            sources: self.sources.iter().map(bandwidth).collect(),
+            targets: self.targets.iter().map(bandwidth).collect(),
+            times,
+            pieces: self.pieces.clone(),
+        }
+    }
+}
+
+fn record(entries: &mut Vec<(String, IoStats)>, device: &str, stats: IoStats) {
+    match entries.iter_mut().find(|(d, _)| d == device) {
+        Some((_, s)) => s.merge(stats),
+        None => entries.push((device.to_string(), stats)),
+    }
+}
+
+fn bandwidth((device, stats): &(String, IoStats)) -> DeviceBandwidth {
+    DeviceBandwidth {
+        device: device.clone(),
+        bytes: stats.bytes,
+        busy_secs: stats.busy.as_secs_f64(),
+        bytes_per_sec: stats.bytes_per_sec(),
+    }
+}
+
+/// Returns a tag for the mount holding `path`: its mount point and source,
+/// e.g. `/mnt/nfs1 (nfs4 server:/export)`.
+pub fn device_of(path: &Path) -> String {
+    mount_of(path)
+        .map(|m| format!("{} ({} {})", m.mount_point, m.fs_type, m.source))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Returns the mount holding `path`, if it is known.
+pub fn mount_of(path: &Path) -> Option<MountEntry> {
+    let path = fs::canonicalize(path)
+        .or_else(|_| {
+            path.parent()
+                .map(fs::canonicalize)
+                .unwrap_or_else(|| Ok(path.to_path_buf()))
+        })
+        .unwrap_or_else(|_| path.to_path_buf());
+
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo
+        .lines()
+        .filter_map(parse_mountinfo_line)
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.len())
+}
+
+/// Returns a tag for a remote source, its scheme and host.
+pub fn host_of(url: &str) -> String {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let host = rest
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .rsplit('@')
+        .next()
+        .unwrap_or_default();
+    format!("{}://{}", scheme, host)
+}
+
+pub struct MountEntry {
+    pub mount_point: String,
+    pub fs_type: String,
+    pub source: String,
+}
+
+impl MountEntry {
+    /// Whether the filesystem is served over the network.
+    pub fn is_network(&self) -> bool {
+        const NETWORK: [&str; 8] = [
+            "nfs",
+            "nfs4",
+            "cifs",
+            "smb3",
+            "smbfs",
+            "ceph",
+            "glusterfs",
+            "9p",
+        ];
+        NETWORK.contains(&self.fs_type.as_str()) || self.fs_type.starts_with("fuse.sshfs")
+    }
+}
+
+/// Parses one line of `/proc/self/mountinfo`.
+pub fn parse_mountinfo_line(line: &str) -> Option<MountEntry> {
+    let (left, right) = line.split_once(" - ")?;
+    let mount_point = left.split(' ').nth(4)?;
+    let mut right = right.split(' ');
+    let fs_type = right.next()?;
+    let source = right.next()?;
+
+    Some(MountEntry {
+        mount_point: unescape(mount_point),
+        fs_type: fs_type.to_string(),
+        source: unescape(source),
+    })
+}
+
+/// Reverts the octal escaping used by the kernel for spaces and the like.
+fn unescape(s: &str) -> String {
+    // escapes are bytes of a possibly multi-byte character, decode once
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.as_bytes();
+    while let Some((&b, rest)) = bytes.split_first() {
+        let code = match (b, rest.get(..3)) {
+            (b'\\', Some(code)) if code.iter().all(|c| (b'0'..=b'7').contains(c)) => code
+                .iter()
+                .try_fold(0u8, |acc, c| acc.checked_mul(8)?.checked_add(c - b'0')),
+            _ => None,
+        };
+        match code {
+            Some(code) => {
+                out.push(code);
+                bytes = &rest[3..];
+            }
+            None => {
+                out.push(b);
+                bytes = rest;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(r"/mnt/my\040disk"), "/mnt/my disk");
+        assert_eq!(unescape(r"/mnt/\303\251t\303\251"), "/mnt/été");
+        assert_eq!(unescape(r"/mnt/a\\b\9"), r"/mnt/a\\b\9");
+    }
+}
@@ -0,0 +1,386 @@
+//! Journal of the chunks which landed in a staged file, kept next to it as
+//! `<staged>.journal` when `chunk_journal` is configured.
+//!
+//! A chunk is journaled once the staged file has been synced past it, one
+//! line per chunk followed by a sync of the journal. A resume only reuses the
+//! pieces of the manifest whose chunks are all journaled, with the roots the
+//! manifest recorded, rather than trusting the length of the staged file.
+
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, BufRead, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use add_piece::{
+    chunk_sink::ChunkRootSink, dedup::ChunkRoot, manifest::ManifestPiece, AddPieceOptions,
+    CHUNK_SIZE,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Index of the piece in the staged file.
+    pub piece: usize,
+    /// Index of the chunk in the piece, pieces added without their chunk
+    /// roots are journaled whole as chunk 0 with their commitment.
+    pub chunk: usize,
+    /// Hex encoded root of the chunk.
+    pub root: String,
+    /// Padded offset right after the chunk, relative to the staged data.
+    pub end: u64,
+}
+
+#[derive(Debug)]
+pub struct ChunkJournal {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    journal: fs::File,
+    /// Shares its offset with the handle the pieces are written through.
+    staged: fs::File,
+    /// Offset of the staged data in the file.
+    data_offset: u64,
+    entries: Vec<JournalEntry>,
+    current: Option<CurrentPiece>,
+}
+
+/// The piece being added, whose chunks are journaled as they land.
+#[derive(Debug)]
+struct CurrentPiece {
+    index: usize,
+    /// Padded offset of the piece data, past its left alignment.
+    start: u64,
+    len: u64,
+    /// Chunks hashed but maybe not written yet, in order.
+    pending: Vec<JournalEntry>,
+}
+
+impl ChunkJournal {
+    pub fn path_for(staged: impl AsRef<Path>) -> PathBuf {
+        let mut p = OsString::from(staged.as_ref().as_os_str());
+        p.push(".journal");
+        PathBuf::from(p)
+    }
+
+    /// Loads the journal of `staged`, if there is one. A line torn by a crash
+    /// ends it.
+    pub fn load(staged: impl AsRef<Path>) -> Result<Option<Vec<JournalEntry>>> {
+        let path = Self::path_for(staged);
+        let file = match fs::File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("open journal {}", path.display())),
+        };
+        let mut entries = Vec::new();
+        for line in io::BufReader::new(file).lines() {
+            let line = line.with_context(|| format!("read journal {}", path.display()))?;
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => break,
+            }
+        }
+        Ok(Some(entries))
+    }
+
+    /// Starts the journal of `staged` over with `entries`, the staged data
+    /// starting at `data_offset` in `file`.
+    pub fn open(
+        staged: impl AsRef<Path>,
+        file: &fs::File,
+        data_offset: u64,
+        entries: Vec<JournalEntry>,
+    ) -> Result<Self> {
+        let path = Self::path_for(staged);
+        let journal = rewrite(&path, &entries)?;
+        let staged = file.try_clone().context("clone staged file handle")?;
+        Ok(Self {
+            path,
+            state: Arc::new(Mutex::new(State {
+                journal,
+                staged,
+                data_offset,
+                entries,
+                current: None,
+            })),
+        })
+    }
+
+    /// Journals the chunks of the piece about to be added as `index`, whose
+    /// data spans `len` padded bytes from `start`, through the chunk sink of
+    /// `options`.
+    pub fn start_piece(&self, index: usize, start: u64, len: u64, options: &mut AddPieceOptions) {
+        self.lock().current = Some(CurrentPiece {
+            index,
+            start,
+            len,
+            pending: Vec::new(),
+        });
+        options.chunk_sink = Some(Arc::new(JournalSink {
+            state: self.state.clone(),
+            next: options.chunk_sink.take(),
+        }));
+    }
+
+    /// Journals what is left of `piece`, added or reused as `index`. Pieces
+    /// whose chunks weren't all journaled are journaled whole.
+    pub fn finish_piece(&self, index: usize, piece: &ManifestPiece) -> Result<()> {
+        let mut state = self.lock();
+        let pending = match state.current.take() {
+            Some(current) if current.index == index => current.pending,
+            _ => Vec::new(),
+        };
+        let recorded = state.entries.iter().filter(|e| e.piece == index);
+        let last = recorded.chain(&pending).last().map(|e| e.end);
+        if last == Some(piece.end()) {
+            return state.append(pending).context("append to journal");
+        }
+
+        if last.is_some() {
+            warn!(piece = index, "journaled chunks don't end with the piece");
+            state.entries.retain(|e| e.piece != index);
+            state.journal = rewrite(&self.path, &state.entries)?;
+        }
+        let whole = JournalEntry {
+            piece: index,
+            chunk: 0,
+            root: hex::encode(piece.piece_info.commitment),
+            end: piece.end(),
+        };
+        state.append(vec![whole]).context("append to journal")
+    }
+
+    /// Forgets the pieces from `pieces` on, after the staged file was
+    /// truncated to the ones before.
+    pub fn truncate(&self, pieces: usize) -> Result<()> {
+        let mut state = self.lock();
+        state.current = None;
+        if state.entries.iter().all(|e| e.piece < pieces) {
+            return Ok(());
+        }
+        state.entries.retain(|e| e.piece < pieces);
+        state.journal = rewrite(&self.path, &state.entries)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("journal poisoned")
+    }
+}
+
+impl State {
+    /// Syncs the staged file and journals `entries`.
+    fn append(&mut self, entries: Vec<JournalEntry>) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.staged.sync_data()?;
+        let mut lines = Vec::new();
+        for entry in &entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        self.journal.write_all(&lines)?;
+        self.journal.sync_data()?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Journals the pending chunks of the current piece written so far.
+    fn append_landed(&mut self) -> io::Result<()> {
+        let written = (&self.staged).stream_position()?;
+        let data_offset = self.data_offset;
+        let landed = match &mut self.current {
+            Some(current) => {
+                let n = current
+                    .pending
+                    .iter()
+                    .take_while(|e| data_offset + e.end <= written)
+                    .count();
+                current.pending.drain(..n).collect()
+            }
+            None => Vec::new(),
+        };
+        self.append(landed)
+    }
+}
+
+fn rewrite(path: &Path, entries: &[JournalEntry]) -> Result<fs::File> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let mut content = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut content, entry).context("serialize journal")?;
+        content.push(b'\n');
+    }
+    fs::write(&tmp, content).with_context(|| format!("write journal {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("rename journal {}", path.display()))?;
+    fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open journal {}", path.display()))
+}
+
+/// Number of `pieces`, from the first, whose chunks are all in `entries` and
+/// agree with their recorded roots, or which were journaled whole.
+pub fn journaled_pieces(pieces: &[ManifestPiece], entries: &[JournalEntry]) -> usize {
+    pieces
+        .iter()
+        .enumerate()
+        .take_while(|(index, piece)| {
+            let chunks: Vec<_> = entries.iter().filter(|e| e.piece == *index).collect();
+            let in_order = chunks.iter().enumerate().all(|(k, e)| e.chunk == k);
+            let roots_agree = piece.chunk_roots.len() != chunks.len()
+                || piece
+                    .chunk_roots
+                    .iter()
+                    .zip(&chunks)
+                    .all(|(r, e)| hex::encode(r) == e.root);
+            in_order && roots_agree && chunks.last().map(|e| e.end) == Some(piece.end())
+        })
+        .count()
+}
+
+/// Queues the chunk roots of the current piece, each is journaled once the
+/// staged file is written past its chunk.
+#[derive(Debug)]
+struct JournalSink {
+    state: Arc<Mutex<State>>,
+    next: Option<Arc<dyn ChunkRootSink>>,
+}
+
+impl ChunkRootSink for JournalSink {
+    fn chunk_root(&self, index: usize, root: &ChunkRoot) -> io::Result<()> {
+        if let Some(next) = &self.next {
+            next.chunk_root(index, root)?;
+        }
+        let mut state = self.state.lock().expect("journal poisoned");
+        if let Some(current) = &mut state.current {
+            let end = (index as u64 + 1) * CHUNK_SIZE as u64;
+            current.pending.push(JournalEntry {
+                piece: current.index,
+                chunk: index,
+                root: hex::encode(root),
+                end: current.start + end.min(current.len),
+            });
+        }
+        state.append_landed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::SeekFrom;
+
+    use filecoin_proofs::{PieceInfo, UnpaddedBytesAmount};
+
+    const CHUNK: u64 = CHUNK_SIZE as u64;
+
+    fn piece(offset: u64, len: u64, chunk_roots: Vec<ChunkRoot>) -> ManifestPiece {
+        ManifestPiece {
+            source: "test".to_string(),
+            payload_size: 0,
+            piece_info: PieceInfo {
+                commitment: [9u8; 32],
+                size: UnpaddedBytesAmount(len / 128 * 127),
+            },
+            offset,
+            len,
+            chunk_roots,
+            encryption: None,
+            excessive_alignment: None,
+            payload_blake3: None,
+            payload_sha256: None,
+        }
+    }
+
+    #[test]
+    fn test_chunks_journaled_once_landed() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let staged = dir.path().join("staged");
+        let mut file = fs::File::create(&staged).unwrap();
+        let journal = ChunkJournal::open(&staged, &file, 0, Vec::new()).expect("open journal");
+
+        let roots = [[1u8; 32], [2u8; 32]];
+        let mut options = AddPieceOptions::default();
+        journal.start_piece(0, 0, 2 * CHUNK, &mut options);
+        let sink = options.chunk_sink.expect("no chunk sink");
+
+        // chunk 0 is hashed before it is written
+        sink.chunk_root(0, &roots[0]).unwrap();
+        assert_eq!(ChunkJournal::load(&staged).unwrap(), Some(Vec::new()));
+
+        // the handle shares its offset with the one the journal syncs
+        file.seek(SeekFrom::Start(CHUNK + 1)).unwrap();
+        sink.chunk_root(1, &roots[1]).unwrap();
+        let entries = ChunkJournal::load(&staged).unwrap().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].end, CHUNK);
+
+        let piece = piece(0, 2 * CHUNK, roots.to_vec());
+        journal.finish_piece(0, &piece).expect("finish piece");
+        let entries = ChunkJournal::load(&staged).unwrap().unwrap();
+        assert_eq!(
+            entries.iter().map(|e| (e.chunk, e.end)).collect::<Vec<_>>(),
+            [(0, CHUNK), (1, 2 * CHUNK)]
+        );
+        assert_eq!(journaled_pieces(&[piece], &entries), 1);
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let staged = dir.path().join("staged");
+        let file = fs::File::create(&staged).unwrap();
+        let pieces = [
+            // a single chunk's root is the commitment
+            piece(0, CHUNK, vec![[9u8; 32]]),
+            piece(CHUNK, CHUNK, Vec::new()),
+            piece(2 * CHUNK, 2 * CHUNK, vec![[3u8; 32], [4u8; 32]]),
+        ];
+
+        // the last piece only has its first chunk journaled
+        let journal = ChunkJournal::open(&staged, &file, 0, Vec::new()).expect("open journal");
+        journal.finish_piece(0, &pieces[0]).unwrap();
+        journal.finish_piece(1, &pieces[1]).unwrap();
+        let mut entries = ChunkJournal::load(&staged).unwrap().unwrap();
+        assert_eq!(entries[1].root, hex::encode([9u8; 32]));
+        entries.push(JournalEntry {
+            piece: 2,
+            chunk: 0,
+            root: hex::encode([3u8; 32]),
+            end: 3 * CHUNK,
+        });
+        drop(journal);
+        let journal = ChunkJournal::open(&staged, &file, 0, entries).expect("reopen journal");
+
+        // a line torn by a crash ends the journal
+        let mut raw = fs::OpenOptions::new()
+            .append(true)
+            .open(ChunkJournal::path_for(&staged))
+            .unwrap();
+        raw.write_all(b"{\"piece\":2,\"chu").unwrap();
+        let entries = ChunkJournal::load(&staged).unwrap().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(journaled_pieces(&pieces, &entries), 2);
+
+        // roots disagreeing with the manifest aren't trusted
+        let mut tampered = pieces.clone();
+        tampered[0].chunk_roots = vec![[7u8; 32]];
+        assert_eq!(journaled_pieces(&tampered, &entries), 0);
+
+        journal.truncate(1).unwrap();
+        let entries = ChunkJournal::load(&staged).unwrap().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(journaled_pieces(&pieces, &entries), 1);
+    }
+}
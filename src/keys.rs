@@ -0,0 +1,106 @@
+use std::{env, fs, path::PathBuf, process, sync::Arc};
+
+use add_piece::{
+    encryption::{Key, KeyProvider, PayloadEncryption, SEGMENT_SIZE},
+    AddPieceOptions,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Encryption of the payloads before they are padded, so that the staged files
+/// never hold plaintext.
+///
+/// Keys are never part of the config, only where to get them from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    pub key: KeySource,
+
+    /// Recorded in the manifest to find the key again, unless the key command
+    /// prints one.
+    #[serde(default)]
+    pub key_id: String,
+
+    /// Payload bytes sealed by each tag recorded in the manifest.
+    #[serde(default = "default_segment_size")]
+    pub segment_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// Environment variable holding the hex encoded key.
+    Env(String),
+    /// File holding the hex encoded key.
+    File(PathBuf),
+    /// Program printing the hex encoded key, optionally followed by its id, for
+    /// the piece source given as its last argument, e.g. a KMS client. The
+    /// attestation key is asked for with `attestation`.
+    Command(Vec<String>),
+}
+
+fn default_segment_size() -> usize {
+    SEGMENT_SIZE
+}
+
+impl KeySource {
+    /// Reads the key, `arg` being passed to the key command.
+    pub fn read(&self, arg: &str) -> Result<String> {
+        match self {
+            KeySource::Env(var) => env::var(var).with_context(|| format!("read ${}", var)),
+            KeySource::File(path) => fs::read_to_string(path)
+                .with_context(|| format!("read key file: {}", path.display())),
+            KeySource::Command(command) => run_key_command(command, arg),
+        }
+    }
+}
+
+impl KeyProvider for EncryptionConfig {
+    fn key_for(&self, source: &str) -> Result<(String, Key)> {
+        let output = self.key.read(source)?;
+
+        let mut fields = output.split_whitespace();
+        let key = fields.next().ok_or_else(|| anyhow!("no key given"))?;
+        let mut raw = [0u8; 32];
+        hex::decode_to_slice(key, &mut raw).context("key must be 32 hex encoded bytes")?;
+        let key_id = fields.next().unwrap_or(&self.key_id).to_string();
+        Ok((key_id, raw))
+    }
+}
+
+fn run_key_command(command: &[String], source: &str) -> Result<String> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("empty key command"))?;
+    let output = process::Command::new(program)
+        .args(args)
+        .arg(source)
+        .stderr(process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("run key command {}", program))?;
+    ensure!(
+        output.status.success(),
+        "key command {} failed: {}",
+        program,
+        output.status
+    );
+    String::from_utf8(output.stdout).context("key command output")
+}
+
+/// Sets up the encryption of the payload read from `source` when configured,
+/// returns it so that its record can be taken once the piece is written.
+pub fn encrypt_payload(
+    config: Option<&EncryptionConfig>,
+    options: &mut AddPieceOptions,
+    source: &str,
+) -> Result<Option<Arc<PayloadEncryption>>> {
+    let config = match config {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let encryption = Arc::new(
+        PayloadEncryption::for_piece(config, source)?.with_segment_size(config.segment_size),
+    );
+    options.encryption = Some(encryption.clone());
+    Ok(Some(encryption))
+}
@@ -0,0 +1,77 @@
+//! Sector layout math: the alignment bytes around pieces and where pieces
+//! land in a sector.
+//!
+//! `get_piece_alignment` and `sum_piece_bytes_with_alignment` have the names
+//! and behavior of their `filecoin_proofs::pieces` counterparts, so switching
+//! imports is enough; this module is the only place depending on the
+//! `filecoin_proofs` ones.
+
+use filecoin_proofs::{pieces, UnpaddedBytesAmount};
+
+pub use crate::pure::{
+    piece_lengths, plan_alignment, plan_pieces, sector_alignment, PiecePlacement,
+};
+
+/// Zero bytes written around a piece so that it occupies a whole subtree of
+/// the sector, in unpadded bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceAlignment {
+    /// Written before the piece, up to the next multiple of its size.
+    pub left_bytes: UnpaddedBytesAmount,
+    /// Written after the piece, up to the next power of two of its size.
+    pub right_bytes: UnpaddedBytesAmount,
+}
+
+impl PieceAlignment {
+    /// Bytes taken by a piece of `piece_size` with this alignment.
+    pub fn sum(&self, piece_size: UnpaddedBytesAmount) -> UnpaddedBytesAmount {
+        self.left_bytes + piece_size + self.right_bytes
+    }
+}
+
+/// Alignment of a piece of `piece_bytes` written after `written_bytes`.
+pub fn get_piece_alignment(
+    written_bytes: UnpaddedBytesAmount,
+    piece_bytes: UnpaddedBytesAmount,
+) -> PieceAlignment {
+    let alignment = pieces::get_piece_alignment(written_bytes, piece_bytes);
+    PieceAlignment {
+        left_bytes: alignment.left_bytes,
+        right_bytes: alignment.right_bytes,
+    }
+}
+
+/// Bytes taken by `pieces` written one after another, including their
+/// alignment.
+pub fn sum_piece_bytes_with_alignment(pieces: &[UnpaddedBytesAmount]) -> UnpaddedBytesAmount {
+    pieces::sum_piece_bytes_with_alignment(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let alignment = get_piece_alignment(UnpaddedBytesAmount(127), UnpaddedBytesAmount(127 * 4));
+        assert_eq!(alignment.left_bytes, UnpaddedBytesAmount(127 * 3));
+        assert_eq!(alignment.right_bytes, UnpaddedBytesAmount(0));
+        assert_eq!(
+            alignment.sum(UnpaddedBytesAmount(127 * 4)),
+            UnpaddedBytesAmount(127 * 7)
+        );
+
+        // a piece which isn't a power of two is right aligned to the next one
+        let alignment = get_piece_alignment(UnpaddedBytesAmount(0), UnpaddedBytesAmount(127 * 3));
+        assert_eq!(alignment.left_bytes, UnpaddedBytesAmount(0));
+        assert_eq!(alignment.right_bytes, UnpaddedBytesAmount(127));
+
+        let sizes = [127, 127 * 4, 127 * 2].map(UnpaddedBytesAmount);
+        assert_eq!(
+            sum_piece_bytes_with_alignment(&sizes),
+            UnpaddedBytesAmount(127 * 10)
+        );
+        let last = plan_pieces(&sizes).expect("plan_pieces failed")[2];
+        assert_eq!(last.end(), sum_piece_bytes_with_alignment(&sizes).into());
+    }
+}
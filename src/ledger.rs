@@ -0,0 +1,148 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use add_piece::piece_cid;
+use anyhow::{Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo};
+use rusqlite::{params, types::ValueRef, Connection, OpenFlags};
+use serde_json::{Map, Value};
+use tracing::{debug, warn};
+
+use crate::{config, staging::load_manifest};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS pieces (
+    id INTEGER PRIMARY KEY,
+    piece_cid TEXT NOT NULL,
+    payload_size INTEGER,
+    piece_size INTEGER NOT NULL,
+    staged_file TEXT NOT NULL,
+    proof_type TEXT,
+    piece_index INTEGER NOT NULL,
+    offset INTEGER,
+    len INTEGER,
+    source TEXT,
+    started_at INTEGER NOT NULL,
+    finished_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS pieces_piece_cid ON pieces (piece_cid);
+";
+
+/// Other processes may be recording into the same ledger.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn open(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path).with_context(|| format!("open ledger {}", path.display()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.execute_batch(SCHEMA).context("create ledger schema")?;
+    Ok(conn)
+}
+
+fn unix_millis(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+/// Records the pieces of a task staging `staged_file` which completed, if a
+/// ledger is configured, with their place in the staged file when its
+/// manifest is at hand. Failed tasks are not recorded.
+///
+/// The ledger is an operator aid, so a failure to record does not fail the
+/// task.
+pub fn record(
+    staged_file: &Path,
+    proof_type: &str,
+    started: SystemTime,
+    result: &Result<Vec<PieceInfo>>,
+) {
+    let (path, piece_infos) = match (&config::global().ledger, result) {
+        (Some(path), Ok(piece_infos)) => (path, piece_infos),
+        _ => return,
+    };
+    if let Err(e) = insert(path, staged_file, proof_type, started, piece_infos) {
+        warn!(err = ?e, ledger = %path.display(), "failed to record the task in the ledger");
+    }
+}
+
+fn insert(
+    path: &Path,
+    staged_file: &Path,
+    proof_type: &str,
+    started: SystemTime,
+    piece_infos: &[PieceInfo],
+) -> Result<()> {
+    // remote staged files have no local manifest
+    let manifest = load_manifest(staged_file).ok().flatten();
+    let finished = SystemTime::now();
+
+    let mut conn = open(path)?;
+    let tx = conn.transaction()?;
+    for (index, piece_info) in piece_infos.iter().enumerate() {
+        let placed = manifest
+            .as_ref()
+            .and_then(|m| m.pieces.get(index))
+            .filter(|p| p.piece_info == *piece_info);
+        tx.execute(
+            "INSERT INTO pieces (piece_cid, payload_size, piece_size, staged_file, proof_type,
+                piece_index, offset, len, source, started_at, finished_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                piece_cid::encode(&piece_info.commitment),
+                placed.map(|p| p.payload_size as i64),
+                u64::from(PaddedBytesAmount::from(piece_info.size)) as i64,
+                staged_file.display().to_string(),
+                (!proof_type.is_empty()).then_some(proof_type),
+                index as i64,
+                placed.map(|p| p.offset as i64),
+                placed.map(|p| p.len as i64),
+                placed.map(|p| p.source.as_str()),
+                unix_millis(started),
+                unix_millis(finished),
+            ],
+        )?;
+    }
+    tx.commit()?;
+    debug!(ledger = %path.display(), pieces = piece_infos.len(), "task recorded in the ledger");
+    Ok(())
+}
+
+/// Prints the rows of `sql`, a read-only query of the ledger, as JSON lines.
+pub fn query(path: &Path, sql: &str, args: &[&str]) -> Result<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("open ledger {}", path.display()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+
+    let mut stmt = conn.prepare(sql).context("prepare query")?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut rows = stmt.query(rusqlite::params_from_iter(args))?;
+    while let Some(row) = rows.next()? {
+        let mut line = Map::new();
+        for (i, column) in columns.iter().enumerate() {
+            let value = match row.get_ref(i)? {
+                ValueRef::Null => Value::Null,
+                ValueRef::Integer(n) => n.into(),
+                ValueRef::Real(f) => f.into(),
+                ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+                ValueRef::Blob(b) => hex::encode(b).into(),
+            };
+            line.insert(column.clone(), value);
+        }
+        println!("{}", Value::Object(line));
+    }
+    Ok(())
+}
+
+/// Prints every time the piece `cid` was staged, oldest first.
+pub fn history(path: &Path, cid: &str) -> Result<()> {
+    piece_cid::decode(cid).context("invalid piece cid")?;
+    query(
+        path,
+        "SELECT piece_cid, staged_file, proof_type, piece_index, offset, len, payload_size,
+            piece_size, source,
+            strftime('%Y-%m-%dT%H:%M:%fZ', started_at / 1000.0, 'unixepoch') AS started_at,
+            strftime('%Y-%m-%dT%H:%M:%fZ', finished_at / 1000.0, 'unixepoch') AS finished_at
+         FROM pieces WHERE piece_cid = ?1 ORDER BY finished_at, id",
+        &[cid],
+    )
+}
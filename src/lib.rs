@@ -1,20 +1,213 @@
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Arc;
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use filecoin_proofs::{
     constants::MINIMUM_RESERVED_BYTES_FOR_PIECE_IN_FULLY_ALIGNED_SECTOR as MINIMUM_PIECE_SIZE,
-    pieces::{get_piece_alignment, sum_piece_bytes_with_alignment},
     PaddedBytesAmount, PieceInfo, UnpaddedBytesAmount,
 };
 use fr32::Fr32Reader;
-use log::trace;
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
 use storage_proofs_core::measurements::{measure_op, Operation};
 
+pub mod aligned_writer;
+pub mod car;
+pub mod chunk_sink;
+pub mod committer;
+pub mod content_type;
+pub mod dedup;
+pub mod empty_source;
+pub mod encryption;
+pub mod hash_queue;
+pub mod layout;
+pub mod manifest;
+pub mod memory_pressure;
+pub mod metered;
+pub mod middleware;
+pub mod node_sink;
+pub mod overflow;
+pub mod packing;
+pub mod payload_hash;
+pub mod periodic_sync;
+pub mod piece_cid;
+pub mod piece_events;
+pub mod precommit;
+pub mod predict;
+pub mod pure;
+pub mod read_ahead;
+pub mod reservation;
+pub mod seal_proof;
+pub mod sector_builder;
+pub mod sector_reader;
+pub mod sizes;
+pub mod staged_format;
+pub mod support;
+pub mod tee;
+pub mod unpad;
+pub mod would_block;
+pub mod write_behind;
+pub mod zero_fill;
+
 mod chunks_reader;
 mod commitment_reader;
 
-use chunks_reader::ChunksReader;
+/// Internals exposed to the benchmarks in `benches/`.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::chunks_reader::ChunksReader;
+    pub use crate::commitment_reader::{compute_padded, CommitmentReader};
+
+    pub fn write_zeros<W: std::io::Write>(
+        target: &mut W,
+        amount: filecoin_proofs::PaddedBytesAmount,
+    ) -> std::io::Result<()> {
+        crate::zero_fill::ZeroFillConfig::default().write(target, amount.into())
+    }
+}
+
+pub use chunks_reader::{ChunksReader, ChunksState};
+pub use commitment_reader::{CommitmentReader, CommitmentState};
+
+use aligned_writer::AlignedWriterConfig;
+use car::{CarIndex, Cid};
+use chunk_sink::ChunkRootSink;
+use committer::{CommitterBackend, CommitterThresholds};
+use content_type::ContentPolicy;
+use dedup::ChunkIndex;
+use empty_source::{EmptySource, EmptySourcePolicy};
+use encryption::PayloadEncryption;
+use middleware::{SourceLayer, TargetLayer};
+use node_sink::{NodeSink, NodeTap};
+use overflow::{OverflowGuard, SourceOverflow, SourceTooLong};
+use payload_hash::PayloadHash;
+use piece_events::{PieceEvents, ProgressReader};
+use pure::PiecePlacement;
 use vc_processors::fil_proofs::RegisteredSealProof;
+use would_block::{RetryingReader, WouldBlockWait};
+use zero_fill::ZeroFillConfig;
+
+/// Padded bytes hashed into each chunk root by `add_piece`, the last chunk of
+/// a piece smaller than this holds the whole piece.
+pub const CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Tunables for `add_piece_with_options`, `add_piece_for_proof` and
+/// `write_and_preprocess_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct AddPieceOptions {
+    /// Reuses the roots of padded chunks already recorded in this index
+    /// instead of hashing them again.
+    pub chunk_index: Option<Arc<ChunkIndex>>,
+
+    /// Write sizes and flushes of the target.
+    pub writes: AlignedWriterConfig,
+
+    /// Bounds the alignment bytes of a sector.
+    pub alignment_limit: Option<AlignmentLimit>,
+
+    /// Warns when the alignment of the piece is large next to its size.
+    pub padding_warning: Option<PaddingWarning>,
+
+    /// Receives the chunk roots of the piece while it is hashed.
+    pub chunk_sink: Option<Arc<dyn ChunkRootSink>>,
+
+    /// Receives the padded nodes of the piece as they are written.
+    pub node_sink: Option<Arc<dyn NodeSink>>,
+
+    /// Encrypts the payload before it is padded.
+    pub encryption: Option<Arc<PayloadEncryption>>,
+
+    /// Hashes the payload with blake3 as it is read.
+    pub payload_hash: Option<Arc<PayloadHash>>,
+
+    /// Transforms of the source read ahead of the payload hash, content
+    /// policy, CAR verification and encryption, in order.
+    pub source_layers: Vec<Arc<dyn SourceLayer>>,
+
+    /// Transforms of the padded bytes written ahead of the aligned writes to
+    /// the target, in order.
+    pub target_layers: Vec<Arc<dyn TargetLayer>>,
+
+    /// Rejects the piece before anything is written if the type of its
+    /// payload isn't allowed.
+    pub content_policy: Option<ContentPolicy>,
+
+    /// Verifies the payload as a CARv1 listing this root, failing on the
+    /// first block that doesn't match its CID.
+    pub payload_root: Option<Cid>,
+
+    /// Collects the CARv2 index of the payload, if it is a CARv1.
+    pub car_index: Option<Arc<CarIndex>>,
+
+    /// What to do with source bytes past `piece_size`.
+    pub source_overflow: SourceOverflow,
+
+    /// What to do with a source which is empty from the start.
+    pub empty_source: EmptySourcePolicy,
+
+    /// How long to wait for a non-blocking source which has no data yet.
+    pub would_block: WouldBlockWait,
+
+    /// Hashes the padded chunks of the piece, unless they are looked up in
+    /// `chunk_index`.
+    pub committer: CommitterBackend,
+
+    /// Piece sizes from which an `Auto` committer is picked by the size of
+    /// the piece.
+    pub committer_thresholds: CommitterThresholds,
+
+    /// Writes of the alignment zeros.
+    pub zero_fill: ZeroFillConfig,
+
+    /// Receives the progress of the piece, and when it is fetched, written
+    /// and hashed.
+    pub piece_events: Option<PieceEvents>,
+}
+
+impl AddPieceOptions {
+    /// Reads the source through `layer` after the layers already added.
+    pub fn source_layer(mut self, layer: impl SourceLayer + 'static) -> Self {
+        self.source_layers.push(Arc::new(layer));
+        self
+    }
+
+    /// Writes the target through `layer` after the layers already added.
+    pub fn target_layer(mut self, layer: impl TargetLayer + 'static) -> Self {
+        self.target_layers.push(Arc::new(layer));
+        self
+    }
+}
+
+/// Guards against sealing sectors made mostly of alignment bytes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlignmentLimit {
+    /// Padded alignment bytes allowed in a sector, counting the alignment of
+    /// the pieces already in it.
+    pub max_bytes: u64,
+    /// Only log a warning when the limit is exceeded instead of failing.
+    #[serde(default)]
+    pub warn_only: bool,
+}
+
+/// Warns about pieces whose alignment is large next to their own size, as
+/// produced by schedulers ordering pieces poorly, without failing them.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PaddingWarning {
+    /// Alignment bytes around a piece, in percent of its padded size, above
+    /// which it is reported.
+    pub max_percent: u64,
+}
+
+impl PaddingWarning {
+    /// Whether `alignment` padded bytes around a piece of `padded_size` are
+    /// to be reported.
+    pub fn exceeded(&self, alignment: u64, padded_size: u64) -> bool {
+        u128::from(alignment) * 100 > u128::from(padded_size) * u128::from(self.max_percent)
+    }
+}
 
 /// Writes bytes from `source` to `target`, adding bit-padding ("preprocessing")
 /// as needed. Returns a tuple containing the number of bytes written to
@@ -41,16 +234,115 @@ where
     R: Read,
     W: Write,
 {
+    write_and_preprocess_with_options(
+        registered_proof,
+        source,
+        target,
+        piece_size,
+        &AddPieceOptions::default(),
+    )
+}
+
+/// Same as `write_and_preprocess`, with explicit `options`.
+pub fn write_and_preprocess_with_options<R, W>(
+    registered_proof: RegisteredSealProof,
+    source: R,
+    target: W,
+    piece_size: UnpaddedBytesAmount,
+    options: &AddPieceOptions,
+) -> Result<(PieceInfo, UnpaddedBytesAmount)>
+where
+    R: Read,
+    W: Write,
+{
+    add_piece_for_proof(registered_proof, source, target, piece_size, &[], options)
+}
+
+/// Same as `add_piece_with_options`, failing before anything is written if
+/// the piece, aligned after `piece_lengths`, would overflow a sector of
+/// `registered_proof`.
+pub fn add_piece_for_proof<R, W>(
+    registered_proof: RegisteredSealProof,
+    source: R,
+    target: W,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+    options: &AddPieceOptions,
+) -> Result<(PieceInfo, UnpaddedBytesAmount)>
+where
+    R: Read,
+    W: Write,
+{
+    let sector_size: u64 = registered_proof.sector_size().into();
+    ensure_fits(
+        sector_size,
+        format_args!("{} bytes sector of {:?}", sector_size, registered_proof),
+        piece_size,
+        piece_lengths,
+    )?;
+
     use RegisteredSealProof::*;
     match registered_proof {
         StackedDrg2KiBV1 | StackedDrg8MiBV1 | StackedDrg512MiBV1 | StackedDrg32GiBV1
         | StackedDrg64GiBV1 | StackedDrg2KiBV1_1 | StackedDrg8MiBV1_1 | StackedDrg512MiBV1_1
         | StackedDrg32GiBV1_1 | StackedDrg64GiBV1_1 => {
-            add_piece(source, target, piece_size, Default::default())
+            add_piece_with_options(source, target, piece_size, piece_lengths, options)
         }
     }
 }
 
+/// Same as `add_piece_for_proof`, for sectors of `sector_size` padded bytes
+/// rather than of a seal proof. With the `test-sectors` feature this includes
+/// the test sector sizes of `seal_proof::TEST_SECTOR_SIZES`.
+pub fn add_piece_for_sector_size<R, W>(
+    sector_size: u64,
+    source: R,
+    target: W,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+    options: &AddPieceOptions,
+) -> Result<(PieceInfo, UnpaddedBytesAmount)>
+where
+    R: Read,
+    W: Write,
+{
+    seal_proof::ensure_sector_size(sector_size)?;
+    ensure_fits(
+        sector_size,
+        format_args!("{} bytes sector", sector_size),
+        piece_size,
+        piece_lengths,
+    )?;
+    add_piece_with_options(source, target, piece_size, piece_lengths, options)
+}
+
+/// Fails if the piece, aligned after `piece_lengths`, would overflow the
+/// `sector` of `sector_size` padded bytes.
+fn ensure_fits(
+    sector_size: u64,
+    sector: std::fmt::Arguments,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+) -> Result<()> {
+    let padded_piece_size = PaddedBytesAmount::from(piece_size);
+    ensure!(
+        u64::from(padded_piece_size) <= sector_size,
+        "piece of {:?} does not fit in a {}",
+        padded_piece_size,
+        sector,
+    );
+    let end = pure::plan_alignment(piece_lengths, piece_size)?.end();
+    ensure!(
+        u64::from(end) <= sector_size,
+        "piece of {:?} following {} pieces ends at {:?}, it does not fit in a {}",
+        padded_piece_size,
+        piece_lengths.len(),
+        end,
+        sector,
+    );
+    Ok(())
+}
+
 /// Computes a NUL-byte prefix and/or suffix for `source` using the provided
 /// `piece_lengths` and `piece_size` (such that the `source`, after
 /// preprocessing, will occupy a subtree of a merkle tree built using the bytes
@@ -63,6 +355,7 @@ where
 /// wastes ($SIZESECTORSIZE/2)-$MINIMUM_PIECE_SIZE space. This function will be
 /// deprecated in favor of `write_and_preprocess`, and miners will be prevented
 /// from sealing sectors containing more than $TOOMUCH alignment bytes.
+/// `AddPieceOptions::alignment_limit` enforces such a bound.
 ///
 /// # Arguments
 ///
@@ -80,52 +373,321 @@ where
     R: Read,
     W: Write,
 {
-    const CHUNK_SIZE: usize = 64 * 1024 * 1024;
+    add_piece_with_options(
+        source,
+        target,
+        piece_size,
+        piece_lengths,
+        &AddPieceOptions::default(),
+    )
+}
+
+/// Same as `add_piece`, with explicit `options`.
+pub fn add_piece_with_options<R, W>(
+    source: R,
+    target: W,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+    options: &AddPieceOptions,
+) -> Result<(PieceInfo, UnpaddedBytesAmount)>
+where
+    R: Read,
+    W: Write,
+{
+    add_piece_with_layout(source, target, piece_size, piece_lengths, options)
+        .map(|(piece_info, placement)| (piece_info, placement.written()))
+}
+
+/// Same as `add_piece_with_options`, but returns where the piece was placed in
+/// the sector instead of the number of bytes written: its padded offset, the
+/// left and right alignment and its padded size.
+pub fn add_piece_with_layout<R, W>(
+    source: R,
+    target: W,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+    options: &AddPieceOptions,
+) -> Result<(PieceInfo, PiecePlacement)>
+where
+    R: Read,
+    W: Write,
+{
     trace!("add_piece:start");
 
     let result = measure_op(Operation::AddPiece, || {
         ensure_piece_size(piece_size)?;
 
-        let source = BufReader::with_capacity(CHUNK_SIZE, source);
-        let mut target = BufWriter::with_capacity(CHUNK_SIZE, target);
+        let source = RetryingReader::new(source, options.would_block);
+        let (source, read) = ProgressReader::new(source, options.piece_events.as_ref());
+        let source = middleware::wrap_source(Box::new(source), options);
+        let mut source = BufReader::with_capacity(CHUNK_SIZE, source);
+        // an empty source is caught before the alignment is written
+        let empty = source
+            .fill_buf()
+            .context("failed to read source")?
+            .is_empty();
+        let zeros = match (empty, options.empty_source) {
+            (false, _) => 0,
+            (true, EmptySourcePolicy::Error) => bail!(EmptySource {
+                piece_size: u64::from(piece_size)
+            }),
+            (true, EmptySourcePolicy::PadUp) => {
+                warn!("add_piece: empty source, padded up to {:?}", piece_size);
+                u64::from(piece_size)
+            }
+        };
+        let source = source.chain(io::repeat(0).take(zeros));
+        let (source, overflow) =
+            OverflowGuard::new(source, u64::from(piece_size), options.source_overflow);
+        let mut target = middleware::wrap_target(Box::new(target), options);
 
-        let written_bytes = sum_piece_bytes_with_alignment(piece_lengths);
-        let piece_alignment = get_piece_alignment(written_bytes, piece_size);
+        let placement = pure::plan_alignment(piece_lengths, piece_size)?;
+        if let Some(limit) = options.alignment_limit {
+            check_alignment(limit, piece_lengths, piece_size)?;
+        }
+        if let Some(warning) = options.padding_warning {
+            check_padding(warning, &placement);
+        }
         let fr32_reader = Fr32Reader::new(source);
 
         // write left alignment
-        for _ in 0..usize::from(PaddedBytesAmount::from(piece_alignment.left_bytes)) {
-            target.write_all(&[0u8][..])?;
-        }
+        options
+            .zero_fill
+            .write(&mut target, placement.left.into())?;
 
-        let mut commitment_reader = ChunksReader::new(CHUNK_SIZE, fr32_reader);
-        let n = io::copy(&mut commitment_reader, &mut target)
-            .context("failed to write and preprocess bytes")?;
+        let mut commitment_reader = match &options.chunk_index {
+            Some(index) => ChunksReader::with_index(CHUNK_SIZE, fr32_reader, index.clone()),
+            None => {
+                let padded_size = u64::from(PaddedBytesAmount::from(piece_size));
+                let mut committer = options
+                    .committer
+                    .for_piece(padded_size, &options.committer_thresholds);
+                if options.committer == CommitterBackend::Auto && memory_pressure::under_pressure()
+                {
+                    committer = CommitterBackend::Streaming;
+                }
+                debug!("add_piece: {:?} committer", committer);
+                let mut reader = ChunksReader::new(CHUNK_SIZE, fr32_reader);
+                reader.set_committer(committer.piece_committer(padded_size));
+                reader
+            }
+        };
+        if let Some(sink) = &options.chunk_sink {
+            commitment_reader.set_sink(sink.clone());
+        }
+        let mut tapped = NodeTap::new(&mut commitment_reader, options.node_sink.clone());
+        let n =
+            io::copy(&mut tapped, &mut target).context("failed to write and preprocess bytes")?;
 
         ensure!(n != 0, "add_piece: read 0 bytes before EOF from source");
+        if let Some(events) = &options.piece_events {
+            events.sink.fetched(&events.piece, read.get());
+        }
         let n = PaddedBytesAmount(n as u64);
         let n: UnpaddedBytesAmount = n.into();
 
         ensure!(n == piece_size, "add_piece: invalid bytes amount written");
+        if overflow.get() > 0 {
+            let err = SourceTooLong {
+                piece_size: u64::from(piece_size),
+                extra: overflow.get(),
+            };
+            ensure!(options.source_overflow != SourceOverflow::Error, err);
+            warn!("add_piece: {}, ignored", err);
+        }
 
         // write right alignment
-        for _ in 0..usize::from(PaddedBytesAmount::from(piece_alignment.right_bytes)) {
-            target.write_all(&[0u8][..])?;
+        options
+            .zero_fill
+            .write(&mut target, placement.right.into())?;
+        target.flush().context("failed to flush target")?;
+        if let Some(events) = &options.piece_events {
+            let written = PaddedBytesAmount::from(placement.written());
+            events.sink.written(&events.piece, u64::from(written));
         }
 
-        let commitment = commitment_reader.finish();
+        let commitment = commitment_reader
+            .finish()
+            .context("failed to compute commitment")?;
         let mut comm = [0u8; 32];
         comm.copy_from_slice(commitment.as_ref());
 
-        let written = piece_alignment.left_bytes + piece_alignment.right_bytes + piece_size;
-
-        Ok((PieceInfo::new(comm, n)?, written))
+        let piece_info = PieceInfo::new(comm, n)?;
+        if let Some(events) = &options.piece_events {
+            events.sink.hashed(&events.piece, &piece_info);
+        }
+        Ok((piece_info, placement))
     });
 
     trace!("add_piece:finish");
     result
 }
 
+/// Same as `add_piece`, for a payload already held in memory.
+///
+/// `source` is padded without going through the read buffering of `add_piece`
+/// and the padded nodes are hashed in parallel straight from memory, which
+/// suits embedders holding small payloads.
+pub fn add_piece_from_slice<W: Write>(
+    source: &[u8],
+    mut target: W,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+) -> Result<(PieceInfo, UnpaddedBytesAmount)> {
+    trace!("add_piece_from_slice:start");
+
+    let result = measure_op(Operation::AddPiece, || {
+        ensure_piece_size(piece_size)?;
+        ensure!(
+            source.len() as u64 == u64::from(piece_size),
+            "add_piece: source holds {} bytes, expected {:?}",
+            source.len(),
+            piece_size
+        );
+
+        let placement = pure::plan_alignment(piece_lengths, piece_size)?;
+        let (padded, piece_info) = pure::pad_and_commit(source)?;
+
+        let zeros = ZeroFillConfig::default();
+        zeros.write(&mut target, placement.left.into())?;
+        target
+            .write_all(&padded)
+            .context("failed to write preprocessed bytes")?;
+        zeros.write(&mut target, placement.right.into())?;
+
+        Ok((piece_info, placement.written()))
+    });
+
+    trace!("add_piece_from_slice:finish");
+    result
+}
+
+/// Padded bytes `pad_only` moves at once.
+const PAD_ONLY_BUFFER: usize = 1 << 20;
+
+/// Same as `add_piece`, without computing the commitment of the piece: the
+/// payload is only fr32 padded and aligned after `piece_lengths`, for callers
+/// which already know the CID of the piece and only need its padded bytes.
+/// Returns the number of unpadded bytes written, alignment included.
+pub fn pad_only<R, W>(
+    source: R,
+    mut target: W,
+    piece_size: UnpaddedBytesAmount,
+    piece_lengths: &[UnpaddedBytesAmount],
+) -> Result<UnpaddedBytesAmount>
+where
+    R: Read,
+    W: Write,
+{
+    trace!("pad_only:start");
+
+    ensure_piece_size(piece_size)?;
+    let placement = pure::plan_alignment(piece_lengths, piece_size)?;
+    let zeros = ZeroFillConfig::default();
+    zeros.write(&mut target, placement.left.into())?;
+
+    let padded_size = u64::from(PaddedBytesAmount::from(piece_size));
+    let source = RetryingReader::new(source, WouldBlockWait::default());
+    let mut fr32_reader = Fr32Reader::new(source);
+    let mut buf = vec![0u8; PAD_ONLY_BUFFER];
+    let mut written = 0u64;
+    loop {
+        let n = fr32_reader
+            .read(&mut buf)
+            .context("failed to preprocess bytes")?;
+        if n == 0 {
+            break;
+        }
+        target
+            .write_all(&buf[..n])
+            .context("failed to write preprocessed bytes")?;
+        written += n as u64;
+    }
+    ensure!(
+        written == padded_size,
+        "pad_only: wrote {} padded bytes, expected {}",
+        written,
+        padded_size
+    );
+
+    zeros.write(&mut target, placement.right.into())?;
+    target.flush().context("failed to flush target")?;
+
+    trace!("pad_only:finish");
+    Ok(placement.written())
+}
+
+/// Computes the `PieceInfo` of a piece from its padded bytes, as written by
+/// `pad_only` or kept in a cache of padded piece files. `source` must hold
+/// exactly `padded_size` bytes, a power of 2 of at least 128.
+pub fn commit_padded<R: Read>(source: R, padded_size: PaddedBytesAmount) -> Result<PieceInfo> {
+    trace!("commit_padded:start");
+
+    let size = u64::from(padded_size);
+    ensure!(
+        size >= 128 && size.is_power_of_two(),
+        "commit_padded: padded size must be a power of 2 of at least 128 bytes, got {}",
+        size
+    );
+    let committer = CommitterBackend::Auto.for_piece(size, &CommitterThresholds::default());
+    // one byte past the piece tells a source which is too long
+    let source = RetryingReader::new(source, WouldBlockWait::default());
+    let mut reader = ChunksReader::new(CHUNK_SIZE, source.take(size + 1));
+    reader.set_committer(committer.piece_committer(size));
+    let n = io::copy(&mut reader, &mut io::sink()).context("failed to read padded bytes")?;
+    ensure!(
+        n == size,
+        "commit_padded: source {} the padded size of {} bytes",
+        if n < size {
+            "ends before"
+        } else {
+            "holds more than"
+        },
+        size
+    );
+
+    let commitment = reader.finish().context("failed to compute commitment")?;
+    let mut comm = [0u8; 32];
+    comm.copy_from_slice(commitment.as_ref());
+    let piece_info = PieceInfo::new(comm, padded_size.into())?;
+
+    trace!("commit_padded:finish");
+    Ok(piece_info)
+}
+
+fn check_alignment(
+    limit: AlignmentLimit,
+    piece_lengths: &[UnpaddedBytesAmount],
+    piece_size: UnpaddedBytesAmount,
+) -> Result<()> {
+    let alignment = u64::from(pure::sector_alignment(piece_lengths, piece_size)?);
+    if alignment <= limit.max_bytes {
+        return Ok(());
+    }
+
+    let msg = format!(
+        "sector would hold {} alignment bytes, more than the {} allowed",
+        alignment, limit.max_bytes
+    );
+    ensure!(limit.warn_only, "add_piece: {}", msg);
+    warn!("add_piece: {}", msg);
+    Ok(())
+}
+
+fn check_padding(warning: PaddingWarning, placement: &PiecePlacement) {
+    let alignment = u64::from(placement.left + placement.right);
+    let padded_size = u64::from(placement.size);
+    if warning.exceeded(alignment, padded_size) {
+        warn!(
+            "add_piece: {} alignment bytes around a piece of {} padded bytes, {}% of its size, more than the {}% allowed",
+            alignment,
+            padded_size,
+            alignment * 100 / padded_size,
+            warning.max_percent
+        );
+    }
+}
+
 fn ensure_piece_size(piece_size: UnpaddedBytesAmount) -> Result<()> {
     ensure!(
         piece_size >= UnpaddedBytesAmount(MINIMUM_PIECE_SIZE),
@@ -142,3 +704,264 @@ fn ensure_piece_size(piece_size: UnpaddedBytesAmount) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_preprocess_piece_too_large() {
+        let piece_size = UnpaddedBytesAmount(127 * 32);
+        let source = vec![0u8; 127 * 32];
+
+        let mut target = Vec::new();
+        let err = write_and_preprocess(
+            RegisteredSealProof::StackedDrg2KiBV1_1,
+            &source[..],
+            &mut target,
+            piece_size,
+        )
+        .expect_err("a 4KiB piece should not fit in a 2KiB sector");
+
+        assert!(err.to_string().contains("does not fit"), "{:?}", err);
+        assert!(target.is_empty());
+    }
+
+    #[test]
+    fn test_add_piece_for_proof_overflow() {
+        // a 1KiB piece following a 128 bytes one is aligned to 1KiB and ends
+        // at 2KiB, a second one would end past the sector
+        let piece_size = UnpaddedBytesAmount(127 * 8);
+        let source = vec![0u8; 127 * 8];
+        let proof = RegisteredSealProof::StackedDrg2KiBV1_1;
+        let options = AddPieceOptions::default();
+
+        let lengths = [UnpaddedBytesAmount(127)];
+        add_piece_for_proof(
+            proof,
+            &source[..],
+            io::sink(),
+            piece_size,
+            &lengths,
+            &options,
+        )
+        .expect("the piece fits");
+
+        let lengths = [UnpaddedBytesAmount(127), piece_size];
+        let mut target = Vec::new();
+        let err = add_piece_for_proof(
+            proof,
+            &source[..],
+            &mut target,
+            piece_size,
+            &lengths,
+            &options,
+        )
+        .expect_err("a third piece should not fit in a 2KiB sector");
+        assert!(err.to_string().contains("does not fit"), "{:?}", err);
+        assert!(target.is_empty());
+
+        let err = add_piece_for_sector_size(
+            2048,
+            &source[..],
+            &mut target,
+            piece_size,
+            &lengths,
+            &options,
+        )
+        .expect_err("a third piece should not fit in a 2KiB sector");
+        assert!(err.to_string().contains("does not fit"), "{:?}", err);
+        let err =
+            add_piece_for_sector_size(4096, &source[..], &mut target, piece_size, &[], &options)
+                .expect_err("there are no 4KiB sectors");
+        assert!(err.to_string().contains("not supported"), "{:?}", err);
+        assert!(target.is_empty());
+    }
+
+    #[test]
+    fn test_add_piece_with_layout() {
+        let piece_lengths = [UnpaddedBytesAmount(127)];
+        let piece_size = UnpaddedBytesAmount(127 * 4);
+        let source = vec![1u8; 127 * 4];
+
+        let mut target = Vec::new();
+        let (_, placement) = add_piece_with_layout(
+            &source[..],
+            &mut target,
+            piece_size,
+            &piece_lengths,
+            &AddPieceOptions::default(),
+        )
+        .expect("add_piece_with_layout failed");
+
+        assert_eq!(placement.left, PaddedBytesAmount(128 * 3));
+        assert_eq!(placement.offset, PaddedBytesAmount(128 * 4));
+        assert_eq!(placement.size, PaddedBytesAmount(128 * 4));
+        assert_eq!(placement.right, PaddedBytesAmount(0));
+        assert_eq!(target.len() as u64, u64::from(placement.end()) - 128);
+    }
+
+    #[test]
+    fn test_source_overflow() {
+        let piece_size = UnpaddedBytesAmount(127 * 4);
+        let source = vec![3u8; 127 * 4 + 10];
+        let add = |source_overflow| {
+            let options = AddPieceOptions {
+                source_overflow,
+                ..Default::default()
+            };
+            add_piece_with_options(&source[..], io::sink(), piece_size, &[], &options)
+        };
+
+        let err = add(SourceOverflow::Error).unwrap_err();
+        let too_long = err
+            .downcast_ref::<SourceTooLong>()
+            .expect("not SourceTooLong");
+        assert_eq!(too_long.extra, 10);
+
+        let (piece_info, _) = add(SourceOverflow::Ignore).unwrap();
+        let (expected, _) = add_piece(&source[..127 * 4], io::sink(), piece_size, &[]).unwrap();
+        assert_eq!(piece_info, expected);
+
+        assert!(add(SourceOverflow::Unchecked).is_err());
+    }
+
+    #[test]
+    fn test_pad_only() {
+        let piece_lengths = [UnpaddedBytesAmount(127)];
+        let piece_size = UnpaddedBytesAmount(127 * 4);
+        let source: Vec<u8> = (0..127 * 4).map(|i| i as u8).collect();
+
+        let mut expected = Vec::new();
+        let (_, expected_written) =
+            add_piece(&source[..], &mut expected, piece_size, &piece_lengths).unwrap();
+        let mut target = Vec::new();
+        let written = pad_only(&source[..], &mut target, piece_size, &piece_lengths).unwrap();
+        assert_eq!(written, expected_written);
+        assert_eq!(target, expected);
+
+        assert!(pad_only(&source[..127], io::sink(), piece_size, &[]).is_err());
+    }
+
+    #[test]
+    fn test_commit_padded() {
+        let piece_size = UnpaddedBytesAmount(127 * 8);
+        let source: Vec<u8> = (0..127 * 8).map(|i| (i * 7) as u8).collect();
+        let mut padded = Vec::new();
+        let (expected, _) = add_piece(&source[..], &mut padded, piece_size, &[]).unwrap();
+
+        let piece_info = commit_padded(&padded[..], PaddedBytesAmount(128 * 8)).unwrap();
+        assert_eq!(piece_info, expected);
+
+        assert!(commit_padded(&padded[..128 * 4], PaddedBytesAmount(128 * 8)).is_err());
+        assert!(commit_padded(&padded[..], PaddedBytesAmount(128 * 4)).is_err());
+        assert!(commit_padded(&padded[..128 * 6], PaddedBytesAmount(128 * 6)).is_err());
+    }
+
+    #[test]
+    fn test_empty_source() {
+        let piece_size = UnpaddedBytesAmount(127);
+        let add = |empty_source| {
+            let options = AddPieceOptions {
+                empty_source,
+                ..Default::default()
+            };
+            let mut target = Vec::new();
+            add_piece_with_options(io::empty(), &mut target, piece_size, &[], &options)
+                .map(|(piece_info, _)| (piece_info, target))
+        };
+
+        let err = add(EmptySourcePolicy::Error).unwrap_err();
+        let empty = err.downcast_ref::<EmptySource>().expect("not EmptySource");
+        assert_eq!(empty.piece_size, 127);
+
+        let (piece_info, target) = add(EmptySourcePolicy::PadUp).unwrap();
+        let (expected, _) = add_piece(&[0u8; 127][..], io::sink(), piece_size, &[]).unwrap();
+        assert_eq!(piece_info, expected);
+        assert_eq!(target, vec![0u8; 128]);
+    }
+
+    #[test]
+    fn test_alignment_limit() {
+        let piece_lengths = [UnpaddedBytesAmount(127)];
+        let piece_size = UnpaddedBytesAmount(127 * 4);
+        let source = vec![1u8; 127 * 4];
+
+        let add = |warn_only| {
+            let options = AddPieceOptions {
+                alignment_limit: Some(AlignmentLimit {
+                    max_bytes: 256,
+                    warn_only,
+                }),
+                ..Default::default()
+            };
+            let mut target = Vec::new();
+            add_piece_with_options(
+                &source[..],
+                &mut target,
+                piece_size,
+                &piece_lengths,
+                &options,
+            )
+            .map(|_| target.len())
+        };
+
+        assert!(add(false).is_err());
+        assert_eq!(add(true).expect("add_piece failed"), 128 * 7);
+    }
+
+    #[test]
+    fn test_padding_warning() {
+        let warning = PaddingWarning { max_percent: 50 };
+        assert!(!warning.exceeded(0, 128));
+        assert!(!warning.exceeded(64, 128));
+        assert!(warning.exceeded(65, 128));
+        assert!(PaddingWarning { max_percent: 0 }.exceeded(1, 1 << 35));
+        assert!(!PaddingWarning { max_percent: 100 }.exceeded(u64::MAX / 2, u64::MAX / 2));
+    }
+
+    #[test]
+    fn test_add_piece_from_slice() {
+        let piece_lengths = [UnpaddedBytesAmount(127)];
+        let piece_size = UnpaddedBytesAmount(127 * 4);
+        let source: Vec<u8> = (0..u64::from(piece_size)).map(|i| i as u8).collect();
+
+        let mut expected = Vec::new();
+        let expected_res =
+            filecoin_proofs::add_piece(&source[..], &mut expected, piece_size, &piece_lengths)
+                .expect("filecoin_proofs::add_piece failed");
+
+        let mut actual = Vec::new();
+        let actual_res = add_piece_from_slice(&source, &mut actual, piece_size, &piece_lengths)
+            .expect("add_piece_from_slice failed");
+
+        assert_eq!(actual_res, expected_res);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_committer_backends() {
+        let piece_lengths = [UnpaddedBytesAmount(127)];
+        let piece_size = UnpaddedBytesAmount(127 * 8);
+        let source: Vec<u8> = (0..u64::from(piece_size)).map(|i| i as u8).collect();
+
+        let (expected, _) =
+            filecoin_proofs::add_piece(&source[..], io::sink(), piece_size, &piece_lengths)
+                .expect("filecoin_proofs::add_piece failed");
+        for committer in [CommitterBackend::Streaming, CommitterBackend::Parallel] {
+            let options = AddPieceOptions {
+                committer,
+                ..Default::default()
+            };
+            let (actual, _) = add_piece_with_options(
+                &source[..],
+                io::sink(),
+                piece_size,
+                &piece_lengths,
+                &options,
+            )
+            .expect("add_piece failed");
+            assert_eq!(actual, expected, "{:?}", committer);
+        }
+    }
+}
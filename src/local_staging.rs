@@ -0,0 +1,88 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use add_piece::{manifest::Manifest, staged_format::StagedFormat};
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{config, journal::ChunkJournal, transfer};
+
+/// Stages the files of the processor tasks in a fast local directory, then
+/// moves them to the path the task asked for once complete.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalStagingConfig {
+    pub dir: PathBuf,
+}
+
+impl LocalStagingConfig {
+    /// Where the staged file of `dest` is written first. Its name is kept,
+    /// prefixed with a hash of `dest` so that tasks staging files of the same
+    /// name into different directories don't meet, and so that a task which
+    /// is retried resumes from it.
+    pub fn local_path(&self, dest: &Path) -> PathBuf {
+        let hash = hmac_sha256::Hash::hash(dest.as_os_str().as_encoded_bytes());
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        self.dir
+            .join(format!("{}-{}", hex::encode(&hash[..8]), name))
+    }
+}
+
+/// Redirects the staged file of a task to the local staging directory, if
+/// configured, and returns where it must be moved once complete.
+pub fn redirect(staged: &mut PathBuf) -> Result<Option<PathBuf>> {
+    let config = config::global();
+    let local = match &config.local_staging {
+        Some(local) => local,
+        None => return Ok(None),
+    };
+    ensure!(
+        config.staged_format == StagedFormat::Raw,
+        "local staging only moves raw staged files"
+    );
+    fs::create_dir_all(&local.dir)
+        .with_context(|| format!("create local staging dir {}", local.dir.display()))?;
+
+    let dest = std::mem::replace(staged, local.local_path(staged));
+    Ok(Some(dest))
+}
+
+/// Moves the complete staged file `local` and its manifest to `dest`. Across
+/// filesystems the file is copied and every chunk read back and checked
+/// against its root before the manifest is written, the local copy is only
+/// removed then.
+pub fn deliver(local: &Path, dest: &Path) -> Result<()> {
+    let renamed = fs::rename(local, dest);
+    match renamed {
+        Ok(()) => {
+            fs::rename(Manifest::path_for(local), Manifest::path_for(dest))
+                .with_context(|| format!("move manifest to {}", dest.display()))?;
+            info!(local = %local.display(), dest = %dest.display(), "staged file moved");
+        }
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            transfer::transfer(local, dest)
+                .with_context(|| format!("copy staged file to {}", dest.display()))?;
+            for path in [local.to_path_buf(), Manifest::path_for(local)] {
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!(err = ?e, path = %path.display(), "failed to remove local staged file");
+                }
+            }
+        }
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("move {} to {}", local.display(), dest.display()))
+        }
+    }
+
+    // the journal describes the local file only
+    match fs::remove_file(ChunkJournal::path_for(local)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            warn!(err = ?e, "failed to remove local journal");
+        }
+        _ => {}
+    }
+    Ok(())
+}
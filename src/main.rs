@@ -1,88 +1,1496 @@
 use std::{
-    fs,
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Read, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use add_piece::write_and_preprocess;
-use anyhow::{Context, Result};
-use clap::{Arg, ArgAction, Command};
-use filecoin_proofs::{PieceInfo, UnpaddedBytesAmount};
+use add_piece::{
+    car::{CarIndex, Cid},
+    chunk_sink::ChunkRoots,
+    empty_source::{EmptySource, EmptySourcePolicy},
+    manifest::{self, Provenance},
+    metered::Metered,
+    payload_hash::PayloadHash,
+    piece_cid,
+    piece_events::{Fanout, PieceEventSink, PieceEvents, PieceRef, ProgressReader},
+    pure,
+    reservation::{self, Claim, LeaseGuard},
+    seal_proof, sizes,
+    staged_format::StagedFormat,
+    support,
+    tee::TeeReader,
+    write_and_preprocess_with_options, AddPieceOptions,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, UnpaddedBytesAmount};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
 use vc_processors::{
     builtin::{processors::piece, tasks::AddPieces},
     core::{ext::run_consumer, Processor, Task},
+    fil_proofs::RegisteredSealProof,
 };
 
+mod abort;
+mod archive;
+mod attestation;
+mod backfill;
+mod checksums;
+mod commp;
+mod config;
+mod convert;
+mod copy_verify;
+mod credentials;
+mod deadline;
+mod diff;
+mod duplicates;
+mod export_car;
+mod fixtures;
+mod http_target;
+mod inspect;
+mod iostats;
+mod journal;
+mod keys;
+mod ledger;
+mod local_staging;
+mod metrics;
+#[cfg(test)]
+mod mock_http;
+mod mount_limits;
+mod multi_sector;
+mod paths;
+mod piece_dir;
+mod preflight;
+mod privileges;
+mod progress;
+mod psi;
+mod record;
+mod redact;
+mod registration;
+mod remote;
+mod retrieval;
+mod s3;
+mod scratch;
+mod sector_map;
+mod seen_chunks;
+mod source;
+mod spec;
+mod staged_target;
+mod staging;
+mod status;
+mod target_health;
+mod target_profile;
+mod task_api;
+mod task_schema;
+mod throughput;
+mod tls;
+mod transfer;
+#[cfg(feature = "tui")]
+mod tui;
+mod unpadded_copy;
+mod verifier;
+mod watchdog;
+mod webhook;
+
+use checksums::{ChecksumStatus, Sha256Reader};
+use duplicates::DuplicatePolicy;
+use iostats::TaskIoStats;
+use mount_limits::Access;
+use record::{RecordedResult, RecordedTask, Recorder, Recording, Sources};
+use redact::RedactMode;
+use scratch::TaskScratch;
+use sector_map::MapFormat;
+use source::{Opener, PieceSource};
+use staged_target::StagedTarget;
+use staging::{with_write_behind, PieceSpec, StagedFile, StagedOpenMode};
+use target_profile::{retry_stale, TargetProfile};
+use task_schema::TaskFormat;
+use unpadded_copy::{PieceCopy, UnpaddedCopy};
+
+/// Whether the processor only computes the pieces of its tasks, set by
+/// `processor --dry-run` or `ADD_PIECE_DRY_RUN=1`.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
 #[derive(Copy, Clone, Default, Debug)]
 pub struct AddPiecesProcessor;
 
 impl Processor<AddPieces> for AddPiecesProcessor {
     fn process(&self, task: AddPieces) -> Result<<AddPieces as Task>::Output> {
-        let staged_file = fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            // to make sure that we won't write into the staged file with any data exists
-            .truncate(true)
-            .open(&task.staged_filepath)
-            .with_context(|| format!("open staged file: {}", task.staged_filepath.display()))?;
-
-        let mut piece_infos = Vec::with_capacity(task.pieces.len().min(1));
-        for piece in task.pieces {
-            debug!(piece_file = ?piece.piece_file, "trying to add piece");
-            let source =
-                piece::fetcher::open(piece.piece_file, piece.payload_size, piece.piece_size.0)
-                    .context("open piece file")?;
-            let (piece_info, _) =
-                write_and_preprocess(task.seal_proof_type, source, &staged_file, piece.piece_size)
-                    .context("add piece")?;
-            piece_infos.push(piece_info);
+        let staged_filepath = task.staged_filepath.clone();
+        let proof_type = seal_proof::name(task.seal_proof_type);
+        let started = SystemTime::now();
+        let recorder = start_recording(|| {
+            Ok(RecordedTask::Processor {
+                task: serde_json::to_value(&task).context("serialize task")?,
+            })
+        });
+
+        let mut io = TaskIoStats::default();
+        let sources = Sources::Live(recorder.as_ref());
+        let dry_run = DRY_RUN.load(Ordering::Relaxed);
+        let duplicates = config::global().duplicate_pieces;
+        let res = match dry_run {
+            true => dry_run_add_pieces(task, &mut io, &sources, duplicates),
+            false => process_add_pieces(task, &mut io, &sources, duplicates).map_err(|e| {
+                target_health::record_failure(target_health::dir_of(&staged_filepath), e)
+            }),
+        };
+        finish_recording(recorder, &res);
+        // as sent back to the worker
+        let res = res.map_err(redact::error);
+        if let Err(e) = &res {
+            status::record_error(&staged_filepath, e);
+        }
+
+        let io = io.report();
+        info!(?io, "add_pieces io stats");
+        // nothing was staged
+        if !dry_run {
+            metrics::record_task(&proof_type, started.elapsed().unwrap_or_default(), &res);
+            webhook::notify(&config::global().webhooks, &staged_filepath, &res, &io);
+            ledger::record(&staged_filepath, &proof_type, started, &res);
+        }
+        res
+    }
+}
+
+/// Starts recording the task if a record directory is configured.
+///
+/// Recording is a debugging aid, so a failure to start it does not fail the task.
+fn start_recording(task: impl FnOnce() -> Result<RecordedTask>) -> Option<Recorder> {
+    let config = config::global();
+    let root = config.record_dir.as_ref()?;
+    match task().and_then(|task| Recorder::start(root, config, task)) {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            warn!(err = ?e, "failed to start recording");
+            None
         }
+    }
+}
+
+fn finish_recording(recorder: Option<Recorder>, res: &Result<Vec<PieceInfo>>) {
+    if let Some(Err(e)) = recorder.map(|r| r.finish(res)) {
+        warn!(err = ?e, "failed to finish recording");
+    }
+}
+
+/// Gathers the chunk roots of a piece for its manifest entry, still passing
+/// them on to the sink already set in `options`.
+fn collect_chunk_roots(options: &mut AddPieceOptions) -> Arc<ChunkRoots> {
+    let chunk_roots = Arc::new(ChunkRoots::forwarding_to(options.chunk_sink.take()));
+    options.chunk_sink = Some(chunk_roots.clone());
+    chunk_roots
+}
+
+/// Collects the CARv2 index of a piece if `car_index_dir` is configured.
+fn collect_car_index(options: &mut AddPieceOptions) -> Option<Arc<CarIndex>> {
+    config::global().car_index_dir.as_ref()?;
+    let index = Arc::new(CarIndex::new());
+    options.car_index = Some(index.clone());
+    Some(index)
+}
 
-        if piece_infos.is_empty() {
-            let sector_size: u64 = task.seal_proof_type.sector_size().into();
+/// Publishes the piece events of a task staging `staged` for a sector of
+/// `proof_type`: the progress shown by `status`, the metrics, the piece
+/// webhooks and the renewals of the lease of `staged`, released once the
+/// events are dropped.
+fn task_events(
+    staged: &Path,
+    piece_sizes: Vec<u64>,
+    proof_type: &str,
+    lease: Option<LeaseGuard>,
+) -> Arc<dyn PieceEventSink> {
+    let mut sinks: Vec<Arc<dyn PieceEventSink>> = vec![
+        Arc::new(status::start_task(staged, piece_sizes)),
+        Arc::new(metrics::PieceMetrics::new(proof_type)),
+    ];
+    if let Some(lease) = lease {
+        sinks.push(Arc::new(lease));
+    }
+    if let Some(hooks) = webhook::PieceWebhooks::new(&config::global().webhooks, staged) {
+        sinks.push(Arc::new(hooks));
+    }
+    Arc::new(Fanout(sinks))
+}
+
+/// Claims the lease of `staged` if leases are configured, failing if another
+/// worker holds it.
+fn claim_lease(staged: &Path) -> Result<Option<LeaseGuard>> {
+    let lease = match &config::global().lease {
+        Some(lease) => lease,
+        None => return Ok(None),
+    };
+    let ttl = Duration::from_secs(lease.ttl_secs);
+    LeaseGuard::claim(staged, &lease.holder(), ttl).map(Some)
+}
+
+/// Publishes that the `index`-th piece of a task started to `sink`, which
+/// `add_piece` then publishes the progress of the piece to through
+/// `options`.
+fn start_piece_events(
+    sink: &Arc<dyn PieceEventSink>,
+    index: usize,
+    source: &str,
+    piece_size: u64,
+    options: &mut AddPieceOptions,
+) -> PieceEvents {
+    let piece = PieceRef {
+        index,
+        source: source.to_string(),
+        piece_size,
+    };
+    let events = PieceEvents::new(piece, sink.clone());
+    events.started();
+    options.piece_events = Some(events.clone());
+    events
+}
+
+/// Hashes the payload of a piece with blake3 if `payload_blake3` is
+/// configured.
+fn collect_payload_hash(
+    options: &mut AddPieceOptions,
+    payload_size: u64,
+) -> Option<Arc<PayloadHash>> {
+    if !config::global().payload_blake3 {
+        return None;
+    }
+    let hash = Arc::new(PayloadHash::new(payload_size));
+    options.payload_hash = Some(hash.clone());
+    Some(hash)
+}
+
+/// Writes the CARv2 index collected for a piece next to the others, payloads
+/// which are not CARv1 are skipped.
+fn save_car_index(
+    scratch: &TaskScratch,
+    index: Option<Arc<CarIndex>>,
+    piece_info: &PieceInfo,
+) -> Result<()> {
+    let (index, dir) = match (index, &config::global().car_index_dir) {
+        (Some(index), Some(dir)) => (index, dir),
+        _ => return Ok(()),
+    };
 
-            let pi = piece::add_piece_for_cc_sector(&staged_file, sector_size)
-                .context("add piece for cc sector")?;
-            piece_infos.push(pi);
+    let cid = piece_cid::encode(&piece_info.commitment);
+    let mut content = Vec::new();
+    if let Err(e) = index.write_to(&mut content) {
+        debug!(piece = %cid, err = %e, "no car index for the piece");
+        return Ok(());
+    }
+
+    let path = dir.join(format!("{}.idx", cid));
+    scratch
+        .write_atomic(&path, &content)
+        .with_context(|| format!("write car index {}", path.display()))?;
+    info!(piece = %cid, path = %path.display(), "car index written");
+    Ok(())
+}
+
+/// Validates `task` and its sources before anything is fetched, looking for
+/// its duplicate pieces and checking they fit in a sector.
+///
+/// The worker expects a piece info for every piece of the task, so
+/// duplicates are only logged under `Dedupe`, which the CLI alone applies.
+fn prepare_task(
+    task: &mut AddPieces,
+    sources: &Sources,
+    duplicates: DuplicatePolicy,
+) -> Result<()> {
+    task_schema::validate(TaskFormat::Processor, &serde_json::to_value(&task)?)
+        .context("task rejected")?;
+    redact::register_path(&task.staged_filepath);
+    for piece in &mut task.pieces {
+        if let piece::PieceFile::Local(path) = &mut piece.piece_file {
+            redact::register_path(path);
+            *path = paths::normalize(path);
+            redact::register_path(path);
         }
+    }
+    let piece_keys = task.pieces.iter().map(|p| match &p.piece_file {
+        piece::PieceFile::Local(path) => vec![duplicates::file_key(path, p.payload_size)],
+        piece::PieceFile::Url(url) => vec![format!("{}:{}", url, p.payload_size)],
+        // pledge pieces are all alike
+        _ => Vec::new(),
+    });
+    let duplicates = match duplicates {
+        DuplicatePolicy::Dedupe => DuplicatePolicy::Warn,
+        policy => policy,
+    };
+    duplicates::resolve(duplicates, piece_keys).context("task rejected")?;
 
-        Ok(piece_infos)
+    let piece_sizes: Vec<_> = task.pieces.iter().map(|p| p.piece_size).collect();
+    let sector_size = u64::from(task.seal_proof_type.sector_size());
+    check_back_to_back_fit(&piece_sizes, sector_size).context("task rejected")?;
+    if let Some(limits) = &config::global().task_limits {
+        let sizes: Vec<_> = task.pieces.iter().map(|p| p.piece_size.0).collect();
+        limits.check(&sizes).context("task rejected")?;
     }
+
+    let local_files = task.pieces.iter().filter_map(|p| match &p.piece_file {
+        piece::PieceFile::Local(path) => Some((path.as_path(), p.payload_size, p.piece_size.0)),
+        _ => None,
+    });
+    check_sources(sources, local_files, Some(&task.staged_filepath))?;
+    Ok(())
+}
+
+fn process_add_pieces(
+    mut task: AddPieces,
+    io: &mut TaskIoStats,
+    sources: &Sources,
+    duplicates: DuplicatePolicy,
+) -> Result<<AddPieces as Task>::Output> {
+    prepare_task(&mut task, sources, duplicates)?;
+
+    let piece_sizes: Vec<_> = task.pieces.iter().map(|p| p.piece_size).collect();
+    let sector_size = u64::from(task.seal_proof_type.sector_size());
+    check_target(&task.staged_filepath, &piece_sizes, sector_size)?;
+    // the path shared with the other workers, not the local one
+    let lease = claim_lease(&task.staged_filepath)?;
+    let destination = local_staging::redirect(&mut task.staged_filepath)?;
+    redact::register_path(&task.staged_filepath);
+    if destination.is_some() {
+        check_target(&task.staged_filepath, &piece_sizes, sector_size)?;
+    }
+    let scratch = TaskScratch::create(task.staged_filepath.parent())?;
+    let mut options = config::global().add_piece_options()?;
+    let seen_chunks = seen_chunks::watch(&mut options)?;
+    let proof_type = seal_proof::name(task.seal_proof_type);
+    let events = task_events(
+        &task.staged_filepath,
+        task.pieces.iter().map(|p| p.piece_size.0).collect(),
+        &proof_type,
+        lease,
+    );
+    let _writing = mount_limits::acquire(&task.staged_filepath, Access::Write);
+    let provenance = Provenance::new(options.committer, Some(scratch.task_id()));
+    let mut staged = StagedFile::open(
+        &task.staged_filepath,
+        &proof_type,
+        provenance,
+        config::global().staged_open,
+    )?;
+    let unpadded_copy = UnpaddedCopy::open(destination.as_ref().unwrap_or(&task.staged_filepath))?;
+    let target_device = iostats::device_of(&task.staged_filepath);
+
+    let mut piece_infos = Vec::with_capacity(task.pieces.len().min(1));
+    for (index, piece) in task.pieces.into_iter().enumerate() {
+        debug!(piece_file = ?piece.piece_file, "trying to add piece");
+        let spec = PieceSpec {
+            source: format!("{:?}", piece.piece_file),
+            payload_size: piece.payload_size,
+            piece_size: piece.piece_size,
+            payload_sha256: None,
+        };
+        let open_source = sources.opener(
+            index,
+            piece_opener(&piece.piece_file, piece.payload_size, piece.piece_size.0),
+        );
+        let _reading = match &piece.piece_file {
+            piece::PieceFile::Local(path) => mount_limits::acquire(path, Access::Read),
+            _ => None,
+        };
+
+        if let Some(piece_info) = staged.try_reuse(&spec, || open_source())? {
+            piece_infos.push(piece_info);
+            continue;
+        }
+
+        let source_device = source_device(&piece.piece_file);
+        let (mut options, stream) = verifier::stream_piece(
+            config::global().chunk_verifier.as_ref(),
+            &options,
+            &task.staged_filepath,
+            index,
+            &spec.source,
+        );
+        let piece_events = start_piece_events(
+            &events,
+            index,
+            &spec.source,
+            piece.piece_size.0,
+            &mut options,
+        );
+        let mut source = watchdog::watch(
+            config::global().stall_watchdog.as_ref(),
+            &spec.source,
+            PieceSource::open(config::global().read_ahead, move || open_source())?,
+        );
+        let chunk_roots = collect_chunk_roots(&mut options);
+        let car_index = collect_car_index(&mut options);
+        let payload_hash = collect_payload_hash(&mut options, piece.payload_size);
+        let encryption = keys::encrypt_payload(
+            config::global().encryption.as_ref(),
+            &mut options,
+            &spec.source,
+        )?;
+        // the processor writes the pieces back to back, without alignment
+        staged.journal_chunks(&spec, &[], &mut options)?;
+        let mut copy = PieceCopy::of(
+            unpadded_copy.as_ref(),
+            staged.piece_offset(&[], piece.piece_size)?,
+            piece.piece_size,
+        )?;
+        let started = Instant::now();
+        let mut times = (Duration::ZERO, Duration::ZERO, Duration::ZERO);
+        let res = staged.add(
+            &spec,
+            &[],
+            Some(&chunk_roots),
+            encryption.as_deref(),
+            payload_hash.as_deref(),
+            |staged_file| {
+                let mut target = Metered::new(staged_file);
+                let res = with_write_behind(&mut target, config::global().write_behind, |w| {
+                    let mut reads = Metered::new(&mut source);
+                    let mut writes = Metered::new(w);
+                    let res = write_and_preprocess_with_options(
+                        task.seal_proof_type,
+                        &mut TeeReader::new(&mut reads, &mut copy),
+                        &mut writes,
+                        piece.piece_size,
+                        &options,
+                    )
+                    .context("add piece");
+                    times = (started.elapsed(), reads.stats().busy, writes.stats().busy);
+                    res
+                });
+                io.record_target(&target_device, target.stats());
+                res
+            },
+        );
+        let res = match stream {
+            Some(s) => s.finish(res),
+            None => res,
+        };
+        piece_events.finished(&res);
+        let piece_info = res?;
+        io.record_piece(index, times.0, times.1, times.2);
+        io.record_source(&source_device, source.get_ref().stats());
+        save_car_index(&scratch, car_index, &piece_info)?;
+        piece_infos.push(piece_info);
+    }
+
+    if piece_infos.is_empty() {
+        let sector_size: u64 = task.seal_proof_type.sector_size().into();
+        let spec = PieceSpec {
+            source: "pledge".to_string(),
+            payload_size: 0,
+            piece_size: PaddedBytesAmount(sector_size).into(),
+            payload_sha256: None,
+        };
+
+        let pi = staged.add(&spec, &[], None, None, None, |staged_file| {
+            let pi = staging::write_zero_piece(staged_file, sector_size)?;
+            let written = pi.size;
+            Ok((pi, written))
+        })?;
+        piece_infos.push(pi);
+    }
+
+    let manifest = staged.finish()?;
+    if let Some(copy) = unpadded_copy {
+        copy.finish(manifest.end())?;
+    }
+    if let Some(seen_chunks) = seen_chunks {
+        seen_chunks.finish(&task.staged_filepath)?;
+    }
+    if let Some(dest) = destination {
+        local_staging::deliver(&task.staged_filepath, &dest)?;
+    }
+    Ok(piece_infos)
+}
+
+/// Opens the source of a piece of a processor task, retrying stale NFS
+/// handles of local files.
+fn piece_opener(piece_file: &piece::PieceFile, payload_size: u64, piece_size: u64) -> Opener {
+    let piece_file = piece_file.clone();
+    let retries = match &piece_file {
+        piece::PieceFile::Local(path) => config::global().source_paths.stale_retries(path),
+        _ => 0,
+    };
+    Arc::new(move || {
+        retry_stale(retries, "open piece file", || {
+            if let piece::PieceFile::Url(url) = &piece_file {
+                let headers = credentials::http_headers(url)?;
+                if !headers.is_empty() {
+                    return open_url(url, &headers, payload_size, piece_size);
+                }
+            }
+            piece::fetcher::open(piece_file.clone(), payload_size, piece_size)
+                .context("open piece file")
+        })
+    })
+}
+
+/// Fetches the piece at `url` sending `headers`, the credentials of its
+/// scheme, zero padded up to `piece_size` as the fetcher does.
+fn open_url(
+    url: &str,
+    headers: &BTreeMap<String, String>,
+    payload_size: u64,
+    piece_size: u64,
+) -> Result<Box<dyn Read>> {
+    let resp = headers
+        .iter()
+        .fold(ureq::get(url), |req, (k, v)| req.set(k, v))
+        .call()
+        .context("fetch piece")?;
+    let padding = piece_size.saturating_sub(payload_size);
+    Ok(Box::new(
+        resp.into_reader()
+            .take(payload_size)
+            .chain(io::repeat(0).take(padding)),
+    ))
+}
+
+/// The device or host the source of a piece is read from, for the io stats.
+fn source_device(piece_file: &piece::PieceFile) -> String {
+    match piece_file {
+        piece::PieceFile::Url(u) => iostats::host_of(u),
+        piece::PieceFile::Local(p) => iostats::device_of(p),
+        _ => "other".to_string(),
+    }
+}
+
+/// Computes the pieces `task` would stage, fetching and hashing their
+/// sources and checking they fit in a sector, without writing anything.
+///
+/// The payloads are never encrypted, with `encryption` configured the
+/// commitments differ from those staged.
+fn dry_run_add_pieces(
+    mut task: AddPieces,
+    io: &mut TaskIoStats,
+    sources: &Sources,
+    duplicates: DuplicatePolicy,
+) -> Result<<AddPieces as Task>::Output> {
+    prepare_task(&mut task, sources, duplicates)?;
+    let sector_size = u64::from(task.seal_proof_type.sector_size());
+    if task.pieces.is_empty() {
+        let piece_size = PaddedBytesAmount(sector_size).into();
+        let piece_info =
+            filecoin_proofs::pieces::zero_padding(piece_size).context("zero piece commitment")?;
+        return Ok(vec![piece_info]);
+    }
+
+    let options = config::global().add_piece_options()?;
+    // only the progress, nothing is staged
+    let events: Arc<dyn PieceEventSink> = Arc::new(status::start_task(
+        &task.staged_filepath,
+        task.pieces.iter().map(|p| p.piece_size.0).collect(),
+    ));
+    let mut piece_infos = Vec::with_capacity(task.pieces.len());
+    for (index, piece) in task.pieces.iter().enumerate() {
+        let name = format!("{:?}", piece.piece_file);
+        let open_source = sources.opener(
+            index,
+            piece_opener(&piece.piece_file, piece.payload_size, piece.piece_size.0),
+        );
+        let _reading = match &piece.piece_file {
+            piece::PieceFile::Local(path) => mount_limits::acquire(path, Access::Read),
+            _ => None,
+        };
+        let mut options = options.clone();
+        let piece_events =
+            start_piece_events(&events, index, &name, piece.piece_size.0, &mut options);
+        let mut source = watchdog::watch(
+            config::global().stall_watchdog.as_ref(),
+            &name,
+            PieceSource::open(config::global().read_ahead, move || open_source())?,
+        );
+
+        let started = Instant::now();
+        let mut reads = Metered::new(&mut source);
+        let res = write_and_preprocess_with_options(
+            task.seal_proof_type,
+            &mut reads,
+            io::sink(),
+            piece.piece_size,
+            &options,
+        )
+        .map(|(piece_info, _)| piece_info)
+        .with_context(|| format!("add piece {}", index));
+        piece_events.finished(&res);
+        let piece_info = res?;
+        io.record_piece(index, started.elapsed(), reads.stats().busy, Duration::ZERO);
+        io.record_source(&source_device(&piece.piece_file), source.get_ref().stats());
+        piece_infos.push(piece_info);
+    }
+    info!(
+        staged = %redact::path(&task.staged_filepath).display(),
+        pieces = piece_infos.len(),
+        "dry run, nothing staged"
+    );
+    Ok(piece_infos)
+}
+
+/// Rejects the task before fetching anything if the directory of `staged`
+/// is read only, lacks space or failed a task lately.
+fn check_target(
+    staged: &Path,
+    piece_sizes: &[UnpaddedBytesAmount],
+    sector_size: u64,
+) -> Result<()> {
+    let needed = target_health::bytes_needed(staged, piece_sizes, sector_size);
+    target_health::check(
+        target_health::dir_of(staged),
+        needed,
+        &config::global().target_health,
+    )
+    .context("task rejected")
 }
 
 fn cli() -> Command<'static> {
     Command::new("add_pieces")
-        .subcommand_required(true)
         .arg_required_else_help(true)
-        .subcommand(Command::new("processor").about("run a vc-processor for add_pieces"))
+        .arg(
+            Arg::new("spec")
+                .long("spec")
+                .action(ArgAction::SetTrue)
+                .help("print the build, CPU features and commitment backend as JSON"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .global(true)
+                .takes_value(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("path to the JSON config file, defaults to $ADD_PIECE_CONFIG"),
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .global(true)
+                .takes_value(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("record tasks with their source bytes under this directory for replay"),
+        )
+        .arg(
+            Arg::new("redact")
+                .long("redact")
+                .global(true)
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("hash")
+                .value_parser(RedactMode::parse)
+                .help("redact piece paths and URLs from logs and errors: hash, the default, or --redact=truncate"),
+        )
+        .arg(
+            Arg::new("target-profile")
+                .long("target-profile")
+                .global(true)
+                .takes_value(true)
+                .value_parser(TargetProfile::parse)
+                .help("tune writes for the filesystem of the staged files: default or nfs"),
+        )
+        .arg(
+            Arg::new("staged-open")
+                .long("staged-open")
+                .global(true)
+                .takes_value(true)
+                .value_parser(StagedOpenMode::parse)
+                .help("what becomes of existing staged files: truncate_existing, create_new, append_existing, fail_if_exists or resume_existing"),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .global(true)
+                .takes_value(true)
+                .value_parser(clap::value_parser!(String))
+                .help("user name or uid to switch to after startup, once the listeners are bound"),
+        )
+        .arg(
+            Arg::new("group")
+                .long("group")
+                .global(true)
+                .takes_value(true)
+                .requires("user")
+                .value_parser(clap::value_parser!(String))
+                .help("group name or gid to switch to with --user, the primary group of --user by default"),
+        )
+        .subcommand(
+            Command::new("processor")
+                .about("run a vc-processor for add_pieces")
+                .arg(
+                    Arg::new("tui")
+                        .long("tui")
+                        .action(ArgAction::SetTrue)
+                        .help("show the tasks in flight on the terminal, needs the tui feature"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("fetch and hash the pieces of the tasks and return them without staging anything, also set by ADD_PIECE_DRY_RUN=1"),
+                ),
+        )
         .subcommand(
             Command::new("add_pieces")
                 .arg(
-                    Arg::new("pieces_json")
-                        .value_parser(clap::value_parser!(String))
+                    Arg::new("pieces_json")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("the pieces, or {\"pieces\": [...], \"staged_open\": ...} to open this staged file otherwise"),
+                )
+                .arg(
+                    Arg::new("out")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                        .help("staged file, s3://<bucket>/<key> or an http(s) URL to upload it to"),
+                )
+                .arg(
+                    Arg::new("target")
+                        .long("target")
+                        .takes_value(true)
+                        .number_of_values(2)
+                        .multiple_occurrences(true)
+                        .value_names(&["PIECES_JSON", "OUT"])
+                        .value_parser(clap::value_parser!(String))
+                        .help("another pieces list and the target to stage it into, concurrently"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .long("jobs")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("2")
+                        .help("targets staged at once when given more with --target, 0 for one per core"),
+                )
+                .arg(Arg::new("origin").long("origin").action(ArgAction::SetTrue))
+                .arg(
+                    Arg::new("infer_sizes")
+                        .long("infer-sizes")
+                        .action(ArgAction::SetTrue)
+                        .help("size the pieces after the length of their files, rounded up to a piece size unless the given size holds them"),
+                )
+                .arg(
+                    Arg::new("proof_type")
+                        .long("proof-type")
+                        .takes_value(true)
+                        .value_parser(parse_proof_type)
+                        .help("seal proof of the target sector, e.g. StackedDrg32GiBV1_1 or 32GiB"),
+                )
+                .arg(
+                    Arg::new("sector_size")
+                        .long("sector-size")
+                        .takes_value(true)
+                        .conflicts_with("proof_type")
+                        .value_parser(seal_proof::parse_size)
+                        .help("size of the target sector, e.g. 32GiB, for its current seal proof"),
+                ),
+        )
+        .subcommand(
+            Command::new("add_pieces_multi")
+                .about("pack pieces into several sectors and stage each of them")
+                .arg(
+                    Arg::new("task_json")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("sector_size, staged_files, pieces and an optional packing policy"),
+                ),
+        )
+        .subcommand(
+            Command::new("pack")
+                .about("plan the assignment of pieces to sectors, and stage them with --execute")
+                .arg(
+                    Arg::new("pieces_json")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                        .help("file holding the pieces, as given to add_pieces"),
+                )
+                .arg(
+                    Arg::new("sector_size")
+                        .long("sector-size")
+                        .takes_value(true)
+                        .value_parser(seal_proof::parse_size)
+                        .required(true)
+                        .help("e.g. 32GiB"),
+                )
+                .arg(
+                    Arg::new("policy")
+                        .long("policy")
+                        .takes_value(true)
+                        .value_parser(["sequential", "first_fit", "first_fit_decreasing"])
+                        .default_value("first_fit_decreasing"),
+                )
+                .arg(
+                    Arg::new("max_sectors")
+                        .long("max-sectors")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("execute")
+                        .long("execute")
+                        .action(ArgAction::SetTrue)
+                        .help("stage the sectors into --out-dir"),
+                )
+                .arg(
+                    Arg::new("out_dir")
+                        .long("out-dir")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .default_value(".")
+                        .help("directory of the staged files sector-<n>"),
+                ),
+        )
+        .subcommand(
+            Command::new("commp")
+                .about("compute the piece CIDs files get once zero padded, without staging them")
+                .arg(
+                    Arg::new("files")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .multiple_values(true)
+                        .required(true)
+                        .help("files, or directories whose files are all hashed"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .long("jobs")
+                        .short('j')
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0")
+                        .help("threads hashing the files, 0 for one per core"),
+                )
+                .arg(
+                    Arg::new("proof_type")
+                        .long("proof-type")
+                        .takes_value(true)
+                        .value_parser(parse_proof_type)
+                        .help("fail for files whose piece doesn't fit the sectors of this proof, e.g. 32GiB"),
+                ),
+        )
+        .subcommand(
+            Command::new("commp-tail")
+                .about("compute the piece CID of a file still being written, as it grows")
+                .arg(
+                    Arg::new("file")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("done")
+                        .long("done")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("created by the writer once the file is complete, defaults to <file>.done"),
+                )
+                .arg(
+                    Arg::new("state")
+                        .long("state")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("progress to resume from after a restart, defaults to <file>.commp-state"),
+                )
+                .arg(
+                    Arg::new("poll_secs")
+                        .long("poll-secs")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("5"),
+                ),
+        )
+        .subcommand(
+            Command::new("check-sources")
+                .about("check that the piece files of a task can be staged, before staging them")
+                .arg(
+                    Arg::new("pieces_json")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                        .help("file holding the pieces, as given to add_pieces"),
+                )
+                .arg(
+                    Arg::new("staged")
+                        .long("staged")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("staged file the pieces are meant for, to check cross-device sources"),
+                )
+                .arg(
+                    Arg::new("spot_checks")
+                        .long("spot-checks")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0")
+                        .help("pieces with a payload_cid whose payload is read and checked against it"),
+                ),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("find where two staged files first differ and the pieces there")
+                .arg(
+                    Arg::new("left")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("right")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("describe the pieces of a staged file from its manifest")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("sample")
+                        .long("sample")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(usize))
+                        .help("show the first and last N payload bytes of each piece"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify-attestation")
+                .about("check the signature of the worker over the manifest of a staged file")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                        .help("staged file, or its manifest"),
+                )
+                .arg(
+                    Arg::new("public-key")
+                        .long("public-key")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .value_parser(clap::value_parser!(String))
+                        .help("hex encoded public key of a trusted worker, besides attestation.trusted_keys"),
+                ),
+        )
+        .subcommand(
+            Command::new("layout-map")
+                .about("draw the pieces, alignment and zero pieces of a staged file or of planned piece sizes")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required_unless_present("sizes")
+                        .conflicts_with("sizes"),
+                )
+                .arg(
+                    Arg::new("sizes")
+                        .long("sizes")
+                        .takes_value(true)
+                        .multiple_values(true)
+                        .use_value_delimiter(true)
+                        .value_parser(backfill::parse_piece_size)
+                        .help("plan pieces of these sizes instead, padded (8MiB) or unpadded (8323072), e.g. 8MiB,32MiB"),
+                )
+                .arg(
+                    Arg::new("sector_size")
+                        .long("sector-size")
+                        .takes_value(true)
+                        .value_parser(backfill::parse_piece_size)
+                        .help("size of the sector, by default the smallest power of two holding the pieces"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_parser(MapFormat::parse)
+                        .default_value("ascii")
+                        .help("ascii or svg"),
+                )
+                .arg(
+                    Arg::new("width")
+                        .long("width")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(usize))
+                        .help("columns of the ASCII map or pixels of the SVG, 64 and 1000 by default"),
+                ),
+        )
+        .subcommand(
+            Command::new("transfer")
+                .about("copy a staged file to its destination, checked against its chunk roots")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dest")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                        .help("e.g. a mount of the sealing host, chunks it already holds are kept"),
+                ),
+        )
+        .subcommand(
+            Command::new("copy-verify")
+                .about("copy a staged file, checking every chunk against its manifest")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dest")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                        .help("must not exist, it only appears once the copy is verified"),
+                ),
+        )
+        .subcommand(
+            Command::new("export-car")
+                .about("unpad the CAR payload of a staged piece, for retrieval markets")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("piece_cid")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("CAR to write, defaults to <piece_cid>.car"),
+                )
+                .arg(
+                    Arg::new("carv2")
+                        .long("carv2")
+                        .action(ArgAction::SetTrue)
+                        .help("wrap the payload into a CARv2 indexing its blocks"),
+                ),
+        )
+        .subcommand(
+            Command::new("archive")
+                .about("compress a completed staged file into a seekable zstd archive, manifest included")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("archive to write, defaults to <staged>.zst"),
+                )
+                .arg(
+                    Arg::new("level")
+                        .long("level")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(i32))
+                        .default_value("3")
+                        .help("zstd compression level"),
+                ),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("decompress an archive back into the staged file and its manifest, verified")
+                .arg(
+                    Arg::new("archive")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dest")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                        .help("must not exist, it only appears once verified"),
+                ),
+        )
+        .subcommand(
+            Command::new("claim")
+                .about("claim the lease of a staged file for a worker, as workers do when `lease` is configured")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(holder_arg())
+                .arg(
+                    Arg::new("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .help("seconds the lease lasts, defaults to `lease.ttl_secs` of the config"),
+                ),
+        )
+        .subcommand(
+            Command::new("release")
+                .about("release the lease of a staged file held by a worker")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(holder_arg()),
+        )
+        .subcommand(
+            Command::new("task-schema")
+                .about("print the JSON Schema tasks are validated against")
+                .arg(
+                    Arg::new("format")
+                        .value_parser(TaskFormat::parse)
+                        .required(true)
+                        .help("pieces (add_pieces, pack, check-sources), multi_sector or processor"),
+                ),
+        )
+        .subcommand(
+            Command::new("supported")
+                .about("print the seal proofs, sector and piece sizes and features this build supports"),
+        )
+        .subcommand(
+            Command::new("piece-sizes")
+                .about("list the valid piece sizes up to a sector size, unpadded and padded, for client side size pickers")
+                .arg(
+                    Arg::new("sector_size")
+                        .long("sector-size")
+                        .takes_value(true)
+                        .value_parser(seal_proof::parse_size)
+                        .required(true)
+                        .help("e.g. 32GiB"),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("measure the throughput of the pipeline on synthetic data with the configured options")
+                .arg(
+                    Arg::new("piece_size")
+                        .long("piece-size")
+                        .takes_value(true)
+                        .value_parser(seal_proof::parse_size)
+                        .default_value("32MiB")
+                        .help("padded size of the piece added every round"),
+                )
+                .arg(
+                    Arg::new("rounds")
+                        .long("rounds")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("3")
+                        .help("rounds run, the best one counts"),
+                )
+                .arg(
+                    Arg::new("min_throughput")
+                        .long("assert-min-throughput")
+                        .takes_value(true)
+                        .value_parser(seal_proof::parse_rate)
+                        .help("exit with an error below this payload throughput, e.g. 1.5GiB/s"),
+                ),
+        )
+        .subcommand(
+            Command::new("backfill-manifest")
+                .about("write the manifest of a staged file from before manifests, given the sizes of its pieces")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("piece_sizes")
+                        .required(true)
+                        .multiple_values(true)
+                        .use_value_delimiter(true)
+                        .value_parser(backfill::parse_piece_size)
+                        .help("sizes of the pieces in order, padded (8MiB) or unpadded (8323072), e.g. 8MiB,32MiB"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("replace the manifest the staged file already has"),
+                ),
+        )
+        .subcommand(
+            Command::new("fixtures")
+                .about("write a small staged sector with its expected CommD and piece CIDs, as golden data")
+                .arg(
+                    Arg::new("dir")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("proof_type")
+                        .long("proof-type")
+                        .takes_value(true)
+                        .value_parser(parse_proof_type)
+                        .default_value("2KiB")
+                        .help("seal proof of the sector, of 2KiB or 8MiB"),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("0")
+                        .help("seed of the generated payloads"),
+                ),
+        )
+        .subcommand(
+            Command::new("fill-sector")
+                .about("complete a staged file up to the end of its sector with zero pieces")
+                .arg(
+                    Arg::new("staged")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("proof_type")
+                        .long("proof-type")
+                        .takes_value(true)
+                        .required(true)
+                        .value_parser(parse_proof_type)
+                        .help("seal proof of the sector, e.g. StackedDrg32GiBV1_1 or 32GiB"),
+                ),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("convert a staged file between the raw and v2 formats")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("to-v2")
+                        .about("add the v2 header and piece table to a staged file")
+                        .arg(
+                            Arg::new("src")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("dest")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("proof_type")
+                                .long("proof-type")
+                                .takes_value(true)
+                                .value_parser(parse_proof_type)
+                                .help("recorded in the header, e.g. StackedDrg32GiBV1_1 or 32GiB"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("to-raw")
+                        .about("strip the v2 header and piece table of a staged file")
+                        .arg(
+                            Arg::new("src")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("dest")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("serve-retrievals")
+                .about("serve GET /piece/<cid>, with range requests, off the staged files of a directory")
+                .arg(
+                    Arg::new("dir")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
+                        .help("staging directory, the staged files are found by their manifests"),
+                )
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(String))
+                        .default_value("127.0.0.1:8080"),
+                )
+                .arg(
+                    Arg::new("tls-cert")
+                        .long("tls-cert")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .requires("tls-key")
+                        .help("serve over TLS with this PEM certificate chain"),
+                )
+                .arg(
+                    Arg::new("tls-key")
+                        .long("tls-key")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .requires("tls-cert")
+                        .help("PEM private key of --tls-cert"),
+                )
+                .arg(
+                    Arg::new("tls-client-ca")
+                        .long("tls-client-ca")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .requires("tls-cert")
+                        .help("require client certificates issued by the CAs of this PEM file"),
+                ),
+        )
+        .subcommand(
+            Command::new("history")
+                .about("list where and when a piece was staged, from the ledger")
+                .arg(
+                    Arg::new("piece_cid")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true),
+                )
+                .arg(ledger_arg()),
+        )
+        .subcommand(
+            Command::new("query")
+                .about("run a read-only SQL query against the ledger, printing JSON lines")
+                .arg(
+                    Arg::new("sql")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("e.g. \"SELECT * FROM pieces WHERE staged_file = ?1\""),
+                )
+                .arg(
+                    Arg::new("args")
+                        .value_parser(clap::value_parser!(String))
+                        .multiple_values(true)
+                        .help("bound to the ?1, ?2... parameters of the query"),
+                )
+                .arg(ledger_arg()),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("show the tasks in flight in a running processor, or one of them")
+                .arg(
+                    Arg::new("task_id")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("as listed without it, and logged when the task starts"),
+                )
+                .arg(api_arg()),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("follow the progress of a task of a running processor until it ends")
+                .arg(
+                    Arg::new("task_id")
+                        .value_parser(clap::value_parser!(u64))
+                        .required(true),
+                )
+                .arg(api_arg())
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("1")
+                        .help("seconds between updates"),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("re-run a recorded task from its captured sources")
+                .arg(
+                    Arg::new("recording")
+                        .value_parser(clap::value_parser!(PathBuf))
                         .required(true),
                 )
                 .arg(
                     Arg::new("out")
+                        .long("out")
+                        .takes_value(true)
                         .value_parser(clap::value_parser!(PathBuf))
-                        .required(true),
-                )
-                .arg(Arg::new("origin").long("origin").action(ArgAction::SetTrue)),
+                        .help("staged file to write, defaults to replay.staged in the recording"),
+                ),
         )
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Parses `--proof-type`, by name or sector size.
+fn parse_proof_type(s: &str) -> Result<RegisteredSealProof, String> {
+    seal_proof::parse(s).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct PieceFile {
+    /// Looked up in the piece directories by `piece_cid` if unset.
+    #[serde(default)]
     path: PathBuf,
+    /// The length of the piece file if unset and it is given by `piece_cid`.
+    #[serde(default)]
     size: u64,
+    /// Epoch the deal of the piece starts at, the piece is skipped if it
+    /// couldn't be sealed in time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deal_start_epoch: Option<i64>,
+    /// Root CID of the CAR the piece holds, its blocks are checked against
+    /// their CIDs while the piece is added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_cid: Option<String>,
+    /// Bytes of payload in the piece file, zero padded up to `size`, `size`
+    /// if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_size: Option<u64>,
+    /// CID of the piece, checked against its commitment once it is added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    piece_cid: Option<String>,
+    /// Hex encoded sha256 of the piece file, checked while it is added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+impl PieceFile {
+    fn payload_root(&self) -> Result<Option<Cid>> {
+        let root = self.payload_cid.as_deref().map(Cid::parse).transpose();
+        root.with_context(|| format!("payload cid of {}", self.path.display()))
+    }
+
+    fn payload_size(&self) -> u64 {
+        self.payload_size.unwrap_or(self.size)
+    }
+
+    /// The path of the piece with its payload and piece sizes.
+    fn declared_size(&self) -> (&Path, u64, u64) {
+        (&self.path, self.payload_size(), self.size)
+    }
+
+    /// What identifies the piece when looking for duplicates in a task.
+    fn keys(&self) -> Vec<String> {
+        let mut keys = vec![duplicates::file_key(&self.path, self.size)];
+        keys.extend(self.payload_cid.as_ref().map(|c| format!("payload {}", c)));
+        keys.extend(self.piece_cid.as_ref().map(|c| format!("piece {}", c)));
+        keys
+    }
+
+    /// Opens the piece file, zero padded up to the piece size.
+    fn opener(&self) -> Opener {
+        let path = paths::normalize(&self.path);
+        let retries = config::global().source_paths.stale_retries(&path);
+        let padding = self.size.saturating_sub(self.payload_size());
+        Arc::new(move || {
+            let f = retry_stale(retries, "open piece file", || fs::File::open(&path))
+                .context("open piece file")?;
+            match padding {
+                0 => Ok(Box::new(f) as Box<dyn Read>),
+                _ => Ok(Box::new(f.chain(io::repeat(0).take(padding))) as Box<dyn Read>),
+            }
+        })
+    }
 }
 
 fn main() -> Result<()> {
+    let res = run();
+    webhook::flush();
+    res.map_err(redact::error)
+}
+
+fn run() -> Result<()> {
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(fmt::layer().with_writer(redact::RedactedStdout))
         .with(
             EnvFilter::builder()
                 .with_default_directive(LevelFilter::DEBUG.into())
@@ -92,10 +1500,73 @@ fn main() -> Result<()> {
         .init();
 
     let m = cli().get_matches();
+    if let Some(mode) = m.get_one::<RedactMode>("redact") {
+        redact::enable(*mode);
+    }
+    // the processor and serve-retrievals drop them themselves once their
+    // listeners are bound
+    let drop_to = privileges::DropTo::from_matches(&m);
+    if !matches!(m.subcommand(), Some(("processor" | "serve-retrievals", _))) {
+        drop_to.apply()?;
+    }
+    let config_path = m
+        .get_one::<PathBuf>("config")
+        .cloned()
+        .or_else(|| env::var_os("ADD_PIECE_CONFIG").map(PathBuf::from));
+
+    if let Some(("replay", replay_m)) = m.subcommand() {
+        let recording = replay_m
+            .get_one::<PathBuf>("recording")
+            .expect("validated by clap");
+        return replay(recording, replay_m.get_one::<PathBuf>("out"), config_path);
+    }
+
+    let mut config = match config_path {
+        Some(config_path) => config::Config::load(config_path)?,
+        None => Default::default(),
+    };
+    if let Some(record_dir) = m.get_one::<PathBuf>("record") {
+        config.record_dir = Some(record_dir.clone());
+    }
+    if let Some(profile) = m.get_one::<TargetProfile>("target-profile") {
+        config.target_profile = *profile;
+    }
+    if let Some(mode) = m.get_one::<StagedOpenMode>("staged-open") {
+        config.staged_open = *mode;
+    }
+    config.target_profile.apply(&mut config);
+    config::init(config);
+    if m.get_flag("spec") {
+        ensure!(m.subcommand().is_none(), "--spec takes no subcommand");
+        target_health::probe_all(&config::global().target_health);
+        println!("{}", serde_json::to_string_pretty(&spec::current())?);
+        return Ok(());
+    }
+    if m.subcommand().is_none() {
+        cli().print_help()?;
+        bail!("a subcommand is required");
+    }
+    let spec = spec::current();
+    info!(
+        committer = ?spec.committer_selected,
+        cpu = ?spec.cpu,
+        features = ?spec.features,
+        "commitment backend"
+    );
+    scratch::cleanup_on_signal()?;
+    if !matches!(m.subcommand(), Some(("processor", _))) {
+        abort::watch(config::global().abort.as_ref());
+    }
+
     match m.subcommand() {
-        Some(("processor", _)) => processor(),
+        Some(("processor", processor_m)) => processor(
+            processor_m.get_flag("tui"),
+            processor_m.get_flag("dry-run"),
+            &drop_to,
+        ),
         Some(("add_pieces", add_pieces_m)) => {
             let origin = add_pieces_m.get_flag("origin");
+            let infer_sizes = add_pieces_m.get_flag("infer_sizes");
             info!("add_pieces for {}", if origin { "origin" } else { "new" });
 
             let pieces_json = add_pieces_m
@@ -104,50 +1575,1272 @@ fn main() -> Result<()> {
             let out = add_pieces_m
                 .get_one::<PathBuf>("out")
                 .expect("validated by clap");
+            let mut targets = vec![(pieces_json.clone(), out.clone())];
+            if let Some(more) = add_pieces_m.get_many::<String>("target") {
+                let more: Vec<_> = more.collect();
+                targets.extend(more.chunks(2).map(|t| (t[0].clone(), PathBuf::from(t[1]))));
+            }
+            let jobs = *add_pieces_m
+                .get_one::<usize>("jobs")
+                .expect("validated by clap");
+
+            let proof = match add_pieces_m.get_one::<u64>("sector_size") {
+                Some(sector_size) => Some(seal_proof::for_sector_size(*sector_size)?),
+                None => add_pieces_m
+                    .get_one::<RegisteredSealProof>("proof_type")
+                    .copied(),
+            };
+
+            let _progress = progress::start();
+            if let [(pieces_json, out)] = targets.as_slice() {
+                let lines = add_pieces_target(pieces_json, out, origin, infer_sizes, proof)?;
+                return write_target_lines(&mut io::stdout().lock(), None, &lines);
+            }
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .context("build add_pieces thread pool")?;
+            // the lines of each target are written in the order of the targets
+            let mut failed = Vec::new();
+            commp::in_order(
+                &pool,
+                &targets,
+                |(pieces_json, out)| {
+                    let res = add_pieces_target(pieces_json, out, origin, infer_sizes, proof);
+                    (out, res)
+                },
+                |(out, res)| {
+                    match res {
+                        Ok(lines) => {
+                            write_target_lines(&mut io::stdout().lock(), Some(out), &lines)?
+                        }
+                        Err(e) => {
+                            warn!(out = %out.display(), err = %format!("{:#}", e), "add_pieces failed");
+                            failed.push(out.display().to_string());
+                        }
+                    }
+                    Ok(())
+                },
+            )?;
+            ensure!(
+                failed.is_empty(),
+                "add_pieces failed for {} of {} targets: {}",
+                failed.len(),
+                targets.len(),
+                failed.join(", ")
+            );
+            Ok(())
+        }
+        Some(("add_pieces_multi", multi_m)) => {
+            let task_json = multi_m
+                .get_one::<String>("task_json")
+                .expect("validated by clap");
+            let mut task: multi_sector::MultiSectorTask =
+                task_schema::parse(TaskFormat::MultiSector, task_json)?;
+            piece_dir::resolve(&mut task.pieces)?;
+
+            let mut io = TaskIoStats::default();
+            let res = multi_sector::run(&task, &mut io);
+            info!(io = ?io.report(), "add_pieces_multi io stats");
+            println!("{}", serde_json::to_string_pretty(&res?)?);
+            Ok(())
+        }
+        Some(("pack", pack_m)) => {
+            let pieces_json = pack_m
+                .get_one::<PathBuf>("pieces_json")
+                .expect("validated by clap");
+            let content = fs::read_to_string(pieces_json)
+                .with_context(|| format!("read {}", pieces_json.display()))?;
+            let pieces = parse_pieces(&content)?;
+            let policy = serde_json::from_value(serde_json::Value::String(
+                pack_m
+                    .get_one::<String>("policy")
+                    .expect("validated by clap")
+                    .clone(),
+            ))
+            .context("parse policy")?;
+
+            let mut task = multi_sector::MultiSectorTask {
+                sector_size: *pack_m
+                    .get_one::<u64>("sector_size")
+                    .expect("validated by clap"),
+                staged_files: Vec::new(),
+                pieces,
+                policy,
+                staged_open: None,
+            };
+            let max_sectors = pack_m.get_one::<usize>("max_sectors").copied();
+            let (packing, expired) = task.plan_with(max_sectors)?;
+
+            if !pack_m.get_flag("execute") {
+                let stats = packing.stats();
+                let report = multi_sector::PackReport {
+                    packing,
+                    stats,
+                    expired,
+                };
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(());
+            }
+
+            let out_dir = pack_m
+                .get_one::<PathBuf>("out_dir")
+                .expect("validated by clap");
+            task.staged_files = (0..packing.sectors.len())
+                .map(|i| out_dir.join(format!("sector-{}", i)))
+                .collect();
+
+            let mut io = TaskIoStats::default();
+            let res = multi_sector::run(&task, &mut io);
+            info!(io = ?io.report(), "pack io stats");
+            println!("{}", serde_json::to_string_pretty(&res?)?);
+            Ok(())
+        }
+        Some(("commp", commp_m)) => {
+            let files: Vec<_> = commp_m
+                .get_many::<PathBuf>("files")
+                .expect("validated by clap")
+                .cloned()
+                .collect();
+            let jobs = *commp_m.get_one::<usize>("jobs").expect("validated by clap");
+            let sector_size = commp_m
+                .get_one::<RegisteredSealProof>("proof_type")
+                .map(|p| u64::from(p.sector_size()));
+
+            let _progress = progress::start();
+            commp::commp_all(&files, jobs, sector_size, &mut io::stdout())
+        }
+        Some(("commp-tail", tail_m)) => {
+            let file = tail_m
+                .get_one::<PathBuf>("file")
+                .expect("validated by clap");
+            let mut options = commp::TailOptions::for_file(file);
+            if let Some(done) = tail_m.get_one::<PathBuf>("done") {
+                options.done_marker = done.clone();
+            }
+            if let Some(state) = tail_m.get_one::<PathBuf>("state") {
+                options.state = state.clone();
+            }
+            let poll_secs = *tail_m
+                .get_one::<u64>("poll_secs")
+                .expect("validated by clap");
+            options.poll = Duration::from_secs(poll_secs);
+
+            let res = commp::commp_tail(file, &options)?;
+            println!("{}", serde_json::to_string(&res)?);
+            Ok(())
+        }
+        Some(("check-sources", check_m)) => {
+            let pieces_json = check_m
+                .get_one::<PathBuf>("pieces_json")
+                .expect("validated by clap");
+            let content = fs::read_to_string(pieces_json)
+                .with_context(|| format!("read {}", pieces_json.display()))?;
+            let pieces = parse_pieces(&content)?;
+            let staged = check_m.get_one::<PathBuf>("staged");
+            let spot_checks = *check_m
+                .get_one::<usize>("spot_checks")
+                .expect("validated by clap");
+
+            let report = preflight::check(&pieces, staged.map(PathBuf::as_path), spot_checks);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            match report.failed {
+                0 => Ok(()),
+                n => bail!("{} of {} sources can't be staged", n, pieces.len()),
+            }
+        }
+        Some(("diff", diff_m)) => {
+            let left = diff_m
+                .get_one::<PathBuf>("left")
+                .expect("validated by clap");
+            let right = diff_m
+                .get_one::<PathBuf>("right")
+                .expect("validated by clap");
+
+            let report = diff::diff(left, right)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            match report.divergence {
+                Some(d) => bail!("staged files differ at padded offset {}", d.offset),
+                None => Ok(()),
+            }
+        }
+        Some(("inspect", inspect_m)) => {
+            let staged = inspect_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let sample = inspect_m.get_one::<usize>("sample").copied();
+
+            let report = inspect::inspect(staged, sample)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Some(("verify-attestation", verify_m)) => {
+            let staged = verify_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let mut trusted: Vec<String> = verify_m
+                .get_many::<String>("public-key")
+                .map(|keys| keys.cloned().collect())
+                .unwrap_or_default();
+            if let Some(config) = &config::global().attestation {
+                trusted.extend(config.trusted_keys.iter().cloned());
+            }
+
+            let manifest = match staged.to_string_lossy().ends_with(".manifest.json") {
+                true => serde_json::from_slice(
+                    &fs::read(staged).with_context(|| format!("read {}", staged.display()))?,
+                )
+                .with_context(|| format!("parse manifest: {}", staged.display()))?,
+                false => staging::load_manifest(staged)?
+                    .ok_or_else(|| anyhow!("no manifest found for {}", staged.display()))?,
+            };
+            let verification = attestation::verify(&manifest, &trusted)?;
+            println!("{}", serde_json::to_string_pretty(&verification)?);
+            if !verification.valid {
+                bail!("the attestation of {} is not valid", staged.display());
+            }
+            Ok(())
+        }
+        Some(("layout-map", map_m)) => {
+            let sector_size = map_m
+                .get_one::<UnpaddedBytesAmount>("sector_size")
+                .map(|size| u64::from(PaddedBytesAmount::from(*size)));
+            let (sector_size, segments) = match map_m.get_many::<UnpaddedBytesAmount>("sizes") {
+                Some(sizes) => {
+                    sector_map::from_plan(&sizes.copied().collect::<Vec<_>>(), sector_size)?
+                }
+                None => {
+                    let staged = map_m
+                        .get_one::<PathBuf>("staged")
+                        .expect("validated by clap");
+                    sector_map::from_staged(staged, sector_size)?
+                }
+            };
+            let format = *map_m
+                .get_one::<MapFormat>("format")
+                .expect("validated by clap");
+            let width = map_m
+                .get_one::<usize>("width")
+                .copied()
+                .unwrap_or(match format {
+                    MapFormat::Ascii => 64,
+                    MapFormat::Svg => 1000,
+                });
+
+            print!(
+                "{}",
+                sector_map::render(format, sector_size, &segments, width)
+            );
+            Ok(())
+        }
+        Some(("convert", convert_m)) => {
+            let (format, m) = match convert_m.subcommand() {
+                Some(("to-v2", m)) => (StagedFormat::V2, m),
+                Some(("to-raw", m)) => (StagedFormat::Raw, m),
+                _ => unreachable!(),
+            };
+            let src = m.get_one::<PathBuf>("src").expect("validated by clap");
+            let dest = m.get_one::<PathBuf>("dest").expect("validated by clap");
+            let proof_type = m
+                .try_get_one::<RegisteredSealProof>("proof_type")
+                .ok()
+                .flatten()
+                .map(|p| seal_proof::name(*p))
+                .unwrap_or_default();
+
+            convert::convert(src, dest, format, &proof_type)
+        }
+        Some(("transfer", transfer_m)) => {
+            let staged = transfer_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let dest = transfer_m
+                .get_one::<PathBuf>("dest")
+                .expect("validated by clap");
+
+            let report = transfer::transfer(staged, dest)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Some(("copy-verify", copy_m)) => {
+            let staged = copy_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let dest = copy_m
+                .get_one::<PathBuf>("dest")
+                .expect("validated by clap");
+
+            let report = copy_verify::copy_verify(staged, dest)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Some(("export-car", export_m)) => {
+            let staged = export_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let piece_cid = export_m
+                .get_one::<String>("piece_cid")
+                .expect("validated by clap");
+            let out = export_m
+                .get_one::<PathBuf>("out")
+                .cloned()
+                .unwrap_or_else(|| export_car::path_for(piece_cid));
+
+            let report =
+                export_car::export_car(staged, piece_cid, &out, export_m.get_flag("carv2"))?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Some(("archive", archive_m)) => {
+            let staged = archive_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let out = archive_m
+                .get_one::<PathBuf>("out")
+                .cloned()
+                .unwrap_or_else(|| archive::path_for(staged));
+            let level = archive_m
+                .get_one::<i32>("level")
+                .expect("validated by clap");
+
+            let report = archive::archive(staged, &out, *level)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Some(("restore", restore_m)) => {
+            let archive = restore_m
+                .get_one::<PathBuf>("archive")
+                .expect("validated by clap");
+            let dest = restore_m
+                .get_one::<PathBuf>("dest")
+                .expect("validated by clap");
+
+            let report = archive::restore(archive, dest)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Some(("claim", claim_m)) => {
+            let staged = claim_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let lease = config::global().lease.clone().unwrap_or_default();
+            let holder = claim_m
+                .get_one::<String>("holder")
+                .cloned()
+                .or_else(|| lease.holder.clone())
+                .unwrap_or_else(manifest::hostname);
+            let ttl = claim_m.get_one::<u64>("ttl").unwrap_or(&lease.ttl_secs);
+
+            match reservation::claim(staged, &holder, None, Duration::from_secs(*ttl))? {
+                Claim::Claimed(lease) => {
+                    println!("{}", serde_json::to_string_pretty(&lease)?);
+                    Ok(())
+                }
+                Claim::Held(lease) => bail!(
+                    "{} is leased to {} until {}",
+                    staged.display(),
+                    lease.holder,
+                    lease.expires_at
+                ),
+            }
+        }
+        Some(("release", release_m)) => {
+            let staged = release_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let holder = release_m
+                .get_one::<String>("holder")
+                .cloned()
+                .or_else(|| config::global().lease.as_ref()?.holder.clone())
+                .unwrap_or_else(manifest::hostname);
+
+            let released = reservation::release(staged, &holder, None)?;
+            let report = serde_json::json!({ "released": released });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Some(("task-schema", schema_m)) => {
+            let format = schema_m
+                .get_one::<TaskFormat>("format")
+                .expect("validated by clap");
+            println!("{}", serde_json::to_string_pretty(&format.schema())?);
+            Ok(())
+        }
+        Some(("supported", _)) => {
+            println!("{}", serde_json::to_string_pretty(&support::supported())?);
+            Ok(())
+        }
+        Some(("piece-sizes", sizes_m)) => {
+            #[derive(Serialize)]
+            struct PieceSize {
+                unpadded: u64,
+                padded: u64,
+            }
+
+            let sector_size = *sizes_m
+                .get_one::<u64>("sector_size")
+                .expect("validated by clap");
+            let sizes: Vec<_> = pure::piece_sizes(PaddedBytesAmount(sector_size))
+                .into_iter()
+                .map(|(unpadded, padded)| PieceSize {
+                    unpadded: unpadded.into(),
+                    padded: padded.into(),
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&sizes)?);
+            Ok(())
+        }
+        Some(("bench", bench_m)) => {
+            let piece_size = *bench_m
+                .get_one::<u64>("piece_size")
+                .expect("validated by clap");
+            let rounds = *bench_m
+                .get_one::<usize>("rounds")
+                .expect("validated by clap");
+            let min = bench_m.get_one::<u64>("min_throughput").copied();
+
+            let report = throughput::measure(piece_size, rounds, min)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            match (report.passed, min) {
+                (false, Some(min)) => bail!(
+                    "throughput {} is below the minimum of {}",
+                    report.throughput,
+                    throughput::rate_string(min)
+                ),
+                _ => Ok(()),
+            }
+        }
+        Some(("backfill-manifest", backfill_m)) => {
+            let staged = backfill_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let piece_sizes: Vec<_> = backfill_m
+                .get_many::<UnpaddedBytesAmount>("piece_sizes")
+                .expect("validated by clap")
+                .copied()
+                .collect();
+
+            let pieces =
+                backfill::backfill_manifest(staged, &piece_sizes, backfill_m.get_flag("force"))?;
+            println!("{}", serde_json::to_string_pretty(&pieces)?);
+            Ok(())
+        }
+        Some(("fixtures", fixtures_m)) => {
+            let dir = fixtures_m
+                .get_one::<PathBuf>("dir")
+                .expect("validated by clap");
+            let proof = fixtures_m
+                .get_one::<RegisteredSealProof>("proof_type")
+                .expect("validated by clap");
+            let seed = fixtures_m
+                .get_one::<u64>("seed")
+                .expect("validated by clap");
+
+            let fixture = fixtures::generate(dir, *proof, *seed)?;
+            println!("{}", serde_json::to_string_pretty(&fixture)?);
+            Ok(())
+        }
+        Some(("fill-sector", fill_m)) => {
+            let staged = fill_m
+                .get_one::<PathBuf>("staged")
+                .expect("validated by clap");
+            let proof = fill_m
+                .get_one::<RegisteredSealProof>("proof_type")
+                .expect("validated by clap");
+
+            let fillers = staging::fill_sector(staged, u64::from(proof.sector_size()))?;
+            println!("{}", serde_json::to_string_pretty(&fillers)?);
+            Ok(())
+        }
+        Some(("serve-retrievals", serve_m)) => {
+            let dir = serve_m
+                .get_one::<PathBuf>("dir")
+                .expect("validated by clap");
+            let listen = serve_m
+                .get_one::<String>("listen")
+                .expect("validated by clap");
+
+            let tls = tls::TlsFiles::from_matches(serve_m)
+                .map(|files| files.server_config())
+                .transpose()?;
+
+            retrieval::serve(dir, listen, tls, &drop_to)
+        }
+        Some(("history", history_m)) => {
+            let cid = history_m
+                .get_one::<String>("piece_cid")
+                .expect("validated by clap");
 
-            let pieces: Vec<PieceFile> =
-                serde_json::from_str(pieces_json).context("parse pieces_json")?;
+            ledger::history(&ledger_path(history_m)?, cid)
+        }
+        Some(("query", query_m)) => {
+            let sql = query_m.get_one::<String>("sql").expect("validated by clap");
+            let args: Vec<&str> = query_m
+                .get_many::<String>("args")
+                .unwrap_or_default()
+                .map(String::as_str)
+                .collect();
 
-            let piece_infos = add_pieces(&pieces, out, origin)?;
-            println!("{:?}", piece_infos);
+            ledger::query(&ledger_path(query_m)?, sql, &args)
+        }
+        Some(("status", status_m)) => {
+            let addr = task_api::api_addr(
+                status_m.get_one::<String>("api"),
+                config::global().metrics.as_ref(),
+            );
+            let report = match status_m.get_one::<u64>("task_id") {
+                Some(id) => serde_json::to_value(
+                    task_api::fetch(&addr, *id)?
+                        .ok_or_else(|| anyhow!("no task {} running", id))?,
+                )?,
+                None => serde_json::to_value(task_api::fetch_all(&addr)?)?,
+            };
+            println!("{}", serde_json::to_string_pretty(&report)?);
             Ok(())
         }
+        Some(("watch", watch_m)) => {
+            let id = watch_m
+                .get_one::<u64>("task_id")
+                .expect("validated by clap");
+            let interval = watch_m
+                .get_one::<u64>("interval")
+                .expect("validated by clap");
+            let addr = task_api::api_addr(
+                watch_m.get_one::<String>("api"),
+                config::global().metrics.as_ref(),
+            );
+
+            task_api::watch(&addr, *id, Duration::from_secs(*interval))
+        }
         _ => unreachable!(),
     }
 }
 
-fn processor() -> Result<()> {
+fn holder_arg() -> Arg<'static> {
+    Arg::new("holder")
+        .long("holder")
+        .takes_value(true)
+        .value_parser(clap::value_parser!(String))
+        .help("worker holding the lease, defaults to `lease.holder` of the config or the host name")
+}
+
+fn api_arg() -> Arg<'static> {
+    Arg::new("api")
+        .long("api")
+        .takes_value(true)
+        .value_parser(clap::value_parser!(String))
+        .help("address of the processor metrics, defaults to `metrics.listen` of the config")
+}
+
+fn ledger_arg() -> Arg<'static> {
+    Arg::new("ledger")
+        .long("ledger")
+        .takes_value(true)
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("SQLite ledger to read, the configured one by default")
+}
+
+/// The ledger given by `--ledger`, or the configured one.
+fn ledger_path(m: &ArgMatches) -> Result<PathBuf> {
+    m.get_one::<PathBuf>("ledger")
+        .or(config::global().ledger.as_ref())
+        .cloned()
+        .context("no ledger configured, set `ledger` or pass --ledger")
+}
+
+/// Parses the pieces of a task, finding those given by CID only.
+fn parse_pieces(pieces_json: &str) -> Result<Vec<PieceFile>> {
+    let mut pieces: Vec<PieceFile> = task_schema::parse(TaskFormat::Pieces, pieces_json)?;
+    piece_dir::resolve(&mut pieces)?;
+    checksums::resolve(&mut pieces)?;
+    Ok(pieces)
+}
+
+/// Parses the pieces of a task whose sizes are inferred from the length of
+/// their files: a given size is kept if it is a piece size holding the file,
+/// the smallest piece size holding it is used otherwise. The payload size is
+/// the length of the file. The task is validated once the sizes are filled.
+fn parse_pieces_inferring_sizes(pieces_json: &str) -> Result<Vec<PieceFile>> {
+    let mut pieces: Vec<PieceFile> = serde_json::from_str(pieces_json).context("parse pieces")?;
+    for (index, piece) in pieces.iter_mut().enumerate() {
+        if piece.path.as_os_str().is_empty() {
+            continue;
+        }
+        let len = fs::metadata(&piece.path)
+            .with_context(|| format!("piece {}: stat {}", index, piece.path.display()))?
+            .len();
+        let given = UnpaddedBytesAmount(piece.size);
+        let size = match sizes::is_piece_size(given) && piece.size >= len {
+            true => given,
+            false => sizes::piece_size_for(len),
+        };
+        if size != given {
+            info!(
+                path = %piece.path.display(),
+                given = piece.size,
+                payload_size = len,
+                piece_size = size.0,
+                "piece size inferred"
+            );
+        }
+        piece.size = size.0;
+        piece.payload_size = (len != size.0).then_some(len);
+    }
+    let value = serde_json::to_value(&pieces).context("serialize pieces")?;
+    task_schema::validate(TaskFormat::Pieces, &value)?;
+    piece_dir::resolve(&mut pieces)?;
+    checksums::resolve(&mut pieces)?;
+    Ok(pieces)
+}
+
+/// The pieces JSON of `add_pieces` as an object, giving the options of its
+/// task along with its pieces.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PiecesTask {
+    pieces: serde_json::Value,
+    /// Overrides `staged_open` for the staged file of the task.
+    #[serde(default)]
+    staged_open: Option<StagedOpenMode>,
+}
+
+/// Splits the pieces of `pieces_json`, a list of pieces or a `PiecesTask`,
+/// from the mode to open their staged file in.
+fn split_task_options(pieces_json: &str) -> Result<(String, StagedOpenMode)> {
+    let staged_open = config::global().staged_open;
+    if !pieces_json.trim_start().starts_with('{') {
+        return Ok((pieces_json.to_string(), staged_open));
+    }
+    let task: PiecesTask = serde_json::from_str(pieces_json).context("parse task")?;
+    Ok((
+        task.pieces.to_string(),
+        task.staged_open.unwrap_or(staged_open),
+    ))
+}
+
+/// Stages the pieces listed by `pieces_json` into `out` and returns the lines
+/// to print: their piece infos, then any expired pieces, checksums and
+/// duplicates.
+fn add_pieces_target(
+    pieces_json: &str,
+    out: &Path,
+    origin: bool,
+    infer_sizes: bool,
+    proof: Option<RegisteredSealProof>,
+) -> Result<Vec<String>> {
+    let (pieces_json, staged_open) = split_task_options(pieces_json)?;
+    let pieces = match infer_sizes {
+        true => parse_pieces_inferring_sizes(&pieces_json)?,
+        false => parse_pieces(&pieces_json)?,
+    };
+    let (unique, duplicates) = duplicates::resolve(
+        config::global().duplicate_pieces,
+        pieces.iter().map(PieceFile::keys),
+    )
+    .context("task rejected")?;
+    let pieces: Vec<_> = unique.iter().map(|i| pieces[*i].clone()).collect();
+    if let Some(proof) = proof {
+        check_sector_fit(proof, &pieces).context("task rejected")?;
+    }
+    if let Some(limits) = &config::global().task_limits {
+        let sizes: Vec<_> = pieces.iter().map(|p| p.size).collect();
+        limits.check(&sizes).context("task rejected")?;
+    }
+    let deadlines = config::global().deadlines.clone().unwrap_or_default();
+    let (live, mut expired) = deadline::split_expired(&pieces, &deadlines);
+    // report the indexes in the task as given
+    let indexes: Vec<_> = live.iter().map(|i| unique[*i]).collect();
+    let pieces: Vec<_> = live.into_iter().map(|i| pieces[i].clone()).collect();
+    expired.iter_mut().for_each(|e| e.index = unique[e.index]);
+
+    let recorder = start_recording(|| {
+        Ok(RecordedTask::AddPieces {
+            pieces: pieces.clone(),
+            origin,
+            proof,
+        })
+    });
+
+    let started = SystemTime::now();
+    let mut io = TaskIoStats::default();
+    let sources = Sources::Live(recorder.as_ref());
+    let res = match remote_target(out) {
+        Some(target) => target.and_then(|target| {
+            let name = out.display().to_string();
+            remote::add_pieces(&pieces, target, &name, origin, proof, &mut io, &sources)
+        }),
+        None => add_pieces(&pieces, out, origin, proof, staged_open, &mut io, &sources),
+    };
+    finish_recording(recorder, &res);
+
+    let io = io.report();
+    info!(?io, "add_pieces io stats");
+    webhook::notify(&config::global().webhooks, out, &res, &io);
+    let proof_type = proof.map(seal_proof::name).unwrap_or_default();
+    ledger::record(out, &proof_type, started, &res);
+    let piece_infos = res?;
+    let mut lines = vec![format!("{:?}", piece_infos)];
+    if !expired.is_empty() {
+        lines.push(format!("expired: {}", serde_json::to_string(&expired)?));
+    }
+    if config::global().source_checksums.is_some() || pieces.iter().any(|p| p.sha256.is_some()) {
+        let statuses: Vec<_> = indexes
+            .iter()
+            .zip(&pieces)
+            .map(|(index, piece)| ChecksumStatus::of(*index, piece))
+            .collect();
+        lines.push(format!("checksums: {}", serde_json::to_string(&statuses)?));
+    }
+    if config::global().duplicate_pieces == DuplicatePolicy::Dedupe && !duplicates.is_empty() {
+        lines.push(format!(
+            "duplicates: {}",
+            serde_json::to_string(&duplicates)?
+        ));
+    }
+    Ok(lines)
+}
+
+/// Writes the output `lines` of a target to `out`, prefixed by `label` if
+/// given to tell targets apart.
+fn write_target_lines(out: &mut impl Write, label: Option<&Path>, lines: &[String]) -> Result<()> {
+    for line in lines {
+        match label {
+            Some(label) => writeln!(out, "{}: {}", label.display(), line)?,
+            None => writeln!(out, "{}", line)?,
+        }
+    }
+    Ok(())
+}
+
+/// Opens the remote store `out` points to, or returns `None` for a local path.
+fn remote_target(out: &Path) -> Option<Result<Box<dyn StagedTarget>>> {
+    let out = out.to_str()?;
+    if out.starts_with("http://") || out.starts_with("https://") {
+        let config = config::global().http_target.clone().unwrap_or_default();
+        return Some(
+            http_target::HttpUpload::create(&config, out)
+                .map(|upload| Box::new(upload) as Box<dyn StagedTarget>),
+        );
+    }
+
+    let location = s3::S3Location::parse(out)?;
+    Some(location.and_then(|location| {
+        let config = config::global()
+            .s3
+            .as_ref()
+            .ok_or_else(|| anyhow!("no s3 store configured for {}", out))?;
+        let upload = s3::MultipartUpload::create(config, location)?;
+        Ok(Box::new(upload) as Box<dyn StagedTarget>)
+    }))
+}
+
+/// Re-runs a recorded task into a fresh staged file and checks that it yields
+/// the recorded result.
+fn replay(dir: &Path, out: Option<&PathBuf>, config_path: Option<PathBuf>) -> Result<()> {
+    let recording = Recording::load(dir)?;
+    let mut config = match config_path {
+        Some(config_path) => config::Config::load(config_path)?,
+        None => recording.config.clone(),
+    };
+    config.target_profile.apply(&mut config);
+    config::init(config);
+    scratch::cleanup_on_signal()?;
+
+    let out = match out {
+        Some(out) => {
+            if out.exists() {
+                bail!("replay target {} already exists", out.display());
+            }
+            out.clone()
+        }
+        None => {
+            let out = dir.join("replay.staged");
+            for p in [out.clone(), add_piece::manifest::Manifest::path_for(&out)] {
+                if p.exists() {
+                    fs::remove_file(&p).with_context(|| format!("remove {}", p.display()))?;
+                }
+            }
+            out
+        }
+    };
+    info!(recording = %dir.display(), out = %out.display(), "replaying task");
+
+    let mut io = TaskIoStats::default();
+    let sources = Sources::Replay(&recording);
+    let res = match &recording.task {
+        RecordedTask::Processor { task } => {
+            let mut task: AddPieces =
+                serde_json::from_value(task.clone()).context("parse recorded task")?;
+            task.staged_filepath = out;
+            process_add_pieces(task, &mut io, &sources, config::global().duplicate_pieces)
+        }
+        RecordedTask::AddPieces {
+            pieces,
+            origin,
+            proof,
+        } => add_pieces(
+            pieces,
+            &out,
+            *origin,
+            *proof,
+            config::global().staged_open,
+            &mut io,
+            &sources,
+        ),
+    };
+
+    let replayed = RecordedResult::new(&res);
+    println!("{}", serde_json::to_string_pretty(&replayed)?);
+
+    match &recording.result {
+        None => warn!("the recording has no result to compare with"),
+        Some(recorded) if recorded.piece_infos != replayed.piece_infos => {
+            return Err(anyhow!(
+                "replay diverged from the recording, recorded: {:?}",
+                recorded
+            ));
+        }
+        Some(_) => info!("replay matches the recording"),
+    }
+
+    Ok(())
+}
+
+fn processor(tui: bool, dry_run: bool, drop_to: &privileges::DropTo) -> Result<()> {
+    if tui {
+        #[cfg(feature = "tui")]
+        tui::spawn()?;
+        #[cfg(not(feature = "tui"))]
+        bail!("--tui needs add_piece to be built with the tui feature");
+    }
+    let dry_run = dry_run || env::var("ADD_PIECE_DRY_RUN").is_ok_and(|v| v == "1");
+    if dry_run {
+        warn!("dry run, tasks are fetched and hashed but nothing is staged");
+        DRY_RUN.store(true, Ordering::Relaxed);
+    }
+    if let Some(metrics) = &config::global().metrics {
+        metrics::serve(metrics)?;
+    }
+    drop_to.apply()?;
+    target_health::start_probes(&config::global().target_health)
+        .context("start staging directory probes")?;
+    if let Some(memory_pressure) = &config::global().memory_pressure {
+        psi::start(memory_pressure)?;
+    }
+    registration::start(config::global().registration.as_ref())?;
     info!("start add_pieces consumer");
     run_consumer::<AddPieces, AddPiecesProcessor>()
 }
 
+/// Checks the local piece `files`, given with their payload and piece sizes,
+/// against the source policy and their lengths before anything is staged into
+/// `staged`, `None` for remote targets. Replayed sources aren't read from the
+/// files.
+fn check_sources<'a>(
+    sources: &Sources,
+    files: impl IntoIterator<Item = (&'a Path, u64, u64)>,
+    staged: Option<&Path>,
+) -> Result<()> {
+    if let Sources::Live(_) = sources {
+        let config = config::global();
+        for (path, payload_size, piece_size) in files {
+            config.source_paths.check(path, staged)?;
+            if source::is_empty_file(path) {
+                if config.empty_source == EmptySourcePolicy::Error {
+                    return Err(anyhow::Error::new(EmptySource { piece_size })
+                        .context(format!("piece file {}", path.display())));
+                }
+                // padded up to its piece whatever its declared size
+                continue;
+            }
+            config
+                .source_size_check
+                .check(path, payload_size, piece_size)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fails if `piece_sizes`, written back to back without alignment as the
+/// processor writes them, overflow a sector of `sector_size` bytes.
+fn check_back_to_back_fit(piece_sizes: &[UnpaddedBytesAmount], sector_size: u64) -> Result<()> {
+    let end: u64 = piece_sizes
+        .iter()
+        .map(|size| u64::from(PaddedBytesAmount::from(*size)))
+        .sum();
+    ensure!(
+        end <= sector_size,
+        "the pieces end at {} padded bytes, past the {} bytes sector",
+        end,
+        sector_size
+    );
+    Ok(())
+}
+
+/// Fails if `pieces`, aligned one after another, overflow a sector of `proof`.
+fn check_sector_fit(proof: RegisteredSealProof, pieces: &[PieceFile]) -> Result<()> {
+    let sector_size = u64::from(proof.sector_size());
+    let sizes: Vec<_> = pieces.iter().map(|p| UnpaddedBytesAmount(p.size)).collect();
+    if let Some(last) = pure::plan_pieces(&sizes)?.last() {
+        ensure!(
+            u64::from(last.end()) <= sector_size,
+            "the pieces end at {} padded bytes, past the {} bytes sectors of {}",
+            u64::from(last.end()),
+            sector_size,
+            seal_proof::name(proof)
+        );
+    }
+    Ok(())
+}
+
+/// Stages the piece of `spec` again with the origin `filecoin_proofs`
+/// implementation if `err`, from staging it first, is a commitment not matching its
+/// `piece_cid`. Both commitments are reported: a piece staged by origin with
+/// the expected one points at a bug of the new implementation.
+fn retry_with_origin(
+    staged: &mut StagedFile,
+    spec: &PieceSpec,
+    piece_lengths: &[UnpaddedBytesAmount],
+    open_source: &Opener,
+    err: anyhow::Error,
+) -> Result<PieceInfo> {
+    let mismatch = match err
+        .chain()
+        .find_map(|e| e.downcast_ref::<piece_dir::CidMismatch>())
+    {
+        Some(mismatch) => mismatch.clone(),
+        None => return Err(err),
+    };
+    if config::global().encryption.is_some() {
+        warn!("origin doesn't encrypt payloads, not retrying the piece with it");
+        return Err(err);
+    }
+    warn!(
+        source = %spec.source,
+        expected = %mismatch.expected,
+        actual = %mismatch.actual,
+        "commitment mismatch, retrying the piece with origin"
+    );
+
+    // a piece not matching with origin either is dropped like any failed one
+    let mut origin = None;
+    let res = staged.add(spec, piece_lengths, None, None, None, |target_file| {
+        let source = open_source()?;
+        let res = filecoin_proofs::add_piece(source, target_file, spec.piece_size, piece_lengths)
+            .context("add_piece with origin")?;
+        let cid = piece_cid::encode(&res.0.commitment);
+        let matches = cid == mismatch.expected;
+        origin = Some(cid);
+        ensure!(matches, "origin commitment doesn't match either");
+        Ok(res)
+    });
+    match (&res, origin) {
+        (Ok(_), Some(cid)) => error!(
+            source = %spec.source,
+            expected = %mismatch.expected,
+            new = %mismatch.actual,
+            origin = %cid,
+            "commitment diverged from origin, the piece was staged with origin"
+        ),
+        (_, Some(cid)) if cid == mismatch.actual => warn!(
+            source = %spec.source,
+            expected = %mismatch.expected,
+            origin = %cid,
+            "origin agrees with the new implementation, the piece_cid is wrong"
+        ),
+        (_, Some(cid)) => error!(
+            source = %spec.source,
+            expected = %mismatch.expected,
+            new = %mismatch.actual,
+            origin = %cid,
+            "commitment diverged from origin, neither matches the piece_cid"
+        ),
+        (_, None) => {}
+    }
+    res.map_err(|e| err.context(format!("retried with origin: {:#}", e)))
+}
+
+/// Stages `pieces` into `out`, a sector of `proof` if given: its pieces are
+/// checked to fit and the proof is recorded in v2 staged files. An existing
+/// `out` is opened as `staged_open` says.
 fn add_pieces(
-    pieces: &Vec<PieceFile>,
+    pieces: &[PieceFile],
     out: impl AsRef<Path>,
     origin: bool,
+    proof: Option<RegisteredSealProof>,
+    staged_open: StagedOpenMode,
+    io: &mut TaskIoStats,
+    sources: &Sources,
 ) -> Result<Vec<PieceInfo>> {
-    let target_file = fs::OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        // to make sure that we won't write into the staged file with any data exists
-        .truncate(true)
-        .open(out.as_ref())
-        .with_context(|| format!("open staged file: {}", out.as_ref().display()))?;
+    let out = out.as_ref();
+    redact::register_path(out);
+    for piece in pieces {
+        redact::register_path(&piece.path);
+    }
+    check_sources(
+        sources,
+        pieces.iter().map(PieceFile::declared_size),
+        Some(out),
+    )?;
+
+    let scratch = TaskScratch::create(out.parent())?;
+    let mut options = config::global().add_piece_options()?;
+    let seen_chunks = seen_chunks::watch(&mut options)?;
+    let target_device = iostats::device_of(out);
+    let proof_type = proof.map(seal_proof::name).unwrap_or_default();
+    let lease = claim_lease(out)?;
+    let events = task_events(
+        out,
+        pieces.iter().map(|p| p.size).collect(),
+        &proof_type,
+        lease,
+    );
+    let _writing = mount_limits::acquire(out, Access::Write);
+    let provenance = Provenance::new(options.committer, Some(scratch.task_id()));
+    let mut staged = StagedFile::open(out, &proof_type, provenance, staged_open)?;
+    let unpadded_copy = UnpaddedCopy::open(out)?;
 
     let mut piece_infos = Vec::with_capacity(pieces.len());
-    for piece in pieces {
-        let source = fs::File::open(&piece.path).context("open piece file")?;
-        let piece_size = UnpaddedBytesAmount(piece.size);
-        let (piece_info, _) = if origin {
-            filecoin_proofs::write_and_preprocess(source, &target_file, piece_size)
-                .context("write_and_preprocess")?
-        } else {
-            add_piece::add_piece(source, &target_file, piece_size, Default::default())
-                .context("add_piece")?
+    for (index, piece) in pieces.iter().enumerate() {
+        let spec = PieceSpec {
+            source: piece.path.display().to_string(),
+            payload_size: piece.payload_size(),
+            piece_size: UnpaddedBytesAmount(piece.size),
+            payload_sha256: piece.sha256.clone(),
+        };
+        let open_source = sources.opener(index, piece.opener());
+        let _reading = mount_limits::acquire(&piece.path, Access::Read);
+
+        if let Some(piece_info) = staged.try_reuse(&spec, || open_source())? {
+            piece_dir::check(piece, &piece_info)?;
+            piece_infos.push(piece_info);
+            continue;
+        }
+
+        let piece_lengths = staged.piece_lengths()?;
+        let (mut options, stream) = verifier::stream_piece(
+            config::global().chunk_verifier.as_ref(),
+            &options,
+            out,
+            index,
+            &spec.source,
+        );
+        let piece_events =
+            start_piece_events(&events, index, &spec.source, piece.size, &mut options);
+        let retry_source = open_source.clone();
+        let mut source = watchdog::watch(
+            config::global().stall_watchdog.as_ref(),
+            &spec.source,
+            PieceSource::open(config::global().read_ahead, move || open_source())?,
+        );
+        let chunk_roots = collect_chunk_roots(&mut options);
+        let car_index = collect_car_index(&mut options);
+        let payload_hash = collect_payload_hash(&mut options, piece.payload_size());
+        let encryption = keys::encrypt_payload(
+            config::global().encryption.as_ref(),
+            &mut options,
+            &spec.source,
+        )?;
+        options.payload_root = piece.payload_root()?;
+        staged.journal_chunks(&spec, &piece_lengths, &mut options)?;
+        let mut copy = PieceCopy::of(
+            unpadded_copy.as_ref(),
+            staged.piece_offset(&piece_lengths, spec.piece_size)?,
+            spec.piece_size,
+        )?;
+        let started = Instant::now();
+        let mut times = (Duration::ZERO, Duration::ZERO, Duration::ZERO);
+        let res = staged.add(
+            &spec,
+            &piece_lengths,
+            Some(&chunk_roots),
+            encryption.as_deref(),
+            payload_hash.as_deref(),
+            |target_file| {
+                let mut target = Metered::new(target_file);
+                let res = with_write_behind(&mut target, config::global().write_behind, |w| {
+                    let mut hashed = Sha256Reader::new(&mut source, piece);
+                    let mut reads = Metered::new(&mut hashed);
+                    let mut tee = TeeReader::new(&mut reads, &mut copy);
+                    let mut writes = Metered::new(w);
+                    let res = match (origin, proof) {
+                        (true, _) => filecoin_proofs::add_piece(
+                            ProgressReader::new(&mut tee, Some(&piece_events)).0,
+                            &mut writes,
+                            spec.piece_size,
+                            &piece_lengths,
+                        ),
+                        (false, Some(proof)) => add_piece::add_piece_for_proof(
+                            proof,
+                            &mut tee,
+                            &mut writes,
+                            spec.piece_size,
+                            &piece_lengths,
+                            &options,
+                        ),
+                        (false, None) => add_piece::add_piece_with_options(
+                            &mut tee,
+                            &mut writes,
+                            spec.piece_size,
+                            &piece_lengths,
+                            &options,
+                        ),
+                    };
+                    times = (started.elapsed(), reads.stats().busy, writes.stats().busy);
+                    res.context("add_piece").and_then(|res| {
+                        // a payload not matching its sha256 isn't retried with origin
+                        hashed.check()?;
+                        piece_dir::check(piece, &res.0)?;
+                        Ok(res)
+                    })
+                });
+                io.record_target(&target_device, target.stats());
+                res
+            },
+        );
+        let res = match res {
+            Err(e) if !origin && config::global().origin_retry => {
+                retry_with_origin(&mut staged, &spec, &piece_lengths, &retry_source, e)
+            }
+            res => res,
         };
+        let res = match stream {
+            Some(s) => s.finish(res),
+            None => res,
+        };
+        piece_events.finished(&res);
+        let piece_info = res?;
+        io.record_piece(index, times.0, times.1, times.2);
+        io.record_source(&iostats::device_of(&piece.path), source.get_ref().stats());
+        save_car_index(&scratch, car_index, &piece_info)?;
         piece_infos.push(piece_info);
     }
 
+    let manifest = staged.finish()?;
+    if let Some(copy) = unpadded_copy {
+        copy.finish(manifest.end())?;
+    }
+    if let Some(seen_chunks) = seen_chunks {
+        seen_chunks.finish(out)?;
+    }
     Ok(piece_infos)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vc_processors::builtin::tasks::Piece;
+
+    use crate::mock_http::{MockServer, Response};
+
+    #[test]
+    fn test_processor_keeps_duplicate_pieces() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let piece_path = dir.path().join("piece");
+        fs::write(&piece_path, vec![7u8; 1016]).unwrap();
+        let piece = Piece {
+            piece_file: piece::PieceFile::Local(piece_path),
+            payload_size: 1016,
+            piece_size: UnpaddedBytesAmount(1016),
+        };
+        let task = AddPieces {
+            seal_proof_type: RegisteredSealProof::StackedDrg2KiBV1_1,
+            pieces: vec![piece.clone(), piece],
+            staged_filepath: dir.path().join("staged"),
+        };
+
+        let mut io = TaskIoStats::default();
+        let sources = Sources::Live(None);
+        let dry_run =
+            dry_run_add_pieces(task.clone(), &mut io, &sources, DuplicatePolicy::Dedupe).unwrap();
+        let piece_infos =
+            process_add_pieces(task, &mut io, &sources, DuplicatePolicy::Dedupe).unwrap();
+        assert_eq!(piece_infos.len(), 2);
+        assert_eq!(piece_infos[0], piece_infos[1]);
+        assert_eq!(dry_run, piece_infos);
+    }
+
+    #[test]
+    fn test_processor_pieces_back_to_back() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let pieces: Vec<_> = [254, 1016, 508, 1016]
+            .iter()
+            .enumerate()
+            .map(|(i, len)| {
+                let path = dir.path().join(format!("piece-{}", i));
+                fs::write(&path, vec![i as u8; *len]).unwrap();
+                Piece {
+                    piece_file: piece::PieceFile::Local(path),
+                    payload_size: *len as u64,
+                    piece_size: UnpaddedBytesAmount(*len as u64),
+                }
+            })
+            .collect();
+        let task = |pieces: &[Piece], name| AddPieces {
+            seal_proof_type: RegisteredSealProof::StackedDrg2KiBV1_1,
+            pieces: pieces.to_vec(),
+            staged_filepath: dir.path().join(name),
+        };
+
+        // would end past the sector aligned, fits back to back
+        let mut io = TaskIoStats::default();
+        let sources = Sources::Live(None);
+        let fitting = task(&pieces[..3], "fitting");
+        let dry_run =
+            dry_run_add_pieces(fitting.clone(), &mut io, &sources, DuplicatePolicy::Warn).unwrap();
+        let piece_infos =
+            process_add_pieces(fitting, &mut io, &sources, DuplicatePolicy::Warn).unwrap();
+        assert_eq!(dry_run, piece_infos);
+
+        let overflowing = task(&pieces[1..], "overflowing");
+        dry_run_add_pieces(
+            overflowing.clone(),
+            &mut io,
+            &sources,
+            DuplicatePolicy::Warn,
+        )
+        .expect_err("dry run accepted an overflowing task");
+        process_add_pieces(overflowing, &mut io, &sources, DuplicatePolicy::Warn)
+            .expect_err("processor accepted an overflowing task");
+        assert!(!dir.path().join("overflowing").exists());
+    }
+
+    #[test]
+    fn test_open_url() {
+        let server = MockServer::start(|_| Response::new(200).body("payload"));
+        let headers = [("Authorization".to_string(), "Bearer x".to_string())].into();
+        let url = format!("{}/piece", server.url());
+        let mut piece = Vec::new();
+        open_url(&url, &headers, 7, 10)
+            .unwrap()
+            .read_to_end(&mut piece)
+            .unwrap();
+        assert_eq!(piece, b"payload\0\0\0");
+        assert_eq!(
+            server.requests()[0].header("Authorization"),
+            Some("Bearer x")
+        );
+    }
+
+    #[test]
+    fn test_split_task_options() {
+        let pieces = r#"[{"path": "piece", "size": 1016}]"#;
+        let (json, mode) = split_task_options(pieces).unwrap();
+        assert_eq!(json, pieces);
+        assert_eq!(mode, StagedOpenMode::TruncateExisting);
+
+        let task = format!(
+            r#" {{"pieces": {}, "staged_open": "fail_if_exists"}}"#,
+            pieces
+        );
+        let (json, mode) = split_task_options(&task).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::from_str::<serde_json::Value>(pieces).unwrap()
+        );
+        assert_eq!(mode, StagedOpenMode::FailIfExists);
+
+        assert!(split_task_options(r#"{"pieces": [], "staged": "x"}"#).is_err());
+    }
+}
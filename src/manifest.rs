@@ -0,0 +1,388 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, UnpaddedBytesAmount};
+use fr32::Fr32Reader;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    committer::CommitterBackend, dedup::ChunkRoot, encryption::PieceEncryption, pure, CHUNK_SIZE,
+};
+
+/// Number of unpadded bytes compared by `spot_check`, one fr32 block.
+const SPOT_CHECK_BYTES: u64 = 127;
+
+/// Records the pieces written into a staged file.
+///
+/// The manifest is stored next to the staged file as `<staged>.manifest.json`
+/// and rewritten after every piece, so it always describes a valid prefix of
+/// the staged file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub pieces: Vec<ManifestPiece>,
+    /// Where and how the staged file was produced, for audits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// Signature of the worker which produced the staged file over its
+    /// pieces, CommD and provenance, when it was configured to sign.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<Attestation>,
+}
+
+/// An ed25519 signature over what a manifest records of a staged file, so
+/// that the hosts sealing it can tell which worker produced it.
+///
+/// The signed statement is derived from the manifest rather than stored, see
+/// the `verify-attestation` command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attestation {
+    pub worker: String,
+    /// CID of the CommD of the sector, when the pieces fill one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comm_d: Option<String>,
+    /// Unix seconds the manifest was signed at.
+    pub signed_at: u64,
+    /// Hex encoded ed25519 public key of the worker.
+    pub public_key: String,
+    /// Hex encoded ed25519 signature of the statement.
+    pub signature: String,
+}
+
+/// Records which build produced a staged file, on which host and for which
+/// task, so that its origin can be traced long after it was sealed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub crate_version: String,
+    pub filecoin_proofs_version: String,
+    /// Backend the chunks of the pieces were hashed with.
+    pub hash_backend: CommitterBackend,
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    /// Unix seconds the staged file was started at.
+    pub started_at: u64,
+    /// Unix seconds the staged file was completed at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+impl Provenance {
+    /// The provenance of a staged file started now by this build, with the
+    /// backend `hash_backend` resolves to.
+    pub fn new(hash_backend: CommitterBackend, task_id: Option<String>) -> Self {
+        Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            filecoin_proofs_version: env!("FILECOIN_PROOFS_VERSION").to_string(),
+            hash_backend: hash_backend.resolve(),
+            host: hostname(),
+            task_id,
+            started_at: unix_now(),
+            finished_at: None,
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.finished_at = Some(unix_now());
+    }
+}
+
+/// Seconds since the Unix epoch, zero if the clock is set before it.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Host name of the machine, `unknown` if it can't be found.
+pub fn hostname() -> String {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .into_iter()
+        .find_map(|var| std::env::var(var).ok())
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestPiece {
+    /// Where the piece was read from, as given by the task.
+    pub source: String,
+    pub payload_size: u64,
+    pub piece_info: PieceInfo,
+    /// Padded offset of the piece data in the staged file.
+    pub offset: u64,
+    /// Padded bytes occupied by the piece from `offset`, including its right
+    /// alignment; left alignment lies before `offset`.
+    pub len: u64,
+    /// Roots of the `CHUNK_SIZE` chunks of the piece, in order, when they were
+    /// recorded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "hex_roots")]
+    pub chunk_roots: Vec<ChunkRoot>,
+    /// How the payload was encrypted before being padded, if it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<PieceEncryption>,
+    /// Alignment bytes around the piece, when they were more than the
+    /// configured `padding_warning` allows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excessive_alignment: Option<u64>,
+    /// Hex encoded blake3 hash of the payload, when `payload_blake3` was
+    /// configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_blake3: Option<String>,
+    /// Hex encoded sha256 of the piece file the piece was checked against
+    /// while it was added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_sha256: Option<String>,
+}
+
+impl ManifestPiece {
+    /// Padded offset right after this piece and its alignment.
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+
+    pub fn padded_size(&self) -> PaddedBytesAmount {
+        self.piece_info.size.into()
+    }
+
+    /// Padded ranges of the staged file covered by the chunks of the piece, in
+    /// the order of `chunk_roots`.
+    pub fn chunk_ranges(&self) -> Vec<Range<u64>> {
+        let padded_size = u64::from(self.padded_size());
+        let chunk_size = padded_size.min(CHUNK_SIZE as u64);
+        (0..padded_size / chunk_size)
+            .map(|i| {
+                let start = self.offset + i * chunk_size;
+                start..start + chunk_size
+            })
+            .collect()
+    }
+
+    /// Checks the recorded commitment against the recorded chunk roots, or
+    /// returns `None` if there are none.
+    ///
+    /// This only hashes the roots together, so it is cheap enough for periodic
+    /// audits but does not read the staged data.
+    pub fn verify_chunk_roots(&self) -> Result<Option<bool>> {
+        if self.chunk_roots.is_empty() {
+            return Ok(None);
+        }
+        let comm = pure::reduce_chunk_roots(&self.chunk_roots)?;
+        Ok(Some(comm == self.piece_info.commitment))
+    }
+}
+
+mod hex_roots {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use crate::dedup::ChunkRoot;
+
+    pub fn serialize<S: Serializer>(roots: &[ChunkRoot], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(roots.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<ChunkRoot>, D::Error> {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|s| {
+                let mut root = [0u8; 32];
+                hex::decode_to_slice(s, &mut root).map_err(D::Error::custom)?;
+                Ok(root)
+            })
+            .collect()
+    }
+}
+
+impl Manifest {
+    /// Returns the manifest path for the given staged file.
+    pub fn path_for(staged: impl AsRef<Path>) -> PathBuf {
+        let mut p = OsString::from(staged.as_ref().as_os_str());
+        p.push(".manifest.json");
+        PathBuf::from(p)
+    }
+
+    /// Loads the manifest of the given staged file, if there is one.
+    pub fn load(staged: impl AsRef<Path>) -> Result<Option<Self>> {
+        let path = Self::path_for(staged);
+        let content = match fs::read(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("read manifest: {}", path.display())),
+        };
+
+        serde_json::from_slice(&content)
+            .map(Some)
+            .with_context(|| format!("parse manifest: {}", path.display()))
+    }
+
+    /// Atomically replaces the manifest of the given staged file.
+    pub fn save(&self, staged: impl AsRef<Path>) -> Result<()> {
+        let path = Self::path_for(staged);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let content = serde_json::to_vec_pretty(self).context("serialize manifest")?;
+        fs::write(&tmp, content).with_context(|| format!("write manifest: {:?}", tmp))?;
+        fs::rename(&tmp, &path).with_context(|| format!("rename manifest: {}", path.display()))
+    }
+
+    /// Padded offset right after the last recorded piece.
+    pub fn end(&self) -> u64 {
+        self.pieces.last().map(ManifestPiece::end).unwrap_or(0)
+    }
+
+    /// Returns the `piece_lengths` to pass to `add_piece` for a piece appended
+    /// after the recorded ones, checking that the recorded offsets and lengths
+    /// follow the layout `add_piece` produces.
+    pub fn piece_lengths(&self) -> Result<Vec<UnpaddedBytesAmount>> {
+        let infos: Vec<_> = self.pieces.iter().map(|p| p.piece_info.clone()).collect();
+        let lengths = pure::piece_lengths(&infos)?;
+        let placements = pure::plan_pieces(&lengths)?;
+
+        for (i, (piece, placement)) in self.pieces.iter().zip(&placements).enumerate() {
+            ensure!(
+                piece.offset == u64::from(placement.offset)
+                    && piece.end() == u64::from(placement.end()),
+                "piece {} is recorded at {}..{}, expected {}..{}",
+                i,
+                piece.offset,
+                piece.end(),
+                u64::from(placement.offset),
+                u64::from(placement.end()),
+            );
+        }
+
+        Ok(lengths)
+    }
+}
+
+/// Cheaply checks that the staged bytes of `piece` were produced from `source`,
+/// by comparing the first fr32 block of the piece against the padded source.
+pub fn spot_check<R, S>(source: R, mut staged: S, piece: &ManifestPiece) -> Result<bool>
+where
+    R: Read,
+    S: Read + Seek,
+{
+    let mut expected = Vec::new();
+    Fr32Reader::new(source.take(SPOT_CHECK_BYTES))
+        .read_to_end(&mut expected)
+        .context("read piece source")?;
+    expected.truncate(u64::from(piece.padded_size()) as usize);
+
+    let mut actual = vec![0u8; expected.len()];
+    staged.seek(SeekFrom::Start(piece.offset))?;
+    match staged.read_exact(&mut actual) {
+        Ok(()) => Ok(actual == expected),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).context("read staged file"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_spot_check() {
+        let source = vec![7u8; 127 * 8];
+        let mut staged = vec![0u8; 128];
+        Fr32Reader::new(Cursor::new(&source))
+            .read_to_end(&mut staged)
+            .expect("failed to pad source");
+
+        let piece = ManifestPiece {
+            source: "test".to_string(),
+            payload_size: source.len() as u64,
+            piece_info: PieceInfo {
+                commitment: [0u8; 32],
+                size: UnpaddedBytesAmount(source.len() as u64),
+            },
+            offset: 128,
+            len: 1024,
+            chunk_roots: Vec::new(),
+            encryption: None,
+            excessive_alignment: None,
+            payload_blake3: None,
+            payload_sha256: None,
+        };
+
+        assert!(spot_check(Cursor::new(&source), Cursor::new(&staged), &piece).unwrap());
+        assert!(!spot_check(Cursor::new(vec![8u8; 127]), Cursor::new(&staged), &piece).unwrap());
+        staged.truncate(200);
+        assert!(!spot_check(Cursor::new(&source), Cursor::new(&staged), &piece).unwrap());
+    }
+
+    #[test]
+    fn test_piece_lengths() {
+        let piece = |offset, len, size| ManifestPiece {
+            source: "test".to_string(),
+            payload_size: size,
+            piece_info: PieceInfo {
+                commitment: [0u8; 32],
+                size: UnpaddedBytesAmount(size),
+            },
+            offset,
+            len,
+            chunk_roots: Vec::new(),
+            encryption: None,
+            excessive_alignment: None,
+            payload_blake3: None,
+            payload_sha256: None,
+        };
+
+        let mut manifest = Manifest {
+            pieces: vec![
+                piece(0, 128, 127),
+                piece(128, 128, 127),
+                piece(256, 256, 254),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            manifest.piece_lengths().expect("piece_lengths failed"),
+            [127, 127, 254].map(UnpaddedBytesAmount)
+        );
+
+        assert_eq!(manifest.pieces[2].chunk_ranges(), vec![256..512]);
+        manifest.pieces[2].piece_info.size = PaddedBytesAmount(4 * CHUNK_SIZE as u64).into();
+        assert_eq!(
+            manifest.pieces[2].chunk_ranges()[3],
+            256 + 3 * CHUNK_SIZE as u64..256 + 4 * CHUNK_SIZE as u64
+        );
+        manifest.pieces[2].piece_info.size = UnpaddedBytesAmount(254);
+
+        // a 2 nodes piece can't start at node 1
+        manifest.pieces.remove(1);
+        manifest.pieces[1].offset = 128;
+        assert!(manifest.piece_lengths().is_err());
+    }
+
+    #[test]
+    fn test_provenance() {
+        // manifests from before provenance was recorded still load
+        let old: Manifest = serde_json::from_str(r#"{"pieces":[]}"#).unwrap();
+        assert_eq!(old.provenance, None);
+
+        let mut provenance = Provenance::new(CommitterBackend::Auto, Some("task".to_string()));
+        assert_ne!(provenance.hash_backend, CommitterBackend::Auto);
+        assert_eq!(provenance.finished_at, None);
+        provenance.finish();
+        let manifest = Manifest {
+            pieces: Vec::new(),
+            provenance: Some(provenance),
+            attestation: None,
+        };
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+    }
+}
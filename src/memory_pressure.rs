@@ -0,0 +1,31 @@
+//! Whether the host is short of memory, as reported by whoever watches it,
+//! e.g. the processor following the PSI of its cgroup.
+//!
+//! Under pressure the parts of `add_piece` holding the most memory hold less
+//! of it, at the cost of speed, rather than get the process killed in the
+//! middle of a sector: a `ReadAhead` keeps a single buffer ahead of its
+//! consumer, the `auto` committer hashes new pieces `streaming`, which
+//! buffers no chunk, and the `parallel` committer hashes one chunk at a time
+//! across the pieces. Everything goes back to full speed once the pressure
+//! is gone.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+static UNDER_PRESSURE: AtomicBool = AtomicBool::new(false);
+
+/// Serializes the chunks hashed by the `parallel` committer under pressure.
+static CHUNK_GATE: Mutex<()> = Mutex::new(());
+
+pub fn set_under_pressure(under_pressure: bool) {
+    UNDER_PRESSURE.store(under_pressure, Ordering::Relaxed);
+}
+
+pub fn under_pressure() -> bool {
+    UNDER_PRESSURE.load(Ordering::Relaxed)
+}
+
+/// Held while hashing a chunk, `None` unless under pressure.
+pub(crate) fn chunk_gate() -> Option<MutexGuard<'static, ()>> {
+    under_pressure().then(|| CHUNK_GATE.lock().unwrap_or_else(|e| e.into_inner()))
+}
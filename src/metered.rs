@@ -0,0 +1,75 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Bytes moved through a `Metered` and the wall time spent doing so.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoStats {
+    pub bytes: u64,
+    pub busy: Duration,
+}
+
+impl IoStats {
+    pub fn bytes_per_sec(&self) -> f64 {
+        match self.busy.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    pub fn merge(&mut self, other: IoStats) {
+        self.bytes += other.bytes;
+        self.busy += other.busy;
+    }
+}
+
+/// Wraps a reader or a writer and accounts every call made to it, so the
+/// bandwidth of the source and of the target can be told apart.
+#[derive(Debug)]
+pub struct Metered<T> {
+    inner: T,
+    stats: IoStats,
+}
+
+impl<T> Metered<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            stats: IoStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> IoStats {
+        self.stats
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Metered<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let r = self.inner.read(buf)?;
+        self.stats.busy += start.elapsed();
+        self.stats.bytes += r as u64;
+        Ok(r)
+    }
+}
+
+impl<W: Write> Write for Metered<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let n = self.inner.write(buf)?;
+        self.stats.busy += start.elapsed();
+        self.stats.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let start = Instant::now();
+        self.inner.flush()?;
+        self.stats.busy += start.elapsed();
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+//! Prometheus metrics of the processor, served as text on `GET /metrics`
+//! along with the tasks in flight, see `task_api`.
+//!
+//! Pieces and tasks are labelled by seal proof type and result, pieces also
+//! by size bucket and source scheme, so that dashboards can break the
+//! performance of add_piece down by sector size and data source. Durations
+//! and throughputs are summaries whose quantiles cover the most recent
+//! observations of each series.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use add_piece::{
+    piece_events::{PieceEventSink, PieceRef},
+    sizes,
+};
+use anyhow::{Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{target_health, task_api};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address serving `GET /metrics` and `GET /tasks`, e.g.
+    /// `127.0.0.1:9464`.
+    pub listen: String,
+    /// Most recent observations of each series the quantiles are computed
+    /// over.
+    pub window: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:9464".to_string(),
+            window: 1024,
+        }
+    }
+}
+
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+#[derive(Debug, Default)]
+struct Summary {
+    recent: VecDeque<f64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Summary {
+    fn observe(&mut self, value: f64, window: usize) {
+        if self.recent.len() >= window.max(1) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn quantile(&self, q: f64) -> f64 {
+        let mut sorted: Vec<_> = self.recent.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        match sorted.len() {
+            0 => f64::NAN,
+            n => sorted[((n - 1) as f64 * q).round() as usize],
+        }
+    }
+}
+
+/// Labels of a series, in order.
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Registry {
+    window: usize,
+    /// Summaries by metric name and labels.
+    summaries: BTreeMap<(&'static str, Labels), Summary>,
+}
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+fn observe(name: &'static str, labels: &Labels, value: f64) {
+    let mut registry = REGISTRY.lock().expect("metrics poisoned");
+    // nothing is recorded until the metrics are served
+    if let Some(registry) = registry.as_mut() {
+        let window = registry.window;
+        registry
+            .summaries
+            .entry((name, labels.clone()))
+            .or_default()
+            .observe(value, window);
+    }
+}
+
+/// Padded size of a piece as a bucket label, e.g. `32GiB`.
+fn size_bucket(piece_size: u64) -> String {
+    let padded = PaddedBytesAmount::from(sizes::piece_size_for(piece_size));
+    sizes::human(padded.into())
+}
+
+/// Where the source of a piece is read from: the scheme of its URL, `file`
+/// otherwise. Processor sources are given as `Url("<url>")`.
+fn source_scheme(source: &str) -> String {
+    match source.find("://") {
+        Some(end) => source[..end]
+            .rsplit(|c: char| !c.is_ascii_alphanumeric() && c != '+')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase(),
+        None => "file".to_string(),
+    }
+}
+
+fn result(ok: bool) -> String {
+    match ok {
+        true => "ok",
+        false => "error",
+    }
+    .to_string()
+}
+
+/// Records the pieces of a task of a seal proof type as a piece event sink:
+/// their duration from `started` to `verified` or `failed`, and their
+/// throughput.
+#[derive(Debug)]
+pub struct PieceMetrics {
+    proof: String,
+    started: Mutex<BTreeMap<usize, Instant>>,
+}
+
+impl PieceMetrics {
+    pub fn new(proof: &str) -> Self {
+        Self {
+            proof: proof.to_string(),
+            started: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn record(&self, piece: &PieceRef, ok: bool) {
+        let started = self
+            .started
+            .lock()
+            .expect("metrics poisoned")
+            .remove(&piece.index);
+        let secs = match started {
+            Some(started) => started.elapsed().as_secs_f64(),
+            None => return,
+        };
+        let labels = vec![
+            ("proof", self.proof.clone()),
+            ("size_bucket", size_bucket(piece.piece_size)),
+            ("source", source_scheme(&piece.source)),
+            ("result", result(ok)),
+        ];
+        observe("add_piece_piece_duration_seconds", &labels, secs);
+        if ok && secs > 0.0 {
+            observe(
+                "add_piece_piece_throughput_bytes_per_second",
+                &labels,
+                piece.piece_size as f64 / secs,
+            );
+        }
+    }
+}
+
+impl PieceEventSink for PieceMetrics {
+    fn started(&self, piece: &PieceRef) {
+        self.started
+            .lock()
+            .expect("metrics poisoned")
+            .insert(piece.index, Instant::now());
+    }
+
+    fn verified(&self, piece: &PieceRef, _piece_info: &PieceInfo) {
+        self.record(piece, true);
+    }
+
+    fn failed(&self, piece: &PieceRef, _error: &anyhow::Error) {
+        self.record(piece, false);
+    }
+}
+
+/// Records a task of `proof` done, or failed, in `elapsed`.
+pub fn record_task<T>(proof: &str, elapsed: Duration, res: &Result<T>) {
+    let labels = vec![
+        ("proof", proof.to_string()),
+        ("result", result(res.is_ok())),
+    ];
+    observe(
+        "add_piece_task_duration_seconds",
+        &labels,
+        elapsed.as_secs_f64(),
+    );
+}
+
+fn help(name: &str) -> &'static str {
+    match name {
+        "add_piece_piece_duration_seconds" => "Time taken to stage a piece.",
+        "add_piece_piece_throughput_bytes_per_second" => {
+            "Unpadded bytes of a staged piece per second."
+        }
+        "add_piece_task_duration_seconds" => "Time taken by an AddPieces task.",
+        _ => "",
+    }
+}
+
+fn format_labels(labels: &[(&str, String)]) -> String {
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+    labels.join(",")
+}
+
+/// The metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    {
+        let registry = REGISTRY.lock().expect("metrics poisoned");
+        let mut last = "";
+        for ((name, labels), summary) in registry.iter().flat_map(|r| r.summaries.iter()) {
+            if *name != last {
+                let _ = writeln!(out, "# HELP {} {}", name, help(name));
+                let _ = writeln!(out, "# TYPE {} summary", name);
+                last = name;
+            }
+            let labels = format_labels(labels);
+            for q in QUANTILES {
+                let _ = writeln!(
+                    out,
+                    "{}{{{},quantile=\"{}\"}} {}",
+                    name,
+                    labels,
+                    q,
+                    summary.quantile(q)
+                );
+            }
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, summary.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, summary.count);
+        }
+    }
+
+    let statuses = target_health::statuses();
+    if !statuses.is_empty() {
+        let name = "add_piece_staging_dir_healthy";
+        let _ = writeln!(
+            out,
+            "# HELP {} Whether the staging directory passed its last probe.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for status in statuses {
+            let labels = format_labels(&[("dir", status.dir.display().to_string())]);
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, status.healthy as u8);
+        }
+    }
+    out
+}
+
+/// Starts recording the metrics and serves them from a background thread.
+pub fn serve(config: &MetricsConfig) -> Result<()> {
+    let listener = TcpListener::bind(&config.listen)
+        .with_context(|| format!("listen on {}", config.listen))?;
+    *REGISTRY.lock().expect("metrics poisoned") = Some(Registry {
+        window: config.window,
+        ..Default::default()
+    });
+    info!(addr = %listener.local_addr()?, "serving metrics");
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream
+                    .context("accept connection")
+                    .and_then(|mut s| respond(&mut s));
+                if let Err(e) = res {
+                    debug!(err = ?e, "metrics connection failed");
+                }
+            }
+            warn!("metrics listener closed");
+        })
+        .context("spawn metrics thread")?;
+    Ok(())
+}
+
+fn respond(stream: &mut TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut request = String::new();
+    BufReader::new(&mut *stream).read_line(&mut request)?;
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, content_type, body) = match (path, task_api::respond(path)) {
+        ("/metrics", _) => ("200 OK", "text/plain; version=0.0.4", render()),
+        (_, Some((status, body))) => (status, "application/json", body),
+        _ => ("404 Not Found", "text/plain", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
@@ -0,0 +1,303 @@
+//! Transforms of the source of a piece, chained ahead of its padding and
+//! commitment, and of the padded bytes written to the target.
+//!
+//! `add_piece` reads the source through the layers of
+//! `AddPieceOptions::source_layers` first, in order, then through its own:
+//! the payload hash, the content policy, the CAR verification and the
+//! encryption, each a no-op unless set. It writes through the layers of
+//! `AddPieceOptions::target_layers`, in order, then through the aligned
+//! writer buffering the writes to the target. New transforms are added as
+//! layers instead of into `add_piece`.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::aligned_writer::AlignedWriter;
+use crate::car::CarVerifier;
+use crate::content_type::{ContentPolicy, PolicyReader};
+use crate::encryption::{EncryptingReader, PayloadEncryption};
+use crate::payload_hash::{HashingReader, PayloadHash};
+use crate::AddPieceOptions;
+
+pub type BoxRead<'a> = Box<dyn Read + 'a>;
+pub type BoxWrite<'a> = Box<dyn Write + 'a>;
+
+/// Wraps the source of a piece, or what the previous layer made of it.
+pub trait SourceLayer: fmt::Debug + Send + Sync {
+    fn wrap<'a>(&'a self, inner: BoxRead<'a>) -> BoxRead<'a>;
+}
+
+/// Wraps the target of the padded bytes of a piece, alignment included, or
+/// the next layer.
+pub trait TargetLayer: fmt::Debug + Send + Sync {
+    fn wrap<'a>(&'a self, inner: BoxWrite<'a>) -> BoxWrite<'a>;
+}
+
+impl SourceLayer for PayloadHash {
+    fn wrap<'a>(&'a self, inner: BoxRead<'a>) -> BoxRead<'a> {
+        Box::new(HashingReader::new(inner, Some(self)))
+    }
+}
+
+impl SourceLayer for ContentPolicy {
+    fn wrap<'a>(&'a self, inner: BoxRead<'a>) -> BoxRead<'a> {
+        Box::new(PolicyReader::new(inner, Some(self)))
+    }
+}
+
+impl SourceLayer for PayloadEncryption {
+    fn wrap<'a>(&'a self, inner: BoxRead<'a>) -> BoxRead<'a> {
+        Box::new(EncryptingReader::new(inner, Some(self)))
+    }
+}
+
+/// Reads `source` through the custom layers of `options`, then through
+/// those `add_piece` builds from its other options.
+pub(crate) fn wrap_source<'a>(source: BoxRead<'a>, options: &'a AddPieceOptions) -> BoxRead<'a> {
+    let mut source = options
+        .source_layers
+        .iter()
+        .fold(source, |source, layer| layer.wrap(source));
+
+    if let Some(hash) = &options.payload_hash {
+        source = hash.wrap(source);
+    }
+    if let Some(policy) = &options.content_policy {
+        source = policy.wrap(source);
+    }
+    // the CID and the index are separate options
+    let (root, index) = (options.payload_root.as_ref(), options.car_index.as_deref());
+    if root.is_some() || index.is_some() {
+        source = Box::new(CarVerifier::new(source, root, index));
+    }
+    if let Some(encryption) = &options.encryption {
+        source = encryption.wrap(source);
+    }
+    source
+}
+
+/// Writes the padded bytes of `add_piece` through the target layers of
+/// `options`, then the aligned writer.
+pub(crate) fn wrap_target<'a>(target: BoxWrite<'a>, options: &'a AddPieceOptions) -> BoxWrite<'a> {
+    let target: BoxWrite = Box::new(AlignedWriter::new(target, options.writes));
+    options
+        .target_layers
+        .iter()
+        .rev()
+        .fold(target, |target, layer| layer.wrap(target))
+}
+
+/// Caps the rate at which the source is read or the target written, e.g.
+/// to share a link with retrievals.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+}
+
+impl RateLimit {
+    fn limited<T>(&self, inner: T) -> RateLimited<T> {
+        RateLimited {
+            inner,
+            bytes_per_sec: self.bytes_per_sec.max(1),
+            started: None,
+            done: 0,
+        }
+    }
+}
+
+impl SourceLayer for RateLimit {
+    fn wrap<'a>(&'a self, inner: BoxRead<'a>) -> BoxRead<'a> {
+        Box::new(self.limited(inner))
+    }
+}
+
+impl TargetLayer for RateLimit {
+    fn wrap<'a>(&'a self, inner: BoxWrite<'a>) -> BoxWrite<'a> {
+        Box::new(self.limited(inner))
+    }
+}
+
+struct RateLimited<T> {
+    inner: T,
+    bytes_per_sec: u64,
+    started: Option<Instant>,
+    done: u64,
+}
+
+impl<T> RateLimited<T> {
+    /// Most bytes moved at once, a tenth of a second worth.
+    fn max_len(&self) -> usize {
+        (self.bytes_per_sec / 10).max(1) as usize
+    }
+
+    /// Waits until `n` more bytes are due.
+    fn pace(&mut self, n: usize) {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.done += n as u64;
+        let due = Duration::from_secs_f64(self.done as f64 / self.bytes_per_sec as f64);
+        if let Some(wait) = due.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl<R: Read> Read for RateLimited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.max_len());
+        let n = self.inner.read(&mut buf[..n])?;
+        self.pace(n);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for RateLimited<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.max_len());
+        let n = self.inner.write(&buf[..n])?;
+        self.pace(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copies the padded bytes written to the target into `copy` as well, e.g.
+/// to mirror a staged file.
+#[derive(Clone)]
+pub struct Tee {
+    pub copy: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl fmt::Debug for Tee {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tee").finish_non_exhaustive()
+    }
+}
+
+impl TargetLayer for Tee {
+    fn wrap<'a>(&'a self, inner: BoxWrite<'a>) -> BoxWrite<'a> {
+        Box::new(Teed { inner, tee: self })
+    }
+}
+
+struct Teed<'a> {
+    inner: BoxWrite<'a>,
+    tee: &'a Tee,
+}
+
+impl Write for Teed<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.tee
+            .copy
+            .lock()
+            .expect("lock tee")
+            .write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.tee.copy.lock().expect("lock tee").flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use filecoin_proofs::UnpaddedBytesAmount;
+
+    use crate::{add_piece_with_options, AddPieceOptions};
+
+    /// Flips every byte, to tell whether it ran and in which order.
+    #[derive(Debug)]
+    struct Invert;
+
+    impl SourceLayer for Invert {
+        fn wrap<'a>(&'a self, inner: BoxRead<'a>) -> BoxRead<'a> {
+            struct Inverted<'a>(BoxRead<'a>);
+            impl Read for Inverted<'_> {
+                fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                    let n = self.0.read(buf)?;
+                    buf[..n].iter_mut().for_each(|b| *b = !*b);
+                    Ok(n)
+                }
+            }
+            Box::new(Inverted(inner))
+        }
+    }
+
+    #[test]
+    fn test_source_layers() {
+        let payload: Vec<u8> = (0..1016).map(|i| (i % 251) as u8).collect();
+        let inverted: Vec<u8> = payload.iter().map(|b| !b).collect();
+        let add = |source: &[u8], layers: Vec<Arc<dyn SourceLayer>>| {
+            let hash = Arc::new(PayloadHash::new(1016));
+            let options = AddPieceOptions {
+                source_layers: layers,
+                payload_hash: Some(hash.clone()),
+                ..Default::default()
+            };
+            let mut staged = Vec::new();
+            let (piece_info, _) = add_piece_with_options(
+                Cursor::new(source),
+                &mut staged,
+                UnpaddedBytesAmount(1016),
+                &[],
+                &options,
+            )
+            .expect("add_piece failed");
+            (piece_info, hash.take())
+        };
+
+        // the custom layers run before the payload hash
+        let expected = add(&inverted, Vec::new());
+        assert_eq!(add(&payload, vec![Arc::new(Invert)]), expected);
+        // and in order
+        let twice = add(&payload, vec![Arc::new(Invert), Arc::new(Invert)]);
+        assert_eq!(twice, add(&payload, Vec::new()));
+
+        let started = Instant::now();
+        let limit = RateLimit {
+            bytes_per_sec: 10_000,
+        };
+        io::copy(
+            &mut SourceLayer::wrap(&limit, Box::new(&payload[..])),
+            &mut io::sink(),
+        )
+        .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn test_target_layers() {
+        let payload: Vec<u8> = (0..1016).map(|i| (i % 251) as u8).collect();
+        let copy = Arc::new(Mutex::new(Vec::new()));
+        let options = AddPieceOptions::default()
+            .target_layer(Tee { copy: copy.clone() })
+            .target_layer(RateLimit {
+                bytes_per_sec: 1 << 20,
+            });
+
+        let mut staged = Vec::new();
+        add_piece_with_options(
+            Cursor::new(&payload),
+            &mut staged,
+            UnpaddedBytesAmount(1016),
+            &[UnpaddedBytesAmount(127)],
+            &options,
+        )
+        .expect("add_piece failed");
+        assert_eq!(staged.len(), 1024 + 896);
+        assert_eq!(*copy.lock().unwrap(), staged);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::config;
+
+/// Bounds the staged files written and the piece files read at once under
+/// `path`, usually a mount point, across all the tasks of the process.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountLimit {
+    pub path: PathBuf,
+    pub writers: Option<usize>,
+    pub readers: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// Writing a staged file, for the whole task.
+    Write,
+    /// Reading the source of a piece, while the piece is added.
+    Read,
+}
+
+impl MountLimit {
+    fn limit(&self, access: Access) -> Option<usize> {
+        match access {
+            Access::Write => self.writers,
+            Access::Read => self.readers,
+        }
+    }
+}
+
+/// Number of permits held by limit index and access.
+static HELD: Mutex<Option<HashMap<(usize, Access), usize>>> = Mutex::new(None);
+static RELEASED: Condvar = Condvar::new();
+
+/// Held while accessing a path under a limited mount, released on drop.
+#[must_use]
+pub struct MountPermit {
+    key: (usize, Access),
+}
+
+impl Drop for MountPermit {
+    fn drop(&mut self) {
+        let mut held = HELD.lock().expect("mount limits poisoned");
+        if let Some(count) = held.get_or_insert_with(HashMap::new).get_mut(&self.key) {
+            *count -= 1;
+        }
+        RELEASED.notify_all();
+    }
+}
+
+/// Waits until `path` may be accessed under the configured mount limits,
+/// `None` if no limit applies to it. The most specific limit applies.
+pub fn acquire(path: &Path, access: Access) -> Option<MountPermit> {
+    let limits = &config::global().mount_limits;
+    if limits.is_empty() {
+        return None;
+    }
+
+    // the staged file may not exist yet
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty());
+    let path = fs::canonicalize(path)
+        .or_else(|_| fs::canonicalize(dir.unwrap_or_else(|| Path::new("."))))
+        .unwrap_or_else(|_| path.to_path_buf());
+    let (index, limit) = limits
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| path.starts_with(&l.path))
+        .max_by_key(|(_, l)| l.path.as_os_str().len())?;
+    let max = limit.limit(access)?;
+
+    let key = (index, access);
+    let mut held = HELD.lock().expect("mount limits poisoned");
+    loop {
+        let count = held
+            .get_or_insert_with(HashMap::new)
+            .entry(key)
+            .or_default();
+        if *count < max.max(1) {
+            *count += 1;
+            return Some(MountPermit { key });
+        }
+        debug!(
+            path = %path.display(),
+            mount = %limit.path.display(),
+            ?access,
+            "waiting for the mount limit"
+        );
+        held = RELEASED.wait(held).expect("mount limits poisoned");
+    }
+}
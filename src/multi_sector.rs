@@ -0,0 +1,149 @@
+use std::{path::PathBuf, time::SystemTime};
+
+use add_piece::{
+    packing::{pack, Packing, PackingPolicy, PackingStats},
+    seal_proof,
+};
+use anyhow::{ensure, Result};
+use filecoin_proofs::{PieceInfo, UnpaddedBytesAmount};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    add_pieces, config,
+    deadline::{self, ExpiredPiece},
+    iostats::TaskIoStats,
+    ledger,
+    record::Sources,
+    staging::StagedOpenMode,
+    webhook, PieceFile,
+};
+
+/// Pieces to distribute over several sectors, each one staged into its own file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MultiSectorTask {
+    /// Sector size in padded bytes.
+    pub sector_size: u64,
+    /// One staged file per available sector.
+    pub staged_files: Vec<PathBuf>,
+    pub pieces: Vec<PieceFile>,
+    #[serde(default)]
+    pub policy: PackingPolicy,
+    /// Overrides `staged_open` for the staged files of the task.
+    #[serde(default)]
+    pub staged_open: Option<StagedOpenMode>,
+}
+
+/// A packing computed without staging anything, with its space usage.
+#[derive(Debug, Serialize)]
+pub struct PackReport {
+    #[serde(flatten)]
+    pub packing: Packing,
+    pub stats: PackingStats,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub expired: Vec<ExpiredPiece>,
+}
+
+/// The staged sectors, and the pieces left out because their deals start too
+/// soon.
+#[derive(Debug, Serialize)]
+pub struct MultiSectorOutput {
+    pub sectors: Vec<SectorOutput>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub expired: Vec<ExpiredPiece>,
+}
+
+/// The pieces written to one staged file.
+#[derive(Debug, Serialize)]
+pub struct SectorOutput {
+    pub staged_file: PathBuf,
+    /// Indexes of the pieces of the task, in the order they were written.
+    pub pieces: Vec<usize>,
+    pub piece_infos: Vec<PieceInfo>,
+}
+
+impl MultiSectorTask {
+    /// Plans the packing over the staged files of the task.
+    pub fn plan(&self) -> Result<(Packing, Vec<ExpiredPiece>)> {
+        self.plan_with(Some(self.staged_files.len()))
+    }
+
+    /// Plans the packing over at most `max_sectors` sectors, leaving out the
+    /// pieces whose deadline has passed.
+    pub fn plan_with(&self, max_sectors: Option<usize>) -> Result<(Packing, Vec<ExpiredPiece>)> {
+        let deadlines = config::global().deadlines.clone().unwrap_or_default();
+        let (live, expired) = deadline::split_expired(&self.pieces, &deadlines);
+        let sizes: Vec<_> = live
+            .iter()
+            .map(|i| UnpaddedBytesAmount(self.pieces[*i].size))
+            .collect();
+        let mut packing = pack(&sizes, self.sector_size, max_sectors, self.policy)?;
+
+        // the packing indexes the live pieces, report the indexes in the task
+        for sector in &mut packing.sectors {
+            sector.pieces.iter_mut().for_each(|i| *i = live[*i]);
+        }
+        packing.unassigned.iter_mut().for_each(|i| *i = live[*i]);
+        Ok((packing, expired))
+    }
+}
+
+/// Packs the pieces of `task` into its sectors and stages each sector.
+///
+/// Fails before writing anything if the pieces do not fit, sectors left empty
+/// by the packing are not staged. The packing is deterministic, so the same
+/// task always stages the same pieces in the same order into each file.
+pub fn run(task: &MultiSectorTask, io: &mut TaskIoStats) -> Result<MultiSectorOutput> {
+    let (packing, expired) = task.plan()?;
+    ensure!(
+        packing.unassigned.is_empty(),
+        "pieces {:?} do not fit in {} sectors",
+        packing.unassigned,
+        task.staged_files.len()
+    );
+
+    // packings for sizes no proof seals are staged without one
+    let proof = seal_proof::for_sector_size(task.sector_size).ok();
+    let staged_open = task.staged_open.unwrap_or(config::global().staged_open);
+    let mut sectors = Vec::with_capacity(packing.sectors.len());
+    for (sector, staged_file) in packing.sectors.into_iter().zip(&task.staged_files) {
+        info!(
+            staged_file = %staged_file.display(),
+            pieces = ?sector.pieces,
+            free = sector.free,
+            "stage sector"
+        );
+
+        let pieces: Vec<_> = sector
+            .pieces
+            .iter()
+            .map(|i| task.pieces[*i].clone())
+            .collect();
+        let started = SystemTime::now();
+        let mut sector_io = TaskIoStats::default();
+        let res = add_pieces(
+            &pieces,
+            staged_file,
+            false,
+            proof,
+            staged_open,
+            &mut sector_io,
+            &Sources::Live(None),
+        );
+
+        let report = sector_io.report();
+        webhook::notify(&config::global().webhooks, staged_file, &res, &report);
+        let proof_type = proof.map(seal_proof::name).unwrap_or_default();
+        ledger::record(staged_file, &proof_type, started, &res);
+        io.merge(sector_io);
+
+        sectors.push(SectorOutput {
+            staged_file: staged_file.clone(),
+            pieces: sector.pieces,
+            piece_infos: res?,
+        });
+    }
+
+    Ok(MultiSectorOutput { sectors, expired })
+}
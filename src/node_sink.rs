@@ -0,0 +1,178 @@
+//! Taps the padded 64 bytes nodes of a piece as they are written, for tree
+//! builders or auditors which would otherwise read the staged piece again.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use filecoin_hashers::{HashFunction, Hasher};
+use filecoin_proofs::constants::DefaultPieceHasher;
+
+use crate::commitment_reader::to_root;
+use crate::dedup::ChunkRoot;
+
+/// Size of the nodes, the leaves of the piece tree.
+pub const NODE_SIZE: usize = 64;
+
+/// Receives the padded nodes of a piece as soon as they are produced, in
+/// order, a batch at a time. The alignment around the piece isn't passed.
+///
+/// An error aborts the piece, so sinks which are only informative should log
+/// their failures and return `Ok`.
+pub trait NodeSink: fmt::Debug + Send + Sync {
+    /// `index` counts the nodes of the piece from 0 up to the first of
+    /// `nodes`, which holds a whole number of them.
+    fn nodes(&self, index: u64, nodes: &[u8]) -> io::Result<()>;
+}
+
+/// Passes the hashes of the nodes, the leaves of the piece tree, to a
+/// callback instead of the nodes themselves.
+pub struct LeafHashes<F> {
+    f: F,
+}
+
+impl<F> LeafHashes<F>
+where
+    F: Fn(u64, &[ChunkRoot]) -> io::Result<()> + Send + Sync,
+{
+    /// `f` receives the index of the first leaf and the hashes of a batch.
+    pub fn new(f: F) -> Self {
+        Self { f }
+    }
+}
+
+impl<F> fmt::Debug for LeafHashes<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LeafHashes").finish_non_exhaustive()
+    }
+}
+
+impl<F> NodeSink for LeafHashes<F>
+where
+    F: Fn(u64, &[ChunkRoot]) -> io::Result<()> + Send + Sync,
+{
+    fn nodes(&self, index: u64, nodes: &[u8]) -> io::Result<()> {
+        let hash = <DefaultPieceHasher as Hasher>::Function::hash;
+        let hashes: Vec<_> = nodes
+            .chunks_exact(NODE_SIZE)
+            .map(|node| to_root(&hash(node)))
+            .collect();
+        (self.f)(index, &hashes)
+    }
+}
+
+/// Passes the padded bytes read through it to a `NodeSink`, holding back a
+/// node until it is complete.
+pub(crate) struct NodeTap<R> {
+    inner: R,
+    sink: Option<Arc<dyn NodeSink>>,
+    pending: Vec<u8>,
+    index: u64,
+}
+
+impl<R: Read> NodeTap<R> {
+    pub fn new(inner: R, sink: Option<Arc<dyn NodeSink>>) -> Self {
+        Self {
+            inner,
+            sink,
+            pending: Vec::new(),
+            index: 0,
+        }
+    }
+
+    fn emit(&mut self, sink: &dyn NodeSink, mut buf: &[u8]) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let take = buf.len().min(NODE_SIZE - self.pending.len());
+            self.pending.extend_from_slice(&buf[..take]);
+            buf = &buf[take..];
+            if self.pending.len() < NODE_SIZE {
+                return Ok(());
+            }
+            sink.nodes(self.index, &self.pending)?;
+            self.index += 1;
+            self.pending.clear();
+        }
+
+        let whole = buf.len() / NODE_SIZE * NODE_SIZE;
+        if whole > 0 {
+            sink.nodes(self.index, &buf[..whole])?;
+            self.index += (whole / NODE_SIZE) as u64;
+        }
+        self.pending.extend_from_slice(&buf[whole..]);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for NodeTap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(sink) = self.sink.clone() {
+            self.emit(&*sink, &buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use filecoin_proofs::UnpaddedBytesAmount;
+
+    use crate::{add_piece_with_options, pure, AddPieceOptions};
+
+    #[derive(Debug, Default)]
+    struct Collected(Mutex<Vec<u8>>);
+
+    impl NodeSink for Collected {
+        fn nodes(&self, index: u64, nodes: &[u8]) -> io::Result<()> {
+            let mut collected = self.0.lock().unwrap();
+            assert_eq!(collected.len() as u64, index * NODE_SIZE as u64);
+            assert_eq!(nodes.len() % NODE_SIZE, 0);
+            collected.extend_from_slice(nodes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_node_sink() {
+        let piece_size = UnpaddedBytesAmount(127 * 8);
+        let source: Vec<u8> = (0..u64::from(piece_size)).map(|i| i as u8).collect();
+        let nodes = Arc::new(Collected::default());
+        let leaves = Arc::new(Mutex::new(Vec::new()));
+        let leaves_sink = {
+            let leaves = leaves.clone();
+            LeafHashes::new(move |index, hashes: &[ChunkRoot]| {
+                let mut leaves = leaves.lock().unwrap();
+                assert_eq!(leaves.len() as u64, index);
+                leaves.extend_from_slice(hashes);
+                Ok(())
+            })
+        };
+
+        // aligned after a piece, which isn't tapped
+        let piece_lengths = [UnpaddedBytesAmount(127)];
+        for sink in [nodes.clone() as Arc<dyn NodeSink>, Arc::new(leaves_sink)] {
+            let options = AddPieceOptions {
+                node_sink: Some(sink),
+                ..Default::default()
+            };
+            add_piece_with_options(
+                &source[..],
+                io::sink(),
+                piece_size,
+                &piece_lengths,
+                &options,
+            )
+            .expect("add_piece failed");
+        }
+
+        let padded = pure::pad(&source);
+        assert_eq!(*nodes.0.lock().unwrap(), padded);
+        let hash = <DefaultPieceHasher as Hasher>::Function::hash;
+        let expected: Vec<_> = padded.chunks(64).map(|n| to_root(&hash(n))).collect();
+        assert_eq!(*leaves.lock().unwrap(), expected);
+    }
+}
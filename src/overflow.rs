@@ -0,0 +1,119 @@
+//! Sources yielding more bytes than the size of their piece.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+/// What `add_piece` does with source bytes past `piece_size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceOverflow {
+    /// Reads the whole source and fails once it is exhausted, because more
+    /// bytes than `piece_size` were written.
+    #[default]
+    Unchecked,
+    /// Stops writing at `piece_size`, the extra bytes are counted and logged.
+    Ignore,
+    /// Stops writing at `piece_size` and fails with `SourceTooLong`.
+    Error,
+}
+
+/// The source of a piece held more bytes than its piece size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceTooLong {
+    /// Unpadded piece size, the bytes which were used.
+    pub piece_size: u64,
+    /// Bytes left in the source past `piece_size`.
+    pub extra: u64,
+}
+
+impl fmt::Display for SourceTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "source too long: {} bytes past the piece size of {}",
+            self.extra, self.piece_size
+        )
+    }
+}
+
+impl std::error::Error for SourceTooLong {}
+
+/// Ends the source at `limit` bytes unless `SourceOverflow::Unchecked`. The
+/// bytes left past the limit are counted, the count is shared with the
+/// `OverflowCount` returned by `new`.
+pub(crate) struct OverflowGuard<R> {
+    inner: R,
+    remaining: Option<u64>,
+    extra: Rc<Cell<u64>>,
+}
+
+/// Bytes found past the limit of an `OverflowGuard`.
+pub(crate) struct OverflowCount(Rc<Cell<u64>>);
+
+impl OverflowCount {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl<R: Read> OverflowGuard<R> {
+    pub(crate) fn new(inner: R, limit: u64, mode: SourceOverflow) -> (Self, OverflowCount) {
+        let extra = Rc::new(Cell::new(0));
+        let guard = Self {
+            inner,
+            remaining: (mode != SourceOverflow::Unchecked).then_some(limit),
+            extra: extra.clone(),
+        };
+        (guard, OverflowCount(extra))
+    }
+}
+
+impl<R: Read> Read for OverflowGuard<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = match self.remaining {
+            None => return self.inner.read(buf),
+            Some(remaining) => remaining,
+        };
+        if remaining == 0 {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let extra = io::copy(&mut self.inner, &mut io::sink())?;
+            self.extra.set(self.extra.get() + extra);
+            return Ok(0);
+        }
+
+        let max = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        self.remaining = Some(remaining - n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overflow_guard() {
+        let source = [7u8; 100];
+
+        let (mut guard, extra) = OverflowGuard::new(&source[..], 60, SourceOverflow::Error);
+        let mut out = Vec::new();
+        guard.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 60);
+        assert_eq!(extra.get(), 40);
+
+        let (mut guard, extra) = OverflowGuard::new(&source[..], 60, SourceOverflow::Unchecked);
+        out.clear();
+        guard.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 100);
+        assert_eq!(extra.get(), 0);
+    }
+}
@@ -0,0 +1,220 @@
+//! Assignment of pieces to sectors.
+//!
+//! Pieces of a sector are written from the largest to the smallest, which
+//! keeps every piece aligned without any alignment padding: a sector fits a
+//! set of pieces as long as their padded sizes add up to at most its size.
+//!
+//! Packing is deterministic: pieces of the same size are taken in task order,
+//! so the same pieces always give the same sectors, with their pieces in the
+//! same order and thus the same CommD, whatever stages them afterwards.
+
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, UnpaddedBytesAmount};
+use serde::{Deserialize, Serialize};
+
+use crate::ensure_piece_size;
+
+/// How pieces are assigned to sectors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PackingPolicy {
+    /// Fill sectors one after another in piece order, moving on to the next
+    /// sector as soon as a piece does not fit.
+    Sequential,
+    /// Put each piece, in order, into the first sector with room for it.
+    FirstFit,
+    /// Like `FirstFit`, considering the largest pieces first.
+    #[default]
+    FirstFitDecreasing,
+}
+
+/// The pieces assigned to one sector.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SectorPlan {
+    /// Indexes of the pieces, in the order they are written.
+    pub pieces: Vec<usize>,
+    /// Padded bytes used by the pieces.
+    pub used: u64,
+    /// Padded bytes left unused.
+    pub free: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Packing {
+    pub sector_size: u64,
+    pub sectors: Vec<SectorPlan>,
+    /// Indexes of the pieces which did not fit in `max_sectors` sectors.
+    pub unassigned: Vec<usize>,
+}
+
+/// Space usage of a `Packing`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PackingStats {
+    pub sectors: usize,
+    pub used: u64,
+    pub free: u64,
+    /// Fraction of the sectors left unused.
+    pub waste: f64,
+}
+
+impl Packing {
+    pub fn stats(&self) -> PackingStats {
+        let used: u64 = self.sectors.iter().map(|s| s.used).sum();
+        let free: u64 = self.sectors.iter().map(|s| s.free).sum();
+        PackingStats {
+            sectors: self.sectors.len(),
+            used,
+            free,
+            waste: match used + free {
+                0 => 0.0,
+                total => free as f64 / total as f64,
+            },
+        }
+    }
+}
+
+/// Assigns pieces of `piece_sizes` to sectors of `sector_size` padded bytes,
+/// using at most `max_sectors` sectors if given.
+pub fn pack(
+    piece_sizes: &[UnpaddedBytesAmount],
+    sector_size: u64,
+    max_sectors: Option<usize>,
+    policy: PackingPolicy,
+) -> Result<Packing> {
+    ensure!(
+        sector_size.is_power_of_two(),
+        "sector size must be a power of 2, got {}",
+        sector_size
+    );
+
+    let mut padded = Vec::with_capacity(piece_sizes.len());
+    for (i, size) in piece_sizes.iter().enumerate() {
+        ensure_piece_size(*size).with_context(|| format!("piece {}", i))?;
+        let size = u64::from(PaddedBytesAmount::from(*size));
+        ensure!(
+            size <= sector_size,
+            "piece {} of {} padded bytes does not fit in a sector of {} bytes",
+            i,
+            size,
+            sector_size
+        );
+        padded.push(size);
+    }
+
+    let mut order: Vec<usize> = (0..padded.len()).collect();
+    if policy == PackingPolicy::FirstFitDecreasing {
+        order.sort_by_key(|i| std::cmp::Reverse(padded[*i]));
+    }
+
+    let max_sectors = max_sectors.unwrap_or(usize::MAX);
+    let mut sectors: Vec<SectorPlan> = Vec::new();
+    let mut unassigned = Vec::new();
+    for i in order {
+        // once a piece is left out, keeping the order means leaving out all
+        // the following ones
+        if policy == PackingPolicy::Sequential && !unassigned.is_empty() {
+            unassigned.push(i);
+            continue;
+        }
+
+        let size = padded[i];
+        let fits = |s: &SectorPlan| s.used + size <= sector_size;
+        let found = match policy {
+            PackingPolicy::Sequential => sectors
+                .last()
+                .filter(|s| fits(s))
+                .map(|_| sectors.len() - 1),
+            PackingPolicy::FirstFit | PackingPolicy::FirstFitDecreasing => {
+                sectors.iter().position(fits)
+            }
+        };
+
+        let sector = match found {
+            Some(s) => s,
+            None if sectors.len() < max_sectors => {
+                sectors.push(SectorPlan::default());
+                sectors.len() - 1
+            }
+            None => {
+                unassigned.push(i);
+                continue;
+            }
+        };
+
+        sectors[sector].pieces.push(i);
+        sectors[sector].used += size;
+    }
+
+    for sector in sectors.iter_mut() {
+        sector.pieces.sort_by_key(|i| std::cmp::Reverse(padded[*i]));
+        sector.free = sector_size - sector.used;
+    }
+
+    Ok(Packing {
+        sector_size,
+        sectors,
+        unassigned,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pure::plan_pieces;
+
+    fn sizes(padded: &[u64]) -> Vec<UnpaddedBytesAmount> {
+        padded
+            .iter()
+            .map(|p| PaddedBytesAmount(*p).into())
+            .collect()
+    }
+
+    #[test]
+    fn test_pack() {
+        let pieces = sizes(&[256, 1024, 512, 256, 1024]);
+
+        let packing =
+            pack(&pieces, 2048, None, PackingPolicy::FirstFitDecreasing).expect("pack failed");
+        let assigned: Vec<_> = packing.sectors.iter().map(|s| s.pieces.clone()).collect();
+        assert_eq!(assigned, vec![vec![1, 4], vec![2, 0, 3]]);
+        assert_eq!(packing.sectors[1].free, 1024);
+        assert_eq!(packing.stats().waste, 0.25);
+
+        // pieces written largest first never need alignment
+        for sector in &packing.sectors {
+            let sector_pieces: Vec<_> = sector.pieces.iter().map(|i| pieces[*i]).collect();
+            let placements = plan_pieces(&sector_pieces).expect("plan_pieces failed");
+            assert!(placements.iter().all(|p| p.left == PaddedBytesAmount(0)));
+        }
+
+        let packing = pack(&pieces, 2048, None, PackingPolicy::FirstFit).expect("pack failed");
+        let assigned: Vec<_> = packing.sectors.iter().map(|s| s.pieces.clone()).collect();
+        assert_eq!(assigned, vec![vec![1, 2, 0, 3], vec![4]]);
+
+        let packing = pack(&pieces, 2048, Some(1), PackingPolicy::FirstFit).expect("pack failed");
+        assert_eq!(packing.sectors.len(), 1);
+        assert_eq!(packing.unassigned, vec![4]);
+
+        let pieces = sizes(&[1024, 2048, 256]);
+        let packing = pack(&pieces, 2048, None, PackingPolicy::Sequential).expect("pack failed");
+        let assigned: Vec<_> = packing.sectors.iter().map(|s| s.pieces.clone()).collect();
+        assert_eq!(assigned, vec![vec![0], vec![1], vec![2]]);
+
+        let packing = pack(&pieces, 2048, Some(2), PackingPolicy::Sequential).expect("pack failed");
+        assert_eq!(packing.unassigned, vec![2]);
+
+        assert!(pack(&sizes(&[4096]), 2048, None, PackingPolicy::FirstFit).is_err());
+    }
+
+    #[test]
+    fn test_pack_equal_sizes_in_task_order() {
+        let pieces = sizes(&[512, 256, 512, 1024, 256, 512, 256, 128, 512, 256]);
+        let packing = pack(&pieces, 2048, None, PackingPolicy::FirstFitDecreasing).unwrap();
+        let assigned: Vec<_> = packing.sectors.iter().map(|s| s.pieces.clone()).collect();
+        assert_eq!(
+            assigned,
+            vec![vec![3, 0, 2], vec![5, 8, 1, 4, 6, 9], vec![7]]
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Paths of piece files and staged files on Windows, which are often deep in
+//! SMB shares: Win32 calls refuse paths past `MAX_PATH` (260 characters)
+//! unless they are given in their verbatim `\\?\` form.
+
+use std::path::{self, Path, PathBuf};
+
+/// Returns the path to open `path` with. On Windows it is made absolute and
+/// verbatim, `C:\dir\file` becoming `\\?\C:\dir\file` and `\\server\share\file`
+/// becoming `\\?\UNC\server\share\file`, other platforms get `path` as is.
+pub fn normalize(path: &Path) -> PathBuf {
+    if !cfg!(windows) {
+        return path.to_path_buf();
+    }
+    // resolves `.`, `..` and `/` which verbatim paths take literally
+    let absolute = path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match absolute.to_str().and_then(verbatim) {
+        Some(verbatim) => PathBuf::from(verbatim),
+        None => absolute,
+    }
+}
+
+/// The verbatim form of an absolute Windows path, `None` if it already is
+/// verbatim or is a device path or isn't absolute.
+fn verbatim(path: &str) -> Option<String> {
+    if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+        return None;
+    }
+    if let Some(unc) = path.strip_prefix(r"\\") {
+        let mut parts = unc.splitn(3, '\\');
+        let server = parts.next().filter(|s| !s.is_empty())?;
+        let share = parts.next().filter(|s| !s.is_empty())?;
+        let mut verbatim = format!(r"\\?\UNC\{}\{}", server, share);
+        if let Some(rest) = parts.next() {
+            verbatim.push('\\');
+            verbatim.push_str(rest);
+        }
+        return Some(verbatim);
+    }
+
+    let bytes = path.as_bytes();
+    let drive =
+        bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\';
+    drive.then(|| format!(r"\\?\{}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbatim() {
+        assert_eq!(
+            verbatim(r"C:\dir\file").as_deref(),
+            Some(r"\\?\C:\dir\file")
+        );
+        assert_eq!(verbatim(r"d:\").as_deref(), Some(r"\\?\d:\"));
+        assert_eq!(
+            verbatim(r"\\server\share\dir\file").as_deref(),
+            Some(r"\\?\UNC\server\share\dir\file")
+        );
+        assert_eq!(
+            verbatim(r"\\server\share").as_deref(),
+            Some(r"\\?\UNC\server\share")
+        );
+
+        // already verbatim, device paths, incomplete shares and relative paths
+        assert_eq!(verbatim(r"\\?\C:\dir\file"), None);
+        assert_eq!(verbatim(r"\\?\UNC\server\share\file"), None);
+        assert_eq!(verbatim(r"\\.\PhysicalDrive0"), None);
+        assert_eq!(verbatim(r"\\server"), None);
+        assert_eq!(verbatim(r"\\server\\file"), None);
+        assert_eq!(verbatim(r"C:file"), None);
+        assert_eq!(verbatim(r"dir\file"), None);
+        assert_eq!(verbatim("/dir/file"), None);
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::{self, Read};
+use std::sync::Mutex;
+
+/// Hashes the payload of one piece with blake3 when set as
+/// `AddPieceOptions::payload_hash`, in the same pass as its commitment, for
+/// storage layers keyed by blake3 rather than by piece CID.
+///
+/// Only the first `payload_size` bytes of the source are hashed, not the
+/// zeros completing the payload to the piece size, and before any
+/// encryption.
+#[derive(Debug)]
+pub struct PayloadHash {
+    payload_size: u64,
+    state: Mutex<HashState>,
+}
+
+#[derive(Debug)]
+struct HashState {
+    hasher: blake3::Hasher,
+    hashed: u64,
+    hash: Option<blake3::Hash>,
+}
+
+impl PayloadHash {
+    pub fn new(payload_size: u64) -> Self {
+        let hasher = blake3::Hasher::new();
+        let hash = (payload_size == 0).then(|| hasher.finalize());
+        Self {
+            payload_size,
+            state: Mutex::new(HashState {
+                hasher,
+                hashed: 0,
+                hash,
+            }),
+        }
+    }
+
+    fn update(&self, data: &[u8]) {
+        let mut state = self.state.lock().expect("lock payload hash");
+        let left = self.payload_size - state.hashed;
+        let n = left.min(data.len() as u64);
+        if n == 0 {
+            return;
+        }
+        state.hasher.update(&data[..n as usize]);
+        state.hashed += n;
+        if state.hashed == self.payload_size {
+            state.hash = Some(state.hasher.finalize());
+        }
+    }
+
+    /// Returns the hex encoded hash of the payload once it was read whole.
+    pub fn take(&self) -> Option<String> {
+        let hash = self.state.lock().expect("lock payload hash").hash.take();
+        hash.map(|h| h.to_hex().to_string())
+    }
+}
+
+/// Feeds what is read from `inner` to `hash`, if set.
+pub(crate) struct HashingReader<'a, R> {
+    inner: R,
+    hash: Option<&'a PayloadHash>,
+}
+
+impl<'a, R: Read> HashingReader<'a, R> {
+    pub(crate) fn new(inner: R, hash: Option<&'a PayloadHash>) -> Self {
+        Self { inner, hash }
+    }
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hash) = self.hash {
+            hash.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use filecoin_proofs::UnpaddedBytesAmount;
+
+    use crate::{add_piece_with_options, AddPieceOptions};
+
+    #[test]
+    fn test_payload_hash() {
+        let mut source: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let payload = blake3::hash(&source).to_hex().to_string();
+        source.resize(1016, 0);
+
+        let hash = Arc::new(PayloadHash::new(1000));
+        let options = AddPieceOptions {
+            payload_hash: Some(hash.clone()),
+            ..Default::default()
+        };
+        add_piece_with_options(
+            Cursor::new(&source),
+            io::sink(),
+            UnpaddedBytesAmount(1016),
+            &[],
+            &options,
+        )
+        .expect("add_piece failed");
+        assert_eq!(hash.take(), Some(payload));
+        assert_eq!(hash.take(), None);
+
+        // a payload cut short has no hash
+        let hash = PayloadHash::new(1000);
+        let mut reader = HashingReader::new(Cursor::new(&source[..500]), Some(&hash));
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(hash.take(), None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// How often a target is synced while it is written, whichever bound is
+/// reached first.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeriodicSyncConfig {
+    /// Sync once this many bytes were written since the last sync.
+    pub bytes: Option<u64>,
+    /// Sync once data written this many milliseconds ago is still unsynced.
+    pub interval_ms: Option<u64>,
+}
+
+/// Syncs its target, through `flush`, as often as a `PeriodicSyncConfig`
+/// asks, bounding how much of what was written a crash may lose.
+///
+/// The bounds are checked as data is written, so that the sync happens on
+/// the thread writing the target: behind a `WriteBehind`, its writer thread,
+/// while hashing goes on.
+pub struct PeriodicSync<W: Write> {
+    inner: W,
+    bytes: Option<u64>,
+    interval: Option<Duration>,
+    unsynced: u64,
+    /// When the oldest unsynced byte was written.
+    since: Option<Instant>,
+}
+
+impl<W: Write> PeriodicSync<W> {
+    /// Passes writes through to `inner` without syncing it if `config` is
+    /// `None`.
+    pub fn new(inner: W, config: Option<PeriodicSyncConfig>) -> Self {
+        let config = config.unwrap_or_default();
+        Self {
+            inner,
+            bytes: config.bytes.filter(|n| *n > 0),
+            interval: config
+                .interval_ms
+                .filter(|n| *n > 0)
+                .map(Duration::from_millis),
+            unsynced: 0,
+            since: None,
+        }
+    }
+
+    fn due(&self) -> bool {
+        let bytes_due = self.bytes.is_some_and(|bytes| self.unsynced >= bytes);
+        let deadline_due = match (self.interval, self.since) {
+            (Some(interval), Some(since)) => since.elapsed() >= interval,
+            _ => false,
+        };
+        bytes_due || deadline_due
+    }
+}
+
+impl<W: Write> Write for PeriodicSync<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.unsynced += n as u64;
+            self.since.get_or_insert_with(Instant::now);
+            if self.due() {
+                self.flush()?;
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.unsynced = 0;
+        self.since = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[derive(Default)]
+    struct Recorder {
+        written: usize,
+        flushes: Vec<usize>,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.push(self.written);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_periodic_sync() {
+        let mut recorder = Recorder::default();
+        let mut writer = PeriodicSync::new(
+            &mut recorder,
+            Some(PeriodicSyncConfig {
+                bytes: Some(10),
+                interval_ms: None,
+            }),
+        );
+        for _ in 0..5 {
+            writer.write_all(&[0u8; 4]).expect("write failed");
+        }
+        assert_eq!(recorder.flushes, vec![12]);
+
+        let mut recorder = Recorder::default();
+        let mut writer = PeriodicSync::new(
+            &mut recorder,
+            Some(PeriodicSyncConfig {
+                bytes: Some(1 << 20),
+                interval_ms: Some(5),
+            }),
+        );
+        writer.write_all(&[0u8; 4]).expect("write failed");
+        thread::sleep(Duration::from_millis(10));
+        writer.write_all(&[0u8; 4]).expect("write failed");
+        writer.write_all(&[0u8; 4]).expect("write failed");
+        assert_eq!(recorder.flushes, vec![8]);
+
+        let mut recorder = Recorder::default();
+        let mut writer = PeriodicSync::new(&mut recorder, None);
+        writer.write_all(&[0u8; 1 << 16]).expect("write failed");
+        assert!(recorder.flushes.is_empty());
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::{anyhow, ensure, Result};
+
+/// CIDv1, fil-commitment-unsealed codec (0xf101), sha2-256-trunc254-padded
+/// multihash (0x1012) of 32 bytes, as varints.
+const PREFIX: [u8; 7] = [0x01, 0x81, 0xe2, 0x03, 0x92, 0x20, 0x20];
+
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Returns the piece CID of a commitment, in the usual base32 form
+/// (`baga6ea4seaq...`).
+pub fn encode(commitment: &[u8; 32]) -> String {
+    let mut bytes = PREFIX.to_vec();
+    bytes.extend_from_slice(commitment);
+    format!("b{}", base32_encode(&bytes))
+}
+
+/// Returns the commitment of a piece CID in the form produced by `encode`.
+pub fn decode(cid: &str) -> Result<[u8; 32]> {
+    let data = cid
+        .strip_prefix('b')
+        .ok_or_else(|| anyhow!("piece cid must be base32 encoded: {}", cid))?;
+    let bytes = base32_decode(data).ok_or_else(|| anyhow!("invalid piece cid: {}", cid))?;
+
+    ensure!(
+        bytes.len() == PREFIX.len() + 32 && bytes[..PREFIX.len()] == PREFIX,
+        "not a piece cid: {}",
+        cid
+    );
+    let mut commitment = [0u8; 32];
+    commitment.copy_from_slice(&bytes[PREFIX.len()..]);
+    Ok(commitment)
+}
+
+/// Unpadded RFC 4648 base32 in lower case, as used by multibase `b`.
+pub(crate) fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let (mut acc, mut bits) = (0u16, 0);
+    for b in bytes {
+        acc = (acc << 8) | *b as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((acc >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((acc << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+pub(crate) fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(data.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u16, 0);
+    for c in data.bytes() {
+        let value = BASE32.iter().position(|b| *b == c)?;
+        acc = (acc << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piece_cid() {
+        let commitment = [0x5au8; 32];
+        let cid = encode(&commitment);
+        assert!(cid.starts_with("baga6ea4seaq"), "{}", cid);
+        assert_eq!(decode(&cid).unwrap(), commitment);
+
+        assert!(decode(&cid[1..]).is_err());
+        assert!(decode(&cid[..cid.len() - 2]).is_err());
+        assert!(decode("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi").is_err());
+    }
+}
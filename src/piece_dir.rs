@@ -0,0 +1,95 @@
+//! Pieces given by piece CID only, read from the configured piece
+//! directories where they are stored under their CID, so that a known piece
+//! can be added to a new sector without its path.
+
+use std::{fmt, fs, path::PathBuf};
+
+use add_piece::{piece_cid, sizes};
+use anyhow::{bail, ensure, Context, Result};
+use filecoin_proofs::{PieceInfo, UnpaddedBytesAmount};
+use tracing::debug;
+
+use crate::{config, PieceFile};
+
+/// Fills in the path of the pieces given by piece CID only, and their size
+/// from the length of the piece file unless given.
+pub fn resolve(pieces: &mut [PieceFile]) -> Result<()> {
+    for (index, piece) in pieces.iter_mut().enumerate() {
+        let cid = match &piece.piece_cid {
+            Some(cid) if piece.path.as_os_str().is_empty() => cid.clone(),
+            _ => continue,
+        };
+        let path =
+            find(&config::global().piece_dirs, &cid).with_context(|| format!("piece {}", index))?;
+        if piece.size == 0 {
+            let len = fs::metadata(&path)
+                .with_context(|| format!("stat {}", path.display()))?
+                .len();
+            ensure!(
+                sizes::is_piece_size(UnpaddedBytesAmount(len)),
+                "piece {}: {} holds {} bytes, not a piece size, give its size",
+                index,
+                path.display(),
+                len
+            );
+            piece.size = len;
+        }
+        debug!(%cid, path = %path.display(), "piece found by cid");
+        piece.path = path;
+    }
+    Ok(())
+}
+
+fn find(dirs: &[PathBuf], cid: &str) -> Result<PathBuf> {
+    // never look outside the directories
+    piece_cid::decode(cid)?;
+    for dir in dirs {
+        let path = dir.join(cid);
+        if path.is_file() {
+            return Ok(path);
+        }
+    }
+    match dirs.is_empty() {
+        true => bail!("{} given by cid, but no piece_dirs configured", cid),
+        false => bail!("{} not found in the piece directories", cid),
+    }
+}
+
+/// A piece added with another commitment than the CID its task gave.
+#[derive(Debug, Clone)]
+pub struct CidMismatch {
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for CidMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} holds piece {}, not {}",
+            self.path.display(),
+            self.actual,
+            self.expected
+        )
+    }
+}
+
+impl std::error::Error for CidMismatch {}
+
+/// Fails unless `piece_info` is the piece the task gave the CID of, if any.
+pub fn check(piece: &PieceFile, piece_info: &PieceInfo) -> Result<()> {
+    let cid = match &piece.piece_cid {
+        Some(cid) => cid,
+        None => return Ok(()),
+    };
+    if piece_cid::decode(cid)? != piece_info.commitment {
+        return Err(CidMismatch {
+            path: piece.path.clone(),
+            expected: cid.clone(),
+            actual: piece_cid::encode(&piece_info.commitment),
+        }
+        .into());
+    }
+    Ok(())
+}
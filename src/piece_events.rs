@@ -0,0 +1,223 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use filecoin_proofs::PieceInfo;
+
+/// The piece an event is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceRef {
+    /// Position of the piece in its task.
+    pub index: usize,
+    /// Where the piece is read from, as given by the task.
+    pub source: String,
+    /// Unpadded size of the piece.
+    pub piece_size: u64,
+}
+
+/// Receives the lifecycle events of the pieces being staged, e.g. to show
+/// their progress, record metrics or notify another service.
+///
+/// `add_piece` publishes the events it sees through
+/// `AddPieceOptions::piece_events`: `progressed`, `fetched`, `written` and
+/// `hashed`. `started`, `verified` and `failed` are published by whoever
+/// stages the piece, who knows when it starts and whether it is done.
+///
+/// Every method does nothing by default. The events are published from the
+/// threads staging the pieces, so sinks should be quick and handle their
+/// own failures.
+pub trait PieceEventSink: fmt::Debug + Send + Sync {
+    fn started(&self, _piece: &PieceRef) {}
+
+    /// `bytes` more bytes of the source were read.
+    fn progressed(&self, _piece: &PieceRef, _bytes: u64) {}
+
+    /// The source was read whole, `bytes` bytes.
+    fn fetched(&self, _piece: &PieceRef, _bytes: u64) {}
+
+    /// The padded piece and its alignment, `padded_bytes`, were written and
+    /// flushed to the target.
+    fn written(&self, _piece: &PieceRef, _padded_bytes: u64) {}
+
+    fn hashed(&self, _piece: &PieceRef, _piece_info: &PieceInfo) {}
+
+    /// The piece passed every check and is recorded as staged.
+    fn verified(&self, _piece: &PieceRef, _piece_info: &PieceInfo) {}
+
+    fn failed(&self, _piece: &PieceRef, _error: &anyhow::Error) {}
+}
+
+/// Publishes every event to each of the sinks, in order.
+#[derive(Debug, Default, Clone)]
+pub struct Fanout(pub Vec<Arc<dyn PieceEventSink>>);
+
+impl PieceEventSink for Fanout {
+    fn started(&self, piece: &PieceRef) {
+        self.0.iter().for_each(|s| s.started(piece));
+    }
+
+    fn progressed(&self, piece: &PieceRef, bytes: u64) {
+        self.0.iter().for_each(|s| s.progressed(piece, bytes));
+    }
+
+    fn fetched(&self, piece: &PieceRef, bytes: u64) {
+        self.0.iter().for_each(|s| s.fetched(piece, bytes));
+    }
+
+    fn written(&self, piece: &PieceRef, padded_bytes: u64) {
+        self.0.iter().for_each(|s| s.written(piece, padded_bytes));
+    }
+
+    fn hashed(&self, piece: &PieceRef, piece_info: &PieceInfo) {
+        self.0.iter().for_each(|s| s.hashed(piece, piece_info));
+    }
+
+    fn verified(&self, piece: &PieceRef, piece_info: &PieceInfo) {
+        self.0.iter().for_each(|s| s.verified(piece, piece_info));
+    }
+
+    fn failed(&self, piece: &PieceRef, error: &anyhow::Error) {
+        self.0.iter().for_each(|s| s.failed(piece, error));
+    }
+}
+
+/// The events of one piece, published to `sink`.
+#[derive(Debug, Clone)]
+pub struct PieceEvents {
+    pub piece: PieceRef,
+    pub sink: Arc<dyn PieceEventSink>,
+}
+
+impl PieceEvents {
+    pub fn new(piece: PieceRef, sink: Arc<dyn PieceEventSink>) -> Self {
+        Self { piece, sink }
+    }
+
+    pub fn started(&self) {
+        self.sink.started(&self.piece);
+    }
+
+    /// Publishes `verified` or `failed` as the piece ended.
+    pub fn finished(&self, res: &anyhow::Result<PieceInfo>) {
+        match res {
+            Ok(piece_info) => self.sink.verified(&self.piece, piece_info),
+            Err(e) => self.sink.failed(&self.piece, e),
+        }
+    }
+}
+
+/// Publishes the bytes read from `inner` as progress of the piece, if any,
+/// for stagers reading the source without `add_piece`. The total is shared
+/// with the `ReadCount` returned by `new`.
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    events: Option<&'a PieceEvents>,
+    read: Rc<Cell<u64>>,
+}
+
+/// Bytes read through a `ProgressReader`.
+pub struct ReadCount(Rc<Cell<u64>>);
+
+impl ReadCount {
+    pub fn get(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, events: Option<&'a PieceEvents>) -> (Self, ReadCount) {
+        let read = Rc::new(Cell::new(0));
+        let reader = Self {
+            inner,
+            events,
+            read: read.clone(),
+        };
+        (reader, ReadCount(read))
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.set(self.read.get() + n as u64);
+        if let (Some(events), true) = (self.events, n > 0) {
+            events.sink.progressed(&events.piece, n as u64);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    use filecoin_proofs::UnpaddedBytesAmount;
+
+    use crate::{add_piece_with_options, AddPieceOptions};
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl PieceEventSink for Recorder {
+        fn progressed(&self, _piece: &PieceRef, bytes: u64) {
+            let mut events = self.0.lock().unwrap();
+            // reads are merged, their sizes depend on the buffering
+            if events.last().map(String::as_str) != Some("progressed") {
+                events.push("progressed".to_string());
+            }
+            assert!(bytes > 0);
+        }
+
+        fn fetched(&self, _piece: &PieceRef, bytes: u64) {
+            self.0.lock().unwrap().push(format!("fetched {}", bytes));
+        }
+
+        fn written(&self, _piece: &PieceRef, padded_bytes: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("written {}", padded_bytes));
+        }
+
+        fn hashed(&self, _piece: &PieceRef, piece_info: &PieceInfo) {
+            let size = u64::from(piece_info.size);
+            self.0.lock().unwrap().push(format!("hashed {}", size));
+        }
+    }
+
+    #[test]
+    fn test_piece_events() {
+        let payload: Vec<u8> = (0..1016).map(|i| (i % 251) as u8).collect();
+        let recorder = Arc::new(Recorder::default());
+        let piece = PieceRef {
+            index: 1,
+            source: "test".to_string(),
+            piece_size: 1016,
+        };
+        let options = AddPieceOptions {
+            piece_events: Some(PieceEvents::new(
+                piece,
+                Arc::new(Fanout(vec![recorder.clone()])),
+            )),
+            ..Default::default()
+        };
+        add_piece_with_options(
+            Cursor::new(&payload),
+            io::sink(),
+            UnpaddedBytesAmount(1016),
+            &[UnpaddedBytesAmount(127)],
+            &options,
+        )
+        .expect("add_piece failed");
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            ["progressed", "fetched 1016", "written 1920", "hashed 1016"]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! The pieces of staged sectors as `seal_pre_commit_phase1` takes them.
+//!
+//! PreCommit wants, for every sector, the `PieceInfo`s of its pieces in the
+//! order they were written, and the staged file must hold the whole sector.
+//! The alignment between pieces is implied by their order and sizes, it is
+//! not listed.
+
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, UnpaddedBytesAmount};
+use vc_processors::fil_proofs::RegisteredSealProof;
+
+use crate::{layout, manifest::Manifest, seal_proof};
+
+/// Returns the pieces of the sector `manifest` describes, for PreCommit of a
+/// sector of `proof`.
+///
+/// Fails if the recorded offsets don't follow the layout `add_piece` gives
+/// these pieces, or if the pieces don't cover the sector up to its end, in
+/// which case the error lists the zero pieces to add with `filler_sizes`.
+pub fn sector_piece_infos(
+    proof: RegisteredSealProof,
+    manifest: &Manifest,
+) -> Result<Vec<PieceInfo>> {
+    sector_piece_infos_for_size(u64::from(proof.sector_size()), manifest)
+}
+
+/// Same as `sector_piece_infos` for a sector of `sector_size` padded bytes,
+/// which may be a test sector size with the `test-sectors` feature.
+pub fn sector_piece_infos_for_size(
+    sector_size: u64,
+    manifest: &Manifest,
+) -> Result<Vec<PieceInfo>> {
+    seal_proof::ensure_sector_size(sector_size)?;
+    ensure!(!manifest.pieces.is_empty(), "the sector holds no piece");
+
+    let sizes: Vec<_> = manifest.pieces.iter().map(|p| p.piece_info.size).collect();
+    let placements = layout::plan_pieces(&sizes)?;
+    for (i, (piece, placement)) in manifest.pieces.iter().zip(&placements).enumerate() {
+        ensure!(
+            piece.offset == u64::from(placement.offset),
+            "piece {} is recorded at {}, add_piece places it at {}",
+            i,
+            piece.offset,
+            u64::from(placement.offset)
+        );
+    }
+
+    let end = placements.last().map(|p| u64::from(p.end())).unwrap_or(0);
+    ensure!(
+        end <= sector_size,
+        "the pieces end at {} padded bytes, past the {} bytes sector",
+        end,
+        sector_size
+    );
+    ensure!(
+        end == sector_size,
+        "the pieces cover {} of the {} padded bytes of the sector, fill it with zero pieces of {:?}",
+        end,
+        sector_size,
+        filler_sizes(PaddedBytesAmount(end), PaddedBytesAmount(sector_size))
+    );
+
+    Ok(manifest
+        .pieces
+        .iter()
+        .map(|p| p.piece_info.clone())
+        .collect())
+}
+
+/// Same as `sector_piece_infos` for a batch of sectors of `proof`, in order.
+pub fn batch_piece_infos<'a>(
+    proof: RegisteredSealProof,
+    manifests: impl IntoIterator<Item = &'a Manifest>,
+) -> Result<Vec<Vec<PieceInfo>>> {
+    manifests
+        .into_iter()
+        .enumerate()
+        .map(|(i, manifest)| {
+            sector_piece_infos(proof, manifest).with_context(|| format!("sector {}", i))
+        })
+        .collect()
+}
+
+/// Sizes of the zero pieces completing a sector whose pieces end at `end`,
+/// smallest first so that each of them is aligned without padding.
+pub fn filler_sizes(
+    end: PaddedBytesAmount,
+    sector_size: PaddedBytesAmount,
+) -> Vec<UnpaddedBytesAmount> {
+    let rest = u64::from(sector_size).saturating_sub(u64::from(end));
+    (0..u64::BITS)
+        .map(|bit| 1u64 << bit)
+        .filter(|size| rest & size != 0)
+        .map(|size| PaddedBytesAmount(size).into())
+        .collect()
+}
+
+/// The zero pieces completing a sector whose pieces end at `end`, in the
+/// order they follow the pieces, as sized by `filler_sizes`.
+pub fn filler_pieces(
+    end: PaddedBytesAmount,
+    sector_size: PaddedBytesAmount,
+) -> Result<Vec<PieceInfo>> {
+    filler_sizes(end, sector_size)
+        .into_iter()
+        .map(|size| {
+            filecoin_proofs::pieces::zero_padding(size).context("commitment of a zero piece")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::manifest::ManifestPiece;
+
+    fn manifest(sizes: &[u64]) -> Manifest {
+        let sizes: Vec<_> = sizes.iter().map(|s| UnpaddedBytesAmount(*s)).collect();
+        let placements = layout::plan_pieces(&sizes).unwrap();
+        let pieces = sizes
+            .iter()
+            .zip(placements)
+            .enumerate()
+            .map(|(i, (size, placement))| ManifestPiece {
+                source: format!("piece-{}", i),
+                payload_size: u64::from(*size),
+                piece_info: PieceInfo {
+                    commitment: [i as u8; 32],
+                    size: *size,
+                },
+                offset: placement.offset.into(),
+                len: u64::from(placement.size + placement.right),
+                chunk_roots: Vec::new(),
+                encryption: None,
+                excessive_alignment: None,
+                payload_blake3: None,
+                payload_sha256: None,
+            })
+            .collect();
+        Manifest {
+            pieces,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sector_piece_infos() {
+        let proof = RegisteredSealProof::StackedDrg2KiBV1_1;
+
+        // 128 + 384 of alignment + 512 + 1024 bytes
+        let full = manifest(&[127, 127 * 4, 127 * 8]);
+        let infos = sector_piece_infos(proof, &full).unwrap();
+        assert_eq!(infos.len(), 3);
+        assert_eq!(infos[2].commitment, [2u8; 32]);
+
+        let partial = manifest(&[127, 127 * 4]);
+        let err = sector_piece_infos(proof, &partial).unwrap_err();
+        assert!(err.to_string().contains("fill it"), "{:?}", err);
+        assert_eq!(
+            filler_sizes(PaddedBytesAmount(1024), PaddedBytesAmount(2048)),
+            vec![UnpaddedBytesAmount(127 * 8)]
+        );
+        assert_eq!(
+            filler_sizes(PaddedBytesAmount(128), PaddedBytesAmount(1024)),
+            [1, 2, 4].map(|n| UnpaddedBytesAmount(127 * n))
+        );
+
+        let fillers = filler_pieces(PaddedBytesAmount(128), PaddedBytesAmount(1024)).unwrap();
+        assert_eq!(fillers.len(), 3);
+        assert_eq!(fillers[0].size, UnpaddedBytesAmount(127));
+        assert_eq!(
+            fillers[0].commitment,
+            crate::pure::commit(&[0u8; 128]).unwrap()
+        );
+
+        assert!(sector_piece_infos_for_size(2048, &full).is_ok());
+        assert!(sector_piece_infos_for_size(4096, &full).is_err());
+
+        let mut moved = full.clone();
+        moved.pieces[1].offset = 128;
+        assert!(sector_piece_infos(proof, &moved).is_err());
+
+        let batch = batch_piece_infos(proof, [&full, &partial]).unwrap_err();
+        assert!(
+            format!("{:#}", batch).starts_with("sector 1"),
+            "{:#}",
+            batch
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Piece commitments of local files, computed as fast as possible ahead of
+//! staging: the file is read through a buffer and its chunks are padded and
+//! hashed in parallel, without writing the padded piece anywhere.
+
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo};
+use fr32::Fr32Reader;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{commitment_reader, dedup::ChunkRoot, pure, ChunksReader, ChunksState, CHUNK_SIZE};
+
+pub use crate::sizes::piece_size_for;
+
+/// Returns the `PieceInfo` the file at `path` gets once zero padded to
+/// `piece_size_for` its length, the same `add_piece` computes.
+pub fn predict_piece_info(path: impl AsRef<Path>) -> Result<PieceInfo> {
+    let path = path.as_ref();
+    let file = fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let len = file.metadata().context("stat payload")?.len();
+    ensure!(len > 0, "{} is empty", path.display());
+
+    let piece_size = piece_size_for(len);
+    let mut payload = BufReader::with_capacity(1 << 20, file).take(len);
+    let padded = Fr32Reader::new(
+        (&mut payload)
+            .chain(io::repeat(0))
+            .take(u64::from(piece_size)),
+    );
+    let piece_info = crate::commit_padded(padded, piece_size.into())
+        .with_context(|| format!("hash {}", path.display()))?;
+    ensure!(
+        payload.limit() == 0,
+        "{} was truncated while hashed",
+        path.display()
+    );
+    Ok(piece_info)
+}
+
+/// Same as `predict_piece_info`, for a payload already in memory.
+pub fn predict_from_slice(payload: &[u8]) -> Result<PieceInfo> {
+    predict_with_chunk_size(payload, CHUNK_SIZE)
+}
+
+fn predict_with_chunk_size(payload: &[u8], chunk_size: usize) -> Result<PieceInfo> {
+    let piece_size = piece_size_for(payload.len() as u64);
+    let padded_size = u64::from(PaddedBytesAmount::from(piece_size)) as usize;
+    let chunk_size = chunk_size.min(padded_size);
+    let unpadded_chunk = chunk_size / 128 * 127;
+
+    // chunks past the end of the payload are zeros, their root is shared
+    let data_chunks = payload.len().div_ceil(unpadded_chunk);
+    let zero_root = (data_chunks < padded_size / chunk_size).then(|| chunk_root(&[], chunk_size));
+    let mut roots: Vec<ChunkRoot> = payload
+        .par_chunks(unpadded_chunk)
+        .map(|chunk| chunk_root(chunk, chunk_size))
+        .collect();
+    roots.resize(padded_size / chunk_size, zero_root.unwrap_or_default());
+
+    let comm = pure::reduce_chunk_roots(&roots)?;
+    PieceInfo::new(comm, piece_size)
+}
+
+/// Pads `unpadded`, zero filled to `chunk_size` padded bytes, and hashes it.
+fn chunk_root(unpadded: &[u8], chunk_size: usize) -> ChunkRoot {
+    let mut padded = pure::pad(unpadded);
+    padded.resize(chunk_size, 0);
+    commitment_reader::to_root(&commitment_reader::compute_padded(&padded))
+}
+
+/// Payload bytes making up a padded chunk of `CHUNK_SIZE` bytes.
+pub const UNPADDED_CHUNK_SIZE: u64 = CHUNK_SIZE as u64 / 128 * 127;
+
+/// The commitment of a payload hashed while it is still being written, e.g. by
+/// an export. Its progress can be saved between updates, it is small whenever
+/// `consumed` is a multiple of `UNPADDED_CHUNK_SIZE`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IncrementalCommitment {
+    /// Payload bytes hashed so far, a multiple of 127.
+    consumed: u64,
+    state: ChunksState,
+}
+
+impl Default for IncrementalCommitment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IncrementalCommitment {
+    pub fn new() -> Self {
+        Self {
+            consumed: 0,
+            state: ChunksReader::new(CHUNK_SIZE, io::empty()).snapshot(),
+        }
+    }
+
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Hashes the whole fr32 blocks at the start of `data`, the payload bytes
+    /// following the `consumed` ones. Returns the number of bytes hashed, the
+    /// rest must be given again.
+    pub fn update(&mut self, data: &[u8]) -> Result<usize> {
+        let n = data.len() / 127 * 127;
+        let padded = pure::pad(&data[..n]);
+        let mut reader = ChunksReader::restore(padded.as_slice(), &self.state, None)?;
+        io::copy(&mut reader, &mut io::sink()).context("hash payload")?;
+        // completes a chunk which was just filled, so that it isn't carried
+        // in the state
+        let _ = reader.read(&mut [])?;
+
+        self.state = reader.snapshot();
+        self.consumed += n as u64;
+        Ok(n)
+    }
+
+    /// Completes the commitment of the payload, made of the bytes consumed so
+    /// far followed by `tail` and zero padded to `piece_size_for` its length.
+    pub fn finish(mut self, tail: &[u8]) -> Result<PieceInfo> {
+        let piece_size = piece_size_for(self.consumed + tail.len() as u64);
+
+        let mut rest = tail.to_vec();
+        rest.resize(rest.len().div_ceil(127) * 127, 0);
+        self.update(&rest)?;
+
+        let zeros = vec![0u8; UNPADDED_CHUNK_SIZE as usize];
+        while self.consumed < u64::from(piece_size) {
+            let n = (u64::from(piece_size) - self.consumed).min(UNPADDED_CHUNK_SIZE);
+            self.update(&zeros[..n as usize])?;
+        }
+
+        let reader = ChunksReader::restore(io::empty(), &self.state, None)?;
+        let comm = commitment_reader::to_root(&reader.finish().context("hash payload")?);
+        PieceInfo::new(comm, piece_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use filecoin_proofs::UnpaddedBytesAmount;
+
+    #[test]
+    fn test_predict_piece_info() {
+        assert_eq!(piece_size_for(1), UnpaddedBytesAmount(127));
+        assert_eq!(piece_size_for(127), UnpaddedBytesAmount(127));
+        assert_eq!(piece_size_for(128), UnpaddedBytesAmount(254));
+        assert_eq!(piece_size_for(1017), UnpaddedBytesAmount(2032));
+
+        for len in [1usize, 127, 1000, 5000, 20000] {
+            let payload: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+            let mut padded_payload = payload.clone();
+            padded_payload.resize(u64::from(piece_size_for(len as u64)) as usize, 0);
+            let (_, expected) = pure::pad_and_commit(&padded_payload).unwrap();
+
+            for chunk_size in [128, 1024, CHUNK_SIZE] {
+                let actual = predict_with_chunk_size(&payload, chunk_size).unwrap();
+                assert_eq!(actual, expected, "len {} chunk size {}", len, chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_predict_file() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("payload");
+        for len in [1usize, 1000, 70_000] {
+            let payload: Vec<u8> = (0..len).map(|i| (i * 13 % 255) as u8).collect();
+            fs::write(&path, &payload).unwrap();
+            assert_eq!(
+                predict_piece_info(&path).unwrap(),
+                predict_from_slice(&payload).unwrap(),
+                "len {}",
+                len
+            );
+        }
+
+        fs::write(&path, []).unwrap();
+        assert!(predict_piece_info(&path).is_err());
+    }
+
+    #[test]
+    fn test_incremental_commitment() {
+        let payload: Vec<u8> = (0..5000usize).map(|i| (i * 7 % 253) as u8).collect();
+        let expected = predict_from_slice(&payload).unwrap();
+
+        for step in [100, 127, 1000] {
+            let mut commitment = IncrementalCommitment::new();
+            let mut pending = Vec::new();
+            for part in payload.chunks(step) {
+                pending.extend_from_slice(part);
+                let n = commitment.update(&pending).unwrap();
+                pending.drain(..n);
+
+                // the progress survives a restart
+                let saved = serde_json::to_vec(&commitment).unwrap();
+                commitment = serde_json::from_slice(&saved).unwrap();
+            }
+            assert_eq!(commitment.consumed() % 127, 0);
+            assert_eq!(commitment.finish(&pending).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use add_piece::car;
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{config, paths, source::SizeCheckConfig, PieceFile};
+
+#[derive(Debug, Serialize)]
+pub struct SourcesReport {
+    pub sources: Vec<SourceCheck>,
+    /// Sources which failed a check.
+    pub failed: usize,
+    /// Sources whose payload was read whole and checked against its CID.
+    pub cars_verified: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceCheck {
+    /// Index of the piece in the list.
+    pub index: usize,
+    pub path: String,
+    pub size: u64,
+    /// Whether the payload was checked against its `payload_cid`.
+    pub car_verified: bool,
+    /// Why the source can't be staged, if it can't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Checks that every piece of `pieces` can be read from its file with the
+/// declared size and passes the source policy for the staged file `staged`.
+/// The payloads of up to `spot_checks` pieces with a `payload_cid`, spread
+/// over the list, are read whole and checked against their CID.
+pub fn check(pieces: &[PieceFile], staged: Option<&Path>, spot_checks: usize) -> SourcesReport {
+    let with_cid: Vec<_> = (0..pieces.len())
+        .filter(|i| pieces[*i].payload_cid.is_some())
+        .collect();
+    let spot_checked: Vec<_> = match spot_checks.min(with_cid.len()) {
+        0 => Vec::new(),
+        n => (0..n).map(|k| with_cid[k * with_cid.len() / n]).collect(),
+    };
+
+    let mut report = SourcesReport {
+        sources: Vec::with_capacity(pieces.len()),
+        failed: 0,
+        cars_verified: 0,
+    };
+    for (index, piece) in pieces.iter().enumerate() {
+        let verify_car = spot_checked.contains(&index);
+        let error = check_piece(piece, staged, verify_car)
+            .err()
+            .map(|e| format!("{:#}", e));
+        report.failed += usize::from(error.is_some());
+        report.cars_verified += usize::from(verify_car && error.is_none());
+        report.sources.push(SourceCheck {
+            index,
+            path: piece.path.display().to_string(),
+            size: piece.size,
+            car_verified: verify_car && error.is_none(),
+            error,
+        });
+    }
+    report
+}
+
+fn check_piece(piece: &PieceFile, staged: Option<&Path>, verify_car: bool) -> Result<()> {
+    let config = config::global();
+    config.source_paths.check(&piece.path, staged)?;
+    // the sizes are checked even if staging doesn't
+    let size_check = SizeCheckConfig {
+        enabled: true,
+        ..config.source_size_check
+    };
+    size_check.check(&piece.path, piece.size, piece.size)?;
+
+    let mut file = fs::File::open(paths::normalize(&piece.path))
+        .with_context(|| format!("open piece file {}", piece.path.display()))?;
+    if piece.size > 0 {
+        let mut byte = [0u8];
+        file.read_exact(&mut byte).context("read first byte")?;
+        file.seek(SeekFrom::Start(piece.size - 1))
+            .and_then(|_| file.read_exact(&mut byte))
+            .context("read last byte")?;
+    }
+
+    if let (true, Some(root)) = (verify_car, piece.payload_root()?) {
+        file.rewind().context("rewind piece file")?;
+        car::verify(file.take(piece.size), &root)
+            .with_context(|| format!("verify payload against {}", root))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Dropping root after startup, with `--user` and `--group`: the listeners
+//! are bound first, so that they may use privileged ports, then the process
+//! switches to the unprivileged user before reading any piece data. `--group`
+//! only picks the group `--user` switches to, it can't be given alone since
+//! the process would keep running as root.
+
+use anyhow::{bail, ensure, Result};
+use clap::ArgMatches;
+use tracing::info;
+
+#[derive(Debug, Clone, Default)]
+pub struct DropTo {
+    /// User name or uid to switch to.
+    pub user: Option<String>,
+    /// Group name or gid to switch to, the primary group of `user` if
+    /// unset.
+    pub group: Option<String>,
+}
+
+impl DropTo {
+    pub fn from_matches(m: &ArgMatches) -> Self {
+        Self {
+            user: m.get_one::<String>("user").cloned(),
+            group: m.get_one::<String>("group").cloned(),
+        }
+    }
+
+    /// Switches the process to the user and group, dropping the
+    /// supplementary groups. Does nothing if no user was given, fails rather
+    /// than carrying on with the privileges it was started with.
+    pub fn apply(&self) -> Result<()> {
+        let user = match &self.user {
+            Some(user) => user,
+            None => {
+                ensure!(self.group.is_none(), "--group needs --user");
+                return Ok(());
+            }
+        };
+        let (uid, gid) = imp::drop_to(user, self.group.as_deref())?;
+        info!(uid, gid, "dropped privileges");
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{ffi::CString, io, mem, ptr};
+
+    use anyhow::Context;
+
+    use super::*;
+
+    /// Returns the uid and gid switched to.
+    pub fn drop_to(user: &str, group: Option<&str>) -> Result<(u32, u32)> {
+        let (uid, gid) = lookup_user(user)?;
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => gid,
+        };
+
+        // the groups go first, changing them needs root
+        let res = unsafe { libc::setgroups(1, &gid) };
+        if res != 0 {
+            return Err(io::Error::last_os_error()).context("set supplementary groups");
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(io::Error::last_os_error()).with_context(|| format!("set gid {}", gid));
+        }
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(io::Error::last_os_error()).with_context(|| format!("set uid {}", uid));
+        }
+        ensure!(
+            uid == 0 || unsafe { libc::setuid(0) } != 0,
+            "root could be regained after switching to uid {}",
+            uid
+        );
+        Ok((uid, gid))
+    }
+
+    /// uid and primary gid of `user`, a name or a uid.
+    fn lookup_user(user: &str) -> Result<(u32, u32)> {
+        let name = CString::new(user).context("user name")?;
+        let mut buf = vec![0 as libc::c_char; 16 << 10];
+        let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut found = ptr::null_mut();
+        let res = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res))
+                .with_context(|| format!("look up user {}", user));
+        }
+        if !found.is_null() {
+            return Ok((pwd.pw_uid, pwd.pw_gid));
+        }
+
+        let uid = match user.parse::<u32>() {
+            Ok(uid) => uid,
+            Err(_) => bail!("no such user: {}", user),
+        };
+        let mut found = ptr::null_mut();
+        let res =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res))
+                .with_context(|| format!("look up uid {}", uid));
+        }
+        // a uid without a passwd entry keeps its own number as group
+        if found.is_null() {
+            Ok((uid, uid))
+        } else {
+            Ok((pwd.pw_uid, pwd.pw_gid))
+        }
+    }
+
+    /// gid of `group`, a name or a gid.
+    fn lookup_group(group: &str) -> Result<u32> {
+        let name = CString::new(group).context("group name")?;
+        let mut buf = vec![0 as libc::c_char; 16 << 10];
+        let mut grp: libc::group = unsafe { mem::zeroed() };
+        let mut found = ptr::null_mut();
+        let res = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut grp,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res))
+                .with_context(|| format!("look up group {}", group));
+        }
+        if !found.is_null() {
+            return Ok(grp.gr_gid);
+        }
+        match group.parse::<u32>() {
+            Ok(gid) => Ok(gid),
+            Err(_) => bail!("no such group: {}", group),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::*;
+
+    pub fn drop_to(_user: &str, _group: Option<&str>) -> Result<(u32, u32)> {
+        bail!("--user and --group are only supported on Linux")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_needs_user() {
+        assert!(DropTo::default().apply().is_ok());
+        let group_only = DropTo {
+            user: None,
+            group: Some("nogroup".to_string()),
+        };
+        assert!(group_only.apply().is_err());
+    }
+}
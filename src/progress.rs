@@ -0,0 +1,137 @@
+//! Progress of the standalone commands: a bar per piece being added when
+//! stdout is a terminal, a log line per task every few seconds otherwise.
+
+use std::{
+    collections::HashMap,
+    io::{self, IsTerminal},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tracing::info;
+
+use crate::status::{self, TaskView};
+
+const BAR_REFRESH: Duration = Duration::from_millis(200);
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Reports the progress of the tasks registered with `status` until dropped.
+pub struct Reporter {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub fn start() -> Reporter {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = stop.clone();
+        match io::stdout().is_terminal() {
+            true => thread::spawn(move || show_bars(&stop)),
+            false => thread::spawn(move || log_lines(&stop)),
+        }
+    };
+    Reporter {
+        stop,
+        thread: Some(thread),
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn show_bars(stop: &AtomicBool) {
+    let bars = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+    let style =
+        ProgressStyle::with_template("{msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec}")
+            .expect("valid template")
+            .progress_chars("=> ");
+
+    // the bar of each task with the piece it shows
+    let mut shown: HashMap<u64, (ProgressBar, usize)> = HashMap::new();
+    while !stop.load(Ordering::Relaxed) {
+        let tasks = status::snapshot().tasks;
+        shown.retain(|id, (bar, _)| {
+            let running = tasks.iter().any(|t| t.id == *id);
+            if !running {
+                bar.finish_and_clear();
+            }
+            running
+        });
+        for task in &tasks {
+            let piece = match &task.piece {
+                Some(p) => p,
+                None => continue,
+            };
+            let (bar, index) = shown.entry(task.id).or_insert_with(|| {
+                let bar = bars.add(ProgressBar::new(piece.size).with_style(style.clone()));
+                (bar, piece.index)
+            });
+            if *index != piece.index {
+                bar.reset();
+                bar.set_length(piece.size);
+                *index = piece.index;
+            }
+            bar.set_position(piece.read);
+            bar.set_message(format!(
+                "{} eta {}{}",
+                label(task),
+                status::format_eta(task.piece_eta),
+                match task.pieces {
+                    1 => String::new(),
+                    _ => format!(" (task {})", status::format_eta(task.task_eta)),
+                }
+            ));
+        }
+        thread::sleep(BAR_REFRESH);
+    }
+    for (bar, _) in shown.values() {
+        bar.finish_and_clear();
+    }
+}
+
+fn log_lines(stop: &AtomicBool) {
+    let mut last = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(BAR_REFRESH);
+        if last.elapsed() < LOG_INTERVAL {
+            continue;
+        }
+        last = Instant::now();
+        for task in status::snapshot().tasks {
+            if let Some(p) = &task.piece {
+                info!(
+                    task = %label(&task),
+                    source = %p.source,
+                    pieces_done = task.done,
+                    read = p.read,
+                    size = p.size,
+                    elapsed_secs = task.started.elapsed().as_secs(),
+                    bytes_per_sec = task.bytes_per_sec.unwrap_or_default() as u64,
+                    piece_eta = %status::format_eta(task.piece_eta),
+                    task_eta = %status::format_eta(task.task_eta),
+                    "progress"
+                );
+            }
+        }
+    }
+}
+
+fn label(task: &TaskView) -> String {
+    match (&task.piece, task.pieces) {
+        (Some(p), n) if n > 1 => {
+            format!("{} piece {}/{}", task.staged.display(), p.index + 1, n)
+        }
+        _ => task.staged.display().to_string(),
+    }
+}
@@ -0,0 +1,108 @@
+//! Memory pressure of the processor's cgroup, from the pressure stall
+//! information (PSI) of cgroup v2, followed on a background thread.
+//!
+//! Once the share of time some tasks stalled on memory over the last 10
+//! seconds reaches `enter_avg10`, `add_piece` is told to hold less memory,
+//! see `add_piece::memory_pressure`, until it drops to `leave_avg10`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use add_piece::memory_pressure;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryPressureConfig {
+    /// `memory.pressure` file followed, that of the cgroup of the processor
+    /// by default.
+    pub path: Option<PathBuf>,
+    /// Percentage of `some avg10` from which memory is short.
+    pub enter_avg10: f64,
+    /// Percentage of `some avg10` under which it is not anymore.
+    pub leave_avg10: f64,
+    pub interval_ms: u64,
+}
+
+impl Default for MemoryPressureConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            enter_avg10: 20.0,
+            leave_avg10: 5.0,
+            interval_ms: 1000,
+        }
+    }
+}
+
+/// The `memory.pressure` file of the cgroup of this process.
+fn own_pressure_file() -> Result<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").context("read /proc/self/cgroup")?;
+    // cgroup v2 has a single hierarchy, `0::<path>`
+    let cgroup = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| anyhow!("not in a cgroup v2 hierarchy"))?;
+    Ok(Path::new("/sys/fs/cgroup")
+        .join(cgroup.trim_start_matches('/'))
+        .join("memory.pressure"))
+}
+
+/// `avg10` of the `some` line of a PSI file, e.g.
+/// `some avg10=1.53 avg60=0.87 avg300=0.22 total=1234`.
+fn some_avg10(psi: &str) -> Option<f64> {
+    psi.lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+fn read_avg10(path: &Path) -> Result<f64> {
+    let psi = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    some_avg10(&psi).ok_or_else(|| anyhow!("no `some avg10` in {}", path.display()))
+}
+
+/// Follows the memory pressure every `interval_ms` on a background thread.
+pub fn start(config: &MemoryPressureConfig) -> Result<()> {
+    let path = match &config.path {
+        Some(path) => path.clone(),
+        None => own_pressure_file()?,
+    };
+    read_avg10(&path).context("memory pressure unavailable")?;
+    info!(path = %path.display(), "following memory pressure");
+
+    let config = config.clone();
+    thread::Builder::new()
+        .name("memory-pressure".to_string())
+        .spawn(move || loop {
+            match read_avg10(&path) {
+                Ok(avg10) => update(&config, avg10),
+                Err(e) => warn!(err = ?e, "failed to read memory pressure"),
+            }
+            thread::sleep(Duration::from_millis(config.interval_ms));
+        })
+        .context("spawn memory pressure thread")?;
+    Ok(())
+}
+
+fn update(config: &MemoryPressureConfig, avg10: f64) {
+    match memory_pressure::under_pressure() {
+        false if avg10 >= config.enter_avg10 => {
+            warn!(avg10, "memory pressure, shrinking read ahead and hashing");
+            memory_pressure::set_under_pressure(true);
+        }
+        true if avg10 <= config.leave_avg10 => {
+            info!(avg10, "memory pressure gone, back to full speed");
+            memory_pressure::set_under_pressure(false);
+        }
+        _ => {}
+    }
+}
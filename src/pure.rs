@@ -0,0 +1,246 @@
+//! Padding, commitment and alignment planning over byte slices.
+//!
+//! Nothing here touches the filesystem, logging or process wide state, which
+//! makes these functions suitable for fuzzing (see `fuzz/`).
+
+use std::io::Read;
+
+use anyhow::{ensure, Context, Result};
+use filecoin_hashers::{Domain, Hasher};
+use filecoin_proofs::constants::DefaultPieceHasher;
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, UnpaddedBytesAmount};
+use fr32::Fr32Reader;
+
+use crate::{
+    commitment_reader,
+    dedup::ChunkRoot,
+    ensure_piece_size,
+    layout::{get_piece_alignment, sum_piece_bytes_with_alignment},
+};
+
+/// Where a piece lands in a sector, in padded bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PiecePlacement {
+    /// Zero bytes written before the piece.
+    pub left: PaddedBytesAmount,
+    /// Offset of the piece data from the start of the sector.
+    pub offset: PaddedBytesAmount,
+    /// Padded size of the piece data.
+    pub size: PaddedBytesAmount,
+    /// Zero bytes written after the piece.
+    pub right: PaddedBytesAmount,
+}
+
+impl PiecePlacement {
+    /// Offset of the first byte following the piece and its right alignment.
+    pub fn end(&self) -> PaddedBytesAmount {
+        self.offset + self.size + self.right
+    }
+
+    /// Bytes written for the piece including its alignment, as returned by
+    /// `add_piece`.
+    pub fn written(&self) -> UnpaddedBytesAmount {
+        (self.left + self.size + self.right).into()
+    }
+}
+
+/// Bit-pads `payload`, the last fr32 block is zero filled if incomplete.
+pub fn pad(payload: &[u8]) -> Vec<u8> {
+    let mut padded = Vec::with_capacity(payload.len() / 127 * 128 + 128);
+    Fr32Reader::new(payload)
+        .read_to_end(&mut padded)
+        .expect("reading from a slice cannot fail");
+    padded
+}
+
+/// Computes the commitment of bit-padded piece data.
+pub fn commit(padded: &[u8]) -> Result<[u8; 32]> {
+    ensure!(
+        padded.len() >= 128 && padded.len().is_power_of_two(),
+        "padded piece data must be a power of 2 of at least 128 bytes, got {}",
+        padded.len()
+    );
+
+    let mut comm = [0u8; 32];
+    comm.copy_from_slice(commitment_reader::compute_padded(padded).as_ref());
+    Ok(comm)
+}
+
+/// Computes the commitment of a piece from the roots of its chunks, which must
+/// be a power of 2 in number.
+pub fn reduce_chunk_roots(roots: &[ChunkRoot]) -> Result<[u8; 32]> {
+    ensure!(
+        roots.len().is_power_of_two(),
+        "the number of chunk roots must be a power of 2, got {}",
+        roots.len()
+    );
+
+    let row = roots
+        .iter()
+        .map(|r| <DefaultPieceHasher as Hasher>::Domain::try_from_bytes(r))
+        .collect::<Result<Vec<_>>>()?;
+    let mut comm = [0u8; 32];
+    comm.copy_from_slice(commitment_reader::reduce(row).as_ref());
+    Ok(comm)
+}
+
+/// Pads `payload`, whose length must be a valid unpadded piece size, and
+/// returns the padded bytes with their `PieceInfo`.
+pub fn pad_and_commit(payload: &[u8]) -> Result<(Vec<u8>, PieceInfo)> {
+    let piece_size = UnpaddedBytesAmount(payload.len() as u64);
+    ensure_piece_size(piece_size)?;
+
+    let padded = pad(payload);
+    let comm = commit(&padded)?;
+    Ok((padded, PieceInfo::new(comm, piece_size)?))
+}
+
+/// Plans the placement of a piece of `piece_size` following the pieces
+/// `piece_lengths` already in the sector.
+pub fn plan_alignment(
+    piece_lengths: &[UnpaddedBytesAmount],
+    piece_size: UnpaddedBytesAmount,
+) -> Result<PiecePlacement> {
+    let written = sum_piece_bytes_with_alignment(piece_lengths);
+    place(written.into(), piece_size)
+}
+
+/// Plans the placement of each of `piece_sizes`, written one after another.
+pub fn plan_pieces(piece_sizes: &[UnpaddedBytesAmount]) -> Result<Vec<PiecePlacement>> {
+    let mut placements: Vec<PiecePlacement> = Vec::with_capacity(piece_sizes.len());
+    for size in piece_sizes {
+        let written = placements
+            .last()
+            .map(PiecePlacement::end)
+            .unwrap_or(PaddedBytesAmount(0));
+        placements.push(place(written, *size)?);
+    }
+    Ok(placements)
+}
+
+/// Padded alignment bytes of a sector once a piece of `piece_size` follows the
+/// pieces `piece_lengths` already in it.
+pub fn sector_alignment(
+    piece_lengths: &[UnpaddedBytesAmount],
+    piece_size: UnpaddedBytesAmount,
+) -> Result<PaddedBytesAmount> {
+    let placement = plan_alignment(piece_lengths, piece_size)?;
+    let payload: u64 = piece_lengths
+        .iter()
+        .chain([&piece_size])
+        .map(|size| u64::from(PaddedBytesAmount::from(*size)))
+        .sum();
+    Ok(PaddedBytesAmount(u64::from(placement.end()) - payload))
+}
+
+/// Converts the pieces already in a sector, in order, into the `piece_lengths`
+/// argument of `add_piece`, checking that each of them is a valid piece.
+pub fn piece_lengths(pieces: &[PieceInfo]) -> Result<Vec<UnpaddedBytesAmount>> {
+    let lengths: Vec<_> = pieces.iter().map(|p| p.size).collect();
+    for (i, size) in lengths.iter().enumerate() {
+        ensure_piece_size(*size).with_context(|| format!("piece {}", i))?;
+    }
+    Ok(lengths)
+}
+
+/// Every valid piece size up to `sector_size` padded bytes, smallest first,
+/// unpadded with its padded counterpart.
+pub fn piece_sizes(
+    sector_size: PaddedBytesAmount,
+) -> Vec<(UnpaddedBytesAmount, PaddedBytesAmount)> {
+    std::iter::successors(Some(128u64), |padded| padded.checked_mul(2))
+        .take_while(|padded| *padded <= u64::from(sector_size))
+        .map(|padded| (PaddedBytesAmount(padded).into(), PaddedBytesAmount(padded)))
+        .collect()
+}
+
+fn place(written: PaddedBytesAmount, piece_size: UnpaddedBytesAmount) -> Result<PiecePlacement> {
+    ensure_piece_size(piece_size)?;
+
+    let alignment = get_piece_alignment(written.into(), piece_size);
+    let left: PaddedBytesAmount = alignment.left_bytes.into();
+
+    Ok(PiecePlacement {
+        left,
+        offset: written + left,
+        size: piece_size.into(),
+        right: alignment.right_bytes.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sector_alignment() {
+        let piece_lengths = [127, 127 * 4].map(UnpaddedBytesAmount);
+        let alignment = sector_alignment(&piece_lengths, UnpaddedBytesAmount(127 * 2))
+            .expect("sector_alignment failed");
+        assert_eq!(alignment, PaddedBytesAmount(128 * 3));
+    }
+
+    #[test]
+    fn test_piece_sizes() {
+        let sizes = piece_sizes(PaddedBytesAmount(1024));
+        assert_eq!(
+            sizes,
+            [(127, 128), (254, 256), (508, 512), (1016, 1024)]
+                .map(|(u, p)| (UnpaddedBytesAmount(u), PaddedBytesAmount(p)))
+        );
+        for (unpadded, _) in &sizes {
+            ensure_piece_size(*unpadded).expect("invalid piece size");
+        }
+        assert_eq!(piece_sizes(PaddedBytesAmount(1000)).len(), 3);
+        assert!(piece_sizes(PaddedBytesAmount(64)).is_empty());
+        assert_eq!(piece_sizes(PaddedBytesAmount(64 << 30)).len(), 30);
+    }
+
+    #[test]
+    fn test_reduce_chunk_roots() {
+        let payload: Vec<u8> = (0..127 * 8).map(|i| i as u8).collect();
+        let (padded, piece_info) = pad_and_commit(&payload).expect("pad_and_commit failed");
+
+        let roots: Vec<ChunkRoot> = padded
+            .chunks(256)
+            .map(|chunk| commit(chunk).expect("commit failed"))
+            .collect();
+        assert_eq!(
+            reduce_chunk_roots(&roots).expect("reduce_chunk_roots failed"),
+            piece_info.commitment
+        );
+        assert!(reduce_chunk_roots(&roots[..3]).is_err());
+    }
+
+    #[test]
+    fn test_piece_lengths() {
+        let pieces = [127, 127 * 4].map(|n| PieceInfo {
+            commitment: [1u8; 32],
+            size: UnpaddedBytesAmount(n),
+        });
+        assert_eq!(
+            piece_lengths(&pieces).expect("piece_lengths failed"),
+            vec![UnpaddedBytesAmount(127), UnpaddedBytesAmount(127 * 4)]
+        );
+
+        let mut invalid = pieces.to_vec();
+        invalid[1].size = UnpaddedBytesAmount(127 * 3);
+        assert!(piece_lengths(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_plan_pieces() {
+        let sizes = [127, 127 * 4, 127, 127 * 8, 127 * 2].map(UnpaddedBytesAmount);
+        let placements = plan_pieces(&sizes).expect("plan_pieces failed");
+
+        for (i, placement) in placements.iter().enumerate() {
+            assert_eq!(u64::from(placement.offset) % u64::from(placement.size), 0);
+            let end = sum_piece_bytes_with_alignment(&sizes[..=i]);
+            assert_eq!(placement.end(), end.into(), "piece {}", i);
+            assert_eq!(
+                plan_alignment(&sizes[..i], sizes[i]).expect("plan_alignment failed"),
+                *placement
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::memory_pressure;
+use crate::metered::{IoStats, Metered};
+
+/// How often a prefetch thread held back by memory pressure checks whether
+/// it is gone.
+const PRESSURE_POLL: Duration = Duration::from_millis(100);
+
+/// Sizing of the ring of buffers between a `ReadAhead` and its source.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadAheadConfig {
+    /// Size of each buffer filled by the prefetch thread.
+    pub buffer_size: usize,
+    /// Number of filled buffers that may wait for the consumer.
+    pub queue_depth: usize,
+}
+
+impl Default for ReadAheadConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 8 << 20,
+            queue_depth: 4,
+        }
+    }
+}
+
+/// Reads a source on a dedicated thread, keeping up to `queue_depth` buffers
+/// filled ahead of the consumer so that the latency of slow (e.g. remote)
+/// sources overlaps with hashing. Only one buffer is filled ahead under
+/// memory pressure.
+pub struct ReadAhead {
+    filled: Receiver<io::Result<Vec<u8>>>,
+    recycle: SyncSender<Vec<u8>>,
+    current: Vec<u8>,
+    pos: usize,
+    stats: Arc<Mutex<IoStats>>,
+    /// Filled buffers not taken by the consumer yet.
+    queued: Arc<AtomicUsize>,
+}
+
+impl ReadAhead {
+    /// Spawns the prefetch thread, which opens the source with `open` itself
+    /// so the source does not need to be `Send`.
+    pub fn spawn<F, R>(config: ReadAheadConfig, open: F) -> Self
+    where
+        F: FnOnce() -> anyhow::Result<R> + Send + 'static,
+        R: Read,
+    {
+        let buffer_size = config.buffer_size.max(1);
+        let queue_depth = config.queue_depth.max(1);
+        let (filled_tx, filled) = mpsc::sync_channel(queue_depth);
+        let (recycle, recycle_rx) = mpsc::sync_channel::<Vec<u8>>(queue_depth + 1);
+        let stats = Arc::new(Mutex::new(IoStats::default()));
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let thread_stats = stats.clone();
+        let thread_queued = queued.clone();
+        thread::spawn(move || {
+            let mut source = match open() {
+                Ok(s) => Metered::new(s),
+                Err(e) => {
+                    let _ = filled_tx.send(Err(io::Error::other(format!("{:#}", e))));
+                    return;
+                }
+            };
+
+            loop {
+                let mut recycled = recycle_rx.try_recv().ok();
+                while memory_pressure::under_pressure() && thread_queued.load(Ordering::Relaxed) > 0
+                {
+                    // the consumer hands a buffer back as it takes the next
+                    match recycle_rx.recv_timeout(PRESSURE_POLL) {
+                        Ok(buf) => recycled = Some(buf),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                let mut buf = recycled.unwrap_or_else(|| Vec::with_capacity(buffer_size));
+                let res = fill(&mut source, &mut buf, buffer_size);
+                *thread_stats.lock().expect("stats lock poisoned") = source.stats();
+
+                let eof = match res {
+                    Ok(()) => buf.len() < buffer_size,
+                    Err(e) => {
+                        let _ = filled_tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                // the consumer is gone when sending fails
+                if !buf.is_empty() {
+                    thread_queued.fetch_add(1, Ordering::Relaxed);
+                    if filled_tx.send(Ok(buf)).is_err() {
+                        return;
+                    }
+                }
+
+                if eof {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            filled,
+            recycle,
+            current: Vec::new(),
+            pos: 0,
+            stats,
+            queued,
+        }
+    }
+
+    /// Returns the bytes read from the source and the time spent reading them
+    /// so far, excluding the time the consumer waited on the queue.
+    pub fn source_stats(&self) -> IoStats {
+        *self.stats.lock().expect("stats lock poisoned")
+    }
+}
+
+/// Fills `buf` up to `size` bytes, stopping short only at EOF.
+fn fill<R: Read>(source: &mut R, buf: &mut Vec<u8>, size: usize) -> io::Result<()> {
+    buf.clear();
+    source.take(size as u64).read_to_end(buf)?;
+    Ok(())
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.current.len() {
+            let next = match self.filled.recv() {
+                Ok(Ok(next)) => {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    next
+                }
+                Ok(Err(e)) => return Err(e),
+                // the prefetch thread finished
+                Err(_) => return Ok(0),
+            };
+
+            let used = std::mem::replace(&mut self.current, next);
+            let _ = self.recycle.try_send(used);
+            self.pos = 0;
+        }
+
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_ahead() {
+        let source: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let config = ReadAheadConfig {
+            buffer_size: 333,
+            queue_depth: 2,
+        };
+
+        let expected = source.clone();
+        let mut reader = ReadAhead::spawn(config, move || Ok(Cursor::new(source)));
+        let mut actual = Vec::new();
+        reader.read_to_end(&mut actual).expect("read failed");
+
+        assert_eq!(actual, expected);
+        assert_eq!(reader.source_stats().bytes, expected.len() as u64);
+    }
+
+    #[test]
+    fn test_read_ahead_open_error() {
+        let mut reader = ReadAhead::spawn(ReadAheadConfig::default(), || {
+            Err::<Cursor<Vec<u8>>, _>(anyhow::anyhow!("no such piece"))
+        });
+
+        let err = reader.read(&mut [0u8; 16]).expect_err("should fail");
+        assert!(err.to_string().contains("no such piece"));
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    fs,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use add_piece::tee::TeeReader;
+use anyhow::{Context, Result};
+use filecoin_proofs::PieceInfo;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use vc_processors::fil_proofs::RegisteredSealProof;
+
+use crate::{config::Config, source::Opener, PieceFile};
+
+const TASK_FILE: &str = "task.json";
+const RESULT_FILE: &str = "result.json";
+const SOURCES_DIR: &str = "sources";
+
+/// The inputs of a recorded task.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedTask {
+    /// An `AddPieces` task received by the processor.
+    Processor { task: serde_json::Value },
+    /// A run of the `add_pieces` command.
+    AddPieces {
+        pieces: Vec<PieceFile>,
+        origin: bool,
+        #[serde(default)]
+        proof: Option<RegisteredSealProof>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TaskRecord {
+    version: String,
+    config: Config,
+    task: RecordedTask,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedResult {
+    pub piece_infos: Option<Vec<PieceInfo>>,
+    pub error: Option<String>,
+}
+
+impl RecordedResult {
+    pub fn new(result: &Result<Vec<PieceInfo>>) -> Self {
+        match result {
+            Ok(piece_infos) => Self {
+                piece_infos: Some(piece_infos.clone()),
+                error: None,
+            },
+            Err(e) => Self {
+                piece_infos: None,
+                error: Some(format!("{:?}", e)),
+            },
+        }
+    }
+}
+
+/// Captures the inputs of a task, the exact bytes read from each of its
+/// piece sources and its result, so the task can be replayed later.
+///
+/// Pieces are processed one after another without retries, so the source
+/// bytes are the only non-deterministic input of a task.
+pub struct Recorder {
+    dir: PathBuf,
+}
+
+impl Recorder {
+    /// Starts a recording in a fresh sub directory of `root`.
+    pub fn start(root: &Path, config: &Config, task: RecordedTask) -> Result<Self> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let dir = root.join(format!("{}-{}", millis, process::id()));
+        fs::create_dir_all(dir.join(SOURCES_DIR))
+            .with_context(|| format!("create record dir: {}", dir.display()))?;
+
+        let record = TaskRecord {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            config: config.clone(),
+            task,
+        };
+        write_json(&dir.join(TASK_FILE), &record)?;
+
+        info!(dir = %dir.display(), "recording task");
+        Ok(Self { dir })
+    }
+
+    /// Wraps `open` so that everything read from the source is captured.
+    pub fn tee(&self, index: usize, open: Opener) -> Opener {
+        let path = source_path(&self.dir, index);
+        Arc::new(move || {
+            let copy = fs::File::create(&path)
+                .with_context(|| format!("create source capture: {}", path.display()))?;
+            Ok(Box::new(TeeReader::new(open()?, BufWriter::new(copy))))
+        })
+    }
+
+    pub fn finish(self, result: &Result<Vec<PieceInfo>>) -> Result<()> {
+        write_json(&self.dir.join(RESULT_FILE), &RecordedResult::new(result))
+    }
+}
+
+/// A recording loaded for replay.
+pub struct Recording {
+    pub dir: PathBuf,
+    pub config: Config,
+    pub task: RecordedTask,
+    pub result: Option<RecordedResult>,
+}
+
+impl Recording {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let record: TaskRecord = read_json(&dir.join(TASK_FILE))?;
+        let result_path = dir.join(RESULT_FILE);
+        let result = match result_path.exists() {
+            true => Some(read_json(&result_path)?),
+            false => None,
+        };
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            config: record.config,
+            task: record.task,
+            result,
+        })
+    }
+
+    /// Opens the captured bytes of the source of the `index`-th piece.
+    pub fn opener(&self, index: usize) -> Opener {
+        let path = source_path(&self.dir, index);
+        Arc::new(move || {
+            let f = fs::File::open(&path).with_context(|| {
+                format!(
+                    "source of piece {} was not captured: {}",
+                    index,
+                    path.display()
+                )
+            })?;
+            Ok(Box::new(f))
+        })
+    }
+}
+
+/// Where the sources of a task are read from.
+pub enum Sources<'a> {
+    /// Opened normally, and captured if recording.
+    Live(Option<&'a Recorder>),
+    /// Read back from a recording.
+    Replay(&'a Recording),
+}
+
+impl Sources<'_> {
+    /// Returns the opener to use for the `index`-th piece, whose live source
+    /// would be opened by `live`.
+    pub fn opener(&self, index: usize, live: Opener) -> Opener {
+        match self {
+            Sources::Live(None) => live,
+            Sources::Live(Some(recorder)) => recorder.tee(index, live),
+            Sources::Replay(recording) => recording.opener(index),
+        }
+    }
+}
+
+fn source_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(SOURCES_DIR).join(format!("{}.bin", index))
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let content = serde_json::to_vec_pretty(value).context("serialize record")?;
+    fs::write(path, content).with_context(|| format!("write {}", path.display()))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let content = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    serde_json::from_slice(&content).with_context(|| format!("parse {}", path.display()))
+}
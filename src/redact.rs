@@ -0,0 +1,232 @@
+//! Redaction of the piece paths and URLs, which may name clients or carry
+//! signed tokens, from the logs, the task errors, the webhooks and the
+//! progress display, enabled with `--redact`.
+//!
+//! The paths and URLs of the tasks are registered as they are received and
+//! replaced wherever they appear, URLs are redacted whether registered or
+//! not. Hashes are stable, so a path can still be followed through the logs,
+//! or looked up by hashing it.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Registered strings kept, the oldest are forgotten first.
+const MAX_REGISTERED: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactMode {
+    /// `<redacted:1a2b3c4d>`, from the sha256 of the path or URL.
+    Hash,
+    /// Only the file name of paths, `…/name`, and the host of URLs.
+    Truncate,
+}
+
+impl RedactMode {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("unknown redaction: {}", s))
+    }
+}
+
+static MODE: OnceLock<RedactMode> = OnceLock::new();
+static REGISTERED: Mutex<Registered> = Mutex::new(Registered::new());
+
+/// The registered strings, kept longest first so that a path is replaced
+/// before the shorter ones it contains.
+struct Registered {
+    /// In registration order, the oldest are forgotten first.
+    order: VecDeque<String>,
+    longest_first: Vec<String>,
+}
+
+impl Registered {
+    const fn new() -> Self {
+        Self {
+            order: VecDeque::new(),
+            longest_first: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, s: &str) {
+        if self.order.iter().any(|r| r == s) {
+            return;
+        }
+        if self.order.len() == MAX_REGISTERED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.longest_first.retain(|r| *r != oldest);
+            }
+        }
+        self.order.push_back(s.to_string());
+        let at = self.longest_first.partition_point(|r| r.len() >= s.len());
+        self.longest_first.insert(at, s.to_string());
+    }
+}
+
+pub fn enable(mode: RedactMode) {
+    MODE.set(mode).expect("redaction enabled twice");
+}
+
+/// Redacts `s` from now on, if redaction is enabled.
+pub fn register(s: &str) {
+    if MODE.get().is_none() || s.is_empty() {
+        return;
+    }
+    REGISTERED.lock().expect("redaction poisoned").insert(s);
+}
+
+pub fn register_path(path: &Path) {
+    register(&path.display().to_string());
+}
+
+/// `text` with the registered strings and every URL redacted.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let mode = match MODE.get() {
+        Some(mode) => *mode,
+        None => return Cow::Borrowed(text),
+    };
+    let mut out = Cow::Borrowed(text);
+    {
+        let registered = REGISTERED.lock().expect("redaction poisoned");
+        for s in &registered.longest_first {
+            if out.contains(s.as_str()) {
+                out = Cow::Owned(out.replace(s.as_str(), &replacement(mode, s)));
+            }
+        }
+    }
+    match redact_urls(mode, &out) {
+        Some(redacted) => Cow::Owned(redacted),
+        None => out,
+    }
+}
+
+pub fn path(path: &Path) -> PathBuf {
+    match redact(&path.display().to_string()) {
+        Cow::Borrowed(_) => path.to_path_buf(),
+        Cow::Owned(s) => PathBuf::from(s),
+    }
+}
+
+/// `e` with its message and causes redacted, for the errors of the tasks and
+/// the one `main` exits with.
+pub fn error(e: anyhow::Error) -> anyhow::Error {
+    if MODE.get().is_none() {
+        return e;
+    }
+    let text = format!("{:?}", e);
+    anyhow::anyhow!("{}", redact(&text))
+}
+
+fn replacement(mode: RedactMode, s: &str) -> String {
+    match mode {
+        RedactMode::Hash => {
+            let hash = hmac_sha256::Hash::hash(s.as_bytes());
+            format!("<redacted:{}>", hex::encode(&hash[..4]))
+        }
+        RedactMode::Truncate => match s.split_once("://") {
+            Some((scheme, rest)) => {
+                let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+                let host = authority.rsplit('@').next().unwrap_or_default();
+                format!("{}://{}/…", scheme, host)
+            }
+            None => match Path::new(s).file_name() {
+                Some(name) => format!("…/{}", name.to_string_lossy()),
+                None => "…".to_string(),
+            },
+        },
+    }
+}
+
+/// Redacts the URLs of `text`, from their scheme to the next whitespace,
+/// quote or escape sequence. Returns `None` if there is none.
+fn redact_urls(mode: RedactMode, text: &str) -> Option<String> {
+    let mut found = text.find("://")?;
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    loop {
+        let start = rest[..found]
+            .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.'))
+            .map_or(0, |i| i + 1);
+        let end = rest[found..]
+            .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '`' | '\x1b'))
+            .map_or(rest.len(), |i| found + i);
+        out.push_str(&rest[..start]);
+        if start < found {
+            out.push_str(&replacement(mode, &rest[start..end]));
+        } else {
+            out.push_str(&rest[start..end]);
+        }
+        rest = &rest[end..];
+        match rest.find("://") {
+            Some(i) => found = i,
+            None => break,
+        }
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// Writes the formatted log lines to stdout, redacted.
+pub struct RedactedStdout;
+
+impl<'a> MakeWriter<'a> for RedactedStdout {
+    type Writer = RedactedWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactedWriter(io::stdout())
+    }
+}
+
+pub struct RedactedWriter(io::Stdout);
+
+impl Write for RedactedWriter {
+    /// Takes whole log lines, which the formatter writes at once.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if MODE.get().is_none() {
+            return self.0.write(buf);
+        }
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registered_longest_first() {
+        let mut registered = Registered::new();
+        for s in ["/a/b", "/a/b/piece", "/a", "/a/b"] {
+            registered.insert(s);
+        }
+        assert_eq!(registered.longest_first, ["/a/b/piece", "/a/b", "/a"]);
+
+        for i in 0..MAX_REGISTERED - 3 {
+            registered.insert(&format!("/p/{}", i));
+        }
+        assert_eq!(registered.order.len(), MAX_REGISTERED);
+        assert_eq!(registered.longest_first.len(), MAX_REGISTERED);
+        // the oldest goes first
+        assert_eq!(registered.order.front().map(String::as_str), Some("/a/b"));
+        registered.insert("/q");
+        assert!(!registered.longest_first.iter().any(|r| r == "/a/b"));
+        assert_eq!(
+            registered.longest_first.first().map(String::as_str),
+            Some("/a/b/piece")
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//! Announces the processor to a cluster manager when it starts, and keeps
+//! pinging it while it runs, so that fleets of workers are inventoried without
+//! listing them by hand next to the vc-processors wiring.
+
+use std::{thread, time::Duration, time::Instant};
+
+use add_piece::{manifest, support};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{self, TaskLimits},
+    mount_limits::MountLimit,
+    spec, status,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistrationConfig {
+    /// Endpoint the registration is posted to when the processor starts.
+    pub url: String,
+
+    /// Endpoint the liveness pings are posted to, `url` if unset.
+    #[serde(default)]
+    pub ping_url: Option<String>,
+
+    /// Name of the worker, its host name by default.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Where the worker runs, a rack or zone, passed as-is to the manager.
+    #[serde(default)]
+    pub location: Option<String>,
+
+    /// Seconds between two pings, 0 to only register.
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// Fail the start of the processor if it can't register, instead of
+    /// registering again with the next ping.
+    #[serde(default)]
+    pub required: bool,
+}
+
+fn default_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+impl RegistrationConfig {
+    fn worker(&self) -> String {
+        self.name.clone().unwrap_or_else(manifest::hostname)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Registration<'a> {
+    event: &'static str,
+    worker: String,
+    location: Option<&'a str>,
+    spec: spec::Spec,
+    support: support::Support,
+    limits: Limits<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct Limits<'a> {
+    task_limits: Option<&'a TaskLimits>,
+    mount_limits: &'a [MountLimit],
+}
+
+#[derive(Debug, Serialize)]
+struct Ping {
+    event: &'static str,
+    worker: String,
+    uptime_secs: u64,
+    tasks_in_flight: usize,
+}
+
+/// Registers the worker if `registration` is configured, and starts pinging
+/// the manager from a background thread.
+pub fn start(registration: Option<&RegistrationConfig>) -> Result<()> {
+    let registration = match registration {
+        Some(r) => r.clone(),
+        None => return Ok(()),
+    };
+
+    let mut registered = match register(&registration) {
+        Ok(()) => true,
+        Err(e) if registration.required => return Err(e),
+        Err(e) => {
+            warn!(url = %registration.url, err = ?e, "registration failed, retried with the next ping");
+            false
+        }
+    };
+    if registration.ping_interval_secs == 0 {
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    thread::Builder::new()
+        .name("registration".to_string())
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(registration.ping_interval_secs));
+            let res = match registered {
+                true => ping(&registration, started),
+                false => register(&registration),
+            };
+            match res {
+                Ok(()) => registered = true,
+                Err(e) => warn!(err = ?e, registered, "liveness ping failed"),
+            }
+        })
+        .context("spawn registration thread")?;
+    Ok(())
+}
+
+fn register(registration: &RegistrationConfig) -> Result<()> {
+    let config = config::global();
+    let body = Registration {
+        event: "register",
+        worker: registration.worker(),
+        location: registration.location.as_deref(),
+        spec: spec::current(),
+        support: support::supported(),
+        limits: Limits {
+            task_limits: config.task_limits.as_ref(),
+            mount_limits: &config.mount_limits,
+        },
+    };
+    post(registration, &registration.url, &body).context("register worker")?;
+    info!(url = %registration.url, worker = %body.worker, "worker registered");
+    Ok(())
+}
+
+fn ping(registration: &RegistrationConfig, started: Instant) -> Result<()> {
+    let url = registration
+        .ping_url
+        .as_deref()
+        .unwrap_or(&registration.url);
+    let body = Ping {
+        event: "ping",
+        worker: registration.worker(),
+        uptime_secs: started.elapsed().as_secs(),
+        tasks_in_flight: status::snapshot().tasks.len(),
+    };
+    post(registration, url, &body)?;
+    debug!(url, "liveness ping delivered");
+    Ok(())
+}
+
+fn post(registration: &RegistrationConfig, url: &str, body: &impl Serialize) -> Result<()> {
+    ureq::post(url)
+        .timeout(Duration::from_secs(registration.timeout_secs))
+        .set("Content-Type", "application/json")
+        .send_string(&serde_json::to_string(body)?)?;
+    Ok(())
+}
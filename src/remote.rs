@@ -0,0 +1,206 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use add_piece::{
+    manifest::{Manifest, ManifestPiece, Provenance},
+    metered::Metered,
+    piece_events::ProgressReader,
+    pure,
+    staged_format::StagedFormat,
+};
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::{PieceInfo, UnpaddedBytesAmount};
+use tracing::info;
+use vc_processors::fil_proofs::RegisteredSealProof;
+
+use crate::{
+    attestation, check_sources,
+    checksums::Sha256Reader,
+    collect_car_index, collect_chunk_roots, collect_payload_hash, config,
+    iostats::{self, TaskIoStats},
+    keys,
+    mount_limits::{self, Access},
+    piece_dir,
+    record::Sources,
+    save_car_index,
+    scratch::TaskScratch,
+    seal_proof, seen_chunks,
+    source::PieceSource,
+    staged_target::StagedTarget,
+    staging::{self, with_write_behind},
+    start_piece_events, task_events, verifier, watchdog, PieceFile,
+};
+
+/// Same as `add_pieces`, streaming the staged file to `target`, named `name`
+/// in logs, webhooks and the io stats.
+pub fn add_pieces(
+    pieces: &[PieceFile],
+    mut target: Box<dyn StagedTarget>,
+    name: &str,
+    origin: bool,
+    proof: Option<RegisteredSealProof>,
+    io: &mut TaskIoStats,
+    sources: &Sources,
+) -> Result<Vec<PieceInfo>> {
+    let res = write_pieces(pieces, &mut target, name, origin, proof, io, sources);
+    let mut manifest = match res {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            target.abort();
+            return Err(e);
+        }
+    };
+
+    if let Some(provenance) = &mut manifest.provenance {
+        provenance.finish();
+    }
+    if let Err(e) = attestation::sign(&mut manifest) {
+        target.abort();
+        return Err(e);
+    }
+    let content = serde_json::to_vec_pretty(&manifest).context("serialize manifest")?;
+    target.finalize(&content)?;
+    info!(target = name, "remote staged file completed");
+    Ok(manifest.pieces.into_iter().map(|p| p.piece_info).collect())
+}
+
+fn write_pieces(
+    pieces: &[PieceFile],
+    target: &mut Box<dyn StagedTarget>,
+    name: &str,
+    origin: bool,
+    proof: Option<RegisteredSealProof>,
+    io: &mut TaskIoStats,
+    sources: &Sources,
+) -> Result<Manifest> {
+    ensure!(
+        config::global().staged_format == StagedFormat::Raw,
+        "remote targets only support raw staged files"
+    );
+    check_sources(sources, pieces.iter().map(PieceFile::declared_size), None)?;
+    let scratch = TaskScratch::create(None)?;
+    let mut options = config::global().add_piece_options()?;
+    let seen_chunks = seen_chunks::watch(&mut options)?;
+    let mut manifest = Manifest {
+        pieces: Vec::new(),
+        provenance: Some(Provenance::new(options.committer, Some(scratch.task_id()))),
+        attestation: None,
+    };
+
+    let proof_type = proof.map(seal_proof::name).unwrap_or_default();
+    let events = task_events(
+        Path::new(name),
+        pieces.iter().map(|p| p.size).collect(),
+        &proof_type,
+        None,
+    );
+    for (index, piece) in pieces.iter().enumerate() {
+        let source_name = piece.path.display().to_string();
+        let piece_size = UnpaddedBytesAmount(piece.size);
+        let piece_lengths = manifest.piece_lengths()?;
+        let placement = pure::plan_alignment(&piece_lengths, piece_size)?;
+        target
+            .preallocate(u64::from(placement.left) + u64::from(placement.size))
+            .context("preallocate piece")?;
+
+        let (mut options, stream) = verifier::stream_piece(
+            config::global().chunk_verifier.as_ref(),
+            &options,
+            Path::new(name),
+            index,
+            &source_name,
+        );
+        let chunk_roots = collect_chunk_roots(&mut options);
+        let car_index = collect_car_index(&mut options);
+        let payload_hash = collect_payload_hash(&mut options, piece.payload_size());
+        let encryption = keys::encrypt_payload(
+            config::global().encryption.as_ref(),
+            &mut options,
+            &source_name,
+        )?;
+        options.payload_root = piece.payload_root()?;
+        let open_source = sources.opener(index, piece.opener());
+        let _reading = mount_limits::acquire(&piece.path, Access::Read);
+        let piece_events =
+            start_piece_events(&events, index, &source_name, piece.size, &mut options);
+        let mut source = watchdog::watch(
+            config::global().stall_watchdog.as_ref(),
+            &source_name,
+            PieceSource::open(config::global().read_ahead, move || open_source())?,
+        );
+
+        let started = Instant::now();
+        let mut times = (Duration::ZERO, Duration::ZERO, Duration::ZERO);
+        let mut metered = Metered::new(&mut *target);
+        let res = with_write_behind(&mut metered, config::global().write_behind, |w| {
+            let mut hashed = Sha256Reader::new(&mut source, piece);
+            let mut reads = Metered::new(&mut hashed);
+            let mut writes = Metered::new(w);
+            let res = match (origin, proof) {
+                (true, _) => filecoin_proofs::add_piece(
+                    ProgressReader::new(&mut reads, Some(&piece_events)).0,
+                    &mut writes,
+                    piece_size,
+                    &piece_lengths,
+                ),
+                (false, Some(proof)) => add_piece::add_piece_for_proof(
+                    proof,
+                    &mut reads,
+                    &mut writes,
+                    piece_size,
+                    &piece_lengths,
+                    &options,
+                ),
+                (false, None) => add_piece::add_piece_with_options(
+                    &mut reads,
+                    &mut writes,
+                    piece_size,
+                    &piece_lengths,
+                    &options,
+                ),
+            };
+            times = (started.elapsed(), reads.stats().busy, writes.stats().busy);
+            let (piece_info, _) = res.context("add_piece")?;
+            hashed.check()?;
+            piece_dir::check(piece, &piece_info)?;
+            Ok(piece_info)
+        });
+        io.record_target(name, metered.stats());
+        let res = res.and_then(|piece_info| {
+            target.sync().context("sync staged file")?;
+            Ok(piece_info)
+        });
+        io.record_source(&iostats::device_of(&piece.path), source.get_ref().stats());
+        let res = match stream {
+            Some(s) => s.finish(res),
+            None => res,
+        };
+        piece_events.finished(&res);
+        let piece_info = res?;
+        io.record_piece(index, times.0, times.1, times.2);
+        save_car_index(&scratch, car_index, &piece_info)?;
+
+        manifest.pieces.push(ManifestPiece {
+            source: source_name,
+            payload_size: piece.payload_size(),
+            piece_info,
+            offset: placement.offset.into(),
+            len: u64::from(placement.size + placement.right),
+            chunk_roots: chunk_roots.take(),
+            encryption: encryption.and_then(|e| e.take()),
+            excessive_alignment: staging::excessive_alignment(
+                u64::from(placement.left + placement.right),
+                placement.size.into(),
+            ),
+            payload_blake3: payload_hash.and_then(|h| h.take()),
+            payload_sha256: piece.sha256.clone(),
+        });
+    }
+
+    if let Some(seen_chunks) = seen_chunks {
+        seen_chunks.finish(Path::new(name))?;
+    }
+    Ok(manifest)
+}
@@ -0,0 +1,399 @@
+//! Leases on staged files, so that add_pieces workers on several hosts
+//! sharing the staging storage never stage the same sector at once.
+//!
+//! The lease of a staged file is `<staged>.lease`, next to it, holding the
+//! name of the worker which claimed it, a token drawn for the claim and when
+//! the lease expires. It is claimed lazily, when a worker starts staging the
+//! sector, and expires unless renewed within its TTL so that the sectors of
+//! a worker which died can be claimed by another. Workers renew and release
+//! only the claim their token names, so that two processes sharing a holder
+//! name never take one another's lease for their own.
+//!
+//! Leases are created with a hard link and replaced with a rename, which are
+//! atomic on local filesystems and NFS, so that a lease is never seen half
+//! written. Two races remain, the TTL should be well above the time between
+//! two renewals for them to stay unlikely:
+//! - renewing a lease which expired meanwhile may race with another worker
+//!   claiming it;
+//! - taking over an expired lease moves it aside first, and puts it back if
+//!   another worker renewed or claimed it meanwhile; a third worker claiming
+//!   the free path in between keeps the lease, and the worker whose lease was
+//!   moved aside finds out when it next renews.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use log::{debug, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::{hostname, unix_now};
+use crate::piece_events::{PieceEventSink, PieceRef};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// Worker holding the lease, unique to its process by default.
+    pub holder: String,
+    /// Drawn by the claim, kept by renewals.
+    #[serde(default)]
+    pub token: String,
+    /// Host the lease was last claimed or renewed from.
+    pub host: String,
+    /// Unix seconds the lease was claimed at.
+    pub claimed_at: u64,
+    /// Unix seconds the lease expires at unless renewed.
+    pub expires_at: u64,
+}
+
+/// Outcome of `claim`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    Claimed(Lease),
+    /// Another worker holds a lease which did not expire yet.
+    Held(Lease),
+}
+
+impl Lease {
+    pub fn path_for(staged: impl AsRef<Path>) -> PathBuf {
+        let mut p = OsString::from(staged.as_ref().as_os_str());
+        p.push(".lease");
+        PathBuf::from(p)
+    }
+
+    /// The lease of `staged`, expired or not, `None` if it has none.
+    pub fn read(staged: &Path) -> Result<Option<Self>> {
+        read_lease(&Self::path_for(staged))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        unix_now() >= self.expires_at
+    }
+
+    fn new(holder: &str, token: &str, claimed_at: u64, ttl: Duration) -> Self {
+        Self {
+            holder: holder.to_string(),
+            token: token.to_string(),
+            host: hostname(),
+            claimed_at,
+            expires_at: unix_now() + ttl.as_secs(),
+        }
+    }
+}
+
+/// Name workers hold leases under unless given one, unique to the process:
+/// `<host>:<pid>:<token>`, the token telling apart processes reusing a pid.
+pub fn default_holder() -> String {
+    static TOKEN: OnceLock<String> = OnceLock::new();
+    let token = TOKEN.get_or_init(|| new_token().unwrap_or_default());
+    format!("{}:{}:{}", hostname(), process::id(), token)
+}
+
+/// 8 random bytes, hex encoded.
+fn new_token() -> Result<String> {
+    let mut token = [0u8; 8];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| anyhow!("draw a lease token"))?;
+    Ok(hex::encode(token))
+}
+
+fn read_lease(path: &Path) -> Result<Option<Lease>> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .with_context(|| format!("parse lease {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("read lease {}", path.display())),
+    }
+}
+
+/// A path next to `lease` no other claim uses, on this host or another.
+fn unique_path(lease: &Path, suffix: &str) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut p = OsString::from(lease.as_os_str());
+    p.push(format!(
+        ".{}.{}.{}.{}",
+        hostname(),
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed),
+        suffix
+    ));
+    PathBuf::from(p)
+}
+
+/// Writes `lease` to a file of its own, synced, for it to be linked or
+/// renamed into place.
+fn write_temp(path: &Path, lease: &Lease) -> Result<PathBuf> {
+    let temp = unique_path(path, "tmp");
+    let mut file = fs::File::create(&temp).with_context(|| format!("create {}", temp.display()))?;
+    file.write_all(&serde_json::to_vec(lease)?)?;
+    file.sync_all()?;
+    Ok(temp)
+}
+
+/// Claims `staged` for `holder` until `ttl` from now, unless another claim
+/// has a lease on it which did not expire. Claiming a lease `holder` already
+/// holds renews it, if it was claimed with `token` when given. A new lease
+/// is claimed with `token`, or one drawn for it.
+pub fn claim(staged: &Path, holder: &str, token: Option<&str>, ttl: Duration) -> Result<Claim> {
+    ensure!(!holder.is_empty(), "empty lease holder");
+    let path = Lease::path_for(staged);
+    loop {
+        let current = match read_lease(&path)? {
+            Some(current) => current,
+            None => {
+                let drawn;
+                let token = match token {
+                    Some(token) => token,
+                    None => {
+                        drawn = new_token()?;
+                        &drawn
+                    }
+                };
+                let lease = Lease::new(holder, token, unix_now(), ttl);
+                let temp = write_temp(&path, &lease)?;
+                let res = fs::hard_link(&temp, &path);
+                let _ = fs::remove_file(&temp);
+                match res {
+                    Ok(()) => return Ok(Claim::Claimed(lease)),
+                    // claimed meanwhile, by someone else or not
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                    Err(e) => return Err(e).with_context(|| format!("claim {}", path.display())),
+                }
+            }
+        };
+        if current.holder == holder && token.is_none_or(|t| t == current.token) {
+            return renew(staged, holder, token, ttl).map(Claim::Claimed);
+        }
+        if !current.is_expired() {
+            return Ok(Claim::Held(current));
+        }
+        if let Some(held) = take_expired(&path, &current)? {
+            return Ok(Claim::Held(held));
+        }
+    }
+}
+
+/// Moves the expired lease `expired` out of the way of a new claim, unless
+/// it was replaced since it was read: the lease found instead is put back
+/// and returned.
+fn take_expired(path: &Path, expired: &Lease) -> Result<Option<Lease>> {
+    let stale = unique_path(path, "stale");
+    match fs::rename(path, &stale) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("take over {}", path.display())),
+    }
+    let taken = read_lease(&stale);
+    if matches!(&taken, Ok(Some(taken)) if taken == expired) {
+        debug!(
+            "took over the lease {} of {}, expired",
+            path.display(),
+            expired.holder
+        );
+        fs::remove_file(&stale).with_context(|| format!("remove {}", stale.display()))?;
+        return Ok(None);
+    }
+    // another worker renewed or claimed it between the read and the rename
+    let res = fs::hard_link(&stale, path);
+    let _ = fs::remove_file(&stale);
+    match res {
+        Ok(()) => taken,
+        // and a third one claimed the free path since, see the module doc
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            if let Ok(Some(lost)) = &taken {
+                warn!(
+                    "the lease {} of {} was lost to another claim",
+                    path.display(),
+                    lost.holder
+                );
+            }
+            read_lease(path)
+        }
+        Err(e) => Err(e).with_context(|| format!("restore {}", path.display())),
+    }
+}
+
+/// The lease of `staged` if `holder` holds it, under `token` when given.
+fn read_held(staged: &Path, holder: &str, token: Option<&str>) -> Result<Option<Lease>> {
+    match read_lease(&Lease::path_for(staged))? {
+        Some(current) if current.holder != holder => bail!(
+            "{} is leased to {}, not {}",
+            staged.display(),
+            current.holder,
+            holder
+        ),
+        Some(current) if token.is_some_and(|t| t != current.token) => {
+            bail!("{} was claimed again by {} since", staged.display(), holder)
+        }
+        current => Ok(current),
+    }
+}
+
+/// Extends the lease `holder` holds on `staged`, under `token` when given,
+/// until `ttl` from now.
+pub fn renew(staged: &Path, holder: &str, token: Option<&str>, ttl: Duration) -> Result<Lease> {
+    let path = Lease::path_for(staged);
+    let current = read_held(staged, holder, token)?
+        .ok_or_else(|| anyhow!("{} is not leased", staged.display()))?;
+    let lease = Lease::new(holder, &current.token, current.claimed_at, ttl);
+    let temp = write_temp(&path, &lease)?;
+    fs::rename(&temp, &path).with_context(|| format!("renew {}", path.display()))?;
+    Ok(lease)
+}
+
+/// Releases the lease `holder` holds on `staged`, under `token` when given.
+/// Returns false if `staged` has no lease, fails if another claim has it.
+pub fn release(staged: &Path, holder: &str, token: Option<&str>) -> Result<bool> {
+    let path = Lease::path_for(staged);
+    if read_held(staged, holder, token)?.is_none() {
+        return Ok(false);
+    }
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("release {}", path.display())),
+    }
+}
+
+/// The lease of a staged file held while staging it: renewed as every piece
+/// starts, as a piece event sink, and released on drop.
+#[derive(Debug)]
+pub struct LeaseGuard {
+    staged: PathBuf,
+    holder: String,
+    token: String,
+    ttl: Duration,
+}
+
+impl LeaseGuard {
+    /// Claims `staged` anew, failing if another claim has it, of `holder`
+    /// or not.
+    pub fn claim(staged: &Path, holder: &str, ttl: Duration) -> Result<Self> {
+        let token = new_token()?;
+        match claim(staged, holder, Some(&token), ttl)? {
+            Claim::Claimed(_) => Ok(Self {
+                staged: staged.to_path_buf(),
+                holder: holder.to_string(),
+                token,
+                ttl,
+            }),
+            Claim::Held(lease) => bail!(
+                "{} is leased to {} until {}",
+                staged.display(),
+                lease.holder,
+                lease.expires_at
+            ),
+        }
+    }
+}
+
+impl PieceEventSink for LeaseGuard {
+    fn started(&self, _piece: &PieceRef) {
+        if let Err(e) = renew(&self.staged, &self.holder, Some(&self.token), self.ttl) {
+            warn!(
+                "failed to renew the lease of {}: {:?}",
+                self.staged.display(),
+                e
+            );
+        }
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        if let Err(e) = release(&self.staged, &self.holder, Some(&self.token)) {
+            warn!(
+                "failed to release the lease of {}: {:?}",
+                self.staged.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_release() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let staged = dir.path().join("s-t01000-1");
+        let ttl = Duration::from_secs(60);
+
+        let lease = match claim(&staged, "a", None, ttl).unwrap() {
+            Claim::Claimed(lease) => lease,
+            claim => panic!("not claimed: {:?}", claim),
+        };
+        assert_eq!(Lease::read(&staged).unwrap(), Some(lease.clone()));
+        assert_eq!(claim(&staged, "b", None, ttl).unwrap(), Claim::Held(lease));
+        assert!(matches!(
+            claim(&staged, "a", None, ttl).unwrap(),
+            Claim::Claimed(_)
+        ));
+        assert!(release(&staged, "b", None).is_err());
+        assert!(release(&staged, "a", None).unwrap());
+        assert!(!release(&staged, "a", None).unwrap());
+
+        // an expired lease is claimed by another holder
+        assert!(matches!(
+            claim(&staged, "a", None, Duration::ZERO).unwrap(),
+            Claim::Claimed(_)
+        ));
+        let lease = match claim(&staged, "b", None, ttl).unwrap() {
+            Claim::Claimed(lease) => lease,
+            claim => panic!("not claimed: {:?}", claim),
+        };
+        assert_eq!(lease.holder, "b");
+        assert!(renew(&staged, "a", None, ttl).is_err());
+
+        // a guard claims anew
+        assert!(LeaseGuard::claim(&staged, "b", ttl).is_err());
+        assert!(release(&staged, "b", None).unwrap());
+        {
+            let _guard = LeaseGuard::claim(&staged, "b", ttl).unwrap();
+            assert!(LeaseGuard::claim(&staged, "c", ttl).is_err());
+        }
+        assert_eq!(Lease::read(&staged).unwrap(), None);
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_guards_sharing_a_holder() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let staged = dir.path().join("s-t01000-1");
+        let ttl = Duration::from_secs(60);
+
+        let guard = LeaseGuard::claim(&staged, "host", ttl).unwrap();
+        assert!(LeaseGuard::claim(&staged, "host", ttl).is_err());
+        let lease = Lease::read(&staged).unwrap().unwrap();
+        assert_eq!(lease.token, guard.token);
+
+        // the lease expired and was claimed again under the same name
+        release(&staged, "host", None).unwrap();
+        let other = LeaseGuard::claim(&staged, "host", ttl).unwrap();
+        assert!(renew(&staged, "host", Some(&guard.token), ttl).is_err());
+        drop(guard);
+        assert_eq!(
+            Lease::read(&staged).unwrap().map(|l| l.token),
+            Some(other.token.clone())
+        );
+        drop(other);
+        assert_eq!(Lease::read(&staged).unwrap(), None);
+
+        let holder = default_holder();
+        assert_eq!(holder, default_holder());
+        assert!(holder.starts_with(&format!("{}:{}:", hostname(), process::id())));
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use add_piece::{piece_cid, sector_reader::SectorReader};
+use anyhow::{Context, Result};
+use tracing::{debug, info, warn};
+
+use crate::{privileges::DropTo, staging::load_manifest};
+
+/// Connections served at once, those beyond are closed right away.
+const MAX_CONNECTIONS: usize = 256;
+
+/// How long a connection may stall reading the request or taking the
+/// response.
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest request or header line accepted.
+const MAX_LINE_LEN: usize = 8 << 10;
+
+/// Most header lines accepted in a request.
+const MAX_HEADERS: usize = 100;
+
+/// Least time between two rescans of the staging directory for unknown
+/// pieces, so that requests for pieces which are not there cannot keep the
+/// server listing it.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Staged files of the staging directory, by the commitments of their pieces.
+struct Index {
+    dir: PathBuf,
+    state: Mutex<IndexState>,
+}
+
+struct IndexState {
+    pieces: HashMap<[u8; 32], PathBuf>,
+    scanned: Instant,
+}
+
+impl Index {
+    fn new(dir: &Path) -> Result<Self> {
+        let state = IndexState {
+            pieces: scan(dir)?,
+            scanned: Instant::now(),
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Finds the staged file holding the piece. Unknown pieces rescan the
+    /// directory so that newly staged sectors are served, at most once every
+    /// `RESCAN_INTERVAL`; the scan runs without holding the index.
+    fn locate(&self, commitment: &[u8; 32]) -> Option<PathBuf> {
+        {
+            let mut state = self.state.lock().expect("retrieval index poisoned");
+            if let Some(staged) = state.pieces.get(commitment) {
+                return Some(staged.clone());
+            }
+            if state.scanned.elapsed() < RESCAN_INTERVAL {
+                return None;
+            }
+            state.scanned = Instant::now();
+        }
+
+        let scanned = match scan(&self.dir) {
+            Ok(scanned) => scanned,
+            Err(e) => {
+                warn!(err = ?e, dir = %self.dir.display(), "failed to scan staging dir");
+                return None;
+            }
+        };
+        let mut state = self.state.lock().expect("retrieval index poisoned");
+        state.pieces = scanned;
+        state.pieces.get(commitment).cloned()
+    }
+}
+
+fn scan(dir: &Path) -> Result<HashMap<[u8; 32], PathBuf>> {
+    let mut pieces = HashMap::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("read dir {}", dir.display()))? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !path.is_file() || name.ends_with(".manifest.json") || name.ends_with(".tmp") {
+            continue;
+        }
+
+        let manifest = match load_manifest(&path) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!(err = ?e, staged = %path.display(), "skip staged file");
+                continue;
+            }
+        };
+        for piece in manifest.pieces.iter().filter(|p| p.encryption.is_none()) {
+            pieces.insert(piece.piece_info.commitment, path.clone());
+        }
+    }
+    Ok(pieces)
+}
+
+/// Serves `GET /piece/<cid>` off the staged files of `dir`, with single
+/// `Range` requests, until the process is stopped. Connections are served
+/// over TLS with `tls`, at most `MAX_CONNECTIONS` at once. Privileges are
+/// dropped to `drop_to` once listening, before the staged files are read.
+pub fn serve(
+    dir: &Path,
+    listen: &str,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    drop_to: &DropTo,
+) -> Result<()> {
+    let listener = TcpListener::bind(listen).with_context(|| format!("listen on {}", listen))?;
+    drop_to.apply()?;
+    let index = Arc::new(Index::new(dir)?);
+    info!(
+        dir = %dir.display(),
+        addr = %listener.local_addr()?,
+        pieces = index.state.lock().expect("retrieval index poisoned").pieces.len(),
+        tls = tls.is_some(),
+        "serving retrievals"
+    );
+
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(err = ?e, "failed to accept connection");
+                continue;
+            }
+        };
+        let connection = match Connection::acquire(&active) {
+            Some(c) => c,
+            None => {
+                warn!(max = MAX_CONNECTIONS, "too many connections, closing");
+                continue;
+            }
+        };
+        if let Err(e) = stream
+            .set_read_timeout(Some(IO_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+        {
+            warn!(err = ?e, "failed to set connection timeouts");
+            continue;
+        }
+
+        let index = index.clone();
+        let tls = tls.clone();
+        thread::spawn(move || {
+            let _connection = connection;
+            let res = match tls {
+                Some(config) => serve_tls(config, stream, &index),
+                None => handle(&mut stream, &index),
+            };
+            if let Err(e) = res {
+                debug!(err = ?e, "retrieval connection failed");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// A connection counted against `MAX_CONNECTIONS` until dropped.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn acquire(active: &Arc<AtomicUsize>) -> Option<Self> {
+        active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(active.clone()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Serves a connection over TLS, the handshake, and the check of the client
+/// certificate, happen on the first read of the request.
+fn serve_tls(config: Arc<rustls::ServerConfig>, stream: TcpStream, index: &Index) -> Result<()> {
+    let conn = rustls::ServerConnection::new(config).context("TLS session")?;
+    let mut stream = rustls::StreamOwned::new(conn, stream);
+    handle(&mut stream, index)?;
+    stream.conn.send_close_notify();
+    stream.flush()?;
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+/// Reads a line into `line`, failing with `InvalidData` if it is longer
+/// than `MAX_LINE_LEN`.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    line.clear();
+    let n = reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_line(line)?;
+    if n > MAX_LINE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line too long",
+        ));
+    }
+    Ok(n)
+}
+
+fn read_request<S: Read>(stream: &mut S) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut range = None;
+    for headers in 0.. {
+        if read_line(&mut reader, &mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many request headers",
+            ));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            }
+        }
+    }
+    Ok(Request {
+        method,
+        path,
+        range,
+    })
+}
+
+/// Resolves a `Range` header against a payload of `size` bytes, `None` if it
+/// can't be satisfied. Multiple ranges are not supported.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(size.checked_sub(1)?),
+        ),
+    };
+    (start <= end && end < size).then(|| (start, end - start + 1))
+}
+
+fn write_head<S: Write>(stream: &mut S, status: &str, headers: &[String], len: u64) -> Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nConnection: close\r\n", status)?;
+    for h in headers {
+        write!(stream, "{}\r\n", h)?;
+    }
+    write!(stream, "Content-Length: {}\r\n\r\n", len)?;
+    Ok(())
+}
+
+fn respond<S: Write>(stream: &mut S, status: &str, headers: &[String], body: &str) -> Result<()> {
+    write_head(stream, status, headers, body.len() as u64)?;
+    stream.write_all(body.as_bytes())?;
+    Ok(())
+}
+
+fn handle<S: Read + Write>(stream: &mut S, index: &Index) -> Result<()> {
+    let req = match read_request(stream) {
+        Ok(req) => req,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            return respond(stream, "431 Request Header Fields Too Large", &[], "");
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if req.method != "GET" && req.method != "HEAD" {
+        return respond(stream, "405 Method Not Allowed", &[], "");
+    }
+    let cid = match req.path.strip_prefix("/piece/") {
+        Some(cid) => cid,
+        None => return respond(stream, "404 Not Found", &[], "not found\n"),
+    };
+    let commitment = match piece_cid::decode(cid) {
+        Ok(c) => c,
+        Err(e) => return respond(stream, "400 Bad Request", &[], &format!("{}\n", e)),
+    };
+    let staged = match index.locate(&commitment) {
+        Some(staged) => staged,
+        None => return respond(stream, "404 Not Found", &[], "unknown piece\n"),
+    };
+
+    let opened = load_manifest(&staged)
+        .and_then(|m| m.with_context(|| format!("manifest of {} vanished", staged.display())))
+        .and_then(|m| SectorReader::open(&staged, m));
+    let mut reader = match opened {
+        Ok(reader) => reader,
+        Err(e) => {
+            warn!(err = ?e, staged = %staged.display(), "failed to open staged file");
+            return respond(stream, "500 Internal Server Error", &[], "");
+        }
+    };
+    let size = reader.piece(cid)?.payload_size;
+
+    let (status, offset, len, mut headers) = match &req.range {
+        None => ("200 OK", 0, size, Vec::new()),
+        Some(range) => match parse_range(range, size) {
+            Some((offset, len)) => (
+                "206 Partial Content",
+                offset,
+                len,
+                vec![format!(
+                    "Content-Range: bytes {}-{}/{}",
+                    offset,
+                    offset + len - 1,
+                    size
+                )],
+            ),
+            None => {
+                let headers = [format!("Content-Range: bytes */{}", size)];
+                return respond(stream, "416 Range Not Satisfiable", &headers, "");
+            }
+        },
+    };
+    headers.push("Accept-Ranges: bytes".to_string());
+    headers.push("Content-Type: application/octet-stream".to_string());
+
+    write_head(stream, status, &headers, len)?;
+    if req.method == "GET" {
+        let mut payload = reader.read_piece_range(cid, offset, len)?;
+        let sent = io::copy(&mut payload, stream)?;
+        debug!(piece = cid, staged = %staged.display(), offset, sent, "piece served");
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use add_piece::manifest::Provenance;
+    use filecoin_proofs::{PieceInfo, UnpaddedBytesAmount};
+
+    use crate::staging::{PieceSpec, StagedFile, StagedOpenMode};
+
+    #[test]
+    fn test_rescans_rate_limited() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let index = Index::new(dir.path()).expect("scan failed");
+
+        let mut staged = StagedFile::open(
+            dir.path().join("sector"),
+            "",
+            Provenance::new(Default::default(), None),
+            StagedOpenMode::TruncateExisting,
+        )
+        .expect("open failed");
+        let spec = PieceSpec {
+            source: "piece".to_string(),
+            payload_size: 127,
+            piece_size: UnpaddedBytesAmount(127),
+            payload_sha256: None,
+        };
+        staged
+            .add(&spec, &[], None, None, None, |file| {
+                file.write_all(&[0u8; 128])?;
+                Ok((PieceInfo::new([7u8; 32], spec.piece_size)?, spec.piece_size))
+            })
+            .expect("add failed");
+        staged.finish().expect("finish failed");
+
+        // staged right after the last scan
+        assert_eq!(index.locate(&[7u8; 32]), None);
+        index.state.lock().unwrap().scanned -= RESCAN_INTERVAL;
+        assert_eq!(index.locate(&[7u8; 32]), Some(dir.path().join("sector")));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 100)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 100)));
+        // suffixes and ends beyond the payload are clamped
+        assert_eq!(parse_range("bytes=-2000", 1000), Some((0, 1000)));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some((990, 10)));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=100-99", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=0-1,4-5", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let req = read_request(&mut &b"GET /piece/x HTTP/1.1\r\nRange: bytes=0-1\r\n\r\n"[..])
+            .expect("read request");
+        assert_eq!(
+            (req.method.as_str(), req.path.as_str(), req.range.as_deref()),
+            ("GET", "/piece/x", Some("bytes=0-1"))
+        );
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+        let err = read_request(&mut long.as_bytes())
+            .err()
+            .expect("long line read");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let many = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: y\r\n".repeat(MAX_HEADERS + 1)
+        );
+        let err = read_request(&mut many.as_bytes())
+            .err()
+            .expect("headers read");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    io::{self, Write},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{credentials, staged_target::StagedTarget};
+
+/// Smallest part S3 accepts, except for the last part of an upload.
+const MIN_PART_SIZE: usize = 5 << 20;
+
+/// An S3 compatible store receiving staged files written to `s3://` targets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// e.g. `https://s3.us-east-1.amazonaws.com` or `http://minio:9000`.
+    pub endpoint: String,
+
+    #[serde(default = "default_region")]
+    pub region: String,
+
+    /// Address buckets as `<endpoint>/<bucket>` rather than
+    /// `<bucket>.<endpoint host>`.
+    #[serde(default = "default_true")]
+    pub path_style: bool,
+
+    /// Credentials, taken from the `s3` chain of `credentials` when unset, by
+    /// default from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+    /// `AWS_SESSION_TOKEN`. They are never written back, e.g. into
+    /// recordings.
+    #[serde(default, skip_serializing)]
+    pub access_key_id: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub secret_access_key: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub session_token: Option<String>,
+
+    /// Size of the uploaded parts, at least 5MiB.
+    #[serde(default = "default_part_size")]
+    pub part_size: usize,
+
+    /// Attempts made for each part before the upload is aborted.
+    #[serde(default = "default_part_attempts")]
+    pub part_attempts: u32,
+
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_part_size() -> usize {
+    64 << 20
+}
+
+fn default_part_attempts() -> u32 {
+    3
+}
+
+fn default_timeout_secs() -> u64 {
+    300
+}
+
+/// The bucket and key of an `s3://<bucket>/<key>` target.
+#[derive(Debug, Clone)]
+pub struct S3Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Location {
+    /// Returns `None` if `target` is not an `s3://` URL.
+    pub fn parse(target: &str) -> Option<Result<Self>> {
+        let rest = target.strip_prefix("s3://")?;
+        Some(match rest.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Self {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+            }),
+            _ => Err(anyhow!("expected s3://<bucket>/<key>, got {}", target)),
+        })
+    }
+}
+
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    /// The credentials of `config`, those it doesn't set from the `s3` chain
+    /// of `credentials`, the `AWS_*` environment variables by default.
+    fn resolve(config: &S3Config) -> Result<Self> {
+        let provided = credentials::get("s3")?;
+        let provided = |name: &str| {
+            provided
+                .as_ref()
+                .and_then(|c| c.get(name))
+                .map(str::to_string)
+        };
+        Ok(Self {
+            access_key_id: config
+                .access_key_id
+                .clone()
+                .or_else(|| provided("access_key_id"))
+                .context("no S3 access key id configured")?,
+            secret_access_key: config
+                .secret_access_key
+                .clone()
+                .or_else(|| provided("secret_access_key"))
+                .context("no S3 secret access key configured")?,
+            session_token: config
+                .session_token
+                .clone()
+                .or_else(|| provided("session_token")),
+        })
+    }
+}
+
+/// Requests signed with AWS signature version 4.
+struct Client {
+    config: S3Config,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    fn new(config: &S3Config) -> Result<Self> {
+        ensure!(
+            config.part_size >= MIN_PART_SIZE,
+            "S3 part size must be at least {} bytes, got {}",
+            MIN_PART_SIZE,
+            config.part_size
+        );
+
+        // fails early, they are resolved again for every request as they
+        // may be refreshed while a sector is uploaded
+        Credentials::resolve(config)?;
+        Ok(Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(config.timeout_secs))
+                .build(),
+            config: config.clone(),
+        })
+    }
+
+    /// Returns the scheme and host to send requests for `bucket` to, and the
+    /// path of `key` on that host.
+    fn address(&self, bucket: &str, key: &str) -> Result<(String, String, String)> {
+        let (scheme, host) = self
+            .config
+            .endpoint
+            .trim_end_matches('/')
+            .split_once("://")
+            .ok_or_else(|| anyhow!("invalid S3 endpoint: {}", self.config.endpoint))?;
+        let key = uri_encode(key, false);
+        Ok(match self.config.path_style {
+            true => (
+                scheme.to_string(),
+                host.to_string(),
+                format!("/{}/{}", uri_encode(bucket, true), key),
+            ),
+            false => (
+                scheme.to_string(),
+                format!("{}.{}", bucket, host),
+                format!("/{}", key),
+            ),
+        })
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        location: &S3Location,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<ureq::Response> {
+        let (scheme, host, path) = self.address(&location.bucket, &location.key)?;
+        let credentials = Credentials::resolve(&self.config)?;
+
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let (amz_date, date) = amz_date(SystemTime::now());
+        let payload_hash = hex::encode(hmac_sha256::Hash::hash(body));
+
+        let mut headers = vec![
+            ("host", host.clone()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+            .collect();
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(hmac_sha256::Hash::hash(canonical_request.as_bytes()))
+        );
+        let signature = hex::encode(sign(
+            &credentials.secret_access_key,
+            &date,
+            &self.config.region,
+            string_to_sign.as_bytes(),
+        ));
+
+        let url = match query.is_empty() {
+            true => format!("{}://{}{}", scheme, host, path),
+            false => format!("{}://{}{}?{}", scheme, host, path, query),
+        };
+        let mut request = self.agent.request(method, &url).set(
+            "Authorization",
+            &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credentials.access_key_id, scope, signed_headers, signature
+            ),
+        );
+        for (k, v) in headers.iter().filter(|(k, _)| *k != "host") {
+            request = request.set(k, v);
+        }
+
+        request.send_bytes(body).map_err(|e| match e {
+            ureq::Error::Status(code, resp) => anyhow!(
+                "{} {}: status {}: {}",
+                method,
+                url,
+                code,
+                resp.into_string().unwrap_or_default()
+            ),
+            e => anyhow!("{} {}: {}", method, url, e),
+        })
+    }
+}
+
+/// Streams a staged file into a multipart upload, `part_size` bytes at a
+/// time. Parts are retried with a backoff, and the upload is aborted if it
+/// is not completed.
+pub struct MultipartUpload {
+    client: Client,
+    location: S3Location,
+    upload_id: String,
+    buf: Vec<u8>,
+    etags: Vec<String>,
+}
+
+impl MultipartUpload {
+    pub fn create(config: &S3Config, location: S3Location) -> Result<Self> {
+        let client = Client::new(config)?;
+        let body = client
+            .send("POST", &location, &[("uploads", "")], &[])?
+            .into_string()
+            .context("read create multipart upload response")?;
+        let upload_id = xml_element(&body, "UploadId")
+            .ok_or_else(|| anyhow!("no UploadId in response: {}", body))?
+            .to_string();
+        debug!(
+            bucket = location.bucket.as_str(),
+            key = location.key.as_str(),
+            upload_id = upload_id.as_str(),
+            "multipart upload created"
+        );
+
+        Ok(Self {
+            buf: Vec::with_capacity(client.config.part_size),
+            client,
+            location,
+            upload_id,
+            etags: Vec::new(),
+        })
+    }
+
+    fn upload_part(&mut self) -> Result<()> {
+        let part_number = (self.etags.len() + 1).to_string();
+        let mut attempt = 1;
+        let etag = loop {
+            let res = self.client.send(
+                "PUT",
+                &self.location,
+                &[("partNumber", &part_number), ("uploadId", &self.upload_id)],
+                &self.buf,
+            );
+            match res {
+                Ok(resp) => {
+                    break resp
+                        .header("ETag")
+                        .ok_or_else(|| anyhow!("no ETag for part {}", part_number))?
+                        .to_string()
+                }
+                Err(e) if attempt < self.client.config.part_attempts => {
+                    warn!(part = part_number.as_str(), attempt, "retry part: {:?}", e);
+                    thread::sleep(Duration::from_secs(1 << attempt.min(6)));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e).with_context(|| format!("upload part {}", part_number)),
+            }
+        };
+
+        self.etags.push(etag);
+        self.buf.clear();
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        if !self.buf.is_empty() || self.etags.is_empty() {
+            self.upload_part()?;
+        }
+
+        let parts: String = self
+            .etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    i + 1,
+                    etag
+                )
+            })
+            .collect();
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+        let resp = self
+            .client
+            .send(
+                "POST",
+                &self.location,
+                &[("uploadId", &self.upload_id)],
+                body.as_bytes(),
+            )?
+            .into_string()
+            .context("read complete multipart upload response")?;
+        // errors may be reported after a 200 status
+        if let Some(code) = xml_element(&resp, "Code") {
+            bail!("complete multipart upload: {}", code);
+        }
+        Ok(())
+    }
+}
+
+impl Write for MultipartUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.client.config.part_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == self.client.config.part_size {
+            self.upload_part().map_err(io::Error::other)?;
+        }
+        Ok(n)
+    }
+
+    /// Parts can only be uploaded once full, flushing does nothing.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StagedTarget for MultipartUpload {
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let end = (self.etags.len() * self.client.config.part_size + self.buf.len()) as u64;
+        if offset != end {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("uploads are only appended to, at {} not {}", end, offset),
+            ));
+        }
+        self.write_all(buf)
+    }
+
+    /// The store is left to allocate the parts as they come.
+    fn preallocate(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Parts are stored once uploaded, the last one is only uploaded by
+    /// `finalize`.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>, manifest: &[u8]) -> Result<()> {
+        self.complete()?;
+
+        let manifest_location = S3Location {
+            bucket: self.location.bucket.clone(),
+            key: format!("{}.manifest.json", self.location.key),
+        };
+        self.client
+            .send("PUT", &manifest_location, &[], manifest)
+            .context("upload manifest")?;
+        Ok(())
+    }
+
+    fn abort(self: Box<Self>) {
+        let res = self.client.send(
+            "DELETE",
+            &self.location,
+            &[("uploadId", &self.upload_id)],
+            &[],
+        );
+        if let Err(e) = res {
+            warn!(
+                upload_id = self.upload_id.as_str(),
+                "abort multipart upload: {:?}", e
+            );
+        }
+    }
+}
+
+fn sign(secret: &str, date: &str, region: &str, string_to_sign: &[u8]) -> [u8; 32] {
+    let hmac = |key: &[u8], input: &[u8]| hmac_sha256::HMAC::mac(input, key);
+    let k_date = hmac(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let k_region = hmac(&k_date, region.as_bytes());
+    let k_service = hmac(&k_region, b"s3");
+    let k_signing = hmac(&k_service, b"aws4_request");
+    hmac(&k_signing, string_to_sign)
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` unless
+/// `encode_slash` is set.
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Formats `t` as `YYYYMMDDTHHMMSSZ` and `YYYYMMDD`.
+fn amz_date(t: SystemTime) -> (String, String) {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // civil date from days since the epoch, after Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!("{:02}{:02}{:02}", rem / 3600, rem % 3600 / 60, rem % 60);
+    (format!("{}T{}Z", date, time), date)
+}
+
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(&xml[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::mock_http::{MockServer, Response};
+
+    fn config(server: &MockServer, part_attempts: u32) -> S3Config {
+        S3Config {
+            endpoint: server.url().to_string(),
+            region: default_region(),
+            path_style: true,
+            access_key_id: Some("AKID".to_string()),
+            secret_access_key: Some("secret".to_string()),
+            session_token: None,
+            part_size: MIN_PART_SIZE,
+            part_attempts,
+            timeout_secs: 10,
+        }
+    }
+
+    fn location() -> S3Location {
+        S3Location::parse("s3://bucket/sectors/s-t01000-1")
+            .unwrap()
+            .unwrap()
+    }
+
+    const CREATED: &str = "<InitiateMultipartUploadResult>\
+                           <UploadId>up-1</UploadId>\
+                           </InitiateMultipartUploadResult>";
+    const COMPLETED: &str = "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>";
+    const FAILED: &str = "<Error><Code>InternalError</Code></Error>";
+
+    /// Answers a multipart upload, failing the first `failures` part uploads.
+    fn store(failures: usize) -> MockServer {
+        let failed = Arc::new(Mutex::new(0));
+        MockServer::start(move |req| {
+            let query = req.path.split_once('?').map(|(_, q)| q).unwrap_or("");
+            match (req.method.as_str(), query) {
+                ("POST", "uploads=") => Response::new(200).body(CREATED),
+                ("PUT", q) if q.starts_with("partNumber=") => {
+                    let mut failed = failed.lock().unwrap();
+                    if *failed < failures {
+                        *failed += 1;
+                        return Response::new(500).body(FAILED);
+                    }
+                    let part = &q["partNumber=".len()..q.find('&').unwrap()];
+                    Response::new(200).header("ETag", format!("\"etag-{}\"", part))
+                }
+                ("POST", "uploadId=up-1") => Response::new(200).body(COMPLETED),
+                _ => Response::new(200),
+            }
+        })
+    }
+
+    #[test]
+    fn test_multipart_upload_retries_part() {
+        let server = store(1);
+        let data: Vec<u8> = (0..MIN_PART_SIZE + 100).map(|i| i as u8).collect();
+
+        let mut upload =
+            Box::new(MultipartUpload::create(&config(&server, 2), location()).expect("create"));
+        upload.write_at(&data[..1000], 0).expect("write");
+        assert!(upload.write_at(&data[..10], 0).is_err());
+        upload.write_at(&data[1000..], 1000).expect("write");
+        upload.finalize(b"{}").expect("finalize");
+
+        let requests = server.requests();
+        let parts: Vec<_> = requests
+            .iter()
+            .filter(|r| r.path.contains("partNumber="))
+            .collect();
+        // the first part is sent twice
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].body, parts[1].body);
+        assert_eq!([&parts[1].body[..], &parts[2].body[..]].concat(), data);
+        assert!(parts
+            .iter()
+            .all(|r| r.path.starts_with("/bucket/sectors/s-t01000-1?")));
+        assert!(parts[0]
+            .header("Authorization")
+            .unwrap()
+            .starts_with("AWS4-HMAC-SHA256 Credential=AKID/"));
+
+        let complete = requests
+            .iter()
+            .find(|r| r.method == "POST" && r.path.ends_with("?uploadId=up-1"))
+            .expect("upload not completed");
+        assert_eq!(
+            String::from_utf8_lossy(&complete.body),
+            "<CompleteMultipartUpload>\
+             <Part><PartNumber>1</PartNumber><ETag>\"etag-1\"</ETag></Part>\
+             <Part><PartNumber>2</PartNumber><ETag>\"etag-2\"</ETag></Part>\
+             </CompleteMultipartUpload>"
+        );
+        let manifest = requests.last().unwrap();
+        assert_eq!(
+            (manifest.method.as_str(), manifest.path.as_str()),
+            ("PUT", "/bucket/sectors/s-t01000-1.manifest.json")
+        );
+    }
+
+    #[test]
+    fn test_multipart_upload_aborted() {
+        let server = store(usize::MAX);
+        let mut upload =
+            Box::new(MultipartUpload::create(&config(&server, 1), location()).expect("create"));
+        assert!(upload.write_at(&vec![0u8; MIN_PART_SIZE], 0).is_err());
+        upload.abort();
+
+        let requests = server.requests();
+        let abort = requests.last().unwrap();
+        assert_eq!(
+            (abort.method.as_str(), abort.path.as_str()),
+            ("DELETE", "/bucket/sectors/s-t01000-1?uploadId=up-1")
+        );
+    }
+
+    #[test]
+    fn test_amz_date() {
+        let t = UNIX_EPOCH + Duration::from_secs(1369353600 + 3661);
+        assert_eq!(
+            amz_date(t),
+            ("20130524T010101Z".to_string(), "20130524".to_string())
+        );
+    }
+}
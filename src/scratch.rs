@@ -0,0 +1,139 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{Context, Result};
+use tracing::{debug, info, warn};
+
+use crate::config;
+
+/// Name of the scratch root next to the staged files when `scratch_dir` isn't
+/// configured.
+const DEFAULT_ROOT: &str = ".add_piece-scratch";
+
+/// Scratch directories of the running tasks, removed if the process is
+/// interrupted.
+static LIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// The scratch directory of a task, holding its temporary files until they
+/// are moved into place. It is removed with everything left in it when the
+/// task ends, whether it succeeded or failed.
+pub struct TaskScratch {
+    dir: PathBuf,
+}
+
+impl TaskScratch {
+    /// Creates the scratch directory of a task staging into `staged_dir`,
+    /// under the configured `scratch_dir` or in `staged_dir` itself. The
+    /// directories left behind by processes which are no longer running are
+    /// removed first.
+    pub fn create(staged_dir: Option<&Path>) -> Result<Self> {
+        let root = match (&config::global().scratch_dir, staged_dir) {
+            (Some(root), _) => root.clone(),
+            (None, Some(dir)) => dir.join(DEFAULT_ROOT),
+            (None, None) => std::env::temp_dir().join(DEFAULT_ROOT),
+        };
+        sweep(&root);
+
+        let name = format!("{}-{}", process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let dir = root.join(name);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("create scratch dir {}", dir.display()))?;
+        LIVE.lock().unwrap().push(dir.clone());
+        debug!(dir = %dir.display(), "task scratch dir created");
+        Ok(Self { dir })
+    }
+
+    /// Identifies the task among those of the process, its id in the logs
+    /// and manifests.
+    pub fn task_id(&self) -> String {
+        self.dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Path of the temporary file `name` of the task.
+    fn temp_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Writes `content` to `dest` through a temporary file of the task, so
+    /// that `dest` is either complete or missing.
+    pub fn write_atomic(&self, dest: &Path, content: &[u8]) -> Result<()> {
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = self.temp_path(&name);
+        fs::write(&tmp, content).with_context(|| format!("write {}", tmp.display()))?;
+        match fs::rename(&tmp, dest) {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                // the scratch dir is on another filesystem, copy next to
+                // `dest` and rename there instead
+                let mut near = dest.as_os_str().to_owned();
+                near.push(".tmp");
+                fs::copy(&tmp, &near)
+                    .and_then(|_| fs::rename(&near, dest))
+                    .with_context(|| format!("move {} to {}", tmp.display(), dest.display()))
+            }
+            res => res.with_context(|| format!("move {} to {}", tmp.display(), dest.display())),
+        }
+    }
+}
+
+impl Drop for TaskScratch {
+    fn drop(&mut self) {
+        LIVE.lock().unwrap().retain(|dir| dir != &self.dir);
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!(err = ?e, dir = %self.dir.display(), "failed to remove scratch dir");
+        }
+    }
+}
+
+/// Removes the scratch directories of the running tasks when the process is
+/// interrupted or terminated, then exits.
+///
+/// Directories of processes killed outright are removed by the next task
+/// using the same scratch root.
+pub fn cleanup_on_signal() -> Result<()> {
+    ctrlc::set_handler(|| {
+        for dir in LIVE.lock().unwrap().drain(..) {
+            let _ = fs::remove_dir_all(&dir);
+        }
+        warn!("interrupted, task scratch dirs removed");
+        process::exit(130);
+    })
+    .context("install signal handler")
+}
+
+/// Removes the directories of `root` whose process isn't running anymore.
+fn sweep(root: &Path) {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let pid = name.to_string_lossy().split('-').next().map(str::to_string);
+        let running = match pid.and_then(|pid| pid.parse::<u32>().ok()) {
+            Some(pid) => pid == process::id() || Path::new("/proc").join(pid.to_string()).exists(),
+            // not one of ours
+            None => true,
+        };
+        if running {
+            continue;
+        }
+        match fs::remove_dir_all(entry.path()) {
+            Ok(()) => info!(dir = %entry.path().display(), "removed orphan scratch dir"),
+            Err(e) => {
+                warn!(err = ?e, dir = %entry.path().display(), "failed to remove orphan scratch dir")
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Seal proofs and sizes as users write them, e.g. on the command line.
+
+use anyhow::{anyhow, bail, ensure, Result};
+use filecoin_proofs::constants::{
+    SECTOR_SIZE_16_MIB, SECTOR_SIZE_1_GIB, SECTOR_SIZE_2_KIB, SECTOR_SIZE_32_GIB,
+    SECTOR_SIZE_512_MIB, SECTOR_SIZE_64_GIB, SECTOR_SIZE_8_MIB,
+};
+use vc_processors::fil_proofs::RegisteredSealProof;
+
+/// Sizes of the sectors of the seal proofs.
+pub const SECTOR_SIZES: [u64; 5] = [
+    SECTOR_SIZE_2_KIB,
+    SECTOR_SIZE_8_MIB,
+    SECTOR_SIZE_512_MIB,
+    SECTOR_SIZE_32_GIB,
+    SECTOR_SIZE_64_GIB,
+];
+
+/// Sizes of the sectors filecoin-proofs seals without a seal proof on
+/// mainnet, which devnets and integration tests use to pack several pieces
+/// into sectors far smaller than 32GiB. Only supported with the
+/// `test-sectors` feature.
+pub const TEST_SECTOR_SIZES: [u64; 2] = [SECTOR_SIZE_16_MIB, SECTOR_SIZE_1_GIB];
+
+/// Parses a size in bytes such as `2048`, `8MiB` or `32GiB`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    const UNITS: [(&str, u64); 5] = [
+        ("TiB", 1 << 40),
+        ("GiB", 1 << 30),
+        ("MiB", 1 << 20),
+        ("KiB", 1 << 10),
+        ("B", 1),
+    ];
+
+    let s = s.trim();
+    let (number, unit) = UNITS
+        .iter()
+        .find_map(|(suffix, unit)| s.strip_suffix(suffix).map(|n| (n, *unit)))
+        .unwrap_or((s, 1));
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size: {}", s))
+}
+
+/// Parses a rate in bytes per second such as `200MiB/s` or `1.5GiB/s`, the
+/// `/s` being optional.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    const UNITS: [(&str, f64); 5] = [
+        ("TiB", (1u64 << 40) as f64),
+        ("GiB", (1u64 << 30) as f64),
+        ("MiB", (1u64 << 20) as f64),
+        ("KiB", (1u64 << 10) as f64),
+        ("B", 1.0),
+    ];
+
+    let rate = s.trim();
+    let rate = rate.strip_suffix("/s").unwrap_or(rate);
+    let (number, unit) = UNITS
+        .iter()
+        .find_map(|(suffix, unit)| rate.strip_suffix(suffix).map(|n| (n, *unit)))
+        .unwrap_or((rate, 1.0));
+    number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .map(|n| n * unit)
+        .filter(|n| n.is_finite() && *n >= 0.0 && *n <= u64::MAX as f64)
+        .map(|n| n as u64)
+        .ok_or_else(|| format!("invalid rate: {}", s))
+}
+
+/// Returns the current seal proof of sectors of `sector_size` bytes.
+pub fn for_sector_size(sector_size: u64) -> Result<RegisteredSealProof> {
+    use RegisteredSealProof::*;
+    Ok(match sector_size {
+        s if s == 2 << 10 => StackedDrg2KiBV1_1,
+        s if s == 8 << 20 => StackedDrg8MiBV1_1,
+        s if s == 512 << 20 => StackedDrg512MiBV1_1,
+        s if s == 32 << 30 => StackedDrg32GiBV1_1,
+        s if s == 64 << 30 => StackedDrg64GiBV1_1,
+        _ => bail!("no seal proof for sectors of {} bytes", sector_size),
+    })
+}
+
+/// Fails unless sectors of `sector_size` bytes are supported, the test
+/// sector sizes only with the `test-sectors` feature.
+pub fn ensure_sector_size(sector_size: u64) -> Result<()> {
+    let test_sectors = cfg!(feature = "test-sectors") && TEST_SECTOR_SIZES.contains(&sector_size);
+    ensure!(
+        test_sectors || SECTOR_SIZES.contains(&sector_size),
+        "sectors of {} bytes are not supported",
+        sector_size
+    );
+    Ok(())
+}
+
+/// Parses a seal proof given by its name (`StackedDrg32GiBV1_1`) or by the
+/// size of its sectors (`32GiB`), optionally followed by the proof version
+/// (`32GiB-v1`, `32GiB-v1_1`, the default).
+pub fn parse(s: &str) -> Result<RegisteredSealProof> {
+    let s = s.trim();
+    if let Ok(proof) = serde_json::from_value(serde_json::Value::String(s.to_string())) {
+        return Ok(proof);
+    }
+
+    let (size, version) = s.split_once('-').unwrap_or((s, "v1_1"));
+    let sector_size = parse_size(size).map_err(|e| anyhow!("invalid seal proof {}: {}", s, e))?;
+    let proof = for_sector_size(sector_size)?;
+    match version.to_ascii_lowercase().as_str() {
+        "v1_1" | "v1.1" => Ok(proof),
+        "v1" => Ok(legacy(proof)),
+        "synthetic" => bail!("synthetic PoRep is not supported by this build: {}", s),
+        _ => bail!("unknown seal proof version in {}", s),
+    }
+}
+
+/// Returns the name of `proof`, as accepted by `parse`.
+pub fn name(proof: RegisteredSealProof) -> String {
+    serde_json::to_value(proof)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("{:?}", proof))
+}
+
+/// The v1 version of `proof`.
+pub(crate) fn legacy(proof: RegisteredSealProof) -> RegisteredSealProof {
+    use RegisteredSealProof::*;
+    match proof {
+        StackedDrg2KiBV1_1 => StackedDrg2KiBV1,
+        StackedDrg8MiBV1_1 => StackedDrg8MiBV1,
+        StackedDrg512MiBV1_1 => StackedDrg512MiBV1,
+        StackedDrg32GiBV1_1 => StackedDrg32GiBV1,
+        StackedDrg64GiBV1_1 => StackedDrg64GiBV1,
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use RegisteredSealProof::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_size("2048"), Ok(2048));
+        assert_eq!(parse_size("8MiB"), Ok(8 << 20));
+        assert!(parse_size("8 parsecs").is_err());
+        assert_eq!(parse_rate("1.5GiB/s"), Ok(3 << 29));
+        assert_eq!(parse_rate("200MiB"), Ok(200 << 20));
+        assert_eq!(parse_rate("1000/s"), Ok(1000));
+        assert!(parse_rate("-1GiB/s").is_err());
+        assert!(parse_rate("fast").is_err());
+
+        assert_eq!(parse("32GiB").unwrap(), StackedDrg32GiBV1_1);
+        assert_eq!(parse("2KiB-v1").unwrap(), StackedDrg2KiBV1);
+        assert_eq!(parse("StackedDrg512MiBV1_1").unwrap(), StackedDrg512MiBV1_1);
+        assert_eq!(parse(&name(StackedDrg64GiBV1)).unwrap(), StackedDrg64GiBV1);
+        assert!(parse("8MiB-synthetic").is_err());
+        assert!(parse("16GiB").is_err());
+        assert!(parse("32GiB-v2").is_err());
+
+        for size in SECTOR_SIZES {
+            assert!(ensure_sector_size(size).is_ok());
+            assert_eq!(parse_size(&size.to_string()), Ok(size));
+        }
+        assert_eq!(
+            ensure_sector_size(16 << 20).is_ok(),
+            cfg!(feature = "test-sectors")
+        );
+        assert!(ensure_sector_size(4 << 10).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! A sector staged piece by piece over time, for market nodes which accept
+//! deals as they come rather than staging all the pieces of a sector at once.
+//!
+//! `SectorBuilder` owns the staged file and its manifest. Every piece added
+//! is appended and recorded in the manifest, synced before the manifest is
+//! rewritten, so that a builder reopened after a crash resumes after the last
+//! piece recorded. `finalize` completes the sector with zero pieces and
+//! returns what PreCommit needs.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, SectorSize, UnpaddedBytesAmount};
+use log::{debug, warn};
+use vc_processors::fil_proofs::RegisteredSealProof;
+
+use crate::manifest::{Manifest, ManifestPiece, Provenance};
+use crate::{add_piece_for_sector_size, precommit, pure, AddPieceOptions};
+
+/// A sector completed by `SectorBuilder::finalize`.
+#[derive(Debug, Clone)]
+pub struct FinalizedSector {
+    /// The pieces of the sector in order, the zero pieces included, as
+    /// `seal_pre_commit_phase1` takes them.
+    pub pieces: Vec<PieceInfo>,
+    pub comm_d: [u8; 32],
+    pub manifest: Manifest,
+}
+
+pub struct SectorBuilder {
+    path: PathBuf,
+    file: File,
+    sector_size: u64,
+    manifest: Manifest,
+    options: AddPieceOptions,
+}
+
+impl SectorBuilder {
+    /// Opens the staged file `path` of a sector of `proof`, created if
+    /// missing, resuming after the pieces its manifest records.
+    pub fn open(
+        path: impl AsRef<Path>,
+        proof: RegisteredSealProof,
+        options: AddPieceOptions,
+    ) -> Result<Self> {
+        Self::open_for_sector_size(path, u64::from(proof.sector_size()), options)
+    }
+
+    /// Same as `open` for a sector of `sector_size` padded bytes, which may be
+    /// a test sector size with the `test-sectors` feature.
+    ///
+    /// Bytes of the staged file past the recorded pieces, left by a piece
+    /// which was interrupted, are dropped.
+    pub fn open_for_sector_size(
+        path: impl AsRef<Path>,
+        sector_size: u64,
+        options: AddPieceOptions,
+    ) -> Result<Self> {
+        crate::seal_proof::ensure_sector_size(sector_size)?;
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("open staged file: {}", path.display()))?;
+
+        let manifest = match Manifest::load(&path)? {
+            Some(manifest) => manifest,
+            None => Manifest {
+                pieces: Vec::new(),
+                provenance: Some(Provenance::new(options.committer, None)),
+                attestation: None,
+            },
+        };
+        manifest.piece_lengths().context("resume staged file")?;
+        let end = manifest.end();
+        ensure!(
+            end <= sector_size,
+            "the recorded pieces end at {}, past the {} bytes sector",
+            end,
+            sector_size
+        );
+
+        let len = file.metadata().context("stat staged file")?.len();
+        if len < end {
+            bail!(
+                "staged file {} holds {} bytes, its manifest records {}",
+                path.display(),
+                len,
+                end
+            );
+        }
+        if len > end {
+            warn!(
+                "dropping {} unrecorded bytes of {}",
+                len - end,
+                path.display()
+            );
+            file.set_len(end).context("truncate staged file")?;
+        }
+        debug!(
+            "sector builder opened at {} with {} pieces",
+            end,
+            manifest.pieces.len()
+        );
+
+        Ok(Self {
+            path,
+            file,
+            sector_size,
+            manifest,
+            options,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// The pieces added so far, in order.
+    pub fn pieces(&self) -> Vec<PieceInfo> {
+        self.manifest
+            .pieces
+            .iter()
+            .map(|p| p.piece_info.clone())
+            .collect()
+    }
+
+    /// Unpadded bytes of the sector following the pieces added so far. A
+    /// piece may need fewer than these to fit, but not more, see `fits`.
+    pub fn remaining(&self) -> UnpaddedBytesAmount {
+        PaddedBytesAmount(self.sector_size - self.manifest.end()).into()
+    }
+
+    /// Whether a piece of `piece_size` still fits in the sector, aligned after
+    /// the pieces added so far.
+    pub fn fits(&self, piece_size: UnpaddedBytesAmount) -> Result<bool> {
+        let lengths = self.manifest.piece_lengths()?;
+        let end = pure::plan_alignment(&lengths, piece_size)?.end();
+        Ok(u64::from(end) <= self.sector_size)
+    }
+
+    /// Appends the piece of `piece_size` read from `source`, as `add_piece`
+    /// does, and records it. A piece which fails leaves the staged file as it
+    /// was before it.
+    pub fn add<R: Read>(
+        &mut self,
+        source: R,
+        piece_size: UnpaddedBytesAmount,
+    ) -> Result<PieceInfo> {
+        let start = self.manifest.end();
+        let lengths = self.manifest.piece_lengths()?;
+        self.file
+            .seek(SeekFrom::Start(start))
+            .context("seek staged file")?;
+
+        let added = add_piece_for_sector_size(
+            self.sector_size,
+            source,
+            &mut self.file,
+            piece_size,
+            &lengths,
+            &self.options,
+        );
+        let (piece_info, written) = match added {
+            Ok(added) => added,
+            Err(e) => {
+                if let Err(truncate) = self.file.set_len(start) {
+                    warn!("failed to drop the bytes of a failed piece: {}", truncate);
+                }
+                return Err(e);
+            }
+        };
+
+        // the piece data is right after its left alignment, at the end of
+        // what was written
+        let written = u64::from(PaddedBytesAmount::from(written));
+        let padded_size = u64::from(PaddedBytesAmount::from(piece_info.size));
+        self.record(ManifestPiece {
+            source: format!("piece-{}", self.manifest.pieces.len()),
+            payload_size: piece_size.0,
+            piece_info: piece_info.clone(),
+            offset: start + written - padded_size,
+            len: padded_size,
+            chunk_roots: Vec::new(),
+            encryption: None,
+            excessive_alignment: None,
+            payload_blake3: None,
+            payload_sha256: None,
+        })?;
+        Ok(piece_info)
+    }
+
+    /// Completes the sector with the zero pieces of `precommit::filler_pieces`
+    /// and returns its pieces and CommD.
+    pub fn finalize(mut self) -> Result<FinalizedSector> {
+        let fillers = precommit::filler_pieces(
+            PaddedBytesAmount(self.manifest.end()),
+            PaddedBytesAmount(self.sector_size),
+        )?;
+        for filler in fillers {
+            let start = self.manifest.end();
+            let padded_size = u64::from(PaddedBytesAmount::from(filler.size));
+            self.file
+                .seek(SeekFrom::Start(start))
+                .context("seek staged file")?;
+            self.options
+                .zero_fill
+                .write(&mut self.file, padded_size)
+                .context("write zero piece")?;
+            self.record(ManifestPiece {
+                source: "filler".to_string(),
+                payload_size: 0,
+                piece_info: filler,
+                offset: start,
+                len: padded_size,
+                chunk_roots: Vec::new(),
+                encryption: None,
+                excessive_alignment: None,
+                payload_blake3: None,
+                payload_sha256: None,
+            })?;
+        }
+
+        let pieces = precommit::sector_piece_infos_for_size(self.sector_size, &self.manifest)?;
+        let comm_d = filecoin_proofs::compute_comm_d(SectorSize(self.sector_size), &pieces)
+            .context("compute CommD")?;
+        if let Some(provenance) = &mut self.manifest.provenance {
+            provenance.finish();
+        }
+        self.manifest.save(&self.path)?;
+        Ok(FinalizedSector {
+            pieces,
+            comm_d,
+            manifest: self.manifest,
+        })
+    }
+
+    /// Records `piece`, written and synced, in the manifest.
+    fn record(&mut self, piece: ManifestPiece) -> Result<()> {
+        self.file.sync_data().context("sync staged file")?;
+        self.manifest.pieces.push(piece);
+        self.manifest.save(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_sector_builder() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("s-t01000-1");
+        let proof = RegisteredSealProof::StackedDrg2KiBV1_1;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let first: Vec<u8> = (0..127).map(|_| rng.gen()).collect();
+        let second: Vec<u8> = (0..508).map(|_| rng.gen()).collect();
+
+        let mut builder = SectorBuilder::open(&path, proof, AddPieceOptions::default()).unwrap();
+        assert_eq!(builder.remaining(), UnpaddedBytesAmount(2032));
+        let first_info = builder.add(&first[..], UnpaddedBytesAmount(127)).unwrap();
+        assert!(!builder.fits(UnpaddedBytesAmount(2032)).unwrap());
+        assert!(builder.fits(UnpaddedBytesAmount(1016)).unwrap());
+        drop(builder);
+
+        // an interrupted piece is dropped on reopen
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| std::io::Write::write_all(&mut f, &[1u8; 64]))
+            .unwrap();
+        let mut builder = SectorBuilder::open(&path, proof, AddPieceOptions::default()).unwrap();
+        assert_eq!(builder.pieces(), vec![first_info]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 128);
+        assert!(builder
+            .add(&[0u8; 10][..], UnpaddedBytesAmount(4064))
+            .is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 128);
+        builder.add(&second[..], UnpaddedBytesAmount(508)).unwrap();
+
+        let sector = builder.finalize().unwrap();
+        let staged = fs::read(&path).unwrap();
+        assert_eq!(staged.len(), 2048);
+        assert_eq!(pure::commit(&staged).unwrap(), sector.comm_d);
+        assert_eq!(
+            sector.pieces,
+            precommit::sector_piece_infos(proof, &Manifest::load(&path).unwrap().unwrap()).unwrap()
+        );
+
+        let builder = SectorBuilder::open(&path, proof, AddPieceOptions::default()).unwrap();
+        assert_eq!(builder.remaining(), UnpaddedBytesAmount(0));
+    }
+}
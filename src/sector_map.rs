@@ -0,0 +1,319 @@
+//! Maps of the layout of a sector, its pieces, the alignment between them
+//! and the zero pieces completing it, as ASCII or SVG: the quickest way to
+//! show a deal client where the padding of a sector goes.
+
+use std::{fmt::Write as _, path::Path};
+
+use add_piece::{piece_cid, pure};
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::UnpaddedBytesAmount;
+use serde::Serialize;
+
+use crate::staging;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    Piece,
+    /// Zeros aligning the next piece, or completing a piece to a power of
+    /// two.
+    Alignment,
+    /// Zero pieces, of CC sectors or completing a sector.
+    Filler,
+    /// Not staged yet, completed by zero pieces when sealing.
+    Free,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    /// Padded offset in the sector.
+    pub offset: u64,
+    /// Padded length.
+    pub len: u64,
+    /// Piece CID, or source of zero pieces.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapFormat {
+    Ascii,
+    Svg,
+}
+
+impl MapFormat {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "ascii" => Ok(MapFormat::Ascii),
+            "svg" => Ok(MapFormat::Svg),
+            _ => Err(format!("unknown map format: {}", s)),
+        }
+    }
+}
+
+/// The segments of the sector of `sector_size` padded bytes staged into
+/// `staged`, by its manifest.
+pub fn from_staged(staged: &Path, sector_size: Option<u64>) -> Result<(u64, Vec<Segment>)> {
+    let manifest = staging::load_manifest(staged)?
+        .with_context(|| format!("no manifest found for {}", staged.display()))?;
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    for piece in &manifest.pieces {
+        if piece.offset > pos {
+            segments.push(alignment(pos, piece.offset - pos));
+        }
+        let padded_size = u64::from(piece.padded_size());
+        let (kind, label) = match piece.payload_size {
+            0 => (SegmentKind::Filler, Some(piece.source.clone())),
+            _ => (
+                SegmentKind::Piece,
+                Some(piece_cid::encode(&piece.piece_info.commitment)),
+            ),
+        };
+        segments.push(Segment {
+            kind,
+            offset: piece.offset,
+            len: padded_size,
+            label,
+        });
+        if piece.len > padded_size {
+            segments.push(alignment(
+                piece.offset + padded_size,
+                piece.len - padded_size,
+            ));
+        }
+        pos = piece.end();
+    }
+    complete(segments, sector_size)
+}
+
+/// The segments of a sector of `sector_size` padded bytes holding pieces of
+/// `piece_sizes` in order, as `add_piece` would lay them out.
+pub fn from_plan(
+    piece_sizes: &[UnpaddedBytesAmount],
+    sector_size: Option<u64>,
+) -> Result<(u64, Vec<Segment>)> {
+    let mut segments = Vec::new();
+    for (index, placement) in pure::plan_pieces(piece_sizes)?.iter().enumerate() {
+        let offset = u64::from(placement.offset);
+        let left = u64::from(placement.left);
+        if left > 0 {
+            segments.push(alignment(offset - left, left));
+        }
+        segments.push(Segment {
+            kind: SegmentKind::Piece,
+            offset,
+            len: u64::from(placement.size),
+            label: Some(format!("piece {}", index)),
+        });
+        let right = u64::from(placement.right);
+        if right > 0 {
+            segments.push(alignment(offset + u64::from(placement.size), right));
+        }
+    }
+    complete(segments, sector_size)
+}
+
+fn alignment(offset: u64, len: u64) -> Segment {
+    Segment {
+        kind: SegmentKind::Alignment,
+        offset,
+        len,
+        label: None,
+    }
+}
+
+/// Completes `segments` with the free space up to the sector size, by
+/// default the smallest power of two holding them.
+fn complete(mut segments: Vec<Segment>, sector_size: Option<u64>) -> Result<(u64, Vec<Segment>)> {
+    let end = segments.last().map_or(0, |s| s.offset + s.len);
+    let sector_size = sector_size.unwrap_or_else(|| end.max(128).next_power_of_two());
+    ensure!(
+        end <= sector_size,
+        "the pieces end at {}, past the {} bytes sector",
+        end,
+        sector_size
+    );
+    if end < sector_size {
+        segments.push(Segment {
+            kind: SegmentKind::Free,
+            offset: end,
+            len: sector_size - end,
+            label: None,
+        });
+    }
+    Ok((sector_size, segments))
+}
+
+/// Renders the map of a sector `width` cells or pixels wide.
+pub fn render(format: MapFormat, sector_size: u64, segments: &[Segment], width: usize) -> String {
+    match format {
+        MapFormat::Ascii => render_ascii(sector_size, segments, width),
+        MapFormat::Svg => render_svg(sector_size, segments, width),
+    }
+}
+
+/// Cell of a segment in the ASCII map: a letter per piece, in order.
+fn cell(kind: SegmentKind, piece: usize) -> char {
+    match kind {
+        SegmentKind::Piece => (b'A' + (piece % 26) as u8) as char,
+        SegmentKind::Alignment => '-',
+        SegmentKind::Filler => '0',
+        SegmentKind::Free => '.',
+    }
+}
+
+/// Pieces numbered in order, for their cells and colors.
+fn numbered(segments: &[Segment]) -> Vec<usize> {
+    let mut piece = 0;
+    segments
+        .iter()
+        .map(|s| {
+            let n = piece;
+            if s.kind == SegmentKind::Piece {
+                piece += 1;
+            }
+            n
+        })
+        .collect()
+}
+
+fn percent(len: u64, sector_size: u64) -> f64 {
+    len as f64 * 100.0 / sector_size as f64
+}
+
+fn render_ascii(sector_size: u64, segments: &[Segment], width: usize) -> String {
+    let width = width.max(1);
+    let numbers = numbered(segments);
+    // each cell shows the segment covering most of it
+    let bar: String = (0..width as u64)
+        .map(|i| {
+            let start = i * sector_size / width as u64;
+            let end = (i + 1) * sector_size / width as u64;
+            let (index, _) = segments
+                .iter()
+                .enumerate()
+                .map(|(n, s)| {
+                    let covered = (s.offset + s.len)
+                        .min(end)
+                        .saturating_sub(s.offset.max(start));
+                    (n, covered)
+                })
+                .max_by_key(|(n, covered)| (*covered, std::cmp::Reverse(*n)))
+                .unwrap_or((0, 0));
+            segments
+                .get(index)
+                .map_or(' ', |s| cell(s.kind, numbers[index]))
+        })
+        .collect();
+
+    let mut out = format!("[{}]\n", bar);
+    for (segment, piece) in segments.iter().zip(&numbers) {
+        let _ = writeln!(
+            out,
+            "{} {:>14} {:>14} {:>6.2}% {:?}{}",
+            cell(segment.kind, *piece),
+            segment.offset,
+            segment.len,
+            percent(segment.len, sector_size),
+            segment.kind,
+            segment
+                .label
+                .as_ref()
+                .map(|l| format!(" {}", l))
+                .unwrap_or_default(),
+        );
+    }
+    let alignment: u64 = segments
+        .iter()
+        .filter(|s| s.kind == SegmentKind::Alignment)
+        .map(|s| s.len)
+        .sum();
+    let _ = writeln!(
+        out,
+        "alignment: {} bytes, {:.2}% of the {} bytes sector",
+        alignment,
+        percent(alignment, sector_size),
+        sector_size
+    );
+    out
+}
+
+fn color(kind: SegmentKind, piece: usize) -> &'static str {
+    const PIECES: [&str; 6] = [
+        "#4e79a7", "#59a14f", "#b07aa1", "#f28e2b", "#76b7b2", "#edc948",
+    ];
+    match kind {
+        SegmentKind::Piece => PIECES[piece % PIECES.len()],
+        SegmentKind::Alignment => "#e15759",
+        SegmentKind::Filler => "#bab0ac",
+        SegmentKind::Free => "#f4f4f4",
+    }
+}
+
+fn render_svg(sector_size: u64, segments: &[Segment], width: usize) -> String {
+    const BAR_HEIGHT: usize = 40;
+    const LINE_HEIGHT: usize = 18;
+    let width = width.max(100);
+    let numbers = numbered(segments);
+    let height = BAR_HEIGHT + 20 + LINE_HEIGHT * (segments.len() + 1);
+
+    let mut out = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"monospace\" font-size=\"12\">\n",
+        width, height
+    );
+    for (segment, piece) in segments.iter().zip(&numbers) {
+        let x = segment.offset as f64 * width as f64 / sector_size as f64;
+        // tiny segments stay visible
+        let w = (segment.len as f64 * width as f64 / sector_size as f64).max(1.0);
+        let _ = writeln!(
+            out,
+            "  <rect x=\"{:.2}\" y=\"0\" width=\"{:.2}\" height=\"{}\" fill=\"{}\" stroke=\"#ffffff\" stroke-width=\"0.5\"><title>{:?} at {}, {} bytes{}</title></rect>",
+            x,
+            w,
+            BAR_HEIGHT,
+            color(segment.kind, *piece),
+            segment.kind,
+            segment.offset,
+            segment.len,
+            segment.label.as_ref().map(|l| format!(", {}", escape(l))).unwrap_or_default(),
+        );
+    }
+    for (line, (segment, piece)) in segments.iter().zip(&numbers).enumerate() {
+        let y = BAR_HEIGHT + 20 + LINE_HEIGHT * line;
+        let _ = writeln!(
+            out,
+            "  <rect x=\"0\" y=\"{}\" width=\"12\" height=\"12\" fill=\"{}\"/><text x=\"18\" y=\"{}\">{:?} at {}, {} bytes ({:.2}%){}</text>",
+            y,
+            color(segment.kind, *piece),
+            y + 11,
+            segment.kind,
+            segment.offset,
+            segment.len,
+            percent(segment.len, sector_size),
+            segment.label.as_ref().map(|l| format!(" {}", escape(l))).unwrap_or_default(),
+        );
+    }
+    let alignment: u64 = segments
+        .iter()
+        .filter(|s| s.kind == SegmentKind::Alignment)
+        .map(|s| s.len)
+        .sum();
+    let _ = writeln!(
+        out,
+        "  <text x=\"0\" y=\"{}\">alignment: {} bytes, {:.2}% of the {} bytes sector</text>\n</svg>",
+        BAR_HEIGHT + 20 + LINE_HEIGHT * segments.len() + 11,
+        alignment,
+        percent(alignment, sector_size),
+        sector_size
+    );
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
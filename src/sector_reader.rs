@@ -0,0 +1,169 @@
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use anyhow::{anyhow, ensure, Context, Result};
+
+use crate::manifest::{Manifest, ManifestPiece};
+use crate::staged_format::Header;
+use crate::{piece_cid, unpad::UnpadReader};
+
+/// Reads the payloads of the pieces of a staged sector, in the raw or the v2
+/// format, so that retrieval can be served off the unsealed copy.
+pub struct SectorReader<R> {
+    staged: R,
+    data_offset: u64,
+    manifest: Manifest,
+}
+
+impl SectorReader<fs::File> {
+    pub fn open(staged: impl AsRef<Path>, manifest: Manifest) -> Result<Self> {
+        let staged = staged.as_ref();
+        let file = fs::File::open(staged)
+            .with_context(|| format!("open staged file: {}", staged.display()))?;
+        Self::new(file, manifest)
+    }
+}
+
+impl<R: Read + Seek> SectorReader<R> {
+    pub fn new(mut staged: R, manifest: Manifest) -> Result<Self> {
+        let data_offset = Header::read_from(&mut staged)?.map_or(0, |h| h.data_offset);
+        Ok(Self {
+            staged,
+            data_offset,
+            manifest,
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Returns the piece whose CID is `piece_cid`.
+    pub fn piece(&self, piece_cid: &str) -> Result<&ManifestPiece> {
+        let commitment = piece_cid::decode(piece_cid)?;
+        self.manifest
+            .pieces
+            .iter()
+            .find(|p| p.piece_info.commitment == commitment)
+            .ok_or_else(|| anyhow!("piece {} is not in the sector", piece_cid))
+    }
+
+    /// Streams the original payload of the piece whose CID is `piece_cid`.
+    ///
+    /// Encrypted payloads can't be read back this way, see
+    /// `encryption::decrypt`.
+    pub fn read_piece(&mut self, piece_cid: &str) -> Result<impl Read + '_> {
+        let len = self.piece(piece_cid)?.payload_size;
+        self.read_piece_range(piece_cid, 0, len)
+    }
+
+    /// Streams the payload bytes `offset..offset + len` of the piece whose CID
+    /// is `piece_cid`, only the fr32 blocks covering them are read.
+    pub fn read_piece_range(
+        &mut self,
+        piece_cid: &str,
+        offset: u64,
+        len: u64,
+    ) -> Result<impl Read + '_> {
+        let piece = self.piece(piece_cid)?;
+        ensure!(
+            piece.encryption.is_none(),
+            "piece {} is encrypted",
+            piece_cid
+        );
+        ensure!(
+            offset
+                .checked_add(len)
+                .is_some_and(|end| end <= piece.payload_size),
+            "range {}+{} is out of the payload of piece {} ({} bytes)",
+            offset,
+            len,
+            piece_cid,
+            piece.payload_size
+        );
+
+        let piece_offset = self.data_offset + piece.offset;
+        UnpadReader::new(&mut self.staged, piece_offset, offset, len).context("seek staged file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use filecoin_proofs::UnpaddedBytesAmount;
+
+    use crate::{add_piece_with_layout, AddPieceOptions};
+
+    #[test]
+    fn test_read_piece() {
+        let mut staged = Vec::new();
+        let mut manifest = Manifest::default();
+        let mut payloads = Vec::new();
+        // the second piece spans several reads of the unpadding
+        for (size, payload_size) in [(1016u64, 1000u64), (2080768, 2080768)] {
+            let mut payload: Vec<u8> = (0..payload_size).map(|i| (i * 13 % 256) as u8).collect();
+            let mut source = payload.clone();
+            source.resize(size as usize, 0);
+            payload.truncate(payload_size as usize);
+
+            let piece_lengths = manifest.piece_lengths().unwrap();
+            let (piece_info, placement) = add_piece_with_layout(
+                Cursor::new(source),
+                &mut staged,
+                UnpaddedBytesAmount(size),
+                &piece_lengths,
+                &AddPieceOptions::default(),
+            )
+            .unwrap();
+            manifest.pieces.push(ManifestPiece {
+                source: format!("{}", size),
+                payload_size,
+                piece_info,
+                offset: placement.offset.into(),
+                len: u64::from(placement.size + placement.right),
+                chunk_roots: Vec::new(),
+                encryption: None,
+                excessive_alignment: None,
+                payload_blake3: None,
+                payload_sha256: None,
+            });
+            payloads.push(payload);
+        }
+
+        let mut reader = SectorReader::new(Cursor::new(staged), manifest.clone()).unwrap();
+        for (piece, payload) in manifest.pieces.iter().zip(&payloads) {
+            let cid = piece_cid::encode(&piece.piece_info.commitment);
+            let mut read = Vec::new();
+            reader
+                .read_piece(&cid)
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(&read, payload);
+        }
+
+        let cid = piece_cid::encode(&manifest.pieces[1].piece_info.commitment);
+        for (offset, len) in [(0, 1), (127 * 3 + 5, 300), (1048000, 2000), (2080700, 68)] {
+            let mut read = Vec::new();
+            reader
+                .read_piece_range(&cid, offset, len)
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(
+                read,
+                &payloads[1][offset as usize..(offset + len) as usize],
+                "offset {}",
+                offset
+            );
+        }
+        assert!(reader.read_piece_range(&cid, 2080700, 69).is_err());
+        assert!(reader.read_piece_range(&cid, 1, u64::MAX).is_err());
+
+        assert!(reader.read_piece(&piece_cid::encode(&[1u8; 32])).is_err());
+    }
+}
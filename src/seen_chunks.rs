@@ -0,0 +1,128 @@
+//! Remembers the chunk roots staged over the lifetime of the node in a Bloom
+//! filter, to flag tasks made mostly of chunks staged before: clients
+//! resubmitting the same data under new deals.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use add_piece::{
+    chunk_sink::ChunkRootSink,
+    dedup::{ChunkBloom, ChunkRoot},
+    AddPieceOptions,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{config, redact};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SeenChunksConfig {
+    /// File persisting the filter across restarts, it is kept in memory
+    /// only if unset.
+    pub path: Option<PathBuf>,
+    /// Chunks the filter is sized for, 64MiB each.
+    pub expected_chunks: u64,
+    /// Chance of taking a new chunk for a seen one, once `expected_chunks`
+    /// were recorded.
+    pub false_positive_rate: f64,
+    /// Share of the chunks of a task, in percent, seen before from which the
+    /// task is flagged.
+    pub flag_percent: u64,
+}
+
+impl Default for SeenChunksConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            expected_chunks: 1 << 20,
+            false_positive_rate: 0.01,
+            flag_percent: 90,
+        }
+    }
+}
+
+static FILTER: OnceLock<Arc<ChunkBloom>> = OnceLock::new();
+/// Serializes the saves of the filter, tasks finish concurrently.
+static SAVING: Mutex<()> = Mutex::new(());
+
+fn filter(config: &SeenChunksConfig) -> Result<Arc<ChunkBloom>> {
+    if let Some(filter) = FILTER.get() {
+        return Ok(filter.clone());
+    }
+    let loaded = match &config.path {
+        Some(path) => ChunkBloom::load(path)?,
+        None => None,
+    };
+    let filter = loaded
+        .unwrap_or_else(|| ChunkBloom::new(config.expected_chunks, config.false_positive_rate));
+    Ok(FILTER.get_or_init(|| Arc::new(filter)).clone())
+}
+
+/// Collects the chunk roots of the pieces of a task, which are checked
+/// against and then added to the filter once the task succeeds, so that a
+/// task repeating its own chunks isn't flagged.
+#[derive(Debug)]
+pub struct TaskChunks {
+    filter: Arc<ChunkBloom>,
+    roots: Mutex<Vec<ChunkRoot>>,
+    next: Option<Arc<dyn ChunkRootSink>>,
+}
+
+impl ChunkRootSink for TaskChunks {
+    fn chunk_root(&self, index: usize, root: &ChunkRoot) -> io::Result<()> {
+        if let Some(next) = &self.next {
+            next.chunk_root(index, root)?;
+        }
+        self.roots.lock().expect("lock task chunks").push(*root);
+        Ok(())
+    }
+}
+
+/// Watches the chunks of the pieces staged with `options`, unless no filter
+/// is configured.
+pub fn watch(options: &mut AddPieceOptions) -> Result<Option<Arc<TaskChunks>>> {
+    let config = match &config::global().seen_chunks {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+    let chunks = Arc::new(TaskChunks {
+        filter: filter(config)?,
+        roots: Mutex::new(Vec::new()),
+        next: options.chunk_sink.take(),
+    });
+    options.chunk_sink = Some(chunks.clone());
+    Ok(Some(chunks))
+}
+
+impl TaskChunks {
+    /// Records the chunks of the task staged into `staged`, warning if
+    /// enough of them were seen before.
+    pub fn finish(&self, staged: &Path) -> Result<()> {
+        let config = config::global().seen_chunks.clone().unwrap_or_default();
+        let roots = std::mem::take(&mut *self.roots.lock().expect("lock task chunks"));
+        let seen = roots.iter().filter(|r| self.filter.contains(r)).count() as u64;
+        roots.iter().for_each(|r| self.filter.insert(r));
+        if let Some(path) = &config.path {
+            let _saving = SAVING.lock().expect("seen chunks lock poisoned");
+            self.filter.save(path)?;
+        }
+
+        let chunks = roots.len() as u64;
+        if chunks == 0 || seen * 100 < chunks * config.flag_percent {
+            debug!(staged = %redact::path(staged).display(), chunks, seen, "chunks seen before");
+            return Ok(());
+        }
+        warn!(
+            staged = %redact::path(staged).display(),
+            chunks,
+            seen,
+            "task mostly made of chunks staged before, resubmitted data?"
+        );
+        Ok(())
+    }
+}
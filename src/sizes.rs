@@ -0,0 +1,133 @@
+//! Conversions between unpadded and padded sizes.
+//!
+//! Fr32 padding turns every block of 127 bytes into 128. Payloads are zero
+//! padded to a piece size, the unpadded size of a power of 2 number of
+//! padded bytes, 127 bytes at least, and sectors hold pieces by their padded
+//! size.
+
+use filecoin_proofs::{PaddedBytesAmount, UnpaddedBytesAmount};
+use vc_processors::fil_proofs::RegisteredSealProof;
+
+/// Unpadded bytes of an fr32 block.
+pub const UNPADDED_BLOCK: u64 = 127;
+/// Padded bytes of an fr32 block.
+pub const PADDED_BLOCK: u64 = 128;
+
+/// Padded size of `size` unpadded bytes, `None` unless they are whole fr32
+/// blocks.
+pub fn to_padded(size: UnpaddedBytesAmount) -> Option<PaddedBytesAmount> {
+    size.0
+        .is_multiple_of(UNPADDED_BLOCK)
+        .then(|| PaddedBytesAmount(size.0 / UNPADDED_BLOCK * PADDED_BLOCK))
+}
+
+/// Unpadded size of `size` padded bytes, `None` unless they are whole fr32
+/// blocks.
+pub fn to_unpadded(size: PaddedBytesAmount) -> Option<UnpaddedBytesAmount> {
+    size.0
+        .is_multiple_of(PADDED_BLOCK)
+        .then(|| UnpaddedBytesAmount(size.0 / PADDED_BLOCK * UNPADDED_BLOCK))
+}
+
+/// Padded bytes of the fr32 blocks holding `payload_len` bytes, the last one
+/// zero padded.
+pub fn padded_len(payload_len: u64) -> u64 {
+    payload_len.div_ceil(UNPADDED_BLOCK) * PADDED_BLOCK
+}
+
+/// Whether `size` is a piece size: whole fr32 blocks, a power of 2 of them.
+pub fn is_piece_size(size: UnpaddedBytesAmount) -> bool {
+    to_padded(size).is_some_and(|padded| padded.0.is_power_of_two())
+}
+
+/// The smallest piece size holding `payload_len` bytes once zero padded, as
+/// used for deals.
+pub fn piece_size_for(payload_len: u64) -> UnpaddedBytesAmount {
+    let padded = padded_len(payload_len)
+        .next_power_of_two()
+        .max(PADDED_BLOCK);
+    UnpaddedBytesAmount(padded / PADDED_BLOCK * UNPADDED_BLOCK)
+}
+
+/// Unpadded bytes of the pieces filling a sector of `proof`.
+pub fn sector_capacity(proof: RegisteredSealProof) -> UnpaddedBytesAmount {
+    let sector_size = u64::from(proof.sector_size());
+    UnpaddedBytesAmount(sector_size / PADDED_BLOCK * UNPADDED_BLOCK)
+}
+
+/// `bytes` in binary units, e.g. `32GiB` or `7.94MiB`.
+pub fn human(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let unit = (0..UNITS.len())
+        .rev()
+        .find(|i| bytes >> (10 * i) > 0)
+        .unwrap_or(0);
+    let scale = 1u64 << (10 * unit);
+    match bytes % scale {
+        0 => format!("{}{}", bytes / scale, UNITS[unit]),
+        _ => format!("{:.2}{}", bytes as f64 / scale as f64, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(
+            to_padded(UnpaddedBytesAmount(127 * 3)),
+            Some(PaddedBytesAmount(128 * 3))
+        );
+        assert_eq!(to_padded(UnpaddedBytesAmount(128)), None);
+        assert_eq!(
+            to_padded(UnpaddedBytesAmount(0)),
+            Some(PaddedBytesAmount(0))
+        );
+        assert_eq!(
+            to_unpadded(PaddedBytesAmount(32 << 30)),
+            Some(UnpaddedBytesAmount(34359738368 / 128 * 127))
+        );
+        assert_eq!(to_unpadded(PaddedBytesAmount(127)), None);
+        // round trips agree with filecoin_proofs
+        for padded in [128u64, 2048, 8 << 20, 64 << 30] {
+            let unpadded = to_unpadded(PaddedBytesAmount(padded)).unwrap();
+            assert_eq!(
+                unpadded,
+                UnpaddedBytesAmount::from(PaddedBytesAmount(padded))
+            );
+            assert_eq!(to_padded(unpadded), Some(PaddedBytesAmount(padded)));
+        }
+
+        assert_eq!(padded_len(0), 0);
+        assert_eq!(padded_len(1), 128);
+        assert_eq!(padded_len(127), 128);
+        assert_eq!(padded_len(128), 256);
+
+        assert!(is_piece_size(UnpaddedBytesAmount(127)));
+        assert!(is_piece_size(UnpaddedBytesAmount(8323072)));
+        assert!(!is_piece_size(UnpaddedBytesAmount(127 * 3)));
+        assert!(!is_piece_size(UnpaddedBytesAmount(128)));
+        assert!(!is_piece_size(UnpaddedBytesAmount(0)));
+
+        assert_eq!(piece_size_for(0), UnpaddedBytesAmount(127));
+        assert_eq!(piece_size_for(127), UnpaddedBytesAmount(127));
+        assert_eq!(piece_size_for(128), UnpaddedBytesAmount(254));
+        assert_eq!(piece_size_for(8323072), UnpaddedBytesAmount(8323072));
+        assert_eq!(piece_size_for(8323073), UnpaddedBytesAmount(16646144));
+
+        assert_eq!(
+            sector_capacity(RegisteredSealProof::StackedDrg32GiBV1_1),
+            UnpaddedBytesAmount(34091302912)
+        );
+        assert_eq!(
+            sector_capacity(RegisteredSealProof::StackedDrg2KiBV1),
+            UnpaddedBytesAmount(2032)
+        );
+
+        assert_eq!(human(0), "0B");
+        assert_eq!(human(1023), "1023B");
+        assert_eq!(human(32 << 30), "32GiB");
+        assert_eq!(human(8323072), "7.94MiB");
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use add_piece::{
+    metered::{IoStats, Metered},
+    read_ahead::{ReadAhead, ReadAheadConfig},
+};
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{abort, iostats, paths, target_profile::TargetProfile};
+
+/// Opens the source of a piece, it may be called more than once.
+pub type Opener = Arc<dyn Fn() -> Result<Box<dyn Read>> + Send + Sync>;
+
+/// The source of a piece, read either inline or through a prefetch thread.
+pub enum PieceSource {
+    Direct(Metered<Box<dyn Read>>),
+    ReadAhead(ReadAhead),
+}
+
+impl PieceSource {
+    pub fn open<F, R>(read_ahead: Option<ReadAheadConfig>, open: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<R> + Send + 'static,
+        R: Read + 'static,
+    {
+        Ok(match read_ahead {
+            Some(config) => PieceSource::ReadAhead(ReadAhead::spawn(config, open)),
+            None => PieceSource::Direct(Metered::new(Box::new(open()?))),
+        })
+    }
+
+    /// Returns the bytes read from the underlying source and the time spent on it.
+    pub fn stats(&self) -> IoStats {
+        match self {
+            PieceSource::Direct(r) => r.stats(),
+            PieceSource::ReadAhead(r) => r.source_stats(),
+        }
+    }
+}
+
+impl Read for PieceSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        abort::check()?;
+        match self {
+            PieceSource::Direct(r) => r.read(buf),
+            PieceSource::ReadAhead(r) => r.read(buf),
+        }
+    }
+}
+
+/// Which local piece files may be used as sources, checked with their sizes
+/// before anything is staged.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcePathPolicy {
+    /// Accept piece files which are symlinks, on by default.
+    pub follow_symlinks: bool,
+    /// Accept piece files on another device than the staged file, on by
+    /// default.
+    pub cross_device: bool,
+    /// Open the piece files on network filesystems the way the `nfs` target
+    /// profile opens staged files, retrying stale file handles.
+    pub network_mounts_as_nfs: bool,
+}
+
+impl Default for SourcePathPolicy {
+    fn default() -> Self {
+        Self {
+            follow_symlinks: true,
+            cross_device: true,
+            network_mounts_as_nfs: false,
+        }
+    }
+}
+
+impl SourcePathPolicy {
+    /// Fails if the piece file at `path` may not be used as a source for the
+    /// staged file `staged`, which is `None` for remote targets.
+    pub fn check(&self, path: &Path, staged: Option<&Path>) -> Result<()> {
+        let path = paths::normalize(path);
+        if !self.follow_symlinks {
+            let meta = fs::symlink_metadata(&path)
+                .with_context(|| format!("stat piece file {}", path.display()))?;
+            ensure!(
+                !meta.file_type().is_symlink(),
+                "piece file {} is a symlink, which the source policy doesn't follow",
+                path.display()
+            );
+        }
+        if let (false, Some(staged)) = (self.cross_device, staged) {
+            // the staged file may not exist yet
+            let target = staged.parent().filter(|p| !p.as_os_str().is_empty());
+            let target = target.unwrap_or_else(|| Path::new("."));
+            if let (Some(source_dev), Some(target_dev)) = (device_id(&path), device_id(target)) {
+                ensure!(
+                    source_dev == target_dev,
+                    "piece file {} is on another device than {}, which the source policy forbids",
+                    path.display(),
+                    staged.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// How many times opening the piece file at `path` is tried again while
+    /// it fails with ESTALE.
+    pub fn stale_retries(&self, path: &Path) -> u32 {
+        let network =
+            self.network_mounts_as_nfs && iostats::mount_of(path).is_some_and(|m| m.is_network());
+        match network {
+            true => TargetProfile::Nfs.stale_retries(),
+            false => 0,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn device_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Checks the length of local piece files against their declared sizes before
+/// anything is staged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SizeCheckConfig {
+    pub enabled: bool,
+    /// Bytes by which a file may differ from its payload size when the piece
+    /// is padded up, i.e. its payload is smaller than the piece.
+    pub pad_up_tolerance: u64,
+}
+
+impl Default for SizeCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            pad_up_tolerance: 0,
+        }
+    }
+}
+
+/// Whether `path` is a regular file holding no bytes. Files which can't be
+/// stat'ed are left to fail when opened.
+pub fn is_empty_file(path: &Path) -> bool {
+    fs::metadata(paths::normalize(path)).is_ok_and(|meta| meta.is_file() && meta.len() == 0)
+}
+
+impl SizeCheckConfig {
+    /// Fails if the file at `path` can't be the source of a piece of
+    /// `payload_size` bytes padded up to `piece_size` unpadded bytes.
+    pub fn check(&self, path: &Path, payload_size: u64, piece_size: u64) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let len = fs::metadata(paths::normalize(path))
+            .with_context(|| format!("stat piece file {}", path.display()))?
+            .len();
+        let tolerance = if payload_size < piece_size {
+            self.pad_up_tolerance
+        } else {
+            0
+        };
+        ensure!(
+            len.abs_diff(payload_size) <= tolerance,
+            "piece file {} holds {} bytes, {} declared{}",
+            path.display(),
+            len,
+            payload_size,
+            match tolerance {
+                0 => String::new(),
+                t => format!(" with a tolerance of {}", t),
+            }
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use add_piece::{
+    committer::{CommitterBackend, CpuFeatures},
+    support,
+};
+use serde::Serialize;
+
+use crate::{
+    config,
+    target_health::{self, TargetStatus},
+};
+
+/// What this binary was built with and runs on, printed by `--spec`.
+#[derive(Debug, Serialize)]
+pub struct Spec {
+    pub version: &'static str,
+    /// Cargo features the binary was built with.
+    pub features: Vec<&'static str>,
+    pub cpu: CpuFeatures,
+    /// The commitment backend of the config and the one it selects here.
+    pub committer: CommitterBackend,
+    pub committer_selected: CommitterBackend,
+    /// The last probes of the configured staging directories.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub staging_dirs: Vec<TargetStatus>,
+}
+
+pub fn current() -> Spec {
+    let committer = config::global().committer;
+    Spec {
+        version: env!("CARGO_PKG_VERSION"),
+        features: support::features()
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name)
+            .collect(),
+        cpu: CpuFeatures::detect(),
+        committer,
+        committer_selected: committer.resolve(),
+        staging_dirs: target_health::statuses(),
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::Manifest;
+
+/// Leading bytes of a v2 staged file.
+pub const MAGIC: [u8; 8] = *b"APSTAGED";
+
+pub const VERSION: u32 = 2;
+
+/// Bytes reserved for the header of a v2 staged file, the staged data follows
+/// them so that it stays aligned for direct io.
+pub const HEADER_LEN: u64 = 4096;
+
+/// Bytes of the header holding the name of the proof type.
+const PROOF_TYPE_LEN: usize = 32;
+
+/// Layout of the staged files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StagedFormat {
+    /// The unsealed data alone, as expected by sealing.
+    #[default]
+    Raw,
+    /// The unsealed data between a `Header` and the piece table, which makes
+    /// the staged file self-describing.
+    V2,
+}
+
+/// Header of a v2 staged file, all integers are little endian:
+///
+/// | bytes  | field                              |
+/// |--------|------------------------------------|
+/// | 0..8   | `MAGIC`                            |
+/// | 8..12  | version                            |
+/// | 12..16 | reserved, 0                        |
+/// | 16..24 | data offset                        |
+/// | 24..32 | data length                        |
+/// | 32..40 | piece table offset                 |
+/// | 40..48 | piece table length                 |
+/// | 48..80 | proof type, ASCII padded with NULs |
+///
+/// The piece table is the manifest of the staged file as JSON. It is only
+/// written once staging completes, until then its length is 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Header {
+    /// Name of the registered seal proof the sector is staged for, empty if
+    /// unknown.
+    pub proof_type: String,
+    pub data_offset: u64,
+    pub data_len: u64,
+    pub piece_table_offset: u64,
+    pub piece_table_len: u64,
+}
+
+impl Header {
+    pub fn new(proof_type: &str) -> Self {
+        Self {
+            proof_type: proof_type.to_string(),
+            data_offset: HEADER_LEN,
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        ensure!(
+            self.proof_type.is_ascii() && self.proof_type.len() <= PROOF_TYPE_LEN,
+            "invalid proof type: {}",
+            self.proof_type
+        );
+
+        let mut buf = vec![0u8; HEADER_LEN as usize];
+        buf[..8].copy_from_slice(&MAGIC);
+        buf[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.data_offset.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.data_len.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.piece_table_offset.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.piece_table_len.to_le_bytes());
+        buf[48..48 + self.proof_type.len()].copy_from_slice(self.proof_type.as_bytes());
+        Ok(buf)
+    }
+
+    /// Parses the first bytes of a staged file, returns `None` if they are not
+    /// a v2 header, i.e. for raw staged files.
+    pub fn decode(buf: &[u8]) -> Result<Option<Self>> {
+        if buf.len() < 80 || buf[..8] != MAGIC {
+            return Ok(None);
+        }
+
+        let u64_at = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().expect("8 bytes"));
+        let version = u32::from_le_bytes(buf[8..12].try_into().expect("4 bytes"));
+        ensure!(
+            version == VERSION,
+            "unsupported staged file version: {}",
+            version
+        );
+
+        let proof_type = &buf[48..48 + PROOF_TYPE_LEN];
+        let end = proof_type
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(PROOF_TYPE_LEN);
+        let proof_type = std::str::from_utf8(&proof_type[..end]).context("parse proof type")?;
+        Ok(Some(Self {
+            proof_type: proof_type.to_string(),
+            data_offset: u64_at(16),
+            data_len: u64_at(24),
+            piece_table_offset: u64_at(32),
+            piece_table_len: u64_at(40),
+        }))
+    }
+
+    /// Reads the header of `staged`, if it has one.
+    pub fn read_from<R: Read + Seek>(mut staged: R) -> Result<Option<Self>> {
+        let mut buf = Vec::with_capacity(HEADER_LEN as usize);
+        staged.seek(SeekFrom::Start(0))?;
+        staged
+            .take(HEADER_LEN)
+            .read_to_end(&mut buf)
+            .context("read staged file header")?;
+        Self::decode(&buf)
+    }
+
+    /// Reads the piece table, `None` if it hasn't been written yet.
+    pub fn read_piece_table<R: Read + Seek>(&self, mut staged: R) -> Result<Option<Manifest>> {
+        if self.piece_table_len == 0 {
+            return Ok(None);
+        }
+
+        let mut buf = vec![0u8; self.piece_table_len as usize];
+        staged.seek(SeekFrom::Start(self.piece_table_offset))?;
+        staged.read_exact(&mut buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => io::Error::new(e.kind(), "truncated piece table"),
+            _ => e,
+        })?;
+        serde_json::from_slice(&buf)
+            .map(Some)
+            .context("parse piece table")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header() {
+        let manifest = Manifest::default();
+        let table = serde_json::to_vec(&manifest).unwrap();
+        let header = Header {
+            data_len: 2048,
+            piece_table_offset: HEADER_LEN + 2048,
+            piece_table_len: table.len() as u64,
+            ..Header::new("StackedDrg2KiBV1_1")
+        };
+
+        let mut staged = header.encode().unwrap();
+        staged.resize((HEADER_LEN + 2048) as usize, 0);
+        staged.extend_from_slice(&table);
+
+        let decoded = Header::read_from(Cursor::new(&staged)).unwrap();
+        assert_eq!(decoded.as_ref(), Some(&header));
+        assert_eq!(
+            header.read_piece_table(Cursor::new(&staged)).unwrap(),
+            Some(manifest)
+        );
+
+        assert_eq!(
+            Header::read_from(Cursor::new(vec![0u8; 4096])).unwrap(),
+            None
+        );
+        assert!(Header::new(&"x".repeat(33)).encode().is_err());
+    }
+}
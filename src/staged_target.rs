@@ -0,0 +1,200 @@
+//! Where staged files are written: a local file, tuned by the target profile
+//! for local disks or NFS, or a remote store streamed to.
+
+use std::{
+    fs,
+    io::{self, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use add_piece::{aligned_writer::AlignedWriter, manifest::Manifest, zero_fill::ZeroFillMode};
+use anyhow::{Context, Result};
+use tracing::{debug, warn};
+
+use crate::{
+    config,
+    target_profile::{retry_stale, TargetProfile},
+};
+
+/// A staged file being written.
+///
+/// The pieces are appended through `Write`. Local files are resumed from
+/// their manifest, staged files written to a remote store only become
+/// visible once `finalize` succeeds.
+pub trait StagedTarget: Write + Send {
+    /// Writes `buf` at `offset` of the staged file. Targets which can only be
+    /// appended to fail unless `offset` is where the next write goes.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Reserves room for the next `len` bytes where the target can, so that
+    /// running out of space fails before a piece is written.
+    fn preallocate(&mut self, len: u64) -> io::Result<()>;
+
+    /// Makes what was written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Completes the staged file and stores `manifest` next to it.
+    fn finalize(self: Box<Self>, manifest: &[u8]) -> Result<()>;
+
+    /// Discards whatever was written so far.
+    fn abort(self: Box<Self>);
+}
+
+/// A staged file on a local or network filesystem, written as the configured
+/// target profile asks: operations failing with ESTALE are retried, and
+/// flushing syncs the written data to the device so that the configured
+/// flush interval reaches network filesystems.
+pub struct LocalFile {
+    path: PathBuf,
+    file: fs::File,
+    profile: TargetProfile,
+}
+
+impl LocalFile {
+    pub fn new(path: PathBuf, file: fs::File) -> Self {
+        Self {
+            path,
+            file,
+            profile: config::global().target_profile,
+        }
+    }
+
+    pub fn file(&self) -> &fs::File {
+        &self.file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Zeroes the next `len` bytes as the configured zero fill and the target
+    /// profile allow.
+    pub fn write_zeros(&mut self, len: u64) -> io::Result<ZeroFillMode> {
+        let config = config::global();
+        let buffered = AlignedWriter::new(
+            StaleRetrying {
+                file: &self.file,
+                retries: self.profile.stale_retries(),
+            },
+            config.target_writes.unwrap_or_default(),
+        );
+        config
+            .zero_fill
+            .fill(&self.file, len, self.profile.sparse_files(), buffered)
+    }
+}
+
+impl Write for LocalFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = &self.file;
+        retry_stale(self.profile.stale_retries(), "write staged file", || {
+            file.write(buf)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+}
+
+impl StagedTarget for LocalFile {
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut file = &self.file;
+        retry_stale(self.profile.stale_retries(), "write staged file", || {
+            let pos = file.stream_position()?;
+            file.seek(io::SeekFrom::Start(offset))?;
+            file.write_all(buf)?;
+            file.seek(io::SeekFrom::Start(pos)).map(|_| ())
+        })
+    }
+
+    /// Allocates the blocks without extending the file, so that holes and
+    /// truncation work as before. Skipped on NFS, which rarely supports it.
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        if !self.profile.preallocate() || len == 0 {
+            return Ok(());
+        }
+        let offset = (&self.file).stream_position()?;
+        if !allocate(&self.file, offset, len)? {
+            debug!("preallocation unsupported by the target filesystem");
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        retry_stale(self.profile.stale_retries(), "sync staged file", || {
+            self.file.sync_data()
+        })
+    }
+
+    fn finalize(mut self: Box<Self>, manifest: &[u8]) -> Result<()> {
+        self.sync().context("sync staged file")?;
+        let path = Manifest::path_for(&self.path);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        retry_stale(self.profile.stale_retries(), "save manifest", || {
+            fs::write(&tmp, manifest)
+                .with_context(|| format!("write manifest: {:?}", tmp))
+                .and_then(|_| {
+                    fs::rename(&tmp, &path)
+                        .with_context(|| format!("rename manifest: {}", path.display()))
+                })
+        })
+    }
+
+    fn abort(self: Box<Self>) {
+        for path in [Manifest::path_for(&self.path), self.path.clone()] {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!(path = %path.display(), "remove partial staged file: {}", e),
+            }
+        }
+    }
+}
+
+/// Writes at the offset of the staged file, retrying ESTALE.
+struct StaleRetrying<'a> {
+    file: &'a fs::File,
+    retries: u32,
+}
+
+impl Write for StaleRetrying<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.file;
+        retry_stale(self.retries, "write staged file", || file.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        retry_stale(self.retries, "sync staged file", || self.file.sync_data())
+    }
+}
+
+/// Allocates `len` bytes of `file` from `offset`, keeping its size. Returns
+/// false if the filesystem can't.
+#[cfg(target_os = "linux")]
+fn allocate(file: &fs::File, offset: u64, len: u64) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if res == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(_file: &fs::File, _offset: u64, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
@@ -0,0 +1,771 @@
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
+};
+
+use add_piece::{
+    chunk_sink::ChunkRoots,
+    encryption::PayloadEncryption,
+    manifest::{spot_check, Manifest, ManifestPiece, Provenance},
+    payload_hash::PayloadHash,
+    periodic_sync::PeriodicSync,
+    precommit, pure,
+    staged_format::{Header, StagedFormat},
+    write_behind::{WriteBehind, WriteBehindConfig},
+    AddPieceOptions,
+};
+use anyhow::{bail, ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, PieceInfo, UnpaddedBytesAmount};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    attestation, config,
+    journal::{self, ChunkJournal},
+    paths,
+    staged_target::{LocalFile, StagedTarget},
+    status,
+    target_profile::retry_stale,
+};
+
+/// Describes a piece about to be written into a staged file.
+#[derive(Debug, Clone)]
+pub struct PieceSpec {
+    pub source: String,
+    pub payload_size: u64,
+    pub piece_size: UnpaddedBytesAmount,
+    /// Sha256 the source was checked against while the piece is added.
+    pub payload_sha256: Option<String>,
+}
+
+/// What `StagedFile::open` does with a staged file which already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StagedOpenMode {
+    /// Reuses the pieces a previous run recorded in its manifest and
+    /// truncates everything else.
+    #[default]
+    TruncateExisting,
+    /// Creates the staged file, failing if it exists.
+    CreateNew,
+    /// Appends the pieces after the ones its manifest records, as
+    /// `fill-sector` does. A missing staged file is created.
+    AppendExisting,
+    /// Fails if the staged file or its manifest exists, leftovers of another
+    /// run included, before any piece is fetched.
+    FailIfExists,
+    /// Reuses the pieces a previous run recorded like `truncate_existing`,
+    /// but fails rather than discard any of them, or data no manifest
+    /// records. Only the partial piece an interrupted run left is dropped.
+    ResumeExisting,
+}
+
+impl StagedOpenMode {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("unknown staged open mode: {}", s))
+    }
+}
+
+/// A staged file being filled with pieces, together with its manifest.
+///
+/// Pieces recorded by the manifest of a previous run are reused as long as
+/// they match the requested pieces in order and pass a spot check; everything
+/// after the first mismatch is discarded and written again.
+///
+/// In the v2 format the staged data follows a `Header`, offsets in the
+/// manifest stay relative to the start of the data.
+///
+/// With `chunk_journal` configured, only the recorded pieces whose chunks are
+/// all in the journal of the staged file are reused.
+pub struct StagedFile {
+    target: LocalFile,
+    header: Option<Header>,
+    manifest: Manifest,
+    previous: Vec<ManifestPiece>,
+    reusing: bool,
+    mode: StagedOpenMode,
+    journal: Option<ChunkJournal>,
+}
+
+impl StagedFile {
+    /// Opens the staged file in the configured format, `proof_type` is only
+    /// recorded by the v2 header, `provenance` by the manifest. An existing
+    /// staged file is reused, appended to or refused as `mode` says.
+    /// On Windows the path is made verbatim so that staged files deep in
+    /// shares can be opened.
+    pub fn open(
+        path: impl AsRef<Path>,
+        proof_type: &str,
+        provenance: Provenance,
+        mode: StagedOpenMode,
+    ) -> Result<Self> {
+        let path = paths::normalize(path.as_ref());
+        let exists = path.exists();
+        match mode {
+            StagedOpenMode::AppendExisting if exists => return Self::append_to(&path),
+            StagedOpenMode::CreateNew => {
+                ensure!(!exists, "staged file {} already exists", path.display())
+            }
+            StagedOpenMode::FailIfExists => {
+                let manifest = Manifest::path_for(&path);
+                ensure!(
+                    !exists && !manifest.exists(),
+                    "staged file {} already exists, refused by fail_if_exists",
+                    if exists { &path } else { &manifest }.display()
+                );
+            }
+            _ => {}
+        }
+        let create_new = matches!(
+            mode,
+            StagedOpenMode::CreateNew | StagedOpenMode::FailIfExists
+        );
+
+        let mut previous = match exists {
+            true => Manifest::load(&path)
+                .unwrap_or_else(|e| {
+                    warn!("ignore unusable manifest: {:?}", e);
+                    None
+                })
+                .map(|m| m.pieces)
+                .unwrap_or_default(),
+            false => Vec::new(),
+        };
+
+        let file = retry_stale(stale_retries(), "open staged file", || {
+            fs::OpenOptions::new()
+                .create(true)
+                .create_new(create_new)
+                .read(true)
+                .write(true)
+                .truncate(previous.is_empty() && mode != StagedOpenMode::ResumeExisting)
+                .open(&path)
+        })
+        .with_context(|| format!("open staged file: {}", path.display()))?;
+        if mode == StagedOpenMode::ResumeExisting && previous.is_empty() {
+            let len = file.metadata().context("stat staged file")?.len();
+            ensure!(
+                len == 0,
+                "staged file {} holds {} bytes no manifest records, not truncating it",
+                path.display(),
+                len
+            );
+        }
+
+        let header = match config::global().staged_format {
+            StagedFormat::Raw => None,
+            StagedFormat::V2 => Some(Header::new(proof_type)),
+        };
+        if !previous.is_empty() && Header::read_from(&file)?.is_some() != header.is_some() {
+            if mode == StagedOpenMode::ResumeExisting {
+                bail!(
+                    "staged file {} has another format, not truncating it",
+                    path.display()
+                );
+            }
+            warn!("previous staged file has another format, ignore it");
+            previous.clear();
+            file.set_len(0).context("truncate staged file")?;
+        }
+
+        let data_offset = header.as_ref().map_or(0, |h| h.data_offset);
+        let journal = match config::global().chunk_journal {
+            true => {
+                // staged files from before the journal are trusted as they
+                // used to be, and journaled as their pieces are reused
+                let entries = match previous.is_empty() {
+                    true => None,
+                    false => ChunkJournal::load(&path)?,
+                };
+                if let Some(entries) = &entries {
+                    let journaled = journal::journaled_pieces(&previous, entries);
+                    if journaled < previous.len() {
+                        warn!(
+                            journaled,
+                            recorded = previous.len(),
+                            "recorded pieces missing from the journal are written again"
+                        );
+                        previous.truncate(journaled);
+                    }
+                }
+                let mut entries = entries.unwrap_or_default();
+                entries.retain(|e| e.piece < previous.len());
+                Some(ChunkJournal::open(&path, &file, data_offset, entries)?)
+            }
+            false => None,
+        };
+
+        let mut staged = Self {
+            target: LocalFile::new(path, file),
+            header,
+            manifest: Manifest {
+                pieces: Vec::new(),
+                provenance: Some(provenance),
+                attestation: None,
+            },
+            reusing: !previous.is_empty(),
+            previous,
+            mode,
+            journal,
+        };
+
+        if !staged.reusing {
+            // to make sure that we won't keep a manifest describing truncated data
+            staged.save_manifest()?;
+            staged.write_header()?;
+            staged
+                .file()
+                .seek(SeekFrom::Start(staged.data_offset()))
+                .context("seek staged file")?;
+        }
+
+        Ok(staged)
+    }
+
+    /// Opens the complete staged file `path` to append pieces to it, trusting
+    /// its manifest or piece table without reading its pieces again. Its
+    /// format is kept whatever the config says.
+    pub fn append_to(path: impl AsRef<Path>) -> Result<Self> {
+        let path = paths::normalize(path.as_ref());
+        let manifest = load_manifest(&path)?
+            .with_context(|| format!("no manifest found for {}", path.display()))?;
+        let file = retry_stale(stale_retries(), "open staged file", || {
+            fs::OpenOptions::new().read(true).write(true).open(&path)
+        })
+        .with_context(|| format!("open staged file: {}", path.display()))?;
+        let header = Header::read_from(&file)?;
+
+        let data_offset = header.as_ref().map_or(0, |h| h.data_offset);
+        // a staged file without a journal isn't given one for its last pieces
+        let journal = match ChunkJournal::load(&path)? {
+            Some(entries) if config::global().chunk_journal => {
+                Some(ChunkJournal::open(&path, &file, data_offset, entries)?)
+            }
+            _ => None,
+        };
+
+        let mut staged = Self {
+            target: LocalFile::new(path, file),
+            header,
+            manifest,
+            previous: Vec::new(),
+            // drops the piece table of v2 staged files
+            reusing: true,
+            mode: StagedOpenMode::AppendExisting,
+            journal,
+        };
+        staged.stop_reusing()?;
+        Ok(staged)
+    }
+
+    fn file(&self) -> &fs::File {
+        self.target.file()
+    }
+
+    /// Offset of the staged data in the file.
+    fn data_offset(&self) -> u64 {
+        self.header.as_ref().map_or(0, |h| h.data_offset)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        if let Some(header) = &self.header {
+            let buf = header.encode()?;
+            self.target
+                .write_at(&buf, 0)
+                .context("write staged file header")?;
+        }
+        Ok(())
+    }
+
+    /// Reuses the next piece recorded by a previous run if it matches `spec`
+    /// and the staged bytes still agree with the source opened by `open_source`.
+    ///
+    /// Encrypted pieces are never reused, their staged bytes can't be checked
+    /// against the source, nor are pieces which weren't checked against the
+    /// sha256 of `spec`.
+    pub fn try_reuse<R: Read>(
+        &mut self,
+        spec: &PieceSpec,
+        open_source: impl FnOnce() -> Result<R>,
+    ) -> Result<Option<PieceInfo>> {
+        if !self.reusing {
+            return Ok(None);
+        }
+
+        let candidate = match self.previous.get(self.manifest.pieces.len()) {
+            Some(p)
+                if p.source == spec.source
+                    && p.payload_size == spec.payload_size
+                    && p.piece_info.size == spec.piece_size
+                    && p.encryption.is_none()
+                    && (spec.payload_sha256.is_none()
+                        || p.payload_sha256 == spec.payload_sha256) =>
+            {
+                p.clone()
+            }
+            _ => {
+                self.stop_reusing()?;
+                return Ok(None);
+            }
+        };
+
+        let staged_len = self.file().metadata().context("stat staged file")?.len();
+        let mut in_file = candidate.clone();
+        in_file.offset += self.data_offset();
+        let verified = staged_len >= in_file.end()
+            && spot_check(open_source()?, self.file(), &in_file).context("spot check")?;
+        if !verified {
+            warn!(
+                source = spec.source.as_str(),
+                "recorded piece failed verification"
+            );
+            self.stop_reusing()?;
+            return Ok(None);
+        }
+
+        info!(
+            source = spec.source.as_str(),
+            "reuse piece from previous run"
+        );
+        if let Some(journal) = &self.journal {
+            journal.finish_piece(self.manifest.pieces.len(), &candidate)?;
+        }
+        let piece_info = candidate.piece_info.clone();
+        self.manifest.pieces.push(candidate);
+        Ok(Some(piece_info))
+    }
+
+    /// Lengths of the pieces recorded so far, as expected by `add_piece`.
+    pub fn piece_lengths(&self) -> Result<Vec<UnpaddedBytesAmount>> {
+        self.manifest.piece_lengths()
+    }
+
+    /// Padded offset of the data of a piece of `piece_size` added next,
+    /// aligned after `piece_lengths`. Pieces written back to back, as the
+    /// processor writes them, pass no lengths.
+    pub fn piece_offset(
+        &self,
+        piece_lengths: &[UnpaddedBytesAmount],
+        piece_size: UnpaddedBytesAmount,
+    ) -> Result<u64> {
+        let placement = pure::plan_alignment(piece_lengths, piece_size)?;
+        Ok(self.manifest.end() + u64::from(placement.left))
+    }
+
+    /// Journals the chunks of the piece about to be added through the chunk
+    /// sink of `options`, if `chunk_journal` is configured. `piece_lengths`
+    /// are the ones the piece is aligned after.
+    pub fn journal_chunks(
+        &self,
+        spec: &PieceSpec,
+        piece_lengths: &[UnpaddedBytesAmount],
+        options: &mut AddPieceOptions,
+    ) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            let placement = pure::plan_alignment(piece_lengths, spec.piece_size)?;
+            journal.start_piece(
+                self.manifest.pieces.len(),
+                self.manifest.end() + u64::from(placement.left),
+                placement.size.into(),
+                options,
+            );
+        }
+        Ok(())
+    }
+
+    /// Appends a piece using `write`, which receives the staged file positioned
+    /// at its end and returns what `add_piece` returns, aligning the piece
+    /// after `piece_lengths` as for `piece_offset`. The roots gathered by
+    /// `chunk_roots` and the way `encryption` encrypted the payload while
+    /// writing are recorded with the piece, once it is synced.
+    ///
+    /// The blocks of pieces with a payload are allocated first, zero pieces
+    /// are left to the zero fill. If `write` fails, whatever it wrote is
+    /// truncated away and the staged file ends with the last recorded piece
+    /// again.
+    pub fn add(
+        &mut self,
+        spec: &PieceSpec,
+        piece_lengths: &[UnpaddedBytesAmount],
+        chunk_roots: Option<&ChunkRoots>,
+        encryption: Option<&PayloadEncryption>,
+        payload_hash: Option<&PayloadHash>,
+        write: impl FnOnce(&mut LocalFile) -> Result<(PieceInfo, UnpaddedBytesAmount)>,
+    ) -> Result<PieceInfo> {
+        self.stop_reusing()?;
+
+        let start = self.manifest.end();
+        if spec.payload_size > 0 {
+            let placement = pure::plan_alignment(piece_lengths, spec.piece_size)?;
+            self.target
+                .preallocate(u64::from(placement.left) + u64::from(placement.size))
+                .context("preallocate piece")?;
+        }
+        let res = write(&mut self.target).and_then(|res| {
+            self.target.sync().context("sync staged file")?;
+            Ok(res)
+        });
+        let (piece_info, written) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                let end = self.data_offset() + start;
+                let mut file = self.file();
+                if let Err(te) = file
+                    .set_len(end)
+                    .and_then(|_| file.seek(SeekFrom::Start(end)))
+                {
+                    warn!(err = ?te, "failed to truncate the partial piece");
+                }
+                if let Some(journal) = &self.journal {
+                    journal.truncate(self.manifest.pieces.len())?;
+                }
+                return Err(e);
+            }
+        };
+
+        // valid pieces never have right alignment, whatever was written on
+        // top of the piece data is left alignment
+        let written: u64 = PaddedBytesAmount::from(written).into();
+        let padded_size: u64 = PaddedBytesAmount::from(piece_info.size).into();
+        let piece = ManifestPiece {
+            source: spec.source.clone(),
+            payload_size: spec.payload_size,
+            piece_info: piece_info.clone(),
+            offset: start + written - padded_size,
+            len: padded_size,
+            chunk_roots: chunk_roots.map(ChunkRoots::take).unwrap_or_default(),
+            encryption: encryption.and_then(PayloadEncryption::take),
+            excessive_alignment: excessive_alignment(written - padded_size, padded_size),
+            payload_blake3: payload_hash.and_then(PayloadHash::take),
+            payload_sha256: spec.payload_sha256.clone(),
+        };
+        // the manifest never gets ahead of the journal
+        if let Some(journal) = &self.journal {
+            journal.finish_piece(self.manifest.pieces.len(), &piece)?;
+        }
+        self.manifest.pieces.push(piece);
+        self.save_manifest()?;
+
+        Ok(piece_info)
+    }
+
+    /// Drops any data left over from a previous run after the reused pieces,
+    /// completes the v2 header with the piece table, and syncs the staged
+    /// file before its final manifest is saved.
+    pub fn finish(mut self) -> Result<Manifest> {
+        self.stop_reusing()?;
+        if let Some(provenance) = &mut self.manifest.provenance {
+            provenance.finish();
+        }
+        attestation::sign(&mut self.manifest)?;
+
+        let data_offset = self.data_offset();
+        let end = self.manifest.end();
+        if let Some(header) = &mut self.header {
+            let table = serde_json::to_vec(&self.manifest).context("serialize piece table")?;
+            header.data_len = end;
+            header.piece_table_offset = data_offset + end;
+            header.piece_table_len = table.len() as u64;
+            self.target
+                .write_at(&table, data_offset + end)
+                .and_then(|_| self.file().set_len(data_offset + end + table.len() as u64))
+                .context("write piece table")?;
+            self.write_header()?;
+        }
+        let content = serde_json::to_vec_pretty(&self.manifest).context("serialize manifest")?;
+        Box::new(self.target).finalize(&content)?;
+        Ok(self.manifest)
+    }
+
+    fn stop_reusing(&mut self) -> Result<()> {
+        if !self.reusing {
+            return Ok(());
+        }
+
+        let dropped = self
+            .previous
+            .len()
+            .saturating_sub(self.manifest.pieces.len());
+        if dropped > 0 && self.mode == StagedOpenMode::ResumeExisting {
+            bail!(
+                "{} pieces recorded in {} don't match the task, not truncating it",
+                dropped,
+                self.target.path().display()
+            );
+        }
+        self.reusing = false;
+        self.previous.clear();
+
+        let end = self.data_offset() + self.manifest.end();
+        let mut file = self.file();
+        file.set_len(end).context("truncate staged file")?;
+        file.seek(SeekFrom::Start(end))
+            .context("seek staged file")?;
+        if let Some(journal) = &self.journal {
+            journal.truncate(self.manifest.pieces.len())?;
+        }
+        self.save_manifest()
+    }
+
+    fn save_manifest(&self) -> Result<()> {
+        retry_stale(stale_retries(), "save manifest", || {
+            self.manifest.save(self.target.path())
+        })
+    }
+}
+
+/// `alignment`, to record in the manifest entry of a piece of `padded_size`
+/// if it is more than the configured padding warning allows. Such pieces are
+/// counted in the status.
+pub fn excessive_alignment(alignment: u64, padded_size: u64) -> Option<u64> {
+    let warning = config::global().padding_warning?;
+    if !warning.exceeded(alignment, padded_size) {
+        return None;
+    }
+    status::record_excessive_padding();
+    Some(alignment)
+}
+
+fn stale_retries() -> u32 {
+    config::global().target_profile.stale_retries()
+}
+
+/// Loads the manifest of `staged`, the piece table of a v2 staged file stands
+/// in for a missing one.
+pub fn load_manifest(staged: &Path) -> Result<Option<Manifest>> {
+    if let Some(manifest) = Manifest::load(staged)? {
+        return Ok(Some(manifest));
+    }
+    let file = fs::File::open(staged)?;
+    match Header::read_from(&file)? {
+        Some(header) => header.read_piece_table(&file),
+        None => Ok(None),
+    }
+}
+
+/// Zeroes the next `padded_size` bytes of the staged file, as the configured
+/// zero fill and target profile allow, and returns the zero piece they hold.
+/// Fills cc sectors and completes sectors.
+pub fn write_zero_piece(target: &mut LocalFile, padded_size: u64) -> Result<PieceInfo> {
+    let mode = target
+        .write_zeros(padded_size)
+        .context("write zero piece")?;
+    debug!(?mode, padded_size, "zero piece written");
+    let piece_size = PaddedBytesAmount(padded_size).into();
+    filecoin_proofs::pieces::zero_padding(piece_size).context("zero piece commitment")
+}
+
+/// Completes the staged file `path` up to the end of its sector of
+/// `sector_size` padded bytes with zero pieces, and returns them. Only the
+/// missing zeros are written, as the configured zero fill allows, and the
+/// pieces already staged aren't read.
+pub fn fill_sector(path: &Path, sector_size: u64) -> Result<Vec<PieceInfo>> {
+    let mut staged = StagedFile::append_to(path)?;
+    let fillers = precommit::filler_pieces(
+        PaddedBytesAmount(staged.manifest.end()),
+        PaddedBytesAmount(sector_size),
+    )?;
+
+    for filler in &fillers {
+        let spec = PieceSpec {
+            source: "filler".to_string(),
+            payload_size: 0,
+            piece_size: filler.size,
+            payload_sha256: None,
+        };
+        let padded: u64 = PaddedBytesAmount::from(filler.size).into();
+        staged.add(&spec, &[], None, None, None, |target| {
+            write_zero_piece(target, padded)?;
+            Ok((filler.clone(), filler.size))
+        })?;
+    }
+    staged.finish()?;
+    Ok(fillers)
+}
+
+/// Runs `write` against `target`, through a write-behind queue if configured.
+/// `target` is synced as `staged_sync` asks, by the thread writing it.
+pub fn with_write_behind<W, T>(
+    target: W,
+    config: Option<WriteBehindConfig>,
+    write: impl FnOnce(&mut dyn Write) -> Result<T>,
+) -> Result<T>
+where
+    W: Write + Send,
+{
+    let target = PeriodicSync::new(target, config::global().staged_sync);
+    let config = match config {
+        Some(c) => c,
+        None => {
+            let mut target = target;
+            return write(&mut target);
+        }
+    };
+
+    thread::scope(|s| {
+        let mut writer = WriteBehind::scoped(s, target, config);
+        let res = write(&mut writer);
+        let finished = writer.finish();
+        let out = res?;
+        finished.context("write staged file")?;
+        Ok(out)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use fr32::Fr32Reader;
+    use vc_processors::fil_proofs::RegisteredSealProof;
+
+    /// Writes `left` padded zero bytes then the padded `payload`, as
+    /// `add_piece` does for a left aligned piece.
+    fn write_aligned(
+        file: &mut impl Write,
+        payload: &[u8],
+        left: usize,
+    ) -> Result<(PieceInfo, UnpaddedBytesAmount)> {
+        let mut padded = vec![0u8; left];
+        Fr32Reader::new(payload).read_to_end(&mut padded)?;
+        file.write_all(&padded)?;
+        let piece_size = UnpaddedBytesAmount(payload.len() as u64);
+        let written = PaddedBytesAmount(padded.len() as u64).into();
+        Ok((PieceInfo::new([7u8; 32], piece_size)?, written))
+    }
+
+    #[test]
+    fn test_add_records_piece_after_alignment() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("staged");
+        let first = vec![1u8; 127];
+        let second = vec![2u8; 254];
+
+        let mut staged = StagedFile::open(
+            &path,
+            "",
+            Provenance::new(Default::default(), None),
+            StagedOpenMode::TruncateExisting,
+        )
+        .expect("open failed");
+        for (payload, left) in [(&first, 0), (&second, 128)] {
+            let spec = PieceSpec {
+                source: format!("piece-{}", payload[0]),
+                payload_size: payload.len() as u64,
+                piece_size: UnpaddedBytesAmount(payload.len() as u64),
+                payload_sha256: None,
+            };
+            staged
+                .add(&spec, &[], None, None, None, |file| {
+                    write_aligned(file, payload, left)
+                })
+                .expect("add failed");
+        }
+        let manifest = staged.finish().expect("finish failed");
+
+        // the second piece is left aligned to 256 padded bytes
+        let piece = &manifest.pieces[1];
+        assert_eq!((piece.offset, piece.len), (256, 256));
+        let file = fs::File::open(&path).expect("open failed");
+        assert!(spot_check(&second[..], &file, piece).expect("spot check failed"));
+    }
+
+    #[test]
+    fn test_add_unaligned_misordered_pieces() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("staged");
+        let payloads = [vec![1u8; 1016], vec![2u8; 2032], vec![3u8; 1016]];
+
+        // as the processor stages them, back to back without alignment
+        let mut staged = StagedFile::open(
+            &path,
+            "",
+            Provenance::new(Default::default(), None),
+            StagedOpenMode::TruncateExisting,
+        )
+        .expect("open failed");
+        for payload in &payloads {
+            let spec = PieceSpec {
+                source: format!("piece-{}", payload[0]),
+                payload_size: payload.len() as u64,
+                piece_size: UnpaddedBytesAmount(payload.len() as u64),
+                payload_sha256: None,
+            };
+            assert_eq!(
+                staged.piece_offset(&[], spec.piece_size).unwrap(),
+                staged.manifest.end()
+            );
+            staged
+                .add(&spec, &[], None, None, None, |target| {
+                    add_piece::write_and_preprocess_with_options(
+                        RegisteredSealProof::StackedDrg8MiBV1_1,
+                        &payload[..],
+                        target,
+                        spec.piece_size,
+                        &Default::default(),
+                    )
+                })
+                .expect("add failed");
+        }
+        let manifest = staged.finish().expect("finish failed");
+
+        let offsets: Vec<_> = manifest.pieces.iter().map(|p| (p.offset, p.len)).collect();
+        assert_eq!(offsets, [(0, 1024), (1024, 2048), (3072, 1024)]);
+        let file = fs::File::open(&path).expect("open failed");
+        for (payload, piece) in payloads.iter().zip(&manifest.pieces) {
+            assert!(spot_check(&payload[..], &file, piece).expect("spot check failed"));
+        }
+    }
+
+    #[test]
+    fn test_open_modes() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("staged");
+        let open = |mode| {
+            let provenance = Provenance::new(Default::default(), None);
+            StagedFile::open(&path, "", provenance, mode)
+        };
+        let add = |staged: &mut StagedFile, byte: u8| {
+            let payload = vec![byte; 127];
+            let spec = PieceSpec {
+                source: format!("piece-{}", byte),
+                payload_size: 127,
+                piece_size: UnpaddedBytesAmount(127),
+                payload_sha256: None,
+            };
+            staged.add(&spec, &[], None, None, None, |file| {
+                write_aligned(file, &payload, 0)
+            })
+        };
+
+        let mut staged = open(StagedOpenMode::FailIfExists).expect("open failed");
+        add(&mut staged, 1).expect("add failed");
+        staged.finish().expect("finish failed");
+
+        // whatever the manifest records
+        let err = open(StagedOpenMode::FailIfExists).err().expect("opened");
+        assert!(err.to_string().contains("already exists"), "{:?}", err);
+        let manifest = Manifest::path_for(&path);
+        fs::rename(&manifest, dir.path().join("manifest")).unwrap();
+        assert!(open(StagedOpenMode::FailIfExists).is_err());
+        fs::remove_file(&path).unwrap();
+        fs::rename(dir.path().join("manifest"), &manifest).unwrap();
+        assert!(open(StagedOpenMode::FailIfExists).is_err());
+        fs::remove_file(&manifest).unwrap();
+
+        let mut staged = open(StagedOpenMode::FailIfExists).expect("open failed");
+        add(&mut staged, 1).expect("add failed");
+        staged.finish().expect("finish failed");
+
+        // the recorded piece is reused, a different one isn't discarded
+        let mut staged = open(StagedOpenMode::ResumeExisting).expect("open failed");
+        let err = add(&mut staged, 2).expect_err("discarded the piece");
+        assert!(err.to_string().contains("not truncating"), "{:?}", err);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 128);
+    }
+}
@@ -0,0 +1,312 @@
+//! What the tasks in flight are doing, shown by the progress bars of the
+//! standalone commands and the terminal UI of the processor.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use add_piece::{
+    hash_queue::{self, HashQueueStats},
+    piece_events::{PieceEventSink, PieceRef},
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{redact, target_health};
+
+/// Failures kept for display.
+const RECENT_ERRORS: usize = 20;
+/// Span of the throughput the estimated times remaining are derived from.
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The tasks in flight in the process and what they are reading, shown by
+/// the terminal UI.
+#[derive(Default)]
+struct Status {
+    next_id: u64,
+    tasks: BTreeMap<u64, TaskState>,
+    errors: VecDeque<RecentError>,
+}
+
+struct TaskState {
+    staged: PathBuf,
+    started: Instant,
+    /// Unpadded size of every piece.
+    piece_sizes: Vec<u64>,
+    /// Pieces before the current one.
+    done: usize,
+    piece: Option<PieceState>,
+    /// Bytes read for the pieces before the current one.
+    read_before: u64,
+    /// Bytes read by the task over the last `RATE_WINDOW`, oldest first.
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl TaskState {
+    fn read(&self) -> u64 {
+        self.read_before
+            + self
+                .piece
+                .as_ref()
+                .map_or(0, |p| p.read.load(Ordering::Relaxed))
+    }
+
+    /// Samples the bytes read and returns the throughput over the window, if
+    /// it spans long enough.
+    fn sample(&mut self, now: Instant) -> Option<f64> {
+        let read = self.read();
+        let last = self.samples.back().map(|(at, _)| *at);
+        if last.is_none_or(|at| now - at >= SAMPLE_INTERVAL) {
+            self.samples.push_back((now, read));
+        }
+        while self.samples.len() > 2 && now - self.samples[1].0 >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+        let (since, from) = *self.samples.front()?;
+        let elapsed = (now - since).as_secs_f64();
+        (elapsed >= SAMPLE_INTERVAL.as_secs_f64()).then(|| (read - from) as f64 / elapsed)
+    }
+}
+
+struct PieceState {
+    index: usize,
+    source: String,
+    size: u64,
+    read: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub struct RecentError {
+    pub at: SystemTime,
+    pub staged: PathBuf,
+    pub error: String,
+}
+
+static STATUS: Mutex<Option<Status>> = Mutex::new(None);
+/// Source bytes read by every task since the start of the process.
+static TOTAL_READ: AtomicU64 = AtomicU64::new(0);
+/// Pieces staged with more alignment than the padding warning allows.
+static EXCESSIVE_PADDING: AtomicU64 = AtomicU64::new(0);
+
+fn with_status<T>(f: impl FnOnce(&mut Status) -> T) -> T {
+    let mut status = STATUS.lock().expect("status poisoned");
+    f(status.get_or_insert_with(Status::default))
+}
+
+/// A task in flight, which is forgotten once dropped. As a piece event
+/// sink, it shows the progress of the pieces of the task.
+#[derive(Debug)]
+pub struct TaskGuard {
+    id: u64,
+    piece: Mutex<Option<Progress>>,
+}
+
+/// Registers a task adding pieces of `piece_sizes` unpadded bytes to
+/// `staged`.
+pub fn start_task(staged: &Path, piece_sizes: Vec<u64>) -> TaskGuard {
+    with_status(|status| {
+        let id = status.next_id;
+        status.next_id += 1;
+        info!(task_id = id, staged = %redact::path(staged).display(), "task started");
+        status.tasks.insert(
+            id,
+            TaskState {
+                staged: staged.to_path_buf(),
+                started: Instant::now(),
+                piece_sizes,
+                done: 0,
+                piece: None,
+                read_before: 0,
+                samples: VecDeque::new(),
+            },
+        );
+        TaskGuard {
+            id,
+            piece: Mutex::new(None),
+        }
+    })
+}
+
+impl TaskGuard {
+    /// Starts the piece `index` of the task, of `size` unpadded bytes read
+    /// from `source`. Its progress is the bytes read through the returned
+    /// `Progress`.
+    pub fn start_piece(&self, index: usize, source: &str, size: u64) -> Progress {
+        let read = Arc::new(AtomicU64::new(0));
+        with_status(|status| {
+            if let Some(task) = status.tasks.get_mut(&self.id) {
+                // the earlier pieces were added or reused
+                task.done = index;
+                if let Some(previous) = task.piece.take() {
+                    task.read_before += previous.read.load(Ordering::Relaxed);
+                }
+                task.piece = Some(PieceState {
+                    index,
+                    source: source.to_string(),
+                    size,
+                    read: read.clone(),
+                });
+            }
+        });
+        Progress(read)
+    }
+}
+
+impl PieceEventSink for TaskGuard {
+    fn started(&self, piece: &PieceRef) {
+        let progress = self.start_piece(piece.index, &piece.source, piece.piece_size);
+        *self.piece.lock().expect("status poisoned") = Some(progress);
+    }
+
+    fn progressed(&self, _piece: &PieceRef, bytes: u64) {
+        if let Some(progress) = &*self.piece.lock().expect("status poisoned") {
+            progress.add(bytes);
+        }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        with_status(|status| status.tasks.remove(&self.id));
+    }
+}
+
+/// Counts a piece staged with more alignment than the padding warning
+/// allows.
+pub fn record_excessive_padding() {
+    EXCESSIVE_PADDING.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records the failure of the task staging `staged`.
+pub fn record_error(staged: &Path, error: &anyhow::Error) {
+    with_status(|status| {
+        if status.errors.len() == RECENT_ERRORS {
+            status.errors.pop_front();
+        }
+        status.errors.push_back(RecentError {
+            at: SystemTime::now(),
+            staged: redact::path(staged),
+            error: redact::redact(&format!("{:#}", error)).into_owned(),
+        });
+    })
+}
+
+/// Counts the bytes read from the source of a piece.
+#[derive(Debug, Clone)]
+pub struct Progress(Arc<AtomicU64>);
+
+impl Progress {
+    /// Records `n` more bytes read.
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+        TOTAL_READ.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// A task in flight as shown.
+#[derive(Debug, Clone)]
+pub struct TaskView {
+    pub id: u64,
+    pub staged: PathBuf,
+    pub started: Instant,
+    pub pieces: usize,
+    pub done: usize,
+    pub piece: Option<PieceView>,
+    /// Source bytes read per second by the task lately.
+    pub bytes_per_sec: Option<f64>,
+    /// Time left until the current piece, and the whole task, are read at
+    /// the current throughput.
+    pub piece_eta: Option<Duration>,
+    pub task_eta: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PieceView {
+    pub index: usize,
+    pub source: String,
+    pub size: u64,
+    pub read: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub tasks: Vec<TaskView>,
+    /// Most recent last.
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    pub errors: Vec<RecentError>,
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    pub total_read: u64,
+    /// Node hashing batches of the pieces hashed with the `parallel`
+    /// committer.
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    pub hash_queue: HashQueueStats,
+    /// Pieces staged since the start of the process with more alignment than
+    /// the padding warning allows.
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    pub excessive_padding: u64,
+    /// Staging directories rejecting tasks, see `target_health`.
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    pub unhealthy_targets: Vec<PathBuf>,
+}
+
+pub fn snapshot() -> Snapshot {
+    let now = Instant::now();
+    with_status(|status| Snapshot {
+        tasks: status
+            .tasks
+            .iter_mut()
+            .map(|(id, t)| {
+                let bytes_per_sec = t.sample(now);
+                let piece = t.piece.as_ref().map(|p| PieceView {
+                    index: p.index,
+                    source: redact::redact(&p.source).into_owned(),
+                    size: p.size,
+                    read: p.read.load(Ordering::Relaxed),
+                });
+                let piece_left = piece.as_ref().map_or(0, |p| p.size.saturating_sub(p.read));
+                let later: u64 = t.piece_sizes.iter().skip(t.done + 1).sum();
+                let eta = |left: u64| {
+                    let rate = bytes_per_sec.filter(|r| *r > 0.0)?;
+                    Some(Duration::from_secs_f64(left as f64 / rate))
+                };
+                TaskView {
+                    id: *id,
+                    staged: redact::path(&t.staged),
+                    started: t.started,
+                    pieces: t.piece_sizes.len(),
+                    done: t.done,
+                    bytes_per_sec,
+                    piece_eta: piece.as_ref().and_then(|_| eta(piece_left)),
+                    task_eta: eta(piece_left + later),
+                    piece,
+                }
+            })
+            .collect(),
+        errors: status.errors.iter().cloned().collect(),
+        total_read: TOTAL_READ.load(Ordering::Relaxed),
+        hash_queue: hash_queue::stats(),
+        excessive_padding: EXCESSIVE_PADDING.load(Ordering::Relaxed),
+        unhealthy_targets: target_health::unhealthy(),
+    })
+}
+
+/// Formats an estimated time remaining, `?` while it is unknown.
+pub fn format_eta(eta: Option<Duration>) -> String {
+    let secs = match eta {
+        Some(eta) => eta.as_secs(),
+        None => return "?".to_string(),
+    };
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
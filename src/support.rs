@@ -0,0 +1,124 @@
+//! What this build supports, as data, so that orchestrators can check tasks
+//! before dispatching them.
+
+use serde::Serialize;
+
+use crate::{seal_proof, sizes};
+
+#[derive(Debug, Serialize)]
+pub struct Support {
+    pub version: &'static str,
+    /// Seal proofs sectors can be staged for, current and legacy versions.
+    pub seal_proofs: Vec<SupportedProof>,
+    /// Sizes of the sectors which can be staged, the test sector sizes
+    /// included with the `test-sectors` feature.
+    pub sector_sizes: Vec<SupportedSector>,
+    /// Cargo features the crate was built with.
+    pub features: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SupportedProof {
+    /// Name of the proof, as accepted by `seal_proof::parse`.
+    pub name: String,
+    /// `v1` or `v1_1`.
+    pub version: &'static str,
+    pub sector_size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SupportedSector {
+    pub sector_size: u64,
+    /// Unpadded size of the smallest piece.
+    pub min_piece_size: u64,
+    /// Unpadded size of the largest piece, filling the sector.
+    pub max_piece_size: u64,
+}
+
+/// Optional features of the crate, and whether they were compiled in.
+pub fn features() -> [(&'static str, bool); 3] {
+    [
+        ("sha2-asm", cfg!(feature = "sha2-asm")),
+        ("test-sectors", cfg!(feature = "test-sectors")),
+        ("tui", cfg!(feature = "tui")),
+    ]
+}
+
+pub fn supported() -> Support {
+    let mut seal_proofs = Vec::new();
+    for sector_size in seal_proof::SECTOR_SIZES {
+        let proof =
+            seal_proof::for_sector_size(sector_size).expect("every sector size has a seal proof");
+        for (proof, version) in [(seal_proof::legacy(proof), "v1"), (proof, "v1_1")] {
+            seal_proofs.push(SupportedProof {
+                name: seal_proof::name(proof),
+                version,
+                sector_size,
+            });
+        }
+    }
+
+    let test_sizes = match cfg!(feature = "test-sectors") {
+        true => &seal_proof::TEST_SECTOR_SIZES[..],
+        false => &[],
+    };
+    let mut sector_sizes: Vec<_> = seal_proof::SECTOR_SIZES
+        .iter()
+        .chain(test_sizes)
+        .map(|&sector_size| SupportedSector {
+            sector_size,
+            min_piece_size: sizes::UNPADDED_BLOCK,
+            max_piece_size: sector_size / sizes::PADDED_BLOCK * sizes::UNPADDED_BLOCK,
+        })
+        .collect();
+    sector_sizes.sort_by_key(|s| s.sector_size);
+
+    Support {
+        version: env!("CARGO_PKG_VERSION"),
+        seal_proofs,
+        sector_sizes,
+        features: features()
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vc_processors::fil_proofs::RegisteredSealProof;
+
+    #[test]
+    fn test_supported() {
+        let support = supported();
+        assert_eq!(
+            support.seal_proofs.len(),
+            seal_proof::SECTOR_SIZES.len() * 2
+        );
+        for proof in &support.seal_proofs {
+            let parsed = seal_proof::parse(&proof.name).expect("name should parse");
+            assert_eq!(u64::from(parsed.sector_size()), proof.sector_size);
+        }
+
+        let sector = support
+            .sector_sizes
+            .iter()
+            .find(|s| s.sector_size == 32 << 30)
+            .expect("32GiB sectors are supported");
+        assert_eq!(sector.min_piece_size, 127);
+        assert_eq!(
+            sector.max_piece_size,
+            sizes::sector_capacity(RegisteredSealProof::StackedDrg32GiBV1_1).0
+        );
+        assert_eq!(
+            support
+                .sector_sizes
+                .iter()
+                .any(|s| s.sector_size == 16 << 20),
+            cfg!(feature = "test-sectors")
+        );
+    }
+}
@@ -0,0 +1,372 @@
+//! Staging directories failing with errors which retrying won't fix: read
+//! only filesystems, full disks and exceeded quotas.
+//!
+//! The processor checks the filesystem of a task's staging directory before
+//! fetching anything, and once a task fails with one of these errors its
+//! directory is marked unhealthy, so that the next tasks staging there are
+//! rejected at once instead of each failing after long fetches.
+//!
+//! The configured staging directories are also probed when the processor
+//! starts and then periodically, by writing, syncing, reading back and
+//! removing a small file: tasks staging under a directory failing its probe
+//! are rejected until it passes again.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use add_piece::pure;
+use filecoin_proofs::UnpaddedBytesAmount;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{config, redact};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetHealthConfig {
+    /// How long a staging directory stays unhealthy after a task failed
+    /// there, the next task is then let through to try again.
+    pub retry_after_secs: u64,
+    /// Staging directories probed when the processor starts, and reported
+    /// by `--spec`.
+    pub probe_dirs: Vec<PathBuf>,
+    /// How often the directories are probed again, only on start if 0.
+    pub probe_interval_secs: u64,
+}
+
+impl Default for TargetHealthConfig {
+    fn default() -> Self {
+        Self {
+            retry_after_secs: 60,
+            probe_dirs: Vec::new(),
+            probe_interval_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetFault {
+    ReadOnly,
+    NoSpace,
+    QuotaExceeded,
+    /// The probe file couldn't be written, synced or read back.
+    ProbeFailed,
+}
+
+impl TargetFault {
+    pub fn of(err: &io::Error) -> Option<Self> {
+        match err.kind() {
+            io::ErrorKind::ReadOnlyFilesystem => Some(TargetFault::ReadOnly),
+            io::ErrorKind::StorageFull => Some(TargetFault::NoSpace),
+            io::ErrorKind::QuotaExceeded => Some(TargetFault::QuotaExceeded),
+            _ => None,
+        }
+    }
+
+    /// The fault among the causes of `err`, if any.
+    pub fn find(err: &anyhow::Error) -> Option<Self> {
+        err.chain()
+            .filter_map(|e| e.downcast_ref::<io::Error>())
+            .find_map(TargetFault::of)
+    }
+}
+
+impl fmt::Display for TargetFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TargetFault::ReadOnly => "read only",
+            TargetFault::NoSpace => "out of space",
+            TargetFault::QuotaExceeded => "over quota",
+            TargetFault::ProbeFailed => "failing its probe",
+        })
+    }
+}
+
+/// A staging directory which can't take a task, as the cause of its failure.
+#[derive(Debug, Clone)]
+pub struct TargetError {
+    pub fault: TargetFault,
+    pub dir: PathBuf,
+    pub detail: Option<String>,
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "staging directory {} is {}",
+            redact::path(&self.dir).display(),
+            self.fault
+        )?;
+        match &self.detail {
+            Some(detail) => write!(f, ", {}", detail),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for TargetError {}
+
+/// Directory of the staged file `staged`.
+pub fn dir_of(staged: &Path) -> &Path {
+    match staged.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Bytes left to write to `staged` for pieces of `piece_sizes`, or a whole
+/// sector of `sector_size` padded bytes without pieces.
+pub fn bytes_needed(staged: &Path, piece_sizes: &[UnpaddedBytesAmount], sector_size: u64) -> u64 {
+    let end = match pure::plan_pieces(piece_sizes) {
+        Ok(placements) => placements
+            .last()
+            .map_or(sector_size, |p| u64::from(p.end())),
+        // rejected later
+        Err(_) => 0,
+    };
+    let staged = std::fs::metadata(staged).map_or(0, |m| m.len());
+    end.saturating_sub(staged)
+}
+
+static UNHEALTHY: Mutex<BTreeMap<PathBuf, (TargetFault, Instant)>> = Mutex::new(BTreeMap::new());
+/// The last probe of each configured staging directory.
+static PROBES: Mutex<BTreeMap<PathBuf, TargetStatus>> = Mutex::new(BTreeMap::new());
+
+/// Bytes of the probe file, a page.
+const PROBE_SIZE: usize = 4096;
+
+/// Result of the last probe of a staging directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetStatus {
+    pub dir: PathBuf,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fault: Option<TargetFault>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Time taken by the probe, a slow sync hints at a struggling target.
+    pub probe_secs: f64,
+}
+
+/// Fails if `dir` was marked unhealthy lately, or if its filesystem is read
+/// only or has less than `needed` bytes left.
+pub fn check(dir: &Path, needed: u64, config: &TargetHealthConfig) -> Result<(), TargetError> {
+    let retry_after = Duration::from_secs(config.retry_after_secs);
+    {
+        let mut unhealthy = UNHEALTHY.lock().expect("target health poisoned");
+        match unhealthy.get(dir) {
+            Some((fault, since)) if since.elapsed() < retry_after => {
+                return Err(TargetError {
+                    fault: *fault,
+                    dir: dir.to_path_buf(),
+                    detail: Some(format!("since {}s", since.elapsed().as_secs())),
+                });
+            }
+            Some(_) => {
+                unhealthy.remove(dir);
+            }
+            None => {}
+        }
+    }
+    let probed = PROBES
+        .lock()
+        .expect("target health poisoned")
+        .values()
+        .find(|s| !s.healthy && dir.starts_with(&s.dir))
+        .map(|s| TargetError {
+            fault: s.fault.unwrap_or(TargetFault::ProbeFailed),
+            dir: s.dir.clone(),
+            detail: s.error.clone(),
+        });
+    if let Some(err) = probed {
+        return Err(err);
+    }
+
+    let space = match filesystem_space(dir) {
+        Some(space) => space,
+        None => return Ok(()),
+    };
+    let error = |fault, detail| TargetError {
+        fault,
+        dir: dir.to_path_buf(),
+        detail,
+    };
+    if space.read_only {
+        return Err(error(TargetFault::ReadOnly, None));
+    }
+    if space.available < needed {
+        return Err(error(
+            TargetFault::NoSpace,
+            Some(format!(
+                "{} bytes needed, {} available",
+                needed, space.available
+            )),
+        ));
+    }
+    Ok(())
+}
+
+/// Marks `dir` unhealthy if a task staging there failed with `err` because of
+/// its filesystem, which then becomes the cause of `err`.
+pub fn record_failure(dir: &Path, err: anyhow::Error) -> anyhow::Error {
+    if err.chain().any(|e| e.is::<TargetError>()) {
+        return err;
+    }
+    let fault = match TargetFault::find(&err) {
+        Some(fault) => fault,
+        None => return err,
+    };
+    warn!(dir = %redact::path(dir).display(), %fault, "staging directory marked unhealthy");
+    UNHEALTHY
+        .lock()
+        .expect("target health poisoned")
+        .insert(dir.to_path_buf(), (fault, Instant::now()));
+    err.context(TargetError {
+        fault,
+        dir: dir.to_path_buf(),
+        detail: None,
+    })
+}
+
+/// Writes, syncs, reads back and removes a probe file in `dir`.
+fn write_probe(dir: &Path) -> io::Result<()> {
+    let path = dir.join(format!(".add_piece-probe-{}", std::process::id()));
+    let content: Vec<u8> = (0..PROBE_SIZE).map(|i| i as u8).collect();
+    let res = (|| {
+        let mut file = fs::File::create(&path)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        if fs::read(&path)? != content {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "probe file read back differs",
+            ));
+        }
+        Ok(())
+    })();
+    let removed = fs::remove_file(&path);
+    res.and(removed)
+}
+
+fn probe(dir: &Path) -> TargetStatus {
+    let started = Instant::now();
+    let res = write_probe(dir);
+    TargetStatus {
+        dir: dir.to_path_buf(),
+        healthy: res.is_ok(),
+        fault: res
+            .as_ref()
+            .err()
+            .map(|e| TargetFault::of(e).unwrap_or(TargetFault::ProbeFailed)),
+        error: res.err().map(|e| e.to_string()),
+        probe_secs: started.elapsed().as_secs_f64(),
+    }
+}
+
+/// Probes every configured staging directory, tasks are rejected under the
+/// unhealthy ones until they pass a later probe.
+pub fn probe_all(config: &TargetHealthConfig) -> Vec<TargetStatus> {
+    let statuses: Vec<_> = config.probe_dirs.iter().map(|dir| probe(dir)).collect();
+    let mut probes = PROBES.lock().expect("target health poisoned");
+    for status in &statuses {
+        let dir = redact::path(&status.dir);
+        let was_healthy = probes.get(&status.dir).map(|s| s.healthy);
+        match (&status.error, was_healthy) {
+            (Some(error), Some(true) | None) => {
+                warn!(dir = %dir.display(), %error, "staging directory failed its probe")
+            }
+            (None, Some(false)) => info!(dir = %dir.display(), "staging directory healthy again"),
+            _ => {}
+        }
+        probes.insert(status.dir.clone(), status.clone());
+    }
+    statuses
+}
+
+/// Probes the configured staging directories now, then every
+/// `probe_interval_secs` on a background thread.
+pub fn start_probes(config: &TargetHealthConfig) -> io::Result<()> {
+    if config.probe_dirs.is_empty() {
+        return Ok(());
+    }
+    probe_all(config);
+    if config.probe_interval_secs == 0 {
+        return Ok(());
+    }
+    let config = config.clone();
+    thread::Builder::new()
+        .name("target-probe".to_string())
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(config.probe_interval_secs));
+            probe_all(&config);
+        })?;
+    Ok(())
+}
+
+/// The results of the last probes.
+pub fn statuses() -> Vec<TargetStatus> {
+    PROBES
+        .lock()
+        .expect("target health poisoned")
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// Staging directories rejecting tasks: failing their probe, or where a task
+/// failed lately.
+pub fn unhealthy() -> Vec<PathBuf> {
+    let retry_after = Duration::from_secs(config::global().target_health.retry_after_secs);
+    let mut dirs: Vec<_> = PROBES
+        .lock()
+        .expect("target health poisoned")
+        .values()
+        .filter(|s| !s.healthy)
+        .map(|s| s.dir.clone())
+        .collect();
+    dirs.extend(
+        UNHEALTHY
+            .lock()
+            .expect("target health poisoned")
+            .iter()
+            .filter(|(_, (_, since))| since.elapsed() < retry_after)
+            .map(|(dir, _)| dir.clone()),
+    );
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+struct FilesystemSpace {
+    available: u64,
+    read_only: bool,
+}
+
+#[cfg(target_os = "linux")]
+fn filesystem_space(dir: &Path) -> Option<FilesystemSpace> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(FilesystemSpace {
+        available: stat.f_bavail as u64 * stat.f_frsize as u64,
+        read_only: stat.f_flag & libc::ST_RDONLY != 0,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn filesystem_space(_dir: &Path) -> Option<FilesystemSpace> {
+    None
+}
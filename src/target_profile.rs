@@ -0,0 +1,97 @@
+use std::{io, thread, time::Duration};
+
+use add_piece::aligned_writer::AlignedWriterConfig;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::Config;
+
+/// Tuning of the writes to the staged files for the filesystem holding them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetProfile {
+    #[default]
+    Default,
+    /// Staged files on NFS: 1MiB writes synced every 256MiB, zeros written
+    /// out instead of left as holes, and operations failing with ESTALE
+    /// retried.
+    Nfs,
+}
+
+impl TargetProfile {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("unknown target profile: {}", s))
+    }
+
+    /// Fills in the settings of `config` the profile cares about, keeping
+    /// those set explicitly.
+    pub fn apply(self, config: &mut Config) {
+        if self == TargetProfile::Nfs {
+            config.target_writes.get_or_insert(AlignedWriterConfig {
+                write_size: 1 << 20,
+                flush_interval: Some(256 << 20),
+            });
+        }
+    }
+
+    /// Whether zero filled regions may be left as holes, which some NFS
+    /// servers mishandle.
+    pub fn sparse_files(self) -> bool {
+        self != TargetProfile::Nfs
+    }
+
+    /// Whether the blocks of pieces are allocated before they are written,
+    /// which NFS rarely supports.
+    pub fn preallocate(self) -> bool {
+        self != TargetProfile::Nfs
+    }
+
+    /// How many times an operation failing with ESTALE is tried again.
+    pub fn stale_retries(self) -> u32 {
+        match self {
+            TargetProfile::Default => 0,
+            TargetProfile::Nfs => 5,
+        }
+    }
+}
+
+/// Errors which may report a stale NFS file handle.
+pub trait MaybeStale {
+    fn is_stale(&self) -> bool;
+}
+
+impl MaybeStale for io::Error {
+    fn is_stale(&self) -> bool {
+        self.kind() == io::ErrorKind::StaleNetworkFileHandle
+    }
+}
+
+impl MaybeStale for anyhow::Error {
+    fn is_stale(&self) -> bool {
+        self.chain()
+            .filter_map(|e| e.downcast_ref::<io::Error>())
+            .any(MaybeStale::is_stale)
+    }
+}
+
+/// Runs `op` again, up to `retries` times, while it fails with ESTALE: NFS
+/// clients report it when the server no longer knows a file handle, which is
+/// often transient while the server fails over.
+pub fn retry_stale<T, E: MaybeStale>(
+    retries: u32,
+    what: &str,
+    mut op: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if e.is_stale() && attempt < retries => {
+                attempt += 1;
+                warn!(attempt, "{}: stale file handle, retrying", what);
+                thread::sleep(Duration::from_millis(200 << attempt.min(5)));
+            }
+            res => return res,
+        }
+    }
+}
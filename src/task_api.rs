@@ -0,0 +1,157 @@
+//! The tasks in flight in a processor, served as JSON next to its metrics on
+//! `GET /tasks` and `GET /tasks/<id>`, and the `status` and `watch` commands
+//! following them from another terminal.
+
+use std::{
+    io::{self, IsTerminal},
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    metrics::MetricsConfig,
+    status::{self, PieceView, TaskView},
+};
+
+/// A task in flight as served.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskReport {
+    pub id: u64,
+    pub staged: PathBuf,
+    pub elapsed_secs: u64,
+    pub pieces: usize,
+    /// Pieces before the current one.
+    pub done: usize,
+    pub piece: Option<PieceView>,
+    pub bytes_per_sec: Option<f64>,
+    pub piece_eta_secs: Option<u64>,
+    pub task_eta_secs: Option<u64>,
+}
+
+impl TaskReport {
+    fn new(task: TaskView) -> Self {
+        Self {
+            id: task.id,
+            staged: task.staged,
+            elapsed_secs: task.started.elapsed().as_secs(),
+            pieces: task.pieces,
+            done: task.done,
+            piece: task.piece,
+            bytes_per_sec: task.bytes_per_sec,
+            piece_eta_secs: task.piece_eta.map(|d| d.as_secs()),
+            task_eta_secs: task.task_eta.map(|d| d.as_secs()),
+        }
+    }
+}
+
+/// Responds to `path` if it is one of the task endpoints: the status code
+/// and the JSON body.
+pub fn respond(path: &str) -> Option<(&'static str, String)> {
+    let mut tasks = status::snapshot().tasks.into_iter().map(TaskReport::new);
+    let body = match path.strip_prefix("/tasks")? {
+        "" | "/" => serde_json::to_string(&tasks.collect::<Vec<_>>()),
+        id => {
+            let id = id.strip_prefix('/')?;
+            match tasks.find(|t| id.parse() == Ok(t.id)) {
+                Some(task) => serde_json::to_string(&task),
+                None => return Some(("404 Not Found", "null".to_string())),
+            }
+        }
+    };
+    Some(("200 OK", body.expect("serialize tasks")))
+}
+
+/// Address of the processor API, `--api` or where the configured metrics
+/// are served.
+pub fn api_addr(api: Option<&String>, metrics: Option<&MetricsConfig>) -> String {
+    match (api, metrics) {
+        (Some(api), _) => api.clone(),
+        (None, Some(metrics)) => metrics.listen.clone(),
+        (None, None) => MetricsConfig::default().listen,
+    }
+}
+
+/// The task `id` of the processor at `addr`, `None` once it is no longer
+/// running.
+pub fn fetch(addr: &str, id: u64) -> Result<Option<TaskReport>> {
+    match ureq::get(&format!("http://{}/tasks/{}", addr, id)).call() {
+        Ok(resp) => serde_json::from_reader(resp.into_reader())
+            .map(Some)
+            .context("parse task"),
+        Err(ureq::Error::Status(404, _)) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("query processor at {}", addr)),
+    }
+}
+
+/// Every task in flight in the processor at `addr`.
+pub fn fetch_all(addr: &str) -> Result<Vec<TaskReport>> {
+    let resp = ureq::get(&format!("http://{}/tasks", addr))
+        .call()
+        .with_context(|| format!("query processor at {}", addr))?;
+    serde_json::from_reader(resp.into_reader()).context("parse tasks")
+}
+
+/// Shows the progress of the task `id` every `interval` until it is no longer
+/// running: a bar when stdout is a terminal, a line otherwise.
+pub fn watch(addr: &str, id: u64, interval: Duration) -> Result<()> {
+    let mut task = fetch(addr, id)?.ok_or_else(|| anyhow!("no task {} running", id))?;
+    let bar = match io::stdout().is_terminal() {
+        true => ProgressBar::new(0).with_style(
+            ProgressStyle::with_template("{msg} [{bar:30}] {bytes}/{total_bytes}")
+                .expect("valid template")
+                .progress_chars("=> "),
+        ),
+        false => ProgressBar::hidden(),
+    };
+    loop {
+        let line = describe(&task);
+        match &task.piece {
+            Some(piece) => {
+                bar.set_length(piece.size);
+                bar.set_position(piece.read);
+            }
+            None => bar.set_position(0),
+        }
+        match bar.is_hidden() {
+            true => println!("{} {}", line, read(&task)),
+            false => bar.set_message(line),
+        }
+
+        thread::sleep(interval);
+        task = match fetch(addr, id)? {
+            Some(task) => task,
+            None => break,
+        };
+    }
+    bar.finish_and_clear();
+    println!("task {} is no longer running", id);
+    Ok(())
+}
+
+fn describe(task: &TaskReport) -> String {
+    let eta = |secs: Option<u64>| status::format_eta(secs.map(Duration::from_secs));
+    let rate = task.bytes_per_sec.map_or("?".to_string(), |r| {
+        indicatif::HumanBytes(r as u64).to_string()
+    });
+    format!(
+        "{} piece {}/{} {}/s eta {} (task {})",
+        task.staged.display(),
+        (task.done + 1).min(task.pieces),
+        task.pieces,
+        rate,
+        eta(task.piece_eta_secs),
+        eta(task.task_eta_secs)
+    )
+}
+
+fn read(task: &TaskReport) -> String {
+    match &task.piece {
+        Some(p) => format!("{}/{} bytes", p.read, p.size),
+        None => "starting".to_string(),
+    }
+}
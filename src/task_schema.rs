@@ -0,0 +1,211 @@
+//! JSON Schemas of the task formats, which inputs are validated against
+//! before being deserialized so that users get the path of every invalid
+//! value (`pieces[3].size: 1000 is not a multiple of 127`) instead of the
+//! first serde error.
+
+use std::fmt::Write as _;
+
+use anyhow::{anyhow, Context, Result};
+use jsonschema::{paths::PathChunk, JSONSchema};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskFormat {
+    /// The pieces of `add_pieces`, `pack` and `check-sources`.
+    Pieces,
+    /// The task of `add_pieces_multi`.
+    MultiSector,
+    /// The `AddPieces` tasks of the processor.
+    Processor,
+}
+
+impl TaskFormat {
+    pub const ALL: [TaskFormat; 3] = [
+        TaskFormat::Pieces,
+        TaskFormat::MultiSector,
+        TaskFormat::Processor,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskFormat::Pieces => "pieces",
+            TaskFormat::MultiSector => "multi_sector",
+            TaskFormat::Processor => "processor",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| format!("unknown task format: {}", s))
+    }
+
+    pub fn schema(self) -> Value {
+        let (title, mut schema) = match self {
+            TaskFormat::Pieces => ("add_pieces pieces", pieces()),
+            TaskFormat::MultiSector => (
+                "add_pieces_multi task",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "sector_size": {
+                            "description": "padded bytes",
+                            "type": "integer",
+                            "minimum": 128,
+                        },
+                        "staged_files": {
+                            "description": "one staged file per available sector",
+                            "type": "array",
+                            "items": { "type": "string" },
+                        },
+                        "pieces": pieces(),
+                        "policy": {
+                            "enum": ["sequential", "first_fit", "first_fit_decreasing"],
+                        },
+                        "staged_open": {
+                            "enum": [
+                                "truncate_existing",
+                                "create_new",
+                                "append_existing",
+                                "fail_if_exists",
+                                "resume_existing",
+                            ],
+                        },
+                    },
+                    "required": ["sector_size", "staged_files", "pieces"],
+                    "additionalProperties": false,
+                }),
+            ),
+            TaskFormat::Processor => (
+                "AddPieces processor task",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "seal_proof_type": {
+                            "description": "e.g. StackedDrg32GiBV1_1",
+                            "type": "string",
+                        },
+                        "pieces": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "piece_file": {
+                                        "oneOf": [
+                                            variant("Local"),
+                                            variant("Url"),
+                                            { "const": "Pledge" },
+                                        ],
+                                    },
+                                    "payload_size": {
+                                        "type": "integer",
+                                        "minimum": 0,
+                                    },
+                                    "piece_size": piece_size(),
+                                },
+                                "required": ["piece_file", "payload_size", "piece_size"],
+                                "additionalProperties": false,
+                            },
+                        },
+                        "staged_filepath": { "type": "string" },
+                    },
+                    "required": ["seal_proof_type", "pieces", "staged_filepath"],
+                    "additionalProperties": false,
+                }),
+            ),
+        };
+        let object = schema.as_object_mut().expect("schemas are objects");
+        object.insert(
+            "$schema".to_string(),
+            json!("http://json-schema.org/draft-07/schema#"),
+        );
+        object.insert("title".to_string(), json!(title));
+        schema
+    }
+}
+
+/// An unpadded piece size: 127 times a power of two.
+fn piece_size() -> Value {
+    json!({
+        "description": "unpadded bytes, 127 times a power of two",
+        "type": "integer",
+        "minimum": 127,
+        "multipleOf": 127,
+    })
+}
+
+fn pieces() -> Value {
+    json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "properties": {
+                "path": { "type": "string" },
+                "size": piece_size(),
+                "deal_start_epoch": { "type": "integer" },
+                "payload_size": { "type": "integer", "minimum": 0 },
+                "payload_cid": { "type": "string" },
+                "piece_cid": { "type": "string" },
+                "sha256": { "type": "string", "pattern": "^[0-9a-fA-F]{64}$" },
+            },
+            "anyOf": [
+                { "required": ["path", "size"] },
+                { "required": ["piece_cid"] },
+            ],
+            "additionalProperties": false,
+        },
+    })
+}
+
+/// A variant of an externally tagged enum holding a string.
+fn variant(name: &str) -> Value {
+    json!({
+        "type": "object",
+        "properties": { name: { "type": "string" } },
+        "required": [name],
+        "additionalProperties": false,
+    })
+}
+
+/// Checks `value` against the schema of `format`, listing every invalid
+/// value with its path.
+pub fn validate(format: TaskFormat, value: &Value) -> Result<()> {
+    let schema = JSONSchema::compile(&format.schema())
+        .map_err(|e| anyhow!("invalid {} schema: {}", format.name(), e))?;
+    let errors = match schema.validate(value) {
+        Ok(()) => return Ok(()),
+        Err(errors) => errors,
+    };
+
+    let mut msg = format!("invalid {}:", format.name());
+    for error in errors {
+        let mut path = String::new();
+        for chunk in error.instance_path.iter() {
+            match chunk {
+                PathChunk::Property(name) if path.is_empty() => path.push_str(name),
+                PathChunk::Property(name) => {
+                    let _ = write!(path, ".{}", name);
+                }
+                PathChunk::Index(index) => {
+                    let _ = write!(path, "[{}]", index);
+                }
+                PathChunk::Keyword(_) => {}
+            }
+        }
+        if path.is_empty() {
+            path.push_str("(root)");
+        }
+        let _ = write!(msg, "\n  {}: {}", path, error);
+    }
+    Err(anyhow!(msg))
+}
+
+/// Parses `json` as `format`, validated against its schema.
+pub fn parse<T: DeserializeOwned>(format: TaskFormat, json: &str) -> Result<T> {
+    let value: Value =
+        serde_json::from_str(json).with_context(|| format!("parse {}", format.name()))?;
+    validate(format, &value)?;
+    serde_json::from_value(value).with_context(|| format!("parse {}", format.name()))
+}
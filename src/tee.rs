@@ -0,0 +1,25 @@
+use std::io::{self, Read, Write};
+
+/// Copies everything read from `inner` into `copy`.
+pub struct TeeReader<R, W> {
+    inner: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> TeeReader<R, W> {
+    pub fn new(inner: R, copy: W) -> Self {
+        Self { inner, copy }
+    }
+
+    pub fn into_parts(self) -> (R, W) {
+        (self.inner, self.copy)
+    }
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
@@ -0,0 +1,98 @@
+//! Throughput of the add_piece pipeline on synthetic data, with the options
+//! of the config, so that deployments can catch the regressions a new
+//! dependency or config brings before it stages real sectors.
+
+use std::{
+    io::{self, Read},
+    time::Instant,
+};
+
+use add_piece::{add_piece_with_options, sizes};
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, UnpaddedBytesAmount};
+use serde::Serialize;
+use tracing::info;
+
+use crate::config;
+
+#[derive(Debug, Serialize)]
+pub struct ThroughputReport {
+    /// Unpadded size of the piece added every round.
+    pub piece_size: u64,
+    /// Payload bytes per second of each round.
+    pub rounds: Vec<u64>,
+    /// Payload bytes per second of the best round.
+    pub bytes_per_sec: u64,
+    pub throughput: String,
+    pub min_bytes_per_sec: Option<u64>,
+    pub passed: bool,
+}
+
+/// Adds a piece of `padded_size` bytes filled with generated payload
+/// `rounds` times, the padded bytes being thrown away, so that only the
+/// reading, fr32 padding and hashing are measured. The best round is checked
+/// against `min_bytes_per_sec`.
+pub fn measure(
+    padded_size: u64,
+    rounds: usize,
+    min_bytes_per_sec: Option<u64>,
+) -> Result<ThroughputReport> {
+    ensure!(
+        padded_size >= sizes::PADDED_BLOCK && padded_size.is_power_of_two(),
+        "invalid piece size: {}",
+        padded_size
+    );
+    ensure!(rounds > 0, "at least one round is needed");
+    let piece_size = UnpaddedBytesAmount::from(PaddedBytesAmount(padded_size));
+    let options = config::global().add_piece_options()?;
+
+    let mut rates = Vec::with_capacity(rounds);
+    for round in 0..rounds {
+        let payload = Synthetic::new(piece_size.0);
+        let started = Instant::now();
+        add_piece_with_options(payload, io::sink(), piece_size, &[], &options)
+            .context("add synthetic piece")?;
+        let secs = started.elapsed().as_secs_f64();
+        let rate = (piece_size.0 as f64 / secs.max(f64::EPSILON)) as u64;
+        info!(round, secs, throughput = %rate_string(rate), "round done");
+        rates.push(rate);
+    }
+
+    let best = rates.iter().copied().max().unwrap_or_default();
+    Ok(ThroughputReport {
+        piece_size: piece_size.0,
+        rounds: rates,
+        bytes_per_sec: best,
+        throughput: rate_string(best),
+        min_bytes_per_sec,
+        passed: min_bytes_per_sec.is_none_or(|min| best >= min),
+    })
+}
+
+pub fn rate_string(bytes_per_sec: u64) -> String {
+    format!("{}/s", sizes::human(bytes_per_sec))
+}
+
+/// `len` bytes of a fixed pattern, neither compressible zeros nor costly to
+/// generate.
+struct Synthetic {
+    pos: u64,
+    len: u64,
+}
+
+impl Synthetic {
+    fn new(len: u64) -> Self {
+        Self { pos: 0, len }
+    }
+}
+
+impl Read for Synthetic {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min((self.len - self.pos) as usize);
+        for (i, b) in buf[..n].iter_mut().enumerate() {
+            *b = ((self.pos + i as u64) * 31) as u8;
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
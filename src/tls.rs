@@ -0,0 +1,88 @@
+//! TLS of the HTTP listeners, with client certificates required when a
+//! client CA is given, so that they can be exposed beyond localhost.
+
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use rustls::{server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore};
+use rustls_pemfile::Item;
+
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    /// PEM certificate chain of the listener, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key of the listener, PKCS#8, RSA or SEC1.
+    pub key: PathBuf,
+    /// PEM certificates of the CAs the clients must present a certificate
+    /// from, any client is accepted if unset.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    /// The files of `--tls-cert`, `--tls-key` and `--tls-client-ca`, `None`
+    /// for plain HTTP.
+    pub fn from_matches(m: &ArgMatches) -> Option<Self> {
+        Some(Self {
+            cert: m.get_one::<PathBuf>("tls-cert")?.clone(),
+            key: m.get_one::<PathBuf>("tls-key")?.clone(),
+            client_ca: m.get_one::<PathBuf>("tls-client-ca").cloned(),
+        })
+    }
+
+    /// Loads the files, before privileges are dropped since keys are
+    /// usually only readable by root.
+    pub fn server_config(&self) -> Result<Arc<rustls::ServerConfig>> {
+        let certs = read_pem(&self.cert)?
+            .into_iter()
+            .filter_map(|item| match item {
+                Item::X509Certificate(der) => Some(Certificate(der)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            bail!("no certificate in {}", self.cert.display());
+        }
+        let key = read_pem(&self.key)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+                _ => None,
+            })
+            .with_context(|| format!("no private key in {}", self.key.display()))?;
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for item in read_pem(client_ca)? {
+                    if let Item::X509Certificate(der) = item {
+                        roots
+                            .add(&Certificate(der))
+                            .with_context(|| format!("client CA in {}", client_ca.display()))?;
+                    }
+                }
+                if roots.is_empty() {
+                    bail!("no certificate in {}", client_ca.display());
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs, key)
+            .context("certificate doesn't match the key")?;
+        Ok(Arc::new(config))
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let file = fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("read PEM {}", path.display()))
+}
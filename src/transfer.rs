@@ -0,0 +1,218 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+
+use add_piece::{
+    dedup::ChunkRoot,
+    manifest::{Manifest, ManifestPiece},
+    pure,
+    staged_format::Header,
+    CHUNK_SIZE,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde::Serialize;
+use tracing::{debug, info};
+
+#[derive(Debug, Default, Serialize)]
+pub struct TransferReport {
+    pub chunks: usize,
+    /// Chunks the destination already held, which were not sent again.
+    pub chunks_reused: usize,
+    pub bytes_sent: u64,
+}
+
+/// Copies the staged file `src` and its manifest to `dest`, usually a mount
+/// of the sealing host.
+///
+/// Like rsync, only the chunks the destination doesn't already hold are sent:
+/// both sides are compared against the chunk roots of the manifest, computed
+/// from `src` for pieces recorded without them. Every chunk sent is read back
+/// from `dest` and checked against its root, and the manifest is copied last,
+/// so that `dest` is only described by a manifest once it has been verified.
+pub fn transfer(src: &Path, dest: &Path) -> Result<TransferReport> {
+    let manifest =
+        Manifest::load(src)?.ok_or_else(|| anyhow!("no manifest found for {}", src.display()))?;
+    let src_file =
+        fs::File::open(src).with_context(|| format!("open staged file: {}", src.display()))?;
+    ensure!(
+        Header::read_from(&src_file)?.is_none(),
+        "only raw staged files can be transferred"
+    );
+
+    let dest_manifest = Manifest::path_for(dest);
+    match fs::remove_file(&dest_manifest) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("remove {}", dest_manifest.display()));
+        }
+    }
+    let dest_file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(false)
+        .open(dest)
+        .with_context(|| format!("open transfer target: {}", dest.display()))?;
+
+    let mut transfer = Transfer {
+        src: src_file,
+        dest: dest_file,
+        report: TransferReport::default(),
+        src_buf: Vec::new(),
+        dest_buf: Vec::new(),
+    };
+    let mut pos = 0;
+    for (index, piece) in manifest.pieces.iter().enumerate() {
+        transfer
+            .piece(piece, pos)
+            .with_context(|| format!("transfer piece {} ({})", index, piece.source))?;
+        pos = piece.end();
+    }
+
+    transfer
+        .dest
+        .set_len(pos)
+        .context("truncate transfer target")?;
+    transfer.dest.sync_all().context("sync transfer target")?;
+    manifest.save(dest)?;
+
+    let report = transfer.report;
+    info!(
+        src = %src.display(),
+        dest = %dest.display(),
+        chunks = report.chunks,
+        reused = report.chunks_reused,
+        "staged file transferred"
+    );
+    Ok(report)
+}
+
+struct Transfer {
+    src: fs::File,
+    dest: fs::File,
+    report: TransferReport,
+    src_buf: Vec<u8>,
+    dest_buf: Vec<u8>,
+}
+
+impl Transfer {
+    /// Transfers `piece` with the alignment bytes from `pos` up to its end.
+    fn piece(&mut self, piece: &ManifestPiece, pos: u64) -> Result<()> {
+        let ranges = piece.chunk_ranges();
+        let recorded = !piece.chunk_roots.is_empty();
+        if recorded {
+            ensure!(
+                piece.chunk_roots.len() == ranges.len(),
+                "{} chunk roots are recorded for {} chunks",
+                piece.chunk_roots.len(),
+                ranges.len()
+            );
+            ensure!(
+                piece.verify_chunk_roots()? == Some(true),
+                "the recorded chunk roots don't match the commitment"
+            );
+        }
+
+        self.zeros(pos..piece.offset)?;
+
+        let mut roots = Vec::with_capacity(ranges.len());
+        for (i, range) in ranges.into_iter().enumerate() {
+            let root = self
+                .chunk(range, piece.chunk_roots.get(i).copied())
+                .with_context(|| format!("chunk {}", i))?;
+            roots.push(root);
+        }
+        if !recorded {
+            ensure!(
+                pure::reduce_chunk_roots(&roots)? == piece.piece_info.commitment,
+                "the staged data doesn't match the commitment"
+            );
+        }
+
+        self.zeros(piece.offset + u64::from(piece.padded_size())..piece.end())
+    }
+
+    /// Sends the chunk at `range` unless the destination already holds it,
+    /// and returns its root.
+    fn chunk(&mut self, range: Range<u64>, expected: Option<ChunkRoot>) -> Result<ChunkRoot> {
+        self.report.chunks += 1;
+
+        let held = read_at(&self.dest, range.clone(), &mut self.dest_buf)?;
+        let held = match held {
+            true => Some(pure::commit(&self.dest_buf)?),
+            false => None,
+        };
+        if expected.is_some() && held == expected {
+            self.report.chunks_reused += 1;
+            return Ok(held.expect("checked above"));
+        }
+
+        ensure!(
+            read_at(&self.src, range.clone(), &mut self.src_buf)?,
+            "staged file ends before {}",
+            range.end
+        );
+        let root = pure::commit(&self.src_buf)?;
+        if let Some(expected) = expected {
+            ensure!(
+                root == expected,
+                "staged data at {:?} doesn't match its recorded root",
+                range
+            );
+        }
+        if held == Some(root) {
+            self.report.chunks_reused += 1;
+            return Ok(root);
+        }
+
+        write_at(&self.dest, range.start, &self.src_buf)?;
+        self.report.bytes_sent += self.src_buf.len() as u64;
+        debug!(?range, "chunk sent");
+
+        ensure!(
+            read_at(&self.dest, range.clone(), &mut self.dest_buf)?
+                && pure::commit(&self.dest_buf)? == root,
+            "transferred data at {:?} doesn't match its root",
+            range
+        );
+        Ok(root)
+    }
+
+    /// Makes sure the destination holds zeros in `range`.
+    fn zeros(&mut self, range: Range<u64>) -> Result<()> {
+        let mut start = range.start;
+        while start < range.end {
+            let end = range.end.min(start + CHUNK_SIZE as u64);
+            let held = read_at(&self.dest, start..end, &mut self.dest_buf)?;
+            if !held || self.dest_buf.iter().any(|b| *b != 0) {
+                self.dest_buf.fill(0);
+                write_at(&self.dest, start, &self.dest_buf)?;
+                self.report.bytes_sent += end - start;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+/// Reads `range` of `file` into `buf`, returns false if the file is shorter.
+pub fn read_at(mut file: &fs::File, range: Range<u64>, buf: &mut Vec<u8>) -> Result<bool> {
+    buf.resize((range.end - range.start) as usize, 0);
+    file.seek(SeekFrom::Start(range.start))?;
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).context("read staged file"),
+    }
+}
+
+fn write_at(mut file: &fs::File, offset: u64, buf: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+        .and_then(|_| file.sync_data())
+        .context("write transfer target")
+}
@@ -0,0 +1,159 @@
+//! `top`-like view of the processor on the controlling terminal, stdin and
+//! stdout being the channel to the process which runs it.
+
+use std::{
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, Event, KeyCode, KeyModifiers},
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Gauge, Paragraph},
+    Frame, Terminal,
+};
+use tracing::warn;
+
+use crate::status::{self, Snapshot};
+
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// Shows the processor on the terminal until `q` is pressed, the processor
+/// keeps running.
+pub fn spawn() -> Result<()> {
+    let tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("open the controlling terminal")?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(tty)).context("set up the terminal")?;
+    enable_raw_mode().context("enable raw mode")?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen).context("enter alternate screen")?;
+
+    thread::spawn(move || {
+        if let Err(e) = run(&mut terminal) {
+            warn!(err = ?e, "terminal UI failed");
+        }
+        let _ = disable_raw_mode();
+        let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = terminal.show_cursor();
+    });
+    Ok(())
+}
+
+fn run(terminal: &mut Terminal<CrosstermBackend<fs::File>>) -> Result<()> {
+    let mut last = (Instant::now(), status::snapshot().total_read);
+    let mut throughput = 0.0;
+    loop {
+        let snapshot = status::snapshot();
+        let elapsed = last.0.elapsed().as_secs_f64();
+        if elapsed >= REFRESH.as_secs_f64() {
+            throughput = (snapshot.total_read - last.1) as f64 / elapsed;
+            last = (Instant::now(), snapshot.total_read);
+        }
+        terminal.draw(|f| draw(f, &snapshot, throughput))?;
+
+        if event::poll(REFRESH)? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.code == KeyCode::Char('q') || ctrl_c {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn draw(f: &mut Frame, snapshot: &Snapshot, throughput: f64) {
+    let [header, tasks, errors] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(3),
+        Constraint::Length(8),
+    ])
+    .areas(f.area());
+
+    f.render_widget(
+        Paragraph::new(format!(
+            "add_piece processor: {} tasks in flight, reading {}/s, {} read, {} hash batches queued, {} overpadded pieces, {} unhealthy staging dirs, q to quit",
+            snapshot.tasks.len(),
+            bytes(throughput as u64),
+            bytes(snapshot.total_read),
+            snapshot.hash_queue.queued,
+            snapshot.excessive_padding,
+            snapshot.unhealthy_targets.len(),
+        )),
+        header,
+    );
+
+    let rows = Layout::vertical(snapshot.tasks.iter().map(|_| Constraint::Length(3))).split(tasks);
+    for (task, area) in snapshot.tasks.iter().zip(rows.iter()) {
+        let title = format!(
+            " {} ({}s, {}/{} pieces, {}/s, task eta {}) ",
+            task.staged.display(),
+            task.started.elapsed().as_secs(),
+            task.done,
+            task.pieces,
+            bytes(task.bytes_per_sec.unwrap_or_default() as u64),
+            status::format_eta(task.task_eta)
+        );
+        let (ratio, label) = match &task.piece {
+            Some(p) => (
+                (p.read as f64 / p.size.max(1) as f64).min(1.0),
+                format!(
+                    "piece {} {}: {} of {}, eta {}",
+                    p.index,
+                    p.source,
+                    bytes(p.read),
+                    bytes(p.size),
+                    status::format_eta(task.piece_eta)
+                ),
+            ),
+            None => (0.0, "starting".to_string()),
+        };
+        let gauge = Gauge::default()
+            .block(Block::bordered().title(title))
+            .gauge_style(Style::default().fg(Color::Green))
+            .ratio(ratio)
+            .label(label);
+        f.render_widget(gauge, *area);
+    }
+
+    let lines: Vec<Line> = snapshot
+        .errors
+        .iter()
+        .rev()
+        .map(|e| {
+            let at =
+                e.at.duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+            Line::from(format!("{} {}: {}", at, e.staged.display(), e.error))
+        })
+        .collect();
+    f.render_widget(
+        Paragraph::new(lines)
+            .style(Style::default().fg(Color::Red))
+            .block(Block::bordered().title(" recent errors ")),
+        errors,
+    );
+}
+
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
@@ -0,0 +1,131 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use fr32::write_unpadded;
+
+/// Unpadded bytes held by one fr32 block.
+const UNPADDED_BLOCK: u64 = 127;
+/// Padded size of one fr32 block.
+const PADDED_BLOCK: u64 = 128;
+
+/// Reads `len` payload bytes starting at the unpadded `offset` of the piece
+/// whose padded data begins at `piece_offset` in `staged`.
+///
+/// Only the fr32 blocks covering the requested range are read.
+pub fn read_unpadded<R: Read + Seek>(
+    mut staged: R,
+    piece_offset: u64,
+    offset: u64,
+    len: usize,
+) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    if len == 0 {
+        return Ok(out);
+    }
+
+    let first_block = offset / UNPADDED_BLOCK;
+    let last_block = (offset + len as u64 - 1) / UNPADDED_BLOCK;
+    let mut padded = vec![0u8; ((last_block - first_block + 1) * PADDED_BLOCK) as usize];
+
+    staged.seek(SeekFrom::Start(piece_offset + first_block * PADDED_BLOCK))?;
+    staged.read_exact(&mut padded)?;
+
+    let start = (offset - first_block * UNPADDED_BLOCK) as usize;
+    write_unpadded(&padded, &mut out, start, len)?;
+    Ok(out)
+}
+
+/// fr32 blocks unpadded at once by `UnpadReader`, 1MiB of padded bytes.
+const BLOCKS_PER_READ: u64 = 8192;
+
+/// Streams the payload bytes `offset..offset + len` of the piece whose padded
+/// data begins at `piece_offset` in `staged`, reading the fr32 blocks
+/// covering them in order.
+pub struct UnpadReader<R> {
+    staged: R,
+    /// Unpadded offset of the first byte not unpadded yet.
+    offset: u64,
+    end: u64,
+    padded: Vec<u8>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read + Seek> UnpadReader<R> {
+    pub fn new(mut staged: R, piece_offset: u64, offset: u64, len: u64) -> io::Result<Self> {
+        staged.seek(SeekFrom::Start(
+            piece_offset + offset / UNPADDED_BLOCK * PADDED_BLOCK,
+        ))?;
+        Ok(Self {
+            staged,
+            offset,
+            end: offset + len,
+            padded: Vec::new(),
+            buf: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Unpads the next blocks, leaving `offset` on a block boundary unless
+    /// the end was reached.
+    fn fill(&mut self) -> io::Result<()> {
+        let first_block = self.offset / UNPADDED_BLOCK;
+        let last_block = (self.end - 1) / UNPADDED_BLOCK;
+        let blocks = (last_block - first_block + 1).min(BLOCKS_PER_READ);
+        self.padded.resize((blocks * PADDED_BLOCK) as usize, 0);
+        self.staged.read_exact(&mut self.padded)?;
+
+        let start = self.offset - first_block * UNPADDED_BLOCK;
+        let len = ((first_block + blocks) * UNPADDED_BLOCK).min(self.end) - self.offset;
+        self.buf.clear();
+        write_unpadded(&self.padded, &mut self.buf, start as usize, len as usize)?;
+        self.offset += len;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for UnpadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            if self.offset == self.end {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use fr32::Fr32Reader;
+
+    #[test]
+    fn test_read_unpadded() {
+        let source: Vec<u8> = (0..127 * 8).map(|i| (i * 7) as u8).collect();
+        let mut staged = vec![0u8; 256];
+        Fr32Reader::new(Cursor::new(&source))
+            .read_to_end(&mut staged)
+            .expect("failed to pad source");
+
+        for (offset, len) in [(0, 10), (120, 20), (300, 254), (127 * 8 - 5, 5)] {
+            let actual = read_unpadded(Cursor::new(&staged), 256, offset as u64, len)
+                .expect("read_unpadded failed");
+            assert_eq!(actual, &source[offset..offset + len], "offset {}", offset);
+
+            let mut streamed = Vec::new();
+            UnpadReader::new(Cursor::new(&staged), 256, offset as u64, len as u64)
+                .and_then(|mut r| r.read_to_end(&mut streamed))
+                .expect("UnpadReader failed");
+            assert_eq!(streamed, actual, "offset {}", offset);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Fast retrieval copies of the staged files: their fr32-unpadded bytes,
+//! teed from the sources while staging into `<staged>.unpadded`, so that
+//! retrieval setups keeping such a copy don't read the sources again.
+//!
+//! The copy mirrors the data of the staged file at unpadded offsets, the
+//! alignment between the pieces is left as holes.
+
+use std::{
+    fs,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use filecoin_proofs::{PaddedBytesAmount, UnpaddedBytesAmount};
+use tracing::info;
+
+use crate::{config, redact};
+
+pub struct UnpaddedCopy {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl UnpaddedCopy {
+    pub fn path_for(staged: &Path) -> PathBuf {
+        let mut path = staged.as_os_str().to_owned();
+        path.push(".unpadded");
+        PathBuf::from(path)
+    }
+
+    /// Opens the copy of the staged file `staged` if configured, keeping the
+    /// pieces copied by a previous run so that reused pieces stay in it.
+    pub fn open(staged: &Path) -> Result<Option<Self>> {
+        let config = config::global();
+        if !config.unpadded_copy {
+            return Ok(None);
+        }
+        ensure!(
+            config.encryption.is_none(),
+            "unpadded copies hold the payloads, not the encrypted ones staged"
+        );
+        let path = Self::path_for(staged);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("open unpadded copy {}", path.display()))?;
+        Ok(Some(Self { path, file }))
+    }
+
+    /// A writer of the source bytes of a piece of `piece_size` staged at the
+    /// padded `offset`.
+    pub fn piece(&self, offset: u64, piece_size: UnpaddedBytesAmount) -> Result<PieceCopy> {
+        let mut file = self.file.try_clone().context("clone unpadded copy")?;
+        file.seek(SeekFrom::Start(u64::from(UnpaddedBytesAmount::from(
+            PaddedBytesAmount(offset),
+        ))))
+        .context("seek unpadded copy")?;
+        Ok(PieceCopy {
+            file: Some(file),
+            left: u64::from(piece_size),
+        })
+    }
+
+    /// Completes the copy of a staged file holding `data_len` padded bytes.
+    pub fn finish(self, data_len: u64) -> Result<()> {
+        let len = u64::from(UnpaddedBytesAmount::from(PaddedBytesAmount(data_len)));
+        self.file
+            .set_len(len)
+            .and_then(|_| self.file.sync_all())
+            .with_context(|| format!("sync unpadded copy {}", self.path.display()))?;
+        info!(path = %redact::path(&self.path).display(), len, "unpadded copy written");
+        Ok(())
+    }
+}
+
+/// Writes the source bytes of a piece to the copy, the bytes past the piece
+/// size and all bytes without a copy are dropped.
+pub struct PieceCopy {
+    file: Option<fs::File>,
+    left: u64,
+}
+
+impl PieceCopy {
+    pub fn of(
+        copy: Option<&UnpaddedCopy>,
+        offset: u64,
+        piece_size: UnpaddedBytesAmount,
+    ) -> Result<Self> {
+        match copy {
+            Some(copy) => copy.piece(offset, piece_size),
+            None => Ok(PieceCopy {
+                file: None,
+                left: 0,
+            }),
+        }
+    }
+}
+
+impl Write for PieceCopy {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.left.min(buf.len() as u64) as usize;
+        if let Some(file) = &mut self.file {
+            file.write_all(&buf[..n])?;
+        }
+        self.left -= n as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use add_piece::{chunk_sink::ChunkRootSink, dedup::ChunkRoot, AddPieceOptions};
+use anyhow::{Context, Result};
+use filecoin_proofs::PieceInfo;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// An external process verifying the chunk roots of every piece as they are
+/// computed.
+///
+/// Each message is a JSON object: one `chunk_root` event per chunk, then a
+/// `piece` event carrying the commitment (or the error) of the piece.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkVerifierConfig {
+    pub endpoint: VerifierEndpoint,
+
+    /// Fail the piece when a message cannot be delivered, instead of logging
+    /// the failure and no longer streaming the piece.
+    #[serde(default)]
+    pub required: bool,
+
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifierEndpoint {
+    /// Messages are written as JSON lines over one connection per piece.
+    /// Unix only, elsewhere every delivery fails.
+    UnixSocket(PathBuf),
+    /// Every message is posted to this URL.
+    Http(String),
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Message<'a> {
+    ChunkRoot {
+        staged_file: &'a Path,
+        piece: usize,
+        source: &'a str,
+        chunk: usize,
+        root: String,
+    },
+    Piece {
+        staged_file: &'a Path,
+        piece: usize,
+        source: &'a str,
+        commitment: Option<String>,
+        error: Option<String>,
+    },
+}
+
+/// Returns the options to add piece number `piece` of `staged_file` with,
+/// streaming its chunk roots to the verifier when one is configured.
+pub fn stream_piece(
+    config: Option<&ChunkVerifierConfig>,
+    options: &AddPieceOptions,
+    staged_file: &Path,
+    piece: usize,
+    source: &str,
+) -> (AddPieceOptions, Option<Arc<ChunkStream>>) {
+    let mut options = options.clone();
+    let stream = config.map(|c| ChunkStream::new(c, staged_file, piece, source));
+    if let Some(s) = &stream {
+        options.chunk_sink = Some(s.clone());
+    }
+    (options, stream)
+}
+
+/// Streams the chunk roots of one piece to the verifier.
+#[derive(Debug)]
+pub struct ChunkStream {
+    config: ChunkVerifierConfig,
+    staged_file: PathBuf,
+    piece: usize,
+    source: String,
+    state: Mutex<StreamState>,
+}
+
+#[derive(Debug, Default)]
+struct StreamState {
+    socket: Option<Socket>,
+    /// Set once a delivery failed for a stream which is not required.
+    given_up: bool,
+}
+
+impl ChunkStream {
+    pub fn new(
+        config: &ChunkVerifierConfig,
+        staged_file: &Path,
+        piece: usize,
+        source: &str,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            config: config.clone(),
+            staged_file: staged_file.to_path_buf(),
+            piece,
+            source: source.to_string(),
+            state: Mutex::new(StreamState::default()),
+        })
+    }
+
+    /// Reports the outcome of the piece and closes the stream, an error of the
+    /// piece takes precedence over a failed delivery.
+    pub fn finish(&self, result: Result<PieceInfo>) -> Result<PieceInfo> {
+        let msg = Message::Piece {
+            staged_file: &self.staged_file,
+            piece: self.piece,
+            source: &self.source,
+            commitment: result.as_ref().ok().map(|p| hex::encode(p.commitment)),
+            error: result.as_ref().err().map(|e| format!("{:?}", e)),
+        };
+        let delivered = self.deliver(&msg);
+        self.state.lock().expect("lock stream state").socket = None;
+
+        let piece_info = result?;
+        delivered?;
+        Ok(piece_info)
+    }
+
+    fn deliver(&self, msg: &Message) -> Result<()> {
+        let mut state = self.state.lock().expect("lock stream state");
+        if state.given_up {
+            return Ok(());
+        }
+
+        let res = self.send(&mut state, msg);
+        match res {
+            Err(e) if !self.config.required => {
+                warn!(
+                    piece = self.piece,
+                    "stop streaming chunk roots to the verifier: {:?}", e
+                );
+                state.given_up = true;
+                state.socket = None;
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    fn send(&self, state: &mut StreamState, msg: &Message) -> Result<()> {
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        match &self.config.endpoint {
+            VerifierEndpoint::UnixSocket(path) => {
+                if state.socket.is_none() {
+                    state.socket = Some(connect(path, timeout)?);
+                }
+                let socket = state.socket.as_mut().expect("socket connected");
+
+                let mut line = serde_json::to_vec(msg)?;
+                line.push(b'\n');
+                socket.write_all(&line).context("write to verifier socket")
+            }
+            VerifierEndpoint::Http(url) => {
+                ureq::post(url)
+                    .timeout(timeout)
+                    .set("Content-Type", "application/json")
+                    .send_string(&serde_json::to_string(msg)?)
+                    .with_context(|| format!("post to verifier: {}", url))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+type Socket = std::os::unix::net::UnixStream;
+
+/// Never connected, `connect` fails outside unix.
+#[cfg(not(unix))]
+type Socket = std::fs::File;
+
+#[cfg(unix)]
+fn connect(path: &Path, timeout: Duration) -> Result<Socket> {
+    let socket = Socket::connect(path)
+        .with_context(|| format!("connect to verifier socket: {}", path.display()))?;
+    socket.set_write_timeout(Some(timeout))?;
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn connect(path: &Path, _timeout: Duration) -> Result<Socket> {
+    anyhow::bail!(
+        "connect to verifier socket: {}: unix sockets are not supported on this platform",
+        path.display()
+    )
+}
+
+impl ChunkRootSink for ChunkStream {
+    fn chunk_root(&self, index: usize, root: &ChunkRoot) -> io::Result<()> {
+        let msg = Message::ChunkRoot {
+            staged_file: &self.staged_file,
+            piece: self.piece,
+            source: &self.source,
+            chunk: index,
+            root: hex::encode(root),
+        };
+        self.deliver(&msg).map_err(io::Error::other)
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Flags pieces read slower than a floor for a while, which tells a hung
+/// source from a piece that is merely large.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Payload bytes per second below which a piece is considered stalled.
+    pub min_bytes_per_sec: u64,
+    /// How long the throughput may stay below the floor before the piece is
+    /// flagged.
+    pub window_secs: u64,
+    /// Fail the piece once it is flagged rather than only warning.
+    #[serde(default)]
+    pub abort: bool,
+}
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct Progress {
+    bytes: AtomicU64,
+    /// Set when the piece must be aborted.
+    stalled: AtomicBool,
+    done: AtomicBool,
+}
+
+/// Reads the source of a piece while a thread watches its throughput.
+pub struct Watched<R> {
+    inner: R,
+    progress: Arc<Progress>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Watches the reads of `source`, the source of `piece`, if a watchdog is
+/// configured.
+pub fn watch<R: Read>(config: Option<&WatchdogConfig>, piece: &str, source: R) -> Watched<R> {
+    let progress = Arc::new(Progress::default());
+    let thread = config.map(|config| {
+        let (config, piece, progress) = (config.clone(), piece.to_string(), progress.clone());
+        thread::spawn(move || run(&config, &piece, &progress))
+    });
+    Watched {
+        inner: source,
+        progress,
+        thread,
+    }
+}
+
+fn run(config: &WatchdogConfig, piece: &str, progress: &Progress) {
+    let window = Duration::from_secs(config.window_secs.max(1));
+    let start = Instant::now();
+    let mut samples = VecDeque::new();
+    let mut flagged = false;
+
+    while !progress.done.load(Ordering::Acquire) {
+        let now = Instant::now();
+        let bytes = progress.bytes.load(Ordering::Relaxed);
+        samples.push_back((now, bytes));
+        while samples.len() > 1 && now - samples[1].0 >= window {
+            samples.pop_front();
+        }
+
+        let (since, from) = samples[0];
+        let elapsed = now - since;
+        if elapsed >= window {
+            let bytes_per_sec = (bytes - from) as f64 / elapsed.as_secs_f64();
+            let slow = bytes_per_sec < config.min_bytes_per_sec as f64;
+            if slow && !flagged {
+                warn!(
+                    piece,
+                    bytes_read = bytes,
+                    bytes_per_sec,
+                    window_secs = window.as_secs(),
+                    running_secs = start.elapsed().as_secs(),
+                    hung = bytes == from,
+                    abort = config.abort,
+                    "piece stalled"
+                );
+                if config.abort {
+                    progress.stalled.store(true, Ordering::Release);
+                    return;
+                }
+            } else if !slow && flagged {
+                info!(piece, bytes_read = bytes, bytes_per_sec, "piece recovered");
+            }
+            flagged = slow;
+        }
+        thread::park_timeout(SAMPLE_INTERVAL);
+    }
+}
+
+impl<R> Watched<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R> Drop for Watched<R> {
+    fn drop(&mut self) {
+        self.progress.done.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<R: Read> Read for Watched<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.progress.stalled.load(Ordering::Acquire) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "piece aborted by the watchdog, its source stalled",
+            ));
+        }
+        let n = self.inner.read(buf)?;
+        self.progress.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
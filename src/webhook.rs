@@ -0,0 +1,398 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Condvar, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use add_piece::{
+    piece_cid,
+    piece_events::{PieceEventSink, PieceRef},
+};
+use anyhow::Result;
+use filecoin_proofs::PieceInfo;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{iostats::IoReport, redact};
+
+/// A webhook fired when a task finishes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+
+    /// Request body template. The placeholders `{{status}}`, `{{staged_file}}`,
+    /// `{{result}}`, `{{error}}`, `{{io}}` and `{{event}}` are substituted by
+    /// their JSON values, strings included quoted; the event JSON is posted
+    /// as-is when no template is given.
+    #[serde(default)]
+    pub template: Option<String>,
+
+    #[serde(default = "default_true")]
+    pub on_success: bool,
+
+    #[serde(default = "default_true")]
+    pub on_failure: bool,
+
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// Also fire for every piece staged or failed, with a piece event posted
+    /// as-is.
+    #[serde(default)]
+    pub pieces: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Success,
+    Failure,
+}
+
+impl TaskStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TaskStatus::Success => "success",
+            TaskStatus::Failure => "failure",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TaskEvent<'a> {
+    status: TaskStatus,
+    staged_file: &'a Path,
+    result: Option<&'a [PieceInfo]>,
+    error: Option<String>,
+    io: &'a IoReport,
+}
+
+impl TaskEvent<'_> {
+    fn render(&self, template: Option<&str>) -> Result<String> {
+        let event = serde_json::to_string(self)?;
+        let mut rest = match template {
+            Some(t) => t,
+            None => return Ok(event),
+        };
+
+        // substitute in a single pass, so that placeholders inside the
+        // substituted values are left alone
+        let mut body = String::with_capacity(rest.len() + event.len());
+        while let Some(start) = rest.find("{{") {
+            body.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = match rest.find("}}") {
+                Some(end) => end + 2,
+                None => break,
+            };
+            match self.placeholder(&rest[2..end - 2], &event)? {
+                Some(value) => body.push_str(&value),
+                None => body.push_str(&rest[..end]),
+            }
+            rest = &rest[end..];
+        }
+        body.push_str(rest);
+        Ok(body)
+    }
+
+    /// The JSON value of the placeholder `name`, if it is one.
+    fn placeholder(&self, name: &str, event: &str) -> Result<Option<String>> {
+        let value = match name {
+            "status" => serde_json::to_string(&self.status)?,
+            "staged_file" => serde_json::to_string(&self.staged_file)?,
+            "result" => serde_json::to_string(&self.result)?,
+            "error" => serde_json::to_string(&self.error)?,
+            "io" => serde_json::to_string(self.io)?,
+            "event" => event.to_string(),
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Fires the configured webhooks for a finished task, with its staged file
+/// and error redacted.
+///
+/// Delivery is best effort: the requests are sent in the background, failures
+/// are logged and never affect the task result.
+pub fn notify(
+    hooks: &[WebhookConfig],
+    staged_file: &Path,
+    result: &Result<Vec<PieceInfo>>,
+    io: &IoReport,
+) {
+    if hooks.is_empty() {
+        return;
+    }
+
+    let staged_file = &redact::path(staged_file);
+    let event = match result {
+        Ok(piece_infos) => TaskEvent {
+            status: TaskStatus::Success,
+            staged_file,
+            result: Some(piece_infos),
+            error: None,
+            io,
+        },
+        Err(e) => TaskEvent {
+            status: TaskStatus::Failure,
+            staged_file,
+            result: None,
+            error: Some(redact::redact(&format!("{:#}", e)).into_owned()),
+            io,
+        },
+    };
+
+    for hook in hooks {
+        let wanted = match event.status {
+            TaskStatus::Success => hook.on_success,
+            TaskStatus::Failure => hook.on_failure,
+        };
+        if !wanted {
+            continue;
+        }
+
+        match event.render(hook.template.as_deref()) {
+            Ok(body) => queue(hook, body, format!("task {}", event.status.as_str())),
+            Err(e) => warn!(url = hook.url.as_str(), "failed to render webhook: {:?}", e),
+        }
+    }
+}
+
+/// A webhook request waiting to be sent.
+struct Delivery {
+    url: String,
+    timeout: Duration,
+    body: String,
+    /// What is delivered, for the logs.
+    what: String,
+}
+
+/// The queue of the thread sending the webhooks, one at a time in the order
+/// they were fired, and how many of them are not sent yet.
+struct Sender {
+    queue: mpsc::Sender<Delivery>,
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+static SENDER: OnceLock<Sender> = OnceLock::new();
+
+fn queue(hook: &WebhookConfig, body: String, what: String) {
+    let sender = SENDER.get_or_init(|| {
+        let (queue, deliveries) = mpsc::channel::<Delivery>();
+        let pending = Arc::new((Mutex::new(0), Condvar::new()));
+        let sent = pending.clone();
+        thread::Builder::new()
+            .name("webhooks".to_string())
+            .spawn(move || {
+                for delivery in deliveries {
+                    send(&delivery);
+                    let (count, cvar) = &*sent;
+                    *count.lock().expect("webhook queue poisoned") -= 1;
+                    cvar.notify_all();
+                }
+            })
+            .expect("spawn webhook thread");
+        Sender { queue, pending }
+    });
+
+    *sender.pending.0.lock().expect("webhook queue poisoned") += 1;
+    let delivery = Delivery {
+        url: hook.url.clone(),
+        timeout: Duration::from_secs(hook.timeout_secs),
+        body,
+        what,
+    };
+    sender
+        .queue
+        .send(delivery)
+        .expect("webhook thread never exits");
+}
+
+/// Waits for the webhooks fired so far to be sent, for a process to deliver
+/// them before it exits.
+pub fn flush() {
+    let sender = match SENDER.get() {
+        Some(sender) => sender,
+        None => return,
+    };
+    let (count, cvar) = &*sender.pending;
+    let count = count.lock().expect("webhook queue poisoned");
+    drop(
+        cvar.wait_while(count, |count| *count > 0)
+            .expect("webhook queue poisoned"),
+    );
+}
+
+fn send(delivery: &Delivery) {
+    let res = ureq::post(&delivery.url)
+        .timeout(delivery.timeout)
+        .set("Content-Type", "application/json")
+        .send_string(&delivery.body);
+    match res {
+        Ok(_) => debug!(
+            url = delivery.url.as_str(),
+            what = delivery.what.as_str(),
+            "webhook delivered"
+        ),
+        Err(e) => warn!(
+            url = delivery.url.as_str(),
+            what = delivery.what.as_str(),
+            "webhook delivery failed: {:?}",
+            e
+        ),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PieceEvent<'a> {
+    status: TaskStatus,
+    staged_file: &'a Path,
+    index: usize,
+    source: &'a str,
+    piece_size: u64,
+    piece_cid: Option<String>,
+    error: Option<String>,
+}
+
+/// Fires the webhooks configured with `pieces` for every piece of a task
+/// staging `staged_file`, as a piece event sink.
+#[derive(Debug)]
+pub struct PieceWebhooks {
+    hooks: Vec<WebhookConfig>,
+    staged_file: PathBuf,
+}
+
+impl PieceWebhooks {
+    pub fn new(hooks: &[WebhookConfig], staged_file: &Path) -> Option<Self> {
+        let hooks: Vec<_> = hooks.iter().filter(|h| h.pieces).cloned().collect();
+        (!hooks.is_empty()).then(|| Self {
+            hooks,
+            staged_file: redact::path(staged_file),
+        })
+    }
+
+    fn notify(&self, event: PieceEvent) {
+        let body = match serde_json::to_string(&event) {
+            Ok(body) => body,
+            Err(e) => return warn!(err = ?e, "failed to serialize piece event"),
+        };
+        for hook in &self.hooks {
+            let wanted = match event.status {
+                TaskStatus::Success => hook.on_success,
+                TaskStatus::Failure => hook.on_failure,
+            };
+            if !wanted {
+                continue;
+            }
+
+            let what = format!("piece {} {}", event.index, event.status.as_str());
+            queue(hook, body.clone(), what);
+        }
+    }
+}
+
+impl PieceEventSink for PieceWebhooks {
+    fn verified(&self, piece: &PieceRef, piece_info: &PieceInfo) {
+        self.notify(PieceEvent {
+            status: TaskStatus::Success,
+            staged_file: &self.staged_file,
+            index: piece.index,
+            source: &redact::redact(&piece.source),
+            piece_size: piece.piece_size,
+            piece_cid: Some(piece_cid::encode(&piece_info.commitment)),
+            error: None,
+        });
+    }
+
+    fn failed(&self, piece: &PieceRef, error: &anyhow::Error) {
+        self.notify(PieceEvent {
+            status: TaskStatus::Failure,
+            staged_file: &self.staged_file,
+            index: piece.index,
+            source: &redact::redact(&piece.source),
+            piece_size: piece.piece_size,
+            piece_cid: None,
+            error: Some(redact::redact(&format!("{:#}", error)).into_owned()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use crate::mock_http::{MockServer, Response};
+
+    #[test]
+    fn test_notify_in_background() {
+        let server = MockServer::start(|_| {
+            thread::sleep(Duration::from_millis(300));
+            Response::new(200)
+        });
+        let hook = WebhookConfig {
+            url: server.url().to_string(),
+            template: None,
+            on_success: true,
+            on_failure: true,
+            timeout_secs: 10,
+            pieces: false,
+        };
+        let err = anyhow::anyhow!("disk full").context("write piece");
+
+        let started = Instant::now();
+        notify(
+            &[hook],
+            Path::new("/staged"),
+            &Err(err),
+            &IoReport::default(),
+        );
+        assert!(started.elapsed() < Duration::from_millis(300));
+        flush();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let event: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(event["status"], "failure");
+        assert_eq!(event["error"], "write piece: disk full");
+    }
+
+    #[test]
+    fn test_render_escapes_values() {
+        let io = IoReport::default();
+        let event = TaskEvent {
+            status: TaskStatus::Failure,
+            staged_file: Path::new("/staged/\"sector\""),
+            result: None,
+            error: Some("read \"{{status}}\"\nfailed".to_string()),
+            io: &io,
+        };
+
+        let body = event
+            .render(Some(r#"{"ok": false, "status": {{status}}, "file": {{staged_file}}, "error": {{error}}, "unknown": "{{other}}"}"#))
+            .expect("render failed");
+        let body: serde_json::Value = serde_json::from_str(&body).expect("invalid json");
+        assert_eq!(body["status"], "failure");
+        assert_eq!(body["file"], "/staged/\"sector\"");
+        assert_eq!(body["error"], "read \"{{status}}\"\nfailed");
+        assert_eq!(body["unknown"], "{{other}}");
+
+        let event: serde_json::Value =
+            serde_json::from_str(&event.render(None).expect("render failed"))
+                .expect("invalid json");
+        assert_eq!(event["error"], "read \"{{status}}\"\nfailed");
+    }
+}
@@ -0,0 +1,155 @@
+//! Sources whose reads are interrupted or would block, as socket backed
+//! readers in non-blocking mode are.
+//!
+//! `add_piece` reads its source through a `RetryingReader`, so that such
+//! readers can be passed as they are: interrupted reads are retried right
+//! away, and reads which would block are retried after a pause growing up to
+//! `max_backoff_ms` while the source has no data.
+
+use std::io::{self, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+/// How long `add_piece` waits for a source which would block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WouldBlockWait {
+    /// Pause after the first read which would block, doubled on every one in
+    /// a row.
+    pub initial_backoff_us: u64,
+    /// Longest pause between two reads.
+    pub max_backoff_ms: u64,
+    /// Fails the read with `TimedOut` once the source would block for this
+    /// long, waits as long as it takes if unset.
+    pub timeout_ms: Option<u64>,
+}
+
+impl Default for WouldBlockWait {
+    fn default() -> Self {
+        Self {
+            initial_backoff_us: 100,
+            max_backoff_ms: 50,
+            timeout_ms: None,
+        }
+    }
+}
+
+/// Retries the reads of `inner` which are interrupted or would block.
+pub struct RetryingReader<R> {
+    inner: R,
+    wait: WouldBlockWait,
+}
+
+impl<R: Read> RetryingReader<R> {
+    pub fn new(inner: R, wait: WouldBlockWait) -> Self {
+        Self { inner, wait }
+    }
+}
+
+impl<R: Read> Read for RetryingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max_backoff = Duration::from_millis(self.wait.max_backoff_ms);
+        let mut backoff = Duration::from_micros(self.wait.initial_backoff_us).min(max_backoff);
+        let mut blocked_since = None;
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let since = *blocked_since.get_or_insert_with(Instant::now);
+                    if let Some(timeout) = self.wait.timeout_ms.map(Duration::from_millis) {
+                        let blocked = since.elapsed();
+                        if blocked >= timeout {
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                format!("source would block for {:?}", blocked),
+                            ));
+                        }
+                        backoff = backoff.min(timeout - blocked);
+                    }
+                    trace!("source would block, retrying in {:?}", backoff);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).max(Duration::from_micros(1)).min(max_backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Yields its bytes one at a time, failing every other read.
+    struct Flaky {
+        data: Vec<u8>,
+        pos: usize,
+        calls: usize,
+        kind: io::ErrorKind,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls % 2 == 1 || self.kind == io::ErrorKind::Other {
+                return Err(self.kind.into());
+            }
+            if self.pos == self.data.len() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    fn flaky(kind: io::ErrorKind) -> Flaky {
+        Flaky {
+            data: (0..64).collect(),
+            pos: 0,
+            calls: 0,
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_retrying_reader() {
+        let wait = WouldBlockWait {
+            initial_backoff_us: 1,
+            max_backoff_ms: 1,
+            timeout_ms: None,
+        };
+        for kind in [io::ErrorKind::Interrupted, io::ErrorKind::WouldBlock] {
+            let mut read = Vec::new();
+            RetryingReader::new(flaky(kind), wait)
+                .read_to_end(&mut read)
+                .expect("read failed");
+            assert_eq!(read, (0..64).collect::<Vec<u8>>());
+        }
+
+        let err = RetryingReader::new(flaky(io::ErrorKind::Other), wait)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+
+        let blocked = WouldBlockWait {
+            timeout_ms: Some(5),
+            ..wait
+        };
+        let err = RetryingReader::new(Blocked, blocked)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    struct Blocked;
+
+    impl Read for Blocked {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{Scope, ScopedJoinHandle};
+
+use serde::{Deserialize, Serialize};
+
+/// Sizing of the queue between a `WriteBehind` and its target.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriteBehindConfig {
+    /// Size of each buffer handed to the writer thread.
+    pub buffer_size: usize,
+    /// Upper bound of the bytes waiting to be written.
+    pub max_queued_bytes: usize,
+}
+
+impl Default for WriteBehindConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 8 << 20,
+            max_queued_bytes: 256 << 20,
+        }
+    }
+}
+
+enum Message {
+    Data(Vec<u8>),
+    Flush(SyncSender<io::Result<()>>),
+}
+
+/// Writes to a target on a scoped thread through a bounded queue, so that a
+/// stalled target only blocks the producer once `max_queued_bytes` are queued.
+///
+/// Errors of the target are reported by the next `write`/`flush` call after
+/// they happen; call `finish` to make sure everything reached the target.
+pub struct WriteBehind<'scope> {
+    tx: Option<SyncSender<Message>>,
+    buf: Vec<u8>,
+    buffer_size: usize,
+    handle: Option<ScopedJoinHandle<'scope, io::Result<()>>>,
+}
+
+impl<'scope> WriteBehind<'scope> {
+    pub fn scoped<'env, W>(
+        scope: &'scope Scope<'scope, 'env>,
+        target: W,
+        config: WriteBehindConfig,
+    ) -> Self
+    where
+        W: Write + Send + 'scope,
+    {
+        let buffer_size = config.buffer_size.max(1);
+        let depth = (config.max_queued_bytes / buffer_size).max(1);
+        let (tx, rx) = mpsc::sync_channel(depth);
+        let handle = scope.spawn(move || drain(target, rx));
+
+        Self {
+            tx: Some(tx),
+            buf: Vec::with_capacity(buffer_size),
+            buffer_size,
+            handle: Some(handle),
+        }
+    }
+
+    /// Writes out everything queued and waits for the writer thread.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.send_buffered()?;
+        self.flush()?;
+        self.tx.take();
+        self.join()
+    }
+
+    fn send(&mut self, msg: Message) -> io::Result<()> {
+        let sent = match &self.tx {
+            Some(tx) => tx.send(msg).is_ok(),
+            None => false,
+        };
+
+        if sent {
+            return Ok(());
+        }
+
+        // the writer thread stopped, which only happens on error
+        self.tx.take();
+        self.join()
+            .and(Err(io::Error::other("write-behind thread stopped")))
+    }
+
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let buf = std::mem::replace(&mut self.buf, Vec::with_capacity(self.buffer_size));
+        self.send(Message::Data(buf))
+    }
+
+    fn join(&mut self) -> io::Result<()> {
+        match self.handle.take() {
+            Some(h) => h
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("write-behind thread panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn drain<W: Write>(mut target: W, rx: Receiver<Message>) -> io::Result<()> {
+    for msg in rx {
+        match msg {
+            Message::Data(buf) => target.write_all(&buf)?,
+            Message::Flush(reply) => {
+                let res = target.flush();
+                let failed = res.is_err();
+                let _ = reply.send(res);
+                if failed {
+                    return Err(io::Error::other("flush failed"));
+                }
+            }
+        }
+    }
+
+    target.flush()
+}
+
+impl Write for WriteBehind<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(self.buffer_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() >= self.buffer_size {
+            self.send_buffered()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()?;
+
+        let (reply, done) = mpsc::sync_channel(1);
+        self.send(Message::Flush(reply))?;
+        match done.recv() {
+            Ok(res) => res,
+            Err(_) => self
+                .join()
+                .and(Err(io::Error::other("write-behind thread stopped"))),
+        }
+    }
+}
+
+impl Drop for WriteBehind<'_> {
+    fn drop(&mut self) {
+        let _ = self.send_buffered();
+        self.tx.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk on fire"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_behind() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let config = WriteBehindConfig {
+            buffer_size: 333,
+            max_queued_bytes: 1000,
+        };
+
+        let mut target = Vec::new();
+        thread::scope(|s| {
+            let mut writer = WriteBehind::scoped(s, &mut target, config);
+            for chunk in data.chunks(777) {
+                writer.write_all(chunk).expect("write failed");
+            }
+            writer.finish().expect("finish failed");
+        });
+
+        assert_eq!(target, data);
+    }
+
+    #[test]
+    fn test_write_behind_error() {
+        thread::scope(|s| {
+            let mut writer = WriteBehind::scoped(s, FailingWriter, WriteBehindConfig::default());
+            let _ = writer.write_all(&[0u8; 16]);
+            let err = writer.finish().expect_err("should fail");
+            assert!(err.to_string().contains("disk on fire"));
+        });
+    }
+}
@@ -0,0 +1,176 @@
+//! Writing of the zero filled regions of staged files: the alignment around
+//! pieces, committed capacity sectors and the zero pieces completing sectors.
+//!
+//! Regions written through a plain `Write` are always written out, from a
+//! static zero buffer rather than a fresh one per region. Regions of a file
+//! may instead be left as a hole when they extend it, or zeroed by the
+//! filesystem with `fallocate(FALLOC_FL_ZERO_RANGE)` on Linux.
+
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Largest write of zeros issued at once.
+pub const MAX_BUFFER_SIZE: usize = 16 << 20;
+
+static ZEROS: [u8; MAX_BUFFER_SIZE] = [0; MAX_BUFFER_SIZE];
+
+/// How the zero filled regions of a file are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZeroFillMode {
+    /// `sparse` where the target handles holes, `zero_range` otherwise.
+    #[default]
+    Auto,
+    /// Extends the file over the region, leaving a hole. Regions which
+    /// don't extend the file are zeroed with `zero_range`.
+    Sparse,
+    /// Has the filesystem zero the region, which it usually does by marking
+    /// its blocks unwritten. Falls back to `buffered` where unsupported.
+    ZeroRange,
+    /// Writes the zeros out.
+    Buffered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ZeroFillConfig {
+    pub mode: ZeroFillMode,
+    /// Size of the writes of zeros, up to `MAX_BUFFER_SIZE`.
+    pub buffer_size: usize,
+}
+
+impl Default for ZeroFillConfig {
+    fn default() -> Self {
+        Self {
+            mode: ZeroFillMode::Auto,
+            buffer_size: 4 << 20,
+        }
+    }
+}
+
+impl ZeroFillConfig {
+    /// Writes `len` zeros to `target`.
+    pub fn write<W: Write + ?Sized>(&self, target: &mut W, mut len: u64) -> io::Result<()> {
+        let buffer_size = self.buffer_size.clamp(1, MAX_BUFFER_SIZE) as u64;
+        while len > 0 {
+            let n = len.min(buffer_size);
+            target.write_all(&ZEROS[..n as usize])?;
+            len -= n;
+        }
+        Ok(())
+    }
+
+    /// Zeroes `len` bytes of `file` from its current offset, which is left
+    /// after them, on a target which handles holes if `sparse_files`.
+    /// Zeros written out go through `buffered`, which writes at the offset of
+    /// `file`, and are flushed. Returns the mode the region was zeroed with.
+    pub fn fill<W: Write>(
+        &self,
+        mut file: &File,
+        len: u64,
+        sparse_files: bool,
+        mut buffered: W,
+    ) -> io::Result<ZeroFillMode> {
+        let start = file.stream_position()?;
+        let end = start + len;
+        let mode = match self.mode {
+            ZeroFillMode::Auto if sparse_files => ZeroFillMode::Sparse,
+            ZeroFillMode::Auto => ZeroFillMode::ZeroRange,
+            mode => mode,
+        };
+
+        if mode == ZeroFillMode::Sparse && file.metadata()?.len() <= start {
+            file.set_len(end)?;
+            file.seek(SeekFrom::Start(end))?;
+            return Ok(ZeroFillMode::Sparse);
+        }
+        if mode != ZeroFillMode::Buffered && zero_range(file, start, len)? {
+            file.seek(SeekFrom::Start(end))?;
+            return Ok(ZeroFillMode::ZeroRange);
+        }
+        self.write(&mut buffered, len)?;
+        buffered.flush()?;
+        Ok(ZeroFillMode::Buffered)
+    }
+}
+
+/// Zeroes `len` bytes of `file` from `offset`, extending it if needed.
+/// Returns false if the filesystem can't.
+#[cfg(target_os = "linux")]
+fn zero_range(file: &File, offset: u64, len: u64) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(true);
+    }
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_ZERO_RANGE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if res == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn zero_range(_file: &File, _offset: u64, _len: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    #[test]
+    fn test_zero_fill_modes() {
+        let config = ZeroFillConfig {
+            buffer_size: 1000,
+            ..Default::default()
+        };
+        let mut written = Vec::new();
+        config.write(&mut written, 4096).expect("write failed");
+        assert_eq!(written, vec![0u8; 4096]);
+
+        for mode in [
+            ZeroFillMode::Auto,
+            ZeroFillMode::Sparse,
+            ZeroFillMode::ZeroRange,
+            ZeroFillMode::Buffered,
+        ] {
+            let config = ZeroFillConfig { mode, ..config };
+            let mut file = tempfile::tempfile().expect("tempfile failed");
+            file.write_all(&[1u8; 100]).expect("write failed");
+            // a region over stale bytes, then one extending the file
+            file.write_all(&[2u8; 100]).expect("write failed");
+            file.seek(SeekFrom::Start(100)).expect("seek failed");
+            config.fill(&file, 100, true, &file).expect("fill failed");
+            let used = config.fill(&file, 5000, true, &file).expect("fill failed");
+            assert_eq!(file.stream_position().unwrap(), 5200, "{:?}", mode);
+            if matches!(mode, ZeroFillMode::Auto | ZeroFillMode::Sparse) {
+                assert_eq!(used, ZeroFillMode::Sparse);
+            }
+
+            let mut content = Vec::new();
+            file.seek(SeekFrom::Start(0)).expect("seek failed");
+            file.read_to_end(&mut content).expect("read failed");
+            assert_eq!(content.len(), 5200, "{:?}", mode);
+            assert!(content[..100].iter().all(|b| *b == 1), "{:?}", mode);
+            assert!(content[100..].iter().all(|b| *b == 0), "{:?}", mode);
+        }
+    }
+}
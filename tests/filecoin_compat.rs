@@ -0,0 +1,148 @@
+//! Compares the staged bytes and `PieceInfo`s produced by this crate with the
+//! ones of `filecoin_proofs` on random piece sets.
+//!
+//! Gated behind the `compat-tests` feature as it is long running:
+//!
+//! ```text
+//! cargo test --release --features compat-tests --test filecoin_compat
+//! ```
+//!
+//! Each sector size is exercised for `ADD_PIECE_COMPAT_SECS` seconds (10 by
+//! default). Failures report their seed, which `ADD_PIECE_COMPAT_SEED` replays.
+
+#![cfg(feature = "compat-tests")]
+
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use filecoin_proofs::{
+    pieces::sum_piece_bytes_with_alignment, PaddedBytesAmount, UnpaddedBytesAmount,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use vc_processors::fil_proofs::RegisteredSealProof;
+
+const MAX_PIECES: usize = 8;
+
+#[test]
+fn compat_2kib() {
+    run(RegisteredSealProof::StackedDrg2KiBV1_1);
+}
+
+#[test]
+fn compat_8mib() {
+    run(RegisteredSealProof::StackedDrg8MiBV1_1);
+}
+
+fn run(proof: RegisteredSealProof) {
+    if let Some(seed) = env_u64("ADD_PIECE_COMPAT_SEED") {
+        check(proof, seed);
+        return;
+    }
+
+    let budget = Duration::from_secs(env_u64("ADD_PIECE_COMPAT_SECS").unwrap_or(10));
+    let deadline = Instant::now() + budget;
+    let first_seed: u64 = rand::random();
+
+    let mut cases = 0;
+    loop {
+        check(proof, first_seed.wrapping_add(cases));
+        cases += 1;
+        if Instant::now() >= deadline {
+            break;
+        }
+    }
+    println!("{:?}: {} cases from seed {}", proof, cases, first_seed);
+}
+
+/// Adds a random piece set to a sector with both implementations.
+fn check(proof: RegisteredSealProof, seed: u64) {
+    let sector_size: u64 = proof.sector_size().into();
+    let pieces = random_pieces(&mut StdRng::seed_from_u64(seed), sector_size);
+
+    let mut ours = Vec::new();
+    let mut ours_from_slice = Vec::new();
+    let mut theirs = Vec::new();
+    let mut piece_lengths = Vec::new();
+
+    for (i, payload) in pieces.iter().enumerate() {
+        let piece_size = UnpaddedBytesAmount(payload.len() as u64);
+        let ctx = format!("seed {} piece {} of {:?}", seed, i, piece_size);
+
+        let expected =
+            filecoin_proofs::add_piece(&payload[..], &mut theirs, piece_size, &piece_lengths)
+                .expect("filecoin_proofs::add_piece failed");
+        let actual = add_piece::add_piece(&payload[..], &mut ours, piece_size, &piece_lengths)
+            .expect("add_piece failed");
+        assert_eq!(actual, expected, "add_piece, {}", ctx);
+
+        let actual = add_piece::add_piece_from_slice(
+            payload,
+            &mut ours_from_slice,
+            piece_size,
+            &piece_lengths,
+        )
+        .expect("add_piece_from_slice failed");
+        assert_eq!(actual, expected, "add_piece_from_slice, {}", ctx);
+
+        let mut expected_bytes = Vec::new();
+        let expected =
+            filecoin_proofs::write_and_preprocess(&payload[..], &mut expected_bytes, piece_size)
+                .expect("filecoin_proofs::write_and_preprocess failed");
+        let mut actual_bytes = Vec::new();
+        let actual =
+            add_piece::write_and_preprocess(proof, &payload[..], &mut actual_bytes, piece_size)
+                .expect("write_and_preprocess failed");
+        assert_eq!(actual, expected, "write_and_preprocess, {}", ctx);
+        assert!(
+            actual_bytes == expected_bytes,
+            "write_and_preprocess bytes, {}",
+            ctx
+        );
+
+        piece_lengths.push(piece_size);
+    }
+
+    assert!(ours == theirs, "add_piece staged bytes, seed {}", seed);
+    assert!(
+        ours_from_slice == theirs,
+        "add_piece_from_slice staged bytes, seed {}",
+        seed
+    );
+}
+
+/// Generates up to `MAX_PIECES` payloads fitting in a sector once aligned.
+fn random_pieces(rng: &mut StdRng, sector_size: u64) -> Vec<Vec<u8>> {
+    let sector_size: UnpaddedBytesAmount = PaddedBytesAmount(sector_size).into();
+    let max_shift = (u64::from(sector_size) / 127).trailing_zeros();
+
+    let mut lengths = Vec::new();
+    let mut pieces = Vec::new();
+    for _ in 0..rng.gen_range(1..=MAX_PIECES) {
+        let piece_size = UnpaddedBytesAmount(127 << rng.gen_range(0..=max_shift));
+        lengths.push(piece_size);
+        if sum_piece_bytes_with_alignment(&lengths) > sector_size {
+            break;
+        }
+
+        let len = u64::from(piece_size) as usize;
+        let payload = match rng.gen_range(0..4) {
+            0 => vec![0; len],
+            1 => vec![0xff; len],
+            2 => vec![rng.gen(); len],
+            _ => (0..len).map(|_| rng.gen()).collect(),
+        };
+        pieces.push(payload);
+    }
+    pieces
+}
+
+fn env_u64(key: &str) -> Option<u64> {
+    let value = env::var(key).ok()?;
+    Some(
+        value
+            .parse()
+            .unwrap_or_else(|e| panic!("invalid {}={}: {}", key, value, e)),
+    )
+}